@@ -0,0 +1,206 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Echo latency and throughput over a TCP loopback connection
+//!
+//! The server is a bare tungstenite echo, so the differences between the clients are the
+//! cost of the message conversion layer of [`WebSocket`].
+
+use std::net::SocketAddr;
+
+use async_wsocket::futures_util::{SinkExt, StreamExt};
+use async_wsocket::message::{Bytes, Message};
+use async_wsocket::native::{self, Message as TungsteniteMessage, WebSocketStream};
+use async_wsocket::{Url, WebSocket, WebSocketBuilder};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Runtime};
+use tokio_tungstenite::MaybeTlsStream;
+
+const SIZES: [usize; 3] = [16, 1024, 64 * 1024];
+
+/// Messages sent before reading the echoes
+const BATCH: usize = 100;
+
+type TungsteniteClient = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Single message echo
+fn latency(c: &mut Criterion) {
+    let rt: Runtime = runtime();
+    let url: Url = rt.block_on(echo_server());
+    let mut group = c.benchmark_group("loopback/latency");
+
+    for size in SIZES {
+        let payload: Bytes = Bytes::from(vec![0u8; size]);
+
+        let mut client: WebSocket = rt.block_on(connect(&url));
+        group.bench_with_input(
+            BenchmarkId::new("websocket", size),
+            &payload,
+            |b, payload| {
+                b.iter(|| {
+                    rt.block_on(async {
+                        client.send(Message::Binary(payload.clone())).await.unwrap();
+                        black_box(client.next().await.unwrap().unwrap());
+                    })
+                })
+            },
+        );
+
+        let mut client: TungsteniteClient = rt.block_on(connect_tungstenite(&url));
+        group.bench_with_input(
+            BenchmarkId::new("tungstenite", size),
+            &payload,
+            |b, payload| {
+                b.iter(|| {
+                    rt.block_on(async {
+                        client
+                            .send(TungsteniteMessage::Binary(payload.clone()))
+                            .await
+                            .unwrap();
+                        black_box(client.next().await.unwrap().unwrap());
+                    })
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// Messages/sec, with a batch of [`BATCH`] small messages
+fn messages(c: &mut Criterion) {
+    let rt: Runtime = runtime();
+    let url: Url = rt.block_on(echo_server());
+    let mut group = c.benchmark_group("loopback/messages");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    pipeline(&rt, &url, &mut group, SIZES[0]);
+    group.finish();
+}
+
+/// Bytes/sec, with a batch of [`BATCH`] messages for each size
+fn bytes(c: &mut Criterion) {
+    let rt: Runtime = runtime();
+    let url: Url = rt.block_on(echo_server());
+    let mut group = c.benchmark_group("loopback/bytes");
+
+    for size in SIZES {
+        group.throughput(Throughput::Bytes((size * BATCH) as u64));
+        pipeline(&rt, &url, &mut group, size);
+    }
+
+    group.finish();
+}
+
+/// Send [`BATCH`] messages, then read all the echoes
+fn pipeline(
+    rt: &Runtime,
+    url: &Url,
+    group: &mut criterion::BenchmarkGroup<'_, criterion::measurement::WallTime>,
+    size: usize,
+) {
+    let payload: Bytes = Bytes::from(vec![0u8; size]);
+
+    // A flush for every message
+    let mut client: WebSocket = rt.block_on(connect(url));
+    group.bench_with_input(
+        BenchmarkId::new("websocket", size),
+        &payload,
+        |b, payload| {
+            b.iter(|| {
+                rt.block_on(async {
+                    for _ in 0..BATCH {
+                        client.send(Message::Binary(payload.clone())).await.unwrap();
+                    }
+                    for _ in 0..BATCH {
+                        black_box(client.next().await.unwrap().unwrap());
+                    }
+                })
+            })
+        },
+    );
+
+    // A single flush for the batch
+    let mut client: WebSocket = rt.block_on(connect(url));
+    group.bench_with_input(
+        BenchmarkId::new("websocket-batched", size),
+        &payload,
+        |b, payload| {
+            b.iter(|| {
+                rt.block_on(async {
+                    client
+                        .send_all_batched((0..BATCH).map(|_| Message::Binary(payload.clone())))
+                        .await
+                        .unwrap();
+                    for _ in 0..BATCH {
+                        black_box(client.next().await.unwrap().unwrap());
+                    }
+                })
+            })
+        },
+    );
+
+    let mut client: TungsteniteClient = rt.block_on(connect_tungstenite(url));
+    group.bench_with_input(
+        BenchmarkId::new("tungstenite", size),
+        &payload,
+        |b, payload| {
+            b.iter(|| {
+                rt.block_on(async {
+                    for _ in 0..BATCH {
+                        client
+                            .send(TungsteniteMessage::Binary(payload.clone()))
+                            .await
+                            .unwrap();
+                    }
+                    for _ in 0..BATCH {
+                        black_box(client.next().await.unwrap().unwrap());
+                    }
+                })
+            })
+        },
+    );
+}
+
+/// The echo server runs on the worker threads, the clients on the bench thread
+fn runtime() -> Runtime {
+    Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+async fn echo_server() -> Url {
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut ws = native::accept(stream).await.unwrap();
+                while let Some(Ok(msg)) = ws.next().await {
+                    if msg.is_binary() && ws.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    Url::parse(&format!("ws://{addr}")).unwrap()
+}
+
+async fn connect(url: &Url) -> WebSocket {
+    WebSocketBuilder::new().connect(url).await.unwrap()
+}
+
+async fn connect_tungstenite(url: &Url) -> TungsteniteClient {
+    let (client, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .unwrap();
+    client
+}
+
+criterion_group!(benches, latency, messages, bytes);
+criterion_main!(benches);
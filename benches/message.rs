@@ -0,0 +1,66 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use async_wsocket::message::{Bytes, Message};
+use async_wsocket::native::Message as TungsteniteMessage;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const SIZES: [usize; 3] = [1024 * 1024, 4 * 1024 * 1024, 16 * 1024 * 1024];
+
+fn binary(c: &mut Criterion) {
+    let mut group = c.benchmark_group("binary");
+
+    for size in SIZES {
+        let payload: Bytes = Bytes::from(vec![0u8; size]);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        // Previous behavior: the payload was copied at every conversion
+        group.bench_with_input(BenchmarkId::new("copy", size), &payload, |b, payload| {
+            b.iter(|| black_box(payload.to_vec()))
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("from_tungstenite", size),
+            &payload,
+            |b, payload| {
+                b.iter(|| black_box(Message::from(TungsteniteMessage::Binary(payload.clone()))))
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("into_tungstenite", size),
+            &payload,
+            |b, payload| {
+                b.iter(|| black_box(TungsteniteMessage::from(Message::Binary(payload.clone()))))
+            },
+        );
+    }
+
+    group.finish();
+}
+
+fn text(c: &mut Criterion) {
+    let mut group = c.benchmark_group("text");
+
+    for size in SIZES {
+        let payload: String = "a".repeat(size);
+        let msg: TungsteniteMessage = TungsteniteMessage::text(payload.clone());
+        group.throughput(Throughput::Bytes(size as u64));
+
+        // Previous behavior: the payload was copied at every conversion
+        group.bench_with_input(BenchmarkId::new("copy", size), &payload, |b, payload| {
+            b.iter(|| black_box(payload.to_string()))
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("from_tungstenite", size),
+            &msg,
+            |b, msg| b.iter(|| black_box(Message::from(msg.clone()))),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, binary, text);
+criterion_main!(benches);
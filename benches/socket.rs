@@ -0,0 +1,86 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Per-message overhead of [`WebSocket`] and [`TypedWebSocket`] over a bare tungstenite stream
+//!
+//! [`WebSocket`] is an enum of the concrete streams, so a message costs a `match`
+//! (plus the stats, RTT and idle bookkeeping), not a vtable call.
+//! [`TypedWebSocket`] wraps a single concrete stream: only the message conversion is left.
+
+use async_wsocket::futures_util::{SinkExt, StreamExt};
+use async_wsocket::message::{Bytes, Message};
+use async_wsocket::native::{self, Message as TungsteniteMessage, TypedWebSocket, WebSocketStream};
+use async_wsocket::WebSocket;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::io::DuplexStream;
+use tokio::runtime::{Builder, Runtime};
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+/// Smaller than the in-memory pipe, so a message can be sent before being received
+const SIZES: [usize; 3] = [16, 1024, 16 * 1024];
+
+const PIPE_SIZE: usize = 64 * 1024;
+
+fn roundtrip(c: &mut Criterion) {
+    let rt: Runtime = Builder::new_current_thread().enable_all().build().unwrap();
+    let mut group = c.benchmark_group("roundtrip");
+
+    for size in SIZES {
+        let payload: Bytes = Bytes::from(vec![0u8; size]);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let (mut client, mut server): (WebSocket, WebSocket) = rt.block_on(native::duplex());
+        group.bench_with_input(
+            BenchmarkId::new("websocket", size),
+            &payload,
+            |b, payload| {
+                b.iter(|| {
+                    rt.block_on(async {
+                        client.send(Message::Binary(payload.clone())).await.unwrap();
+                        black_box(server.next().await.unwrap().unwrap());
+                    })
+                })
+            },
+        );
+
+        let (client, server) = rt.block_on(tungstenite_duplex());
+        let (mut client, mut server) = (TypedWebSocket::new(client), TypedWebSocket::new(server));
+        group.bench_with_input(BenchmarkId::new("typed", size), &payload, |b, payload| {
+            b.iter(|| {
+                rt.block_on(async {
+                    client.send(Message::Binary(payload.clone())).await.unwrap();
+                    black_box(server.next().await.unwrap().unwrap());
+                })
+            })
+        });
+
+        let (mut client, mut server) = rt.block_on(tungstenite_duplex());
+        group.bench_with_input(
+            BenchmarkId::new("tungstenite", size),
+            &payload,
+            |b, payload| {
+                b.iter(|| {
+                    rt.block_on(async {
+                        client
+                            .send(TungsteniteMessage::Binary(payload.clone()))
+                            .await
+                            .unwrap();
+                        black_box(server.next().await.unwrap().unwrap());
+                    })
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+async fn tungstenite_duplex() -> (WebSocketStream<DuplexStream>, WebSocketStream<DuplexStream>) {
+    let (client, server) = tokio::io::duplex(PIPE_SIZE);
+    let client = WebSocketStream::from_raw_socket(client, Role::Client, None);
+    let server = WebSocketStream::from_raw_socket(server, Role::Server, None);
+    tokio::join!(client, server)
+}
+
+criterion_group!(benches, roundtrip);
+criterion_main!(benches);
@@ -0,0 +1,63 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Autobahn test-suite client
+//!
+//! Run `make autobahn-client`: the reports are written to `autobahn/reports/client`.
+
+use async_wsocket::prelude::*;
+use futures_util::{SinkExt, StreamExt};
+
+const AGENT: &str = "async-wsocket";
+const SERVER: &str = "ws://127.0.0.1:9001";
+
+async fn case_count() -> u32 {
+    let url = Url::parse(&format!("{SERVER}/getCaseCount")).unwrap();
+    let mut socket: WebSocket = WebSocketBuilder::new().connect(&url).await.unwrap();
+
+    let count: u32 = match socket.next().await {
+        Some(Ok(Message::Text(text))) => text.parse().unwrap(),
+        msg => panic!("unexpected case count message: {msg:?}"),
+    };
+
+    let _ = socket.close(1000, "").await;
+    count
+}
+
+async fn run_case(case: u32) -> Result<(), Box<dyn std::error::Error>> {
+    let url = Url::parse(&format!("{SERVER}/runCase?case={case}&agent={AGENT}")).unwrap();
+    let mut socket: WebSocket = WebSocketBuilder::new().connect(&url).await?;
+
+    // Echo the data messages: the control frames are handled by the protocol
+    while let Some(msg) = socket.next().await {
+        match msg? {
+            msg @ (Message::Text(..) | Message::Binary(..)) => socket.send(msg).await?,
+            Message::Close(..) => break,
+            Message::Ping(..) | Message::Pong(..) => {}
+        }
+    }
+
+    Ok(())
+}
+
+async fn update_reports() {
+    let url = Url::parse(&format!("{SERVER}/updateReports?agent={AGENT}")).unwrap();
+    let mut socket: WebSocket = WebSocketBuilder::new().connect(&url).await.unwrap();
+    let _ = socket.close(1000, "").await;
+}
+
+#[tokio::main]
+async fn main() {
+    let count: u32 = case_count().await;
+    println!("Running {count} cases");
+
+    for case in 1..=count {
+        // The failures are expected for some cases: the suite checks how the errors are handled
+        if let Err(e) = run_case(case).await {
+            println!("Case {case}: {e}");
+        }
+    }
+
+    update_reports().await;
+    println!("Done");
+}
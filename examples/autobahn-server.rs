@@ -0,0 +1,48 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Autobahn test-suite echo server
+//!
+//! Run `make autobahn-server`: the reports are written to `autobahn/reports/server`.
+
+use async_wsocket::native::WsServer;
+use async_wsocket::{Error, Message, WebSocket};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+
+const ADDR: &str = "0.0.0.0:9002";
+
+async fn echo(
+    mut tx: SplitSink<WebSocket, Message>,
+    mut rx: SplitStream<WebSocket>,
+) -> Result<(), Error> {
+    // Echo the data messages: the control frames are handled by the protocol
+    while let Some(msg) = rx.next().await {
+        match msg? {
+            msg @ (Message::Text(..) | Message::Binary(..)) => tx.send(msg).await?,
+            Message::Close(..) => break,
+            Message::Ping(..) | Message::Pong(..) => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    let mut server: WsServer = WsServer::bind(ADDR).await.unwrap();
+    println!("Listening on {ADDR}");
+
+    while let Some(conn) = server.next().await {
+        match conn {
+            Ok((tx, rx, peer)) => {
+                tokio::spawn(async move {
+                    if let Err(e) = echo(tx, rx).await {
+                        println!("{}: {e}", peer.remote_addr);
+                    }
+                });
+            }
+            Err(e) => println!("Handshake failed: {e}"),
+        }
+    }
+}
@@ -22,7 +22,10 @@ async fn main() {
 
     // Send ping
     let nonce = NONCE.to_be_bytes().to_vec();
-    socket.send(Message::Ping(nonce.clone())).await.unwrap();
+    socket
+        .send(Message::Ping(nonce.clone().into()))
+        .await
+        .unwrap();
 
     // Listen for messages
     while let Some(msg) = socket.next().await {
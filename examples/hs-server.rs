@@ -17,7 +17,7 @@ async fn main() {
     let service = tor::launch_onion_service("async-wsocket-hs-server-test", local_addr, 80, None)
         .await
         .unwrap();
-    println!("{}", service.onion_name().unwrap().to_string());
+    println!("{}", service.onion_name().unwrap());
 
     while let Ok((stream, addr)) = listener.accept().await {
         tokio::spawn(async move {
@@ -0,0 +1,259 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Actor-style handles
+//!
+//! [`spawn`] moves the socket to a dedicated task and returns the [`WsSender`] and [`WsReceiver`] handles,
+//! cheap to clone and `Send` on all the targets.
+//!
+//! To use a custom executor, create the [`WsActor`] with [`WsActor::new`] and spawn it.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_channel::mpsc::{self, Receiver, Sender};
+use futures_channel::oneshot;
+use futures_util::future;
+use futures_util::lock::Mutex;
+use futures_util::{SinkExt, StreamExt};
+
+use crate::message::CloseCode;
+use crate::{BoxedFuture, Error, Message, WebSocket};
+
+/// Size of the channels between the handles and the actor
+const CHANNEL_SIZE: usize = 64;
+
+struct Command {
+    msg: Message,
+    result: oneshot::Sender<Result<(), Error>>,
+}
+
+/// Spawn the actor owning `socket`
+///
+/// On native targets it's spawned on the current tokio runtime.
+pub fn spawn(socket: WebSocket) -> (WsSender, WsReceiver) {
+    let (sender, receiver, actor) = WsActor::new(socket);
+
+    #[cfg(not(target_arch = "wasm32"))]
+    tokio::spawn(actor);
+
+    #[cfg(target_arch = "wasm32")]
+    wasm_bindgen_futures::spawn_local(actor);
+
+    (sender, receiver)
+}
+
+/// Sender handle
+///
+/// When all the senders are dropped, the socket is closed.
+#[derive(Clone)]
+pub struct WsSender {
+    tx: Sender<Command>,
+}
+
+impl fmt::Debug for WsSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WsSender")
+            .field("connected", &self.is_connected())
+            .finish()
+    }
+}
+
+impl WsSender {
+    /// Send a message, waiting for it to be written to the socket
+    pub async fn send(&self, msg: Message) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .clone()
+            .send(Command { msg, result: tx })
+            .await
+            .map_err(|_| Error::not_connected())?;
+
+        // The actor has been dropped
+        rx.await.map_err(|_| Error::not_connected())?
+    }
+
+    /// Check if the actor is still running
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        !self.tx.is_closed()
+    }
+}
+
+/// Receiver handle
+///
+/// The clones share the same queue: every incoming message is received by only one of them.
+#[derive(Clone)]
+pub struct WsReceiver {
+    rx: Arc<Mutex<Receiver<Result<Message, Error>>>>,
+}
+
+impl fmt::Debug for WsReceiver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WsReceiver").finish()
+    }
+}
+
+impl WsReceiver {
+    /// Receive the next incoming message
+    ///
+    /// Returns `None` when the connection has been closed.
+    pub async fn recv(&self) -> Option<Result<Message, Error>> {
+        self.rx.lock().await.next().await
+    }
+}
+
+/// Actor owning the socket
+///
+/// Writes the messages of the [`WsSender`] and forwards the incoming messages to the [`WsReceiver`].
+/// While the incoming queue is full the socket isn't read, but the outgoing messages are still written.
+/// It must be polled (ex. in a dedicated task) to make progress, until the connection is closed.
+pub struct WsActor {
+    future: BoxedFuture<'static, ()>,
+}
+
+impl WsActor {
+    /// New actor owning `socket`
+    pub fn new(socket: WebSocket) -> (WsSender, WsReceiver, Self) {
+        let (commands_tx, commands_rx) = mpsc::channel(CHANNEL_SIZE);
+        let (incoming_tx, incoming_rx) = mpsc::channel(CHANNEL_SIZE);
+
+        let sender: WsSender = WsSender { tx: commands_tx };
+        let receiver: WsReceiver = WsReceiver {
+            rx: Arc::new(Mutex::new(incoming_rx)),
+        };
+        let actor: Self = Self {
+            future: Box::pin(run(socket, commands_rx, incoming_tx)),
+        };
+
+        (sender, receiver, actor)
+    }
+}
+
+impl Future for WsActor {
+    type Output = ();
+
+    #[inline]
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+enum Event {
+    Command(Option<Command>),
+    Incoming(Option<Result<Message, Error>>),
+}
+
+async fn run(
+    mut socket: WebSocket,
+    mut commands: Receiver<Command>,
+    mut incoming: Sender<Result<Message, Error>>,
+) {
+    // Message read from the socket, waiting for room in the incoming queue
+    let mut pending: Option<Result<Message, Error>> = None;
+
+    loop {
+        let event: Event = future::poll_fn(|cx| {
+            if let Poll::Ready(command) = commands.poll_next_unpin(cx) {
+                return Poll::Ready(Event::Command(command));
+            }
+
+            // Stop reading while the incoming queue is full: a slow receiver doesn't hold back the sends
+            if let Some(res) = pending.take() {
+                match incoming.poll_ready(cx) {
+                    Poll::Ready(Ok(())) => {
+                        let _ = incoming.start_send(res);
+                    }
+                    // The receivers have been dropped: keep writing the outgoing messages
+                    Poll::Ready(Err(..)) => {}
+                    Poll::Pending => {
+                        pending = Some(res);
+                        return Poll::Pending;
+                    }
+                }
+            }
+
+            socket.poll_next_unpin(cx).map(Event::Incoming)
+        })
+        .await;
+
+        match event {
+            Event::Command(Some(Command { msg, result })) => {
+                // The caller may have given up in the meantime
+                let _ = result.send(socket.send(msg).await);
+            }
+            // All the senders have been dropped
+            Event::Command(None) => {
+                let _ = socket.close(CloseCode::Normal, "").await;
+                break;
+            }
+            Event::Incoming(Some(res)) => pending = Some(res),
+            Event::Incoming(None) => break,
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::native;
+
+    #[tokio::test]
+    async fn test_slow_receiver() {
+        let (client, mut server) = native::duplex().await;
+        let (sender, receiver) = spawn(client);
+
+        // Fill the incoming queue, without receiving
+        let count: usize = CHANNEL_SIZE * 2;
+        for i in 0..count {
+            server.feed(Message::text(i.to_string())).await.unwrap();
+        }
+        server.flush().await.unwrap();
+
+        // The sends aren't held back
+        tokio::time::timeout(Duration::from_secs(5), sender.send(Message::text("out")))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap(), Message::text("out"));
+
+        // Nothing lost
+        for i in 0..count {
+            let msg: Message = receiver.recv().await.unwrap().unwrap();
+            assert_eq!(msg, Message::text(i.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receiver_dropped() {
+        let (client, mut server) = native::duplex().await;
+        let (sender, receiver) = spawn(client);
+        drop(receiver);
+
+        for i in 0..CHANNEL_SIZE * 2 {
+            server.send(Message::text(i.to_string())).await.unwrap();
+        }
+        sender.send(Message::text("out")).await.unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap(), Message::text("out"));
+    }
+
+    #[tokio::test]
+    async fn test_senders_dropped() {
+        let (client, mut server) = native::duplex().await;
+        let (sender, receiver) = spawn(client);
+        let other: WsSender = sender.clone();
+        drop(sender);
+        assert!(other.is_connected());
+        drop(other);
+
+        // Closed
+        assert!(matches!(server.next().await, Some(Ok(Message::Close(..)))));
+        assert!(server.next().await.is_none());
+        assert!(receiver.recv().await.is_none());
+    }
+}
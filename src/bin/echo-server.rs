@@ -0,0 +1,165 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Local echo server, for the integration tests
+//!
+//! ```text
+//! wsocket-echo-server [--addr <ADDR>] [--cert <DER> --key <DER>] [--latency <MS>]
+//! ```
+//!
+//! Echoes the text and binary messages, after the optional artificial latency.
+//! With `--cert` and `--key` (DER encoded, the key in PKCS#8 format) it serves `wss://`.
+//! With the `deflate` feature, the `permessage-deflate` offers are accepted.
+
+use std::process;
+use std::time::Duration;
+
+use async_wsocket::futures_util::stream::{SplitSink, SplitStream};
+use async_wsocket::futures_util::{SinkExt, StreamExt};
+#[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+use async_wsocket::native::TlsAcceptor;
+use async_wsocket::native::WsServer;
+#[cfg(feature = "deflate")]
+use async_wsocket::CompressionConfig;
+use async_wsocket::{Error, Message, WebSocket};
+use tokio::runtime::{Builder, Runtime};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:9001";
+
+const USAGE: &str =
+    "Usage: wsocket-echo-server [--addr <ADDR>] [--cert <DER> --key <DER>] [--latency <MS>]
+
+Options:
+    --addr <ADDR>    Listening address (default: 127.0.0.1:9001)
+    --cert <DER>     Certificate chain file (DER), to serve wss://
+    --key <DER>      Private key file (DER, PKCS#8)
+    --latency <MS>   Delay before echoing every message (default: 0)
+    --help           Print this message";
+
+#[derive(Default)]
+struct Args {
+    addr: Option<String>,
+    cert: Option<String>,
+    key: Option<String>,
+    latency: Duration,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args: Self = Self::default();
+        let mut iter = std::env::args().skip(1);
+
+        while let Some(arg) = iter.next() {
+            let mut value = || iter.next().ok_or(format!("missing value of {arg}"));
+            match arg.as_str() {
+                "--addr" => args.addr = Some(value()?),
+                "--cert" => args.cert = Some(value()?),
+                "--key" => args.key = Some(value()?),
+                "--latency" => {
+                    let millis: u64 = value()?
+                        .parse()
+                        .map_err(|_| String::from("invalid latency"))?;
+                    args.latency = Duration::from_millis(millis);
+                }
+                "--help" | "-h" => {
+                    println!("{USAGE}");
+                    process::exit(0);
+                }
+                arg => return Err(format!("unknown argument: {arg}")),
+            }
+        }
+
+        if args.cert.is_some() != args.key.is_some() {
+            return Err(String::from("--cert and --key must be used together"));
+        }
+
+        Ok(args)
+    }
+}
+
+async fn echo(
+    mut tx: SplitSink<WebSocket, Message>,
+    mut rx: SplitStream<WebSocket>,
+    latency: Duration,
+) -> Result<(), Error> {
+    while let Some(msg) = rx.next().await {
+        match msg? {
+            msg @ (Message::Text(..) | Message::Binary(..)) => {
+                if !latency.is_zero() {
+                    tokio::time::sleep(latency).await;
+                }
+                tx.send(msg).await?;
+            }
+            Message::Close(..) => break,
+            Message::Ping(..) | Message::Pong(..) => {}
+        }
+    }
+
+    Ok(())
+}
+
+async fn run(args: Args) -> Result<(), String> {
+    let addr: &str = args.addr.as_deref().unwrap_or(DEFAULT_ADDR);
+    let mut server: WsServer = WsServer::bind(addr).await.map_err(|e| e.to_string())?;
+
+    #[cfg(feature = "deflate")]
+    {
+        server = server.compression(CompressionConfig::default());
+    }
+
+    if let (Some(cert), Some(key)) = (&args.cert, &args.key) {
+        #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+        {
+            let cert: Vec<u8> = std::fs::read(cert).map_err(|e| format!("{cert}: {e}"))?;
+            let key: Vec<u8> = std::fs::read(key).map_err(|e| format!("{key}: {e}"))?;
+            let acceptor: TlsAcceptor =
+                TlsAcceptor::new(vec![cert], key).map_err(|e| e.to_string())?;
+            server = server.tls(acceptor);
+        }
+
+        #[cfg(not(any(feature = "tls-rustls", feature = "tls-native")))]
+        {
+            let _ = (cert, key);
+            return Err(String::from("TLS support not enabled"));
+        }
+    }
+
+    let local_addr = server.local_addr().map_err(|e| e.to_string())?;
+    println!("Listening on {local_addr}");
+
+    while let Some(conn) = server.next().await {
+        match conn {
+            Ok((tx, rx, peer)) => {
+                let latency: Duration = args.latency;
+                tokio::spawn(async move {
+                    if let Err(e) = echo(tx, rx, latency).await {
+                        eprintln!("{}: {e}", peer.remote_addr);
+                    }
+                });
+            }
+            Err(e) => eprintln!("Handshake failed: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let args: Args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            process::exit(2);
+        }
+    };
+
+    let rt: Runtime = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to build the runtime");
+
+    if let Err(e) = rt.block_on(run(args)) {
+        eprintln!("{e}");
+        process::exit(1);
+    }
+}
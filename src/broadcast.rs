@@ -0,0 +1,259 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Broadcast fan-out
+
+use std::time::Duration;
+
+use futures_util::future;
+use futures_util::stream::SplitSink;
+use futures_util::{Sink, SinkExt};
+
+use crate::{time, Error, Message, WebSocket};
+
+const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Client identifier, unique within a [`Broadcaster`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ClientId(u64);
+
+/// Broadcast fan-out
+///
+/// Holds the sinks of many clients (ex. the connections accepted by a server)
+/// and sends the same message to all of them, concurrently.
+///
+/// A client that fails to receive a message within the send timeout, or whose sink fails,
+/// is considered dead and evicted, so a slow client can't stall the others.
+pub struct Broadcaster<S = SplitSink<WebSocket, Message>> {
+    clients: Vec<(ClientId, S)>,
+    next_id: u64,
+    timeout: Duration,
+}
+
+impl<S> Default for Broadcaster<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Broadcaster<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    /// New empty broadcaster
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            clients: Vec::new(),
+            next_id: 0,
+            timeout: DEFAULT_SEND_TIMEOUT,
+        }
+    }
+
+    /// Set the per-client send timeout (default: 10 secs)
+    #[inline]
+    pub fn send_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Add a client
+    pub fn add(&mut self, sink: S) -> ClientId {
+        let id: ClientId = ClientId(self.next_id);
+        self.next_id += 1;
+        self.clients.push((id, sink));
+        id
+    }
+
+    /// Remove a client, returning its sink
+    pub fn remove(&mut self, id: ClientId) -> Option<S> {
+        let pos: usize = self.clients.iter().position(|(i, _)| *i == id)?;
+        Some(self.clients.remove(pos).1)
+    }
+
+    /// Check if a client is still there
+    #[inline]
+    pub fn contains(&self, id: ClientId) -> bool {
+        self.clients.iter().any(|(i, _)| *i == id)
+    }
+
+    /// Number of clients
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Check if there are no clients
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Send a message to all the clients
+    ///
+    /// Returns the evicted clients.
+    #[inline]
+    pub async fn broadcast(&mut self, msg: Message) -> Vec<ClientId> {
+        self.broadcast_filter(msg, |_| true).await
+    }
+
+    /// Send a message to all the clients except one (ex. the sender of a chat message)
+    ///
+    /// Returns the evicted clients.
+    #[inline]
+    pub async fn broadcast_except(&mut self, msg: Message, except: ClientId) -> Vec<ClientId> {
+        self.broadcast_filter(msg, |id| id != except).await
+    }
+
+    /// Send a message to the clients matching `filter`
+    ///
+    /// Returns the evicted clients.
+    pub async fn broadcast_filter<F>(&mut self, msg: Message, filter: F) -> Vec<ClientId>
+    where
+        F: Fn(ClientId) -> bool,
+    {
+        let timeout: Duration = self.timeout;
+        let sends = self
+            .clients
+            .iter_mut()
+            .filter(|(id, _)| filter(*id))
+            .map(|(id, sink)| {
+                let msg: Message = msg.clone();
+                async move {
+                    match time::timeout(timeout, sink.send(msg)).await {
+                        Some(Ok(())) => None,
+                        // Failed or timed out
+                        Some(Err(..)) | None => Some(*id),
+                    }
+                }
+            });
+
+        let dead: Vec<ClientId> = future::join_all(sends)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+
+        if !dead.is_empty() {
+            self.clients.retain(|(id, _)| !dead.contains(id));
+        }
+
+        dead
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    enum Behavior {
+        Accept,
+        Fail,
+        Stall,
+    }
+
+    /// Sink recording the accepted messages
+    #[derive(Clone)]
+    struct Client {
+        behavior: Behavior,
+        received: Arc<Mutex<Vec<Message>>>,
+    }
+
+    impl Client {
+        fn new(behavior: Behavior) -> Self {
+            Self {
+                behavior,
+                received: Arc::default(),
+            }
+        }
+
+        fn received(&self) -> Vec<Message> {
+            self.received
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone()
+        }
+    }
+
+    impl Sink<Message> for Client {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            match self.behavior {
+                Behavior::Accept => Poll::Ready(Ok(())),
+                Behavior::Fail => Poll::Ready(Err(Error::not_connected())),
+                Behavior::Stall => Poll::Pending,
+            }
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Error> {
+            self.received
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            self.poll_ready(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            self.poll_ready(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_except() {
+        let mut broadcaster: Broadcaster<Client> = Broadcaster::new();
+        let a = Client::new(Behavior::Accept);
+        let b = Client::new(Behavior::Accept);
+        let id_a: ClientId = broadcaster.add(a.clone());
+        let id_b: ClientId = broadcaster.add(b.clone());
+        assert_ne!(id_a, id_b);
+
+        assert!(broadcaster.broadcast(Message::text("all")).await.is_empty());
+        assert!(broadcaster
+            .broadcast_except(Message::text("not a"), id_a)
+            .await
+            .is_empty());
+
+        assert_eq!(a.received(), [Message::text("all")]);
+        assert_eq!(b.received(), [Message::text("all"), Message::text("not a")]);
+
+        assert!(broadcaster.remove(id_a).is_some());
+        assert!(!broadcaster.contains(id_a));
+        assert!(broadcaster.remove(id_a).is_none());
+        assert_eq!(broadcaster.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_evict_dead() {
+        let mut broadcaster: Broadcaster<Client> =
+            Broadcaster::new().send_timeout(Duration::from_secs(1));
+        let alive = Client::new(Behavior::Accept);
+        let id_alive: ClientId = broadcaster.add(alive.clone());
+        let id_failed: ClientId = broadcaster.add(Client::new(Behavior::Fail));
+        let id_stalled: ClientId = broadcaster.add(Client::new(Behavior::Stall));
+
+        // The stalled client doesn't hold back the others past the timeout
+        let start = tokio::time::Instant::now();
+        let mut evicted: Vec<ClientId> = broadcaster.broadcast(Message::text("hello")).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        evicted.sort();
+        assert_eq!(evicted, [id_failed, id_stalled]);
+        assert_eq!(alive.received(), [Message::text("hello")]);
+        assert!(broadcaster.contains(id_alive));
+        assert_eq!(broadcaster.len(), 1);
+    }
+}
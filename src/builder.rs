@@ -0,0 +1,841 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! WebSocket builder
+
+use std::borrow::Cow;
+use std::future::Future;
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::time::Duration;
+
+use futures_util::future::{self, Either};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use url::Url;
+
+#[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+use crate::native::CompressionConfig;
+#[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+use crate::native::CookieJar;
+#[cfg(all(
+    any(feature = "tls-rustls", feature = "tls-native"),
+    not(target_arch = "wasm32")
+))]
+use crate::native::TlsConfig;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::native::{Authenticator, RawFramer, Request, RequestHook, Resolver, WebSocketConfig};
+use crate::time::{self, Instant};
+#[cfg(target_arch = "wasm32")]
+use crate::wasm::BinaryType;
+use crate::{
+    ConnectError, ConnectionEvent, ConnectionMode, ConnectionObserver, Extensions,
+    HandshakeResponse, IdleTimeoutConfig, Limits, ModeRouter, RecvOnly, RttEcho, RttMonitor,
+    SendOnly, ShutdownToken, Timeouts, Utf8Policy, WebSocket, WriteCoalescingConfig,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Recommended by RFC 8305
+#[cfg(not(target_arch = "wasm32"))]
+const DEFAULT_CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// WebSocket builder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketBuilder {
+    /// Connection mode
+    pub(crate) mode: ConnectionMode,
+    /// Per-URL connection mode
+    pub(crate) mode_router: Option<ModeRouter>,
+    /// Overall connection timeout
+    pub(crate) timeout: Duration,
+    /// Per-phase connection timeouts
+    pub(crate) timeouts: Timeouts,
+    /// Custom HTTP headers for the handshake request
+    pub(crate) headers: Vec<(String, String)>,
+    /// Requested subprotocols
+    pub(crate) protocols: Vec<String>,
+    /// Incoming message limits
+    pub(crate) limits: Limits,
+    /// Handling of the text messages with invalid UTF-8
+    pub(crate) utf8_policy: Utf8Policy,
+    /// Answer the pings automatically, without yielding them
+    pub(crate) auto_pong: bool,
+    /// Read-idle timeout
+    pub(crate) idle_timeout: Option<IdleTimeoutConfig>,
+    /// Deferred flushes of the sent messages
+    pub(crate) write_coalescing: Option<WriteCoalescingConfig>,
+    /// Graceful shutdown token
+    pub(crate) shutdown: Option<ShutdownToken>,
+    /// User context of the connections
+    pub(crate) extensions: Option<Extensions>,
+    /// Application-level echo for the RTT measurement
+    pub(crate) rtt_echo: Option<RttEcho>,
+    /// Custom TLS config
+    #[cfg(all(
+        any(feature = "tls-rustls", feature = "tls-native"),
+        not(target_arch = "wasm32")
+    ))]
+    pub(crate) tls: Option<TlsConfig>,
+    /// WebSocket protocol config
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) config: Option<WebSocketConfig>,
+    /// Delay between the connection attempts to the resolved addresses
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) connection_attempt_delay: Duration,
+    /// Max handshake redirects to follow
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) max_redirects: usize,
+    /// Provider of the `Authorization` header
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) auth: Option<Authenticator>,
+    /// Refresh the authorization and retry on `401`
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) auth_refresh: bool,
+    /// Customization of the handshake request
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) request_hook: Option<RequestHook>,
+    /// Requested WebSocket extensions
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) ws_extensions: Vec<String>,
+    /// Permessage-deflate offer
+    #[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+    pub(crate) compression: Option<CompressionConfig>,
+    /// Cookie jar shared across connections
+    #[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+    pub(crate) cookie_jar: Option<CookieJar>,
+    /// Fall back to Server-Sent Events + POST when the upgrade is blocked
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) http_fallback: bool,
+    /// Skip the WebSocket handshake and split the raw stream with this framer
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) raw: Option<RawFramer>,
+    /// Custom DNS resolver
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) resolver: Option<Resolver>,
+    /// Local address to bind the outgoing TCP socket to
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) local_addr: Option<SocketAddr>,
+    /// Network interface to bind the outgoing TCP socket to
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub(crate) interface: Option<String>,
+    /// Type of the binary messages delivered by the browser
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) binary_type: BinaryType,
+    /// Connection events observer
+    pub(crate) observer: Option<ConnectionObserver>,
+}
+
+impl Default for WebSocketBuilder {
+    fn default() -> Self {
+        Self {
+            mode: ConnectionMode::default(),
+            mode_router: None,
+            timeout: DEFAULT_TIMEOUT,
+            timeouts: Timeouts::default(),
+            headers: Vec::new(),
+            protocols: Vec::new(),
+            limits: Limits::default(),
+            utf8_policy: Utf8Policy::default(),
+            auto_pong: true,
+            idle_timeout: None,
+            write_coalescing: None,
+            shutdown: None,
+            extensions: None,
+            rtt_echo: None,
+            #[cfg(all(
+                any(feature = "tls-rustls", feature = "tls-native"),
+                not(target_arch = "wasm32")
+            ))]
+            tls: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            config: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            connection_attempt_delay: DEFAULT_CONNECTION_ATTEMPT_DELAY,
+            #[cfg(not(target_arch = "wasm32"))]
+            max_redirects: 0,
+            #[cfg(not(target_arch = "wasm32"))]
+            auth: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            auth_refresh: true,
+            #[cfg(not(target_arch = "wasm32"))]
+            request_hook: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            ws_extensions: Vec::new(),
+            #[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+            compression: None,
+            #[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+            cookie_jar: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            http_fallback: false,
+            #[cfg(not(target_arch = "wasm32"))]
+            raw: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            resolver: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            local_addr: None,
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            interface: None,
+            #[cfg(target_arch = "wasm32")]
+            binary_type: BinaryType::default(),
+            observer: None,
+        }
+    }
+}
+
+impl WebSocketBuilder {
+    /// New default builder
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set connection mode (default: [`ConnectionMode::Direct`])
+    #[inline]
+    pub fn mode(mut self, mode: ConnectionMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Select the connection mode of each URL (default: none, all the URLs use [`WebSocketBuilder::mode`])
+    ///
+    /// Applied to every connection, so also to [`WebSocketBuilder::connect_any`],
+    /// [`WsPool`](crate::WsPool) and [`ReconnectingWebSocket`](crate::reconnect::ReconnectingWebSocket).
+    /// Use [`ModeRouter::onion`] to route the `.onion` hosts to tor.
+    #[inline]
+    pub fn mode_router(mut self, router: ModeRouter) -> Self {
+        self.mode_router = Some(router);
+        self
+    }
+
+    /// Set the overall connection timeout (default: 60 secs)
+    ///
+    /// Check [`WebSocketBuilder::timeouts`] to bound each phase separately.
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the per-phase connection timeouts (default: none)
+    ///
+    /// Check [`Timeouts`] for more details.
+    #[inline]
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Add a custom HTTP header to the handshake request
+    ///
+    /// Calling this multiple times with the same name will send the header multiple times.
+    ///
+    /// Not supported on WASM targets: browsers don't allow to set custom handshake headers.
+    #[inline]
+    pub fn header<K, V>(mut self, name: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Add custom HTTP headers to the handshake request
+    ///
+    /// Check [`WebSocketBuilder::header`] for more details.
+    pub fn headers<I, K, V>(mut self, headers: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.headers
+            .extend(headers.into_iter().map(|(k, v)| (k.into(), v.into())));
+        self
+    }
+
+    /// Set the subprotocols to request during the handshake (`Sec-WebSocket-Protocol`)
+    ///
+    /// The protocol accepted by the server can be retrieved with [`WebSocket::negotiated_protocol`].
+    ///
+    /// The connection fails if the server accepts a subprotocol that wasn't requested.
+    pub fn protocols<S>(mut self, protocols: &[S]) -> Self
+    where
+        S: AsRef<str>,
+    {
+        self.protocols = protocols.iter().map(|p| p.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Set the extensions to request during the handshake (`Sec-WebSocket-Extensions`), with their parameters
+    ///
+    /// The tokens are sent as they are (ex. `x-custom; level=2`), for the interop testing of non-standard extensions:
+    /// the frames aren't transformed, so the caller must handle the extensions accepted by the server,
+    /// retrieved with [`HandshakeResponse::extensions`].
+    ///
+    /// Not supported on WASM targets: the browser negotiates its own extensions.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn ws_extensions<S>(mut self, extensions: &[S]) -> Self
+    where
+        S: AsRef<str>,
+    {
+        self.ws_extensions = extensions.iter().map(|e| e.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Offer the permessage-deflate compression (RFC 7692) during the handshake (default: none)
+    ///
+    /// If the server accepts it, the messages are compressed and decompressed transparently:
+    /// check [`WebSocket::is_compressed`](crate::WebSocket::is_compressed).
+    /// The [`Limits`] and the [`WebSocketConfig`] max sizes apply to the decompressed messages.
+    ///
+    /// Only the WebSocket connections (not the raw streams, the HTTP fallback, HTTP/2, WebTransport
+    /// or [`connect_typed`](crate::native::typed::connect_typed)) are compressed.
+    #[inline]
+    #[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+    pub fn compression(mut self, config: CompressionConfig) -> Self {
+        self.compression = Some(config);
+        self
+    }
+
+    /// Set the incoming message limits
+    ///
+    /// Check [`Limits`] for more details.
+    #[inline]
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Set the handling of the incoming text messages with invalid UTF-8 (default: [`Utf8Policy::Strict`])
+    ///
+    /// Check [`Utf8Policy`] for more details.
+    #[inline]
+    pub fn utf8_policy(mut self, policy: Utf8Policy) -> Self {
+        self.utf8_policy = policy;
+        self
+    }
+
+    /// Answer the incoming pings automatically (default: `true`)
+    ///
+    /// When enabled, the pongs are sent while reading the [`Stream`](futures_util::Stream)
+    /// and the pings aren't yielded, so a split stream keeps the connection alive without
+    /// any help of the sink half. When disabled, the pings are yielded to the application.
+    ///
+    /// No effect on WASM targets: the browser always answers and never exposes the pings.
+    #[inline]
+    pub fn auto_pong(mut self, enabled: bool) -> Self {
+        self.auto_pong = enabled;
+        self
+    }
+
+    /// Set the read-idle timeout (default: none)
+    ///
+    /// When no frames (including pings and pongs) are received for [`IdleTimeoutConfig::timeout`],
+    /// a probing ping is sent (if [`IdleTimeoutConfig::probe`] is set) and, if the connection is still silent,
+    /// the [`Stream`](futures_util::Stream) yields [`Error::IdleTimeout`](crate::Error::IdleTimeout) and then ends.
+    ///
+    /// The timer is driven by polling the stream.
+    #[inline]
+    pub fn idle_timeout(mut self, config: IdleTimeoutConfig) -> Self {
+        self.idle_timeout = Some(config);
+        self
+    }
+
+    /// Coalesce the writes of the fed messages (default: disabled)
+    ///
+    /// The messages queued without flushing (ex. by `feed`) are buffered and written together, with a single syscall,
+    /// as soon as [`WriteCoalescingConfig::max_bytes`] are buffered or [`WriteCoalescingConfig::max_delay`]
+    /// after the first of them. The deferred flush is performed while the socket is written or read.
+    ///
+    /// An explicit flush (ex. by `send`) and a close frame are written immediately:
+    /// to batch many small messages, `feed` them and flush once (or let the delay expire).
+    ///
+    /// No effect on WASM targets: the browser writes the messages.
+    #[inline]
+    pub fn write_coalescing(mut self, config: WriteCoalescingConfig) -> Self {
+        self.write_coalescing = Some(config);
+        self
+    }
+
+    /// Register the connections to a shutdown coordinator
+    ///
+    /// Check [`ShutdownHandle`](crate::ShutdownHandle) for more details.
+    #[inline]
+    pub fn shutdown_token(mut self, token: ShutdownToken) -> Self {
+        self.shutdown = Some(token);
+        self
+    }
+
+    /// Attach a user context to the connections (default: a new empty one for each connection)
+    ///
+    /// All the connections of this builder share the same `extensions`.
+    /// Check [`Extensions`] for more details.
+    #[inline]
+    pub fn extensions(mut self, extensions: Extensions) -> Self {
+        self.extensions = Some(extensions);
+        self
+    }
+
+    /// Set the application-level echo for the RTT measurement
+    ///
+    /// Check [`RttMonitor`] for more details.
+    #[inline]
+    pub fn rtt_echo(mut self, echo: RttEcho) -> Self {
+        self.rtt_echo = Some(echo);
+        self
+    }
+
+    /// Set a custom TLS config
+    #[inline]
+    #[cfg(all(
+        any(feature = "tls-rustls", feature = "tls-native"),
+        not(target_arch = "wasm32")
+    ))]
+    pub fn tls(mut self, config: TlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Set the WebSocket protocol config (max message/frame size, buffering)
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn config(mut self, config: WebSocketConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Set the delay between the connection attempts (default: 250 ms)
+    ///
+    /// When the host resolves to multiple addresses (ex. both IPv6 and IPv4),
+    /// they are dialed alternating the address families ("Happy Eyeballs", RFC 8305):
+    /// a new attempt is started after this delay, without waiting for the previous one to fail.
+    ///
+    /// Only for [`ConnectionMode::Direct`].
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn connection_attempt_delay(mut self, delay: Duration) -> Self {
+        self.connection_attempt_delay = delay;
+        self
+    }
+
+    /// Follow up to `max_hops` handshake redirects (default: `0`, disabled)
+    ///
+    /// When the server answers the upgrade request with a `301`, `302`, `307` or `308` status,
+    /// the handshake is re-attempted at the `Location` URL, with the same mode and options.
+    /// `http` and `https` locations are mapped to `ws` and `wss`.
+    ///
+    /// The redirects from `wss` to `ws` aren't followed: the rejection is returned.
+    /// When the location has a different origin, the [`AuthProvider`](crate::AuthProvider), the
+    /// [`WebSocketBuilder::on_request`] hook and the `Authorization`, `Proxy-Authorization` and `Cookie`
+    /// custom headers aren't used.
+    ///
+    /// Returns [`ConnectError::RedirectLoop`] if a location has already been visited.
+    /// The connection timeout bounds all the hops together.
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn follow_redirects(mut self, max_hops: usize) -> Self {
+        self.max_redirects = max_hops;
+        self
+    }
+
+    /// Set the provider of the `Authorization` header (default: none)
+    ///
+    /// The provider is invoked before each connection attempt, so also before each reconnection of
+    /// [`ReconnectingWebSocket`](crate::reconnect::ReconnectingWebSocket).
+    /// It replaces the `Authorization` header set with [`WebSocketBuilder::header`], if any.
+    ///
+    /// Check [`WebSocketBuilder::auth_refresh`] for the handling of `401` rejections.
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn auth(mut self, auth: Authenticator) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Refresh the authorization and retry once when the handshake is rejected with `401` (default: true)
+    ///
+    /// If the retry is rejected too, [`ConnectError::HandshakeRejected`] is returned:
+    /// it's a permanent error, so it isn't retried by the reconnect subsystem.
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn auth_refresh(mut self, enabled: bool) -> Self {
+        self.auth_refresh = enabled;
+        self
+    }
+
+    /// Customize the handshake request right before it's sent (default: none)
+    ///
+    /// The `callback` receives the request with all the headers already set (authorization and cookies included),
+    /// so it can add the headers depending on the exact request (ex. an HMAC signature of the path and a timestamp).
+    /// It's invoked for each connection attempt, same-origin redirect and reconnection:
+    /// like the credentials, it's skipped for the cross-origin redirects.
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn on_request<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(Request) -> Request + Send + Sync + 'static,
+    {
+        self.request_hook = Some(RequestHook::new(callback));
+        self
+    }
+
+    /// Set the cookie jar, shared across connections (default: none)
+    ///
+    /// Check [`CookieJar`] for more details.
+    #[inline]
+    #[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+    pub fn cookie_jar(mut self, jar: CookieJar) -> Self {
+        self.cookie_jar = Some(jar);
+        self
+    }
+
+    /// Fall back to an HTTP transport when the WebSocket upgrade is blocked (default: false)
+    ///
+    /// If the upgrade request is answered as a plain HTTP request (`200`, `426` or `501`)
+    /// or the connection is dropped during the handshake (ex. by a middlebox stripping the `Upgrade` header),
+    /// the WebSocket is emulated with Server-Sent Events + POST requests to the same URL, with the same headers.
+    /// The server must support it: check [`native::fallback`](crate::native::fallback) for the protocol
+    /// and [`FallbackSessions`](crate::native::FallbackSessions) for the server side.
+    ///
+    /// The pings and pongs are dropped, so don't enable the keepalive. Long-polling isn't supported.
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn http_fallback(mut self, enable: bool) -> Self {
+        self.http_fallback = enable;
+        self
+    }
+
+    /// Skip the WebSocket handshake and exchange the messages over the raw stream, split by `framer` (default: none)
+    ///
+    /// For the servers exposing plain TCP protocols: the connection mode is still used to dial
+    /// (proxies and Tor included) and TLS is performed for `wss` and `tls` URLs (ex. `tls://example.com:7000`).
+    /// The URLs with other schemes (ex. `tcp://`) must include the port.
+    ///
+    /// Only the text and binary messages are exchanged: the pings, pongs and close frames are dropped,
+    /// so don't enable the keepalive. The handshake response is empty (status `0`).
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn raw(mut self, framer: RawFramer) -> Self {
+        self.raw = Some(framer);
+        self
+    }
+
+    /// Set a custom DNS resolver (default: the system one)
+    ///
+    /// Check [`Resolver`] for more details.
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// Bind the outgoing TCP socket to a local address
+    ///
+    /// Useful for multi-homed hosts. Use port `0` to let the OS choose it.
+    ///
+    /// In [`ConnectionMode::Direct`], only the resolved addresses of the same family are dialed.
+    /// Not used with the embedded tor client.
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn local_addr(mut self, addr: SocketAddr) -> Self {
+        self.local_addr = Some(addr);
+        self
+    }
+
+    /// Bind the outgoing TCP socket to a network interface (`SO_BINDTODEVICE`)
+    ///
+    /// Useful for VPN split-tunnel setups. May require the `CAP_NET_RAW` capability.
+    ///
+    /// Not used with the embedded tor client.
+    #[inline]
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub fn interface<S>(mut self, name: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.interface = Some(name.into());
+        self
+    }
+
+    /// Set the type of the binary messages delivered by the browser (default: [`BinaryType::ArrayBuffer`])
+    #[inline]
+    #[cfg(target_arch = "wasm32")]
+    pub fn binary_type(mut self, binary_type: BinaryType) -> Self {
+        self.binary_type = binary_type;
+        self
+    }
+
+    /// Set the connection events observer
+    ///
+    /// Check [`ConnectionEvents`](crate::ConnectionEvents) for more details.
+    #[inline]
+    pub fn observer(mut self, observer: ConnectionObserver) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Connect
+    #[inline]
+    pub async fn connect(&self, url: &Url) -> Result<WebSocket, ConnectError> {
+        let (socket, _) = self.connect_with_response(url).await?;
+        Ok(socket)
+    }
+
+    /// Connect, to only send messages
+    ///
+    /// The incoming messages are discarded by an internal task: check [`SendOnly`].
+    /// On native targets, it must be called within a tokio runtime.
+    #[inline]
+    pub async fn connect_send_only(&self, url: &Url) -> Result<SendOnly, ConnectError> {
+        let socket: WebSocket = self.connect(url).await?;
+        Ok(SendOnly::new(socket))
+    }
+
+    /// Connect, to only receive messages
+    ///
+    /// The pings are sent by an internal task: check [`RecvOnly`].
+    /// On native targets, it must be called within a tokio runtime.
+    #[inline]
+    pub async fn connect_recv_only(&self, url: &Url) -> Result<RecvOnly, ConnectError> {
+        let socket: WebSocket = self.connect(url).await?;
+        Ok(RecvOnly::new(socket))
+    }
+
+    /// Connect, aborting as soon as `cancel` completes
+    ///
+    /// Returns [`ConnectError::Cancelled`] if `cancel` completes first: the pending connection
+    /// (TCP, TLS, proxy or Tor) is dropped immediately, without waiting for the timeout.
+    ///
+    /// With `tokio-util`, pass `token.cancelled()` of a `CancellationToken`.
+    pub async fn connect_until<C>(&self, url: &Url, cancel: C) -> Result<WebSocket, ConnectError>
+    where
+        C: Future<Output = ()>,
+    {
+        let connect = pin!(self.connect(url));
+        let cancel = pin!(cancel);
+        match future::select(connect, cancel).await {
+            Either::Left((res, _)) => res,
+            Either::Right(((), _)) => {
+                let extensions: Extensions = self.extensions.clone().unwrap_or_default();
+                self.notify(
+                    ConnectionEvent::Error(ConnectError::Cancelled.to_string()),
+                    &extensions,
+                );
+                Err(ConnectError::Cancelled)
+            }
+        }
+    }
+
+    /// Race the connection to several URLs, returning the first successful one
+    ///
+    /// The attempt to the URL number `n` (starting from `0`) is started after `n * stagger`
+    /// (use [`Duration::ZERO`] to start all of them at once).
+    /// As soon as a connection succeeds, the other attempts are cancelled.
+    ///
+    /// If all the attempts fail, the last error is returned ([`ConnectError::NoUrl`] if `urls` is empty).
+    pub async fn connect_any(
+        &self,
+        urls: &[Url],
+        stagger: Duration,
+    ) -> Result<(Url, WebSocket), ConnectError> {
+        let mut attempts = urls
+            .iter()
+            .enumerate()
+            .map(|(n, url)| async move {
+                let delay: Duration = stagger.saturating_mul(n.try_into().unwrap_or(u32::MAX));
+                if !delay.is_zero() {
+                    time::sleep(delay).await;
+                }
+                self.connect(url).await.map(|socket| (url.clone(), socket))
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut error: ConnectError = ConnectError::NoUrl;
+        while let Some(res) = attempts.next().await {
+            match res {
+                Ok(res) => return Ok(res),
+                Err(e) => error = e,
+            }
+        }
+
+        Err(error)
+    }
+
+    /// Connect and return also the HTTP response of the handshake
+    pub async fn connect_with_response(
+        &self,
+        url: &Url,
+    ) -> Result<(WebSocket, HandshakeResponse), ConnectError> {
+        let builder: Cow<Self> = self.for_url(url);
+
+        #[cfg(not(target_arch = "wasm32"))]
+        let future = crate::native::connect_with_response(url, &builder);
+
+        #[cfg(all(target_arch = "wasm32", not(feature = "wasm-send")))]
+        let future = crate::wasm::connect_with_response(url, &builder);
+
+        // The browser socket is bound to the thread that created it
+        #[cfg(all(target_arch = "wasm32", feature = "wasm-send"))]
+        let future =
+            send_wrapper::SendWrapper::new(crate::wasm::connect_with_response(url, &builder));
+
+        self.observe(future).await
+    }
+
+    /// Connect, returning the concrete tungstenite stream instead of the [`WebSocket`] enum
+    ///
+    /// Only the connection modes carried over TCP are supported and the redirects aren't followed.
+    /// The connection events observer isn't used.
+    /// Check [`TypedWebSocket`](crate::native::TypedWebSocket) for more details.
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn connect_typed(
+        &self,
+        url: &Url,
+    ) -> Result<(crate::native::TcpWebSocket, HandshakeResponse), ConnectError> {
+        crate::native::typed::connect_typed(url, &self.for_url(url)).await
+    }
+
+    /// Connect over an already opened stream, driven by any async runtime
+    ///
+    /// Check [`native::compat`](crate::native::compat) for more details.
+    #[cfg(all(feature = "futures-io", not(target_arch = "wasm32")))]
+    pub async fn connect_with_stream<S>(
+        &self,
+        url: &Url,
+        stream: S,
+    ) -> Result<(WebSocket, HandshakeResponse), ConnectError>
+    where
+        S: futures_util::io::AsyncRead + futures_util::io::AsyncWrite + Send + Unpin + 'static,
+    {
+        self.observe(crate::native::compat::connect_with_stream(
+            url, stream, self,
+        ))
+        .await
+    }
+
+    /// Perform only the client WebSocket handshake over an already established `stream`
+    ///
+    /// No TCP, proxy or TLS connection is opened: the stream (plain or already TLS) is used as is,
+    /// so the [`ConnectionMode`] and the TLS config are ignored.
+    /// The handshake is bounded by the connection timeout.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn client_from_stream<S>(
+        &self,
+        url: &Url,
+        stream: S,
+    ) -> Result<(WebSocket, HandshakeResponse), ConnectError>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static,
+    {
+        self.observe(crate::native::client_handshake(url, stream, self))
+            .await
+    }
+
+    /// Open a WebSocket over HTTP/2 (RFC 8441), as a new stream of `connection`
+    ///
+    /// The WebSockets opened on the same [`H2Connection`](crate::H2Connection) share its TCP and TLS connection:
+    /// the connection mode and the TLS config are the ones used to open it.
+    /// The redirects aren't followed.
+    #[cfg(all(feature = "h2", not(target_arch = "wasm32")))]
+    pub async fn connect_h2(
+        &self,
+        connection: &crate::H2Connection,
+        url: &Url,
+    ) -> Result<(WebSocket, HandshakeResponse), ConnectError> {
+        self.observe(crate::native::http2::connect(connection, url, self))
+            .await
+    }
+
+    /// Connect over a WebTransport session (HTTP/3 over QUIC, experimental)
+    ///
+    /// The WebSocket frames are exchanged over a bidirectional stream of the session,
+    /// so the server must support the same. Only `wss` (or `https`) URLs are supported.
+    ///
+    /// QUIC runs over UDP, so only [`ConnectionMode::Direct`] is supported:
+    /// the other modes return an error, instead of leaking a direct connection.
+    /// The headers are sent in the session request, but the redirects aren't followed.
+    #[cfg(all(feature = "h3", not(target_arch = "wasm32")))]
+    pub async fn connect_h3(
+        &self,
+        url: &Url,
+    ) -> Result<(WebSocket, HandshakeResponse), ConnectError> {
+        let builder: Cow<Self> = self.for_url(url);
+        self.observe(crate::native::webtransport::connect(url, &builder))
+            .await
+    }
+
+    /// Connect with frame-level access to the incoming messages
+    ///
+    /// Check [`FrameStream`](crate::native::frame::FrameStream) for more details.
+    ///
+    /// The connection events observer isn't used.
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn connect_frames(
+        &self,
+        url: &Url,
+    ) -> Result<(crate::native::frame::FrameStream, HandshakeResponse), ConnectError> {
+        crate::native::frame::connect(url, &self.for_url(url)).await
+    }
+
+    /// Apply the mode selected for `url`, if any
+    pub(crate) fn for_url(&self, url: &Url) -> Cow<'_, Self> {
+        match &self.mode_router {
+            Some(router) => {
+                let mode: ConnectionMode = router.select(url, &self.mode);
+                if mode == self.mode {
+                    Cow::Borrowed(self)
+                } else {
+                    Cow::Owned(self.clone().mode(mode))
+                }
+            }
+            None => Cow::Borrowed(self),
+        }
+    }
+
+    /// Notify the connection events of `future`
+    async fn observe<F>(&self, future: F) -> Result<(WebSocket, HandshakeResponse), ConnectError>
+    where
+        F: Future<Output = Result<(WebSocket, HandshakeResponse), ConnectError>>,
+    {
+        let extensions: Extensions = self.extensions.clone().unwrap_or_default();
+
+        self.notify(ConnectionEvent::Connecting, &extensions);
+
+        let start: Instant = Instant::now();
+
+        match future.await {
+            Ok((socket, response)) => {
+                self.notify(
+                    ConnectionEvent::Connected {
+                        took: start.elapsed(),
+                    },
+                    &extensions,
+                );
+                let socket: WebSocket = socket
+                    .with_extensions(extensions)
+                    .with_auto_pong(self.auto_pong)
+                    .with_idle_timeout(self.idle_timeout)
+                    .with_write_coalescing(self.write_coalescing)
+                    .with_shutdown(self.shutdown.as_ref())
+                    .with_rtt(RttMonitor::new(self.rtt_echo.clone()))
+                    .with_observer(self.observer.clone());
+                Ok((socket, response))
+            }
+            Err(e) => {
+                self.notify(ConnectionEvent::Error(e.to_string()), &extensions);
+                Err(e)
+            }
+        }
+    }
+
+    #[inline]
+    fn notify(&self, event: ConnectionEvent, extensions: &Extensions) {
+        if let Some(observer) = &self.observer {
+            observer.notify(event, extensions);
+        }
+    }
+}
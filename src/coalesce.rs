@@ -0,0 +1,165 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Write coalescing
+
+use std::task::Context;
+use std::time::Duration;
+
+use crate::{time, BoxedFuture};
+
+const DEFAULT_MAX_DELAY: Duration = Duration::from_millis(1);
+const DEFAULT_MAX_BYTES: usize = 64 * 1024;
+
+/// Write coalescing config
+///
+/// Check [`WebSocketBuilder::write_coalescing`](crate::WebSocketBuilder::write_coalescing).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WriteCoalescingConfig {
+    /// Max time a fed message may wait in the write buffer (default: 1 ms)
+    pub max_delay: Duration,
+    /// Buffered bytes that trigger an immediate flush (default: 64 KiB)
+    pub max_bytes: usize,
+}
+
+impl Default for WriteCoalescingConfig {
+    fn default() -> Self {
+        Self {
+            max_delay: DEFAULT_MAX_DELAY,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+/// Deferred flushes of the fed messages
+pub(crate) struct Coalescer {
+    config: WriteCoalescingConfig,
+    /// Bytes fed since the last flush
+    pending: usize,
+    /// Deadline of the deferred flush, started by the first fed message
+    timer: Option<BoxedFuture<'static, ()>>,
+}
+
+impl Coalescer {
+    pub(crate) fn new(config: WriteCoalescingConfig) -> Self {
+        Self {
+            config,
+            pending: 0,
+            timer: None,
+        }
+    }
+
+    /// Account a message written to the buffer
+    pub(crate) fn on_sent(&mut self, len: usize) {
+        self.pending = self.pending.saturating_add(len);
+
+        if self.timer.is_none() {
+            self.timer = Some(Box::pin(time::sleep(self.config.max_delay)));
+        }
+    }
+
+    /// Flush at the next check (ex. after a close frame)
+    #[inline]
+    pub(crate) fn force(&mut self) {
+        self.pending = usize::MAX;
+    }
+
+    /// Check if the buffered messages must be flushed now: too many bytes or the delay expired
+    ///
+    /// Otherwise the waker is registered with the timer of the deferred flush.
+    pub(crate) fn poll_due(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.pending == 0 {
+            return false;
+        }
+
+        if self.pending >= self.config.max_bytes {
+            return true;
+        }
+
+        match &mut self.timer {
+            Some(timer) => timer.as_mut().poll(cx).is_ready(),
+            None => true,
+        }
+    }
+
+    /// The buffer has been flushed
+    #[inline]
+    pub(crate) fn reset(&mut self) {
+        self.pending = 0;
+        self.timer = None;
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::{native, Message, WebSocket};
+
+    async fn pair(max_delay: Duration, max_bytes: usize) -> (WebSocket, WebSocket) {
+        let (client, server) = native::duplex().await;
+        let client = client.with_write_coalescing(Some(WriteCoalescingConfig {
+            max_delay,
+            max_bytes,
+        }));
+        (client, server)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_latency() {
+        for max_delay in [Duration::from_millis(1), Duration::from_secs(60)] {
+            let (mut client, mut server) = pair(max_delay, 1024).await;
+
+            // Flushed immediately, whatever the delay
+            let start = Instant::now();
+            client.send(Message::text("hello")).await.unwrap();
+            assert_eq!(start.elapsed(), Duration::ZERO);
+            assert_eq!(
+                server.next().await.unwrap().unwrap(),
+                Message::text("hello")
+            );
+            assert_eq!(start.elapsed(), Duration::ZERO);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deferred_flush() {
+        let (mut client, mut server) = pair(Duration::from_millis(10), 1024).await;
+
+        let start = Instant::now();
+        client.feed(Message::text("a")).await.unwrap();
+        client.feed(Message::text("b")).await.unwrap();
+
+        // Written together when the delay expires, while the client is read
+        let msg = tokio::select! {
+            _ = client.next() => panic!("unexpected message"),
+            msg = server.next() => msg.unwrap().unwrap(),
+        };
+        assert_eq!(msg, Message::text("a"));
+        assert_eq!(start.elapsed(), Duration::from_millis(10));
+        assert_eq!(server.next().await.unwrap().unwrap(), Message::text("b"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_max_bytes() {
+        let (mut client, mut server) = pair(Duration::from_secs(60), 8).await;
+
+        let start = Instant::now();
+        client.feed(Message::text("hello")).await.unwrap();
+        client.feed(Message::text("world")).await.unwrap();
+
+        // Over the threshold: written before accepting the next message
+        client.feed(Message::text("!")).await.unwrap();
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::text("hello")
+        );
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::text("world")
+        );
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Typed message codecs
+//!
+//! | Codec             | Feature   | Message               |
+//! |-------------------|-----------|-----------------------|
+//! | `Json`            | `json`    | [`Message::Text`]     |
+//! | `Cbor`            | `cbor`    | [`Message::Binary`]   |
+//! | `MessagePack`     | `msgpack` | [`Message::Binary`]   |
+
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::future::{self, Either, Ready};
+use futures_util::sink::Send as SendFuture;
+use futures_util::{ready, Sink, SinkExt, Stream};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{Error, Message};
+
+/// Typed message codec
+pub trait Codec {
+    /// Serialize a value to a message
+    fn encode<T>(value: &T) -> Result<Message, Error>
+    where
+        T: Serialize + ?Sized;
+
+    /// Deserialize a value from the payload of a text or binary message
+    fn decode<T>(data: &[u8]) -> Result<T, Error>
+    where
+        T: DeserializeOwned;
+
+    /// Deserialize a value from a message
+    ///
+    /// Returns `None` for the control messages (ping, pong and close).
+    fn decode_message<T>(msg: &Message) -> Result<Option<T>, Error>
+    where
+        T: DeserializeOwned,
+    {
+        match msg {
+            Message::Text(text) => Self::decode(text.as_bytes()).map(Some),
+            Message::Binary(data) => Self::decode(data).map(Some),
+            Message::Ping(..) | Message::Pong(..) | Message::Close(..) => Ok(None),
+        }
+    }
+}
+
+/// JSON codec
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    #[inline]
+    fn encode<T>(value: &T) -> Result<Message, Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        crate::json::to_message(value)
+    }
+
+    #[inline]
+    fn decode<T>(data: &[u8]) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// CBOR codec
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<T>(value: &T) -> Result<Message, Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        let mut buf: Vec<u8> = Vec::new();
+        ciborium::into_writer(value, &mut buf).map_err(Error::codec)?;
+        Ok(Message::binary(buf))
+    }
+
+    #[inline]
+    fn decode<T>(data: &[u8]) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        ciborium::from_reader(data).map_err(Error::codec)
+    }
+}
+
+/// MessagePack codec
+///
+/// The structs are encoded as maps, with the field names.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    #[inline]
+    fn encode<T>(value: &T) -> Result<Message, Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        let buf: Vec<u8> = rmp_serde::to_vec_named(value).map_err(Error::codec)?;
+        Ok(Message::binary(buf))
+    }
+
+    #[inline]
+    fn decode<T>(data: &[u8]) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        rmp_serde::from_slice(data).map_err(Error::codec)
+    }
+}
+
+/// Codec extension for the sinks of [`Message`]
+pub trait CodecSinkExt: Sink<Message, Error = Error> + Unpin {
+    /// Serialize a value with the codec `C` and send it
+    fn send_encoded<C, T>(
+        &mut self,
+        value: &T,
+    ) -> Either<Ready<Result<(), Error>>, SendFuture<'_, Self, Message>>
+    where
+        C: Codec,
+        T: Serialize + ?Sized,
+    {
+        match C::encode(value) {
+            Ok(msg) => Either::Right(self.send(msg)),
+            Err(e) => Either::Left(future::ready(Err(e))),
+        }
+    }
+}
+
+impl<S> CodecSinkExt for S where S: Sink<Message, Error = Error> + Unpin + ?Sized {}
+
+/// Codec extension for the streams of [`Message`]
+pub trait CodecStreamExt: Stream<Item = Result<Message, Error>> + Sized {
+    /// Deserialize the incoming messages with the codec `C`
+    ///
+    /// Check [`CodecStream`] for more details.
+    #[inline]
+    fn decode<C, T>(self) -> CodecStream<Self, C, T>
+    where
+        C: Codec,
+        T: DeserializeOwned,
+    {
+        CodecStream::new(self)
+    }
+}
+
+impl<S> CodecStreamExt for S where S: Stream<Item = Result<Message, Error>> {}
+
+/// Stream of deserialized messages
+///
+/// The control messages (ping, pong and close) are skipped.
+/// A message that fails to deserialize yields an error, without ending the stream.
+pub struct CodecStream<S, C, T> {
+    stream: S,
+    _codec: PhantomData<fn() -> (C, T)>,
+}
+
+impl<S, C, T> CodecStream<S, C, T> {
+    /// Wrap a stream
+    #[inline]
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            _codec: PhantomData,
+        }
+    }
+
+    /// Get a reference to the inner stream
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Consume the wrapper, returning the inner stream
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S, C, T> Stream for CodecStream<S, C, T>
+where
+    S: Stream<Item = Result<Message, Error>> + Unpin,
+    C: Codec,
+    T: DeserializeOwned,
+{
+    type Item = Result<T, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+                Some(Ok(msg)) => match C::decode_message(&msg) {
+                    Ok(Some(value)) => return Poll::Ready(Some(Ok(value))),
+                    Ok(None) => continue,
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
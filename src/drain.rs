@@ -0,0 +1,165 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Closing handshake draining the incoming messages
+
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::{ready, Sink, Stream};
+
+use crate::message::CloseFrame;
+use crate::{time, BoxedFuture, Error, Message, WebSocket};
+
+enum State {
+    /// Sending our close frame
+    Sending(Option<CloseFrame>),
+    /// Flushing our close frame
+    Flushing,
+    /// Waiting for the close frame of the peer
+    Receiving,
+    /// Closing the underlying connection
+    Closing,
+    Done,
+}
+
+/// Messages received during the closing handshake
+///
+/// Check [`WebSocket::close_and_drain`].
+pub struct Drain<'a> {
+    socket: &'a mut WebSocket,
+    state: State,
+    deadline: BoxedFuture<'static, ()>,
+}
+
+impl fmt::Debug for Drain<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Drain")
+            .field("done", &matches!(self.state, State::Done))
+            .finish()
+    }
+}
+
+impl<'a> Drain<'a> {
+    pub(crate) fn new(socket: &'a mut WebSocket, frame: CloseFrame, timeout: Duration) -> Self {
+        Self {
+            socket,
+            state: State::Sending(Some(frame)),
+            deadline: Box::pin(time::sleep(timeout)),
+        }
+    }
+
+    fn fail(&mut self, e: Error) -> Poll<Option<Result<Message, Error>>> {
+        self.state = State::Done;
+        Poll::Ready(Some(Err(e)))
+    }
+}
+
+impl Stream for Drain<'_> {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if matches!(this.state, State::Done) {
+            return Poll::Ready(None);
+        }
+
+        if this.deadline.as_mut().poll(cx).is_ready() {
+            return this.fail(Error::CloseTimeout);
+        }
+
+        loop {
+            match &mut this.state {
+                State::Sending(frame) => {
+                    if let Err(e) = ready!(Pin::new(&mut *this.socket).poll_ready(cx)) {
+                        return this.fail(e);
+                    }
+
+                    let msg: Message = Message::Close(frame.take());
+                    if let Err(e) = Pin::new(&mut *this.socket).start_send(msg) {
+                        return this.fail(e);
+                    }
+                    this.state = State::Flushing;
+                }
+                State::Flushing => {
+                    if let Err(e) = ready!(Pin::new(&mut *this.socket).poll_flush(cx)) {
+                        return this.fail(e);
+                    }
+                    this.state = State::Receiving;
+                }
+                State::Receiving => match ready!(Pin::new(&mut *this.socket).poll_next(cx)) {
+                    Some(Ok(Message::Close(..))) => this.state = State::Closing,
+                    Some(Ok(msg)) => return Poll::Ready(Some(Ok(msg))),
+                    Some(Err(e)) => return this.fail(e),
+                    // The connection has been dropped by the peer
+                    None => {
+                        this.state = State::Done;
+                        return Poll::Ready(None);
+                    }
+                },
+                State::Closing => {
+                    let res = ready!(Pin::new(&mut *this.socket).poll_close(cx));
+                    this.state = State::Done;
+                    return match res {
+                        Ok(()) => Poll::Ready(None),
+                        Err(e) => Poll::Ready(Some(Err(e))),
+                    };
+                }
+                State::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::message::CloseCode;
+    use crate::native;
+
+    #[tokio::test]
+    async fn test_in_flight_messages() {
+        let (mut client, mut server) = native::duplex().await;
+
+        // Sent before the peer sees our close frame
+        server.send(Message::text("a")).await.unwrap();
+        server.send(Message::text("b")).await.unwrap();
+        let server = tokio::spawn(async move {
+            let mut received: Vec<Message> = Vec::new();
+            while let Some(Ok(msg)) = server.next().await {
+                received.push(msg);
+            }
+            received
+        });
+
+        let drained: Vec<Message> = client
+            .close_and_drain(CloseCode::Normal, "bye", Duration::from_secs(10))
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(drained, [Message::text("a"), Message::text("b")]);
+
+        let frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".into(),
+        };
+        assert_eq!(server.await.unwrap(), [Message::Close(Some(frame))]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout() {
+        // The peer never completes the closing handshake
+        let (mut client, _server) = native::duplex().await;
+
+        let start = time::Instant::now();
+        let mut drain = client.close_and_drain(CloseCode::Normal, "bye", Duration::from_secs(1));
+        assert!(matches!(drain.next().await, Some(Err(Error::CloseTimeout))));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert!(drain.next().await.is_none());
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Error classification
+
+use std::fmt;
+
+/// Error kind, shared by the native and WASM errors
+///
+/// Check [`Error::kind`](crate::Error::kind) and [`ConnectError::kind`](crate::ConnectError::kind).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorKind {
+    /// Invalid URL, config or argument
+    InvalidInput,
+    /// The host couldn't be resolved
+    Dns,
+    /// The connection has been refused
+    Refused,
+    /// The host or the network is unreachable
+    ///
+    /// On WASM targets, the browsers hide why a connection failed: every failure is reported as unreachable.
+    Unreachable,
+    /// TLS failure (handshake, certificate, etc.)
+    Tls,
+    /// Proxy failure (SOCKS, HTTP or Tor)
+    Proxy,
+    /// The server rejected the HTTP handshake with this status code
+    HandshakeRejected(u16),
+    /// WebSocket protocol violation
+    Protocol,
+    /// The connection has been closed by the peer
+    ClosedByPeer,
+    /// The connection has been reset or dropped
+    ConnectionLost,
+    /// Connection phase, keepalive, idle or request timeout
+    Timeout,
+    /// Limit exceeded by the peer
+    Limit,
+    /// Invalid application data (codec, correlator, etc.)
+    InvalidData,
+    /// Cancelled by the application
+    Cancelled,
+    /// Other error
+    Other,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidInput => write!(f, "invalid input"),
+            Self::Dns => write!(f, "dns failure"),
+            Self::Refused => write!(f, "connection refused"),
+            Self::Unreachable => write!(f, "unreachable"),
+            Self::Tls => write!(f, "tls failure"),
+            Self::Proxy => write!(f, "proxy failure"),
+            Self::HandshakeRejected(status) => write!(f, "handshake rejected: status={status}"),
+            Self::Protocol => write!(f, "protocol violation"),
+            Self::ClosedByPeer => write!(f, "closed by peer"),
+            Self::ConnectionLost => write!(f, "connection lost"),
+            Self::Timeout => write!(f, "timeout"),
+            Self::Limit => write!(f, "limit exceeded"),
+            Self::InvalidData => write!(f, "invalid data"),
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::Other => write!(f, "other"),
+        }
+    }
+}
+
+impl ErrorKind {
+    /// Check if retrying (i.e. reconnecting) may succeed
+    ///
+    /// Network failures, timeouts and server overload (`408`, `429` and `5xx` statuses) are retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Dns
+            | Self::Refused
+            | Self::Unreachable
+            | Self::Proxy
+            | Self::ClosedByPeer
+            | Self::ConnectionLost
+            | Self::Timeout
+            | Self::Other => true,
+            Self::HandshakeRejected(status) => {
+                matches!(status, 408 | 429) || (500..600).contains(status)
+            }
+            Self::InvalidInput
+            | Self::Tls
+            | Self::Protocol
+            | Self::Limit
+            | Self::InvalidData
+            | Self::Cancelled => false,
+        }
+    }
+
+    /// Check if retrying will fail the same way
+    #[inline]
+    pub fn is_permanent(&self) -> bool {
+        !self.is_retryable()
+    }
+}
+
+/// WebSocket protocol violation by the peer
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ProtocolViolation {
+    /// Reserved bits set, without a negotiated extension
+    NonZeroReservedBits,
+    /// Unmasked frame from a client or masked frame from a server
+    InvalidMasking,
+    /// Unknown opcode
+    UnknownOpcode(u8),
+    /// Fragmented or too big control frame
+    InvalidControlFrame,
+    /// Unexpected continuation frame or missing continuation of a fragmented message
+    InvalidFragmentation,
+    /// Invalid close frame (ex. close code not allowed)
+    InvalidCloseFrame,
+    /// Data frame received or sent after the close frame
+    DataAfterClose,
+    /// Connection closed without the closing handshake
+    ResetWithoutClosingHandshake,
+    /// Invalid opening handshake
+    Handshake(String),
+}
+
+impl fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NonZeroReservedBits => write!(f, "reserved bits are non-zero"),
+            Self::InvalidMasking => write!(f, "invalid frame masking"),
+            Self::UnknownOpcode(opcode) => write!(f, "unknown opcode: {opcode}"),
+            Self::InvalidControlFrame => write!(f, "invalid control frame"),
+            Self::InvalidFragmentation => write!(f, "invalid fragmentation"),
+            Self::InvalidCloseFrame => write!(f, "invalid close frame"),
+            Self::DataAfterClose => write!(f, "data after close"),
+            Self::ResetWithoutClosingHandshake => {
+                write!(f, "connection reset without closing handshake")
+            }
+            Self::Handshake(e) => write!(f, "handshake: {e}"),
+        }
+    }
+}
+
+impl ProtocolViolation {
+    /// Get the error kind
+    ///
+    /// A reset without the closing handshake is [`ErrorKind::ConnectionLost`], the others are [`ErrorKind::Protocol`].
+    #[inline]
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::ResetWithoutClosingHandshake => ErrorKind::ConnectionLost,
+            _ => ErrorKind::Protocol,
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::{ConnectError, Error, Phase};
+
+    #[test]
+    fn test_retryable_status() {
+        assert!(ErrorKind::HandshakeRejected(429).is_retryable());
+        assert!(ErrorKind::HandshakeRejected(503).is_retryable());
+        assert!(ErrorKind::HandshakeRejected(404).is_permanent());
+        assert!(ErrorKind::Tls.is_permanent());
+    }
+
+    #[test]
+    fn test_connect_error() {
+        let e = ConnectError::Timeout(Phase::Handshake);
+        assert_eq!(e.kind(), ErrorKind::Timeout);
+        assert!(e.is_retryable());
+        assert!(ConnectError::NoUrl.is_permanent());
+    }
+
+    #[test]
+    fn test_error_size() {
+        // Below the threshold of `clippy::result_large_err`: the large payloads are boxed
+        assert!(std::mem::size_of::<Error>() <= 128);
+        assert!(std::mem::size_of::<ConnectError>() <= 128);
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection events
+
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures_util::Stream;
+
+use crate::message::{CloseCode, CloseFrame};
+use crate::Extensions;
+
+/// Connection event
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ConnectionEvent {
+    /// Connection attempt started
+    Connecting,
+    /// Connection established
+    Connected {
+        /// Time taken to connect (including the handshake)
+        took: Duration,
+    },
+    /// The connection is closing
+    Closing,
+    /// The connection has been closed
+    ///
+    /// If the close frame has no status code, the code is `1005` (no status received).
+    /// If the connection was dropped without a close frame, the code is `1006` (abnormal closure).
+    Closed(CloseFrame),
+    /// Connection error
+    Error(String),
+}
+
+impl ConnectionEvent {
+    /// Close frame received
+    #[inline]
+    pub(crate) fn closed(frame: Option<CloseFrame>) -> Self {
+        Self::Closed(frame.unwrap_or_else(|| CloseFrame {
+            code: CloseCode::NoStatusReceived,
+            reason: Default::default(),
+        }))
+    }
+
+    /// Connection dropped without a close frame
+    #[inline]
+    pub(crate) fn dropped() -> Self {
+        Self::Closed(CloseFrame {
+            code: CloseCode::Abnormal,
+            reason: Default::default(),
+        })
+    }
+}
+
+/// Connection observer
+///
+/// Pass it to [`WebSocketBuilder::observer`](crate::WebSocketBuilder::observer)
+/// to receive the events in the paired [`ConnectionEvents`] stream.
+#[derive(Clone)]
+pub struct ConnectionObserver {
+    tx: UnboundedSender<(ConnectionEvent, Extensions)>,
+}
+
+impl fmt::Debug for ConnectionObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionObserver").finish()
+    }
+}
+
+impl PartialEq for ConnectionObserver {
+    fn eq(&self, other: &Self) -> bool {
+        self.tx.same_receiver(&other.tx)
+    }
+}
+
+impl Eq for ConnectionObserver {}
+
+impl ConnectionObserver {
+    /// Notify the event (ignored if the [`ConnectionEvents`] stream has been dropped)
+    #[inline]
+    pub(crate) fn notify(&self, event: ConnectionEvent, extensions: &Extensions) {
+        let _ = self.tx.unbounded_send((event, extensions.clone()));
+    }
+}
+
+/// Stream of [`ConnectionEvent`]
+///
+/// The stream ends when all the paired [`ConnectionObserver`]s
+/// (and the connections that use them) are dropped.
+pub struct ConnectionEvents {
+    rx: UnboundedReceiver<(ConnectionEvent, Extensions)>,
+}
+
+impl fmt::Debug for ConnectionEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionEvents").finish()
+    }
+}
+
+impl ConnectionEvents {
+    /// Create a new observer and the paired stream of events
+    pub fn channel() -> (ConnectionObserver, Self) {
+        let (tx, rx) = mpsc::unbounded();
+        (ConnectionObserver { tx }, Self { rx })
+    }
+
+    /// Yield also the [`Extensions`] of the connection that emitted the event
+    ///
+    /// Useful when the same observer is shared by multiple connections.
+    #[inline]
+    pub fn tagged(self) -> TaggedEvents {
+        TaggedEvents { rx: self.rx }
+    }
+}
+
+impl Stream for ConnectionEvents {
+    type Item = ConnectionEvent;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx)
+            .poll_next(cx)
+            .map(|item| item.map(|(event, _)| event))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.rx.size_hint()
+    }
+}
+
+/// Stream of [`ConnectionEvent`], with the [`Extensions`] of the connection
+///
+/// Check [`ConnectionEvents::tagged`].
+pub struct TaggedEvents {
+    rx: UnboundedReceiver<(ConnectionEvent, Extensions)>,
+}
+
+impl fmt::Debug for TaggedEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaggedEvents").finish()
+    }
+}
+
+impl Stream for TaggedEvents {
+    type Item = (ConnectionEvent, Extensions);
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.rx.size_hint()
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Per-connection user context
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+type Map = HashMap<TypeId, Box<dyn Any + Send + Sync>>;
+
+/// Typed map of user values attached to a connection, one value per type
+///
+/// Set it with [`WebSocketBuilder::extensions`](crate::WebSocketBuilder::extensions)
+/// and get it back from the socket, its [`StatsHandle`](crate::StatsHandle),
+/// the [`TaggedEvents`](crate::event::TaggedEvents), a [`ReconnectingWebSocket`](crate::reconnect::ReconnectingWebSocket)
+/// or a [`WsPool`](crate::WsPool), to correlate the connections with the application state.
+///
+/// The map is cheap to clone: all the clones refer to the same values.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: Arc<Mutex<Map>>,
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl PartialEq for Extensions {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.map, &other.map)
+    }
+}
+
+impl Eq for Extensions {}
+
+impl Extensions {
+    /// New empty map
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a value, returning the previous one of the same type
+    pub fn insert<T>(&self, value: T) -> Option<T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.lock()
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|prev| prev.downcast().ok())
+            .map(|prev| *prev)
+    }
+
+    /// Get a clone of the value of type `T`
+    pub fn get<T>(&self) -> Option<T>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        self.lock()
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+            .cloned()
+    }
+
+    /// Check if a value of type `T` is set
+    pub fn contains<T>(&self) -> bool
+    where
+        T: Send + Sync + 'static,
+    {
+        self.lock().contains_key(&TypeId::of::<T>())
+    }
+
+    /// Remove the value of type `T`
+    pub fn remove<T>(&self) -> Option<T>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.lock()
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    /// Number of values
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Check if the map is empty
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all the values
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Map> {
+        self.map.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
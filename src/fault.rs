@@ -0,0 +1,260 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Fault injection
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::{ready, Sink, Stream};
+
+use crate::{time, BoxedFuture, Error, Message};
+
+/// Fault injection config
+///
+/// The probabilities (`0.0..=1.0`) are applied to every message independently.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Faults {
+    /// Seed of the random number generator: the same seed injects the same faults
+    pub seed: u64,
+    /// Probability to drop an incoming message
+    pub drop_incoming: f64,
+    /// Probability to drop an outgoing message
+    pub drop_outgoing: f64,
+    /// Probability to delay an incoming message
+    pub delay: f64,
+    /// Max delay of an incoming message (the actual one is random in `0..=max_delay`)
+    pub max_delay: Duration,
+    /// Probability to truncate a close: the outgoing close frame is dropped and the connection cut
+    pub truncate_close: f64,
+    /// Probability to disconnect when receiving a message
+    pub disconnect: f64,
+    /// Disconnect when receiving the message following the first `n` ones
+    pub disconnect_after: Option<usize>,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            drop_incoming: 0.0,
+            drop_outgoing: 0.0,
+            delay: 0.0,
+            max_delay: Duration::ZERO,
+            truncate_close: 0.0,
+            disconnect: 0.0,
+            disconnect_after: None,
+        }
+    }
+}
+
+/// SplitMix64 generator: tiny, deterministic and good enough for fault injection
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z: u64 = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns `true` with probability `p`
+    fn chance(&mut self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+
+        // 53 random bits, uniform in `0.0..1.0`
+        let x: f64 = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        x < p
+    }
+
+    /// Random duration in `0..=max`
+    fn duration(&mut self, max: Duration) -> Duration {
+        let max: u64 = u64::try_from(max.as_nanos()).unwrap_or(u64::MAX);
+        match max.checked_add(1) {
+            Some(n) => Duration::from_nanos(self.next_u64() % n),
+            None => Duration::from_nanos(self.next_u64()),
+        }
+    }
+}
+
+/// Fault injection wrapper
+///
+/// Drops, delays and disconnects according to [`Faults`], to test the reconnect and error handling
+/// of an application deterministically.
+///
+/// After a disconnection, the stream yields an error (once) and then ends and the sends fail.
+/// The inner socket is left untouched: drop the wrapper to release it.
+pub struct FaultInjector<S> {
+    socket: S,
+    faults: Faults,
+    rng: Rng,
+    /// Number of incoming messages
+    received: usize,
+    /// Incoming message waiting for its delay to elapse
+    delayed: Option<(BoxedFuture<'static, ()>, Message)>,
+    disconnected: bool,
+    /// Disconnection error not yielded yet
+    error: bool,
+}
+
+impl<S> FaultInjector<S> {
+    /// Wrap a socket
+    pub fn new(socket: S, faults: Faults) -> Self {
+        Self {
+            socket,
+            rng: Rng(faults.seed),
+            faults,
+            received: 0,
+            delayed: None,
+            disconnected: false,
+            error: false,
+        }
+    }
+
+    /// Get a reference to the inner socket
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    /// Get a mutable reference to the inner socket
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    /// Check if a disconnection has been injected
+    #[inline]
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    /// Consume the wrapper, returning the inner socket
+    ///
+    /// The delayed message, if any, is dropped.
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+
+    fn disconnect(&mut self) {
+        self.disconnected = true;
+        self.error = true;
+        self.delayed = None;
+    }
+}
+
+impl<S> Sink<Message> for FaultInjector<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.disconnected {
+            return Poll::Ready(Err(Error::not_connected()));
+        }
+
+        Pin::new(&mut self.socket).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+
+        if this.disconnected {
+            return Err(Error::not_connected());
+        }
+
+        if let Message::Close(..) = item {
+            if this.rng.chance(this.faults.truncate_close) {
+                this.disconnect();
+                return Ok(());
+            }
+        }
+
+        if this.rng.chance(this.faults.drop_outgoing) {
+            return Ok(());
+        }
+
+        Pin::new(&mut this.socket).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.disconnected {
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.disconnected {
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.socket).poll_close(cx)
+    }
+}
+
+impl<S> Stream for FaultInjector<S>
+where
+    S: Stream<Item = Result<Message, Error>> + Unpin,
+{
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.disconnected {
+                if this.error {
+                    this.error = false;
+                    return Poll::Ready(Some(Err(Error::not_connected())));
+                }
+
+                return Poll::Ready(None);
+            }
+
+            if let Some((sleep, _)) = &mut this.delayed {
+                ready!(sleep.as_mut().poll(cx));
+
+                if let Some((_, msg)) = this.delayed.take() {
+                    return Poll::Ready(Some(Ok(msg)));
+                }
+            }
+
+            match ready!(Pin::new(&mut this.socket).poll_next(cx)) {
+                Some(Ok(msg)) => {
+                    this.received += 1;
+
+                    let scheduled: bool = this
+                        .faults
+                        .disconnect_after
+                        .is_some_and(|n| this.received > n);
+                    if scheduled || this.rng.chance(this.faults.disconnect) {
+                        this.disconnect();
+                        continue;
+                    }
+
+                    if this.rng.chance(this.faults.drop_incoming) {
+                        continue;
+                    }
+
+                    if this.rng.chance(this.faults.delay) {
+                        let delay: Duration = this.rng.duration(this.faults.max_delay);
+                        this.delayed = Some((Box::pin(time::sleep(delay)), msg));
+                        continue;
+                    }
+
+                    return Poll::Ready(Some(Ok(msg)));
+                }
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
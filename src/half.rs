@@ -0,0 +1,247 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Send-only and receive-only connections
+//!
+//! A half that is never polled stalls the connection: the pings aren't answered
+//! and the close frame of the peer isn't processed.
+//! [`SendOnly`] and [`RecvOnly`] service the other direction with an internal task
+//! (on native targets, spawned on the current tokio runtime), stopped when they are dropped.
+//!
+//! Check [`WebSocketBuilder::connect_send_only`](crate::WebSocketBuilder::connect_send_only)
+//! and [`WebSocketBuilder::connect_recv_only`](crate::WebSocketBuilder::connect_recv_only).
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+
+use futures_util::future::{self, AbortHandle};
+use futures_util::stream::{SplitSink, SplitStream};
+#[cfg(not(target_arch = "wasm32"))]
+use futures_util::SinkExt;
+use futures_util::{Sink, Stream, StreamExt};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::message::Bytes;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::time;
+use crate::{Error, Message, WebSocket};
+
+/// Interval of the pings sent by [`RecvOnly`]
+#[cfg(not(target_arch = "wasm32"))]
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Spawn a task, stopped by the returned handle
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn spawn<F>(task: F) -> AbortHandle
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (task, handle) = future::abortable(task);
+    tokio::spawn(task);
+    handle
+}
+
+/// Spawn a task, stopped by the returned handle
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn spawn<F>(task: F) -> AbortHandle
+where
+    F: Future<Output = ()> + 'static,
+{
+    let (task, handle) = future::abortable(task);
+    wasm_bindgen_futures::spawn_local(async move {
+        let _ = task.await;
+    });
+    handle
+}
+
+/// Send-only connection
+///
+/// The incoming messages are read and discarded by an internal task.
+pub struct SendOnly {
+    sink: SplitSink<WebSocket, Message>,
+    reader: AbortHandle,
+}
+
+impl fmt::Debug for SendOnly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendOnly").finish_non_exhaustive()
+    }
+}
+
+impl Drop for SendOnly {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl SendOnly {
+    /// Discard the incoming messages of `socket`, keeping only the sending half
+    pub fn new(socket: WebSocket) -> Self {
+        let (sink, mut stream) = socket.split();
+
+        // Reading answers the pings and processes the close frame of the peer
+        let reader: AbortHandle =
+            spawn(async move { while let Some(Ok(..)) = stream.next().await {} });
+
+        Self { sink, reader }
+    }
+}
+
+impl Sink<Message> for SendOnly {
+    type Error = Error;
+
+    #[inline]
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.sink).poll_ready(cx)
+    }
+
+    #[inline]
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        Pin::new(&mut self.sink).start_send(item)
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.sink).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.sink).poll_close(cx)
+    }
+}
+
+/// Receive-only connection
+///
+/// On native targets, an internal task sends a ping every 30 secs, so the idle connections aren't dropped
+/// by the proxies. On WASM targets the browser handles the pings.
+pub struct RecvOnly {
+    stream: SplitStream<WebSocket>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pinger: AbortHandle,
+}
+
+impl fmt::Debug for RecvOnly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvOnly").finish_non_exhaustive()
+    }
+}
+
+impl Drop for RecvOnly {
+    fn drop(&mut self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        self.pinger.abort();
+    }
+}
+
+impl RecvOnly {
+    /// Keep only the receiving half of `socket`
+    pub fn new(socket: WebSocket) -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let (mut sink, stream) = socket.split();
+
+            let pinger: AbortHandle = spawn(async move {
+                loop {
+                    time::sleep(PING_INTERVAL).await;
+                    if sink.send(Message::Ping(Bytes::new())).await.is_err() {
+                        break;
+                    }
+                }
+            });
+
+            Self { stream, pinger }
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            let (_, stream) = socket.split();
+            Self { stream }
+        }
+    }
+}
+
+impl Stream for RecvOnly {
+    type Item = Result<Message, Error>;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::message::{CloseCode, CloseFrame};
+    use crate::native;
+
+    #[tokio::test]
+    async fn test_send_only() {
+        let (client, mut server) = native::duplex().await;
+        let mut client = SendOnly::new(client);
+
+        client.send(Message::text("hello")).await.unwrap();
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::text("hello")
+        );
+
+        // The pings are answered by the internal reader
+        server.send(Message::Ping(vec![1].into())).await.unwrap();
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Pong(vec![1].into())
+        );
+
+        // The close frame of the peer is echoed
+        let frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".into(),
+        };
+        server
+            .send(Message::Close(Some(frame.clone())))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Close(Some(frame))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recv_only() {
+        let (client, server) = native::duplex().await;
+        let mut client = RecvOnly::new(client);
+
+        // Yield the pings
+        let mut server = server.with_auto_pong(false);
+
+        server.send(Message::text("hello")).await.unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::text("hello")
+        );
+
+        // Pinged by the internal task
+        let start = time::Instant::now();
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Ping(Bytes::new())
+        );
+        assert_eq!(start.elapsed(), PING_INTERVAL);
+
+        // Dropping stops the task and the connection
+        drop(client);
+        assert!(!matches!(server.next().await, Some(Ok(Message::Ping(..)))));
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Read-idle timeout
+
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::{time, BoxedFuture};
+
+/// Read-idle timeout config
+///
+/// Check [`WebSocketBuilder::idle_timeout`](crate::WebSocketBuilder::idle_timeout).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IdleTimeoutConfig {
+    /// Max time without incoming frames
+    pub timeout: Duration,
+    /// Send a ping after [`IdleTimeoutConfig::timeout`] of silence and wait this long for any frame
+    /// before giving up (default: none)
+    ///
+    /// On WASM targets the browsers don't expose the pings: no probe is sent, but the grace period is still waited.
+    pub probe: Option<Duration>,
+}
+
+impl IdleTimeoutConfig {
+    /// New config, without probe
+    #[inline]
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            probe: None,
+        }
+    }
+
+    /// Send a probing ping and wait `grace` before giving up
+    #[inline]
+    pub fn probe(mut self, grace: Duration) -> Self {
+        self.probe = Some(grace);
+        self
+    }
+}
+
+pub(crate) enum IdleEvent {
+    /// Silent for the timeout: send a probe
+    Probe,
+    /// Silent for too long
+    Expired,
+}
+
+enum State {
+    /// Waiting for the timeout to expire
+    Idle(BoxedFuture<'static, ()>),
+    /// Probe sent, waiting for the grace period to expire
+    Probing(BoxedFuture<'static, ()>),
+    Expired,
+}
+
+/// Read-idle timer
+pub(crate) struct IdleTimer {
+    config: IdleTimeoutConfig,
+    state: State,
+}
+
+impl IdleTimer {
+    pub(crate) fn new(config: IdleTimeoutConfig) -> Self {
+        Self {
+            state: State::Idle(Box::pin(time::sleep(config.timeout))),
+            config,
+        }
+    }
+
+    /// Restart the timer, after a frame has been received
+    #[inline]
+    pub(crate) fn reset(&mut self) {
+        self.state = State::Idle(Box::pin(time::sleep(self.config.timeout)));
+    }
+
+    #[inline]
+    pub(crate) fn is_expired(&self) -> bool {
+        matches!(self.state, State::Expired)
+    }
+
+    /// Drive the timer
+    pub(crate) fn poll(&mut self, cx: &mut Context<'_>) -> Poll<IdleEvent> {
+        match &mut self.state {
+            State::Idle(sleep) => {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+
+                match self.config.probe {
+                    Some(grace) => {
+                        let mut sleep: BoxedFuture<'static, ()> = Box::pin(time::sleep(grace));
+
+                        // Register the waker
+                        if sleep.as_mut().poll(cx).is_ready() {
+                            self.state = State::Expired;
+                            return Poll::Ready(IdleEvent::Expired);
+                        }
+
+                        self.state = State::Probing(sleep);
+                        Poll::Ready(IdleEvent::Probe)
+                    }
+                    None => {
+                        self.state = State::Expired;
+                        Poll::Ready(IdleEvent::Expired)
+                    }
+                }
+            }
+            State::Probing(sleep) => {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+
+                self.state = State::Expired;
+                Poll::Ready(IdleEvent::Expired)
+            }
+            State::Expired => Poll::Ready(IdleEvent::Expired),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::{native, Error, Message};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired() {
+        let (client, _server) = native::duplex().await;
+        let mut client = client.with_idle_timeout(Some(IdleTimeoutConfig::new(TIMEOUT)));
+
+        let start = Instant::now();
+        assert!(matches!(client.next().await, Some(Err(Error::IdleTimeout))));
+        assert_eq!(start.elapsed(), TIMEOUT);
+        assert!(client.next().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reset_by_frames() {
+        let (client, mut server) = native::duplex().await;
+        let mut client = client.with_idle_timeout(Some(IdleTimeoutConfig::new(TIMEOUT)));
+
+        let start = Instant::now();
+        let (msg, ()) = futures_util::future::join(client.next(), async {
+            time::sleep(TIMEOUT / 2).await;
+            server.send(Message::text("alive")).await.unwrap();
+        })
+        .await;
+        assert_eq!(msg.unwrap().unwrap(), Message::text("alive"));
+
+        // Restarted by the message
+        assert!(matches!(client.next().await, Some(Err(Error::IdleTimeout))));
+        assert_eq!(start.elapsed(), TIMEOUT / 2 + TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_probe_answered() {
+        let (client, mut server) = native::duplex().await;
+        let mut client = client.with_idle_timeout(Some(
+            IdleTimeoutConfig::new(TIMEOUT).probe(Duration::from_secs(1)),
+        ));
+
+        // The server answers the ping while reading
+        tokio::spawn(async move { while server.next().await.is_some() {} });
+
+        let start = Instant::now();
+        assert!(matches!(client.next().await, Some(Ok(Message::Pong(..)))));
+        assert_eq!(start.elapsed(), TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_probe_unanswered() {
+        let (client, _server) = native::duplex().await;
+        let mut client = client.with_idle_timeout(Some(
+            IdleTimeoutConfig::new(TIMEOUT).probe(Duration::from_secs(1)),
+        ));
+
+        let start = Instant::now();
+        assert!(matches!(client.next().await, Some(Err(Error::IdleTimeout))));
+        assert_eq!(start.elapsed(), TIMEOUT + Duration::from_secs(1));
+    }
+}
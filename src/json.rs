@@ -0,0 +1,127 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Typed JSON messages
+
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::future::{self, Either, Ready};
+use futures_util::sink::Send as SendFuture;
+use futures_util::{ready, Sink, SinkExt, Stream};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{Error, Message};
+
+/// Serialize a value to a [`Message::Text`]
+pub fn to_message<T>(value: &T) -> Result<Message, Error>
+where
+    T: Serialize + ?Sized,
+{
+    Ok(Message::text(serde_json::to_string(value)?))
+}
+
+/// Deserialize a value from a text or binary message
+///
+/// Returns `None` for the control messages (ping, pong and close).
+pub fn from_message<T>(msg: &Message) -> Result<Option<T>, Error>
+where
+    T: DeserializeOwned,
+{
+    match msg {
+        Message::Text(text) => Ok(Some(serde_json::from_str(text)?)),
+        Message::Binary(data) => Ok(Some(serde_json::from_slice(data)?)),
+        Message::Ping(..) | Message::Pong(..) | Message::Close(..) => Ok(None),
+    }
+}
+
+/// JSON extension for the sinks of [`Message`]
+pub trait JsonSinkExt: Sink<Message, Error = Error> + Unpin {
+    /// Serialize a value and send it as a [`Message::Text`]
+    fn send_json<T>(
+        &mut self,
+        value: &T,
+    ) -> Either<Ready<Result<(), Error>>, SendFuture<'_, Self, Message>>
+    where
+        T: Serialize + ?Sized,
+    {
+        match to_message(value) {
+            Ok(msg) => Either::Right(self.send(msg)),
+            Err(e) => Either::Left(future::ready(Err(e))),
+        }
+    }
+}
+
+impl<S> JsonSinkExt for S where S: Sink<Message, Error = Error> + Unpin + ?Sized {}
+
+/// JSON extension for the streams of [`Message`]
+pub trait JsonStreamExt: Stream<Item = Result<Message, Error>> + Sized {
+    /// Deserialize the incoming messages
+    ///
+    /// Check [`JsonStream`] for more details.
+    #[inline]
+    fn json<T>(self) -> JsonStream<Self, T>
+    where
+        T: DeserializeOwned,
+    {
+        JsonStream::new(self)
+    }
+}
+
+impl<S> JsonStreamExt for S where S: Stream<Item = Result<Message, Error>> {}
+
+/// Stream of deserialized JSON messages
+///
+/// The control messages (ping, pong and close) are skipped.
+/// A message that fails to deserialize yields [`Error::Json`], without ending the stream.
+pub struct JsonStream<S, T> {
+    stream: S,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<S, T> JsonStream<S, T> {
+    /// Wrap a stream
+    #[inline]
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            _type: PhantomData,
+        }
+    }
+
+    /// Get a reference to the inner stream
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Consume the wrapper, returning the inner stream
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S, T> Stream for JsonStream<S, T>
+where
+    S: Stream<Item = Result<Message, Error>> + Unpin,
+    T: DeserializeOwned,
+{
+    type Item = Result<T, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+                Some(Ok(msg)) => match from_message(&msg) {
+                    Ok(Some(value)) => return Poll::Ready(Some(Ok(value))),
+                    Ok(None) => continue,
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Keepalive
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::{Sink, Stream};
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::message::Bytes;
+use crate::{time, BoxedFuture, Error, Message};
+
+/// Keepalive config
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeepAliveConfig {
+    /// Send a probe after this period of inactivity
+    pub interval: Duration,
+    /// Max time to wait for an incoming message after a probe was sent
+    pub timeout: Duration,
+    /// Probe to send
+    ///
+    /// On native targets, `None` means an empty [`Message::Ping`].
+    ///
+    /// On WASM targets, the browsers don't expose ping frames ([`Message::Ping`] is a no-op),
+    /// so an application-level heartbeat must be set (ex. `Message::Text("ping")`).
+    /// If `None`, no probe is sent and the connection is only marked as dead
+    /// if no messages are received for `interval + timeout`.
+    pub probe: Option<Message>,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+            probe: None,
+        }
+    }
+}
+
+impl KeepAliveConfig {
+    fn probe(&self) -> Option<Message> {
+        match &self.probe {
+            Some(probe) => Some(probe.clone()),
+            #[cfg(not(target_arch = "wasm32"))]
+            None => Some(Message::Ping(Bytes::new())),
+            #[cfg(target_arch = "wasm32")]
+            None => None,
+        }
+    }
+}
+
+enum State {
+    /// Waiting for the inactivity interval to expire
+    Idle(BoxedFuture<'static, ()>),
+    /// Probe sent, waiting for an incoming message
+    Probing(BoxedFuture<'static, ()>),
+    /// No messages received within the timeout
+    Expired,
+}
+
+/// Keepalive wrapper
+///
+/// Sends a probe when no messages are received for [`KeepAliveConfig::interval`]
+/// and yields [`Error::KeepAliveTimeout`] (ending the stream) if nothing arrives within [`KeepAliveConfig::timeout`].
+/// Any incoming message is considered a proof of liveness.
+///
+/// The timers are driven by polling the [`Stream`].
+pub struct KeepAlive<S> {
+    socket: S,
+    config: KeepAliveConfig,
+    state: State,
+    /// Probe waiting to be sent
+    pending: Option<Message>,
+    /// Probe sent but not flushed yet
+    flush: bool,
+}
+
+impl<S> KeepAlive<S>
+where
+    S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+{
+    /// Wrap a socket
+    pub fn new(socket: S, config: KeepAliveConfig) -> Self {
+        let state: State = State::Idle(Box::pin(time::sleep(config.interval)));
+        Self {
+            socket,
+            config,
+            state,
+            pending: None,
+            flush: false,
+        }
+    }
+
+    /// Get a reference to the inner socket
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    /// Get a mutable reference to the inner socket
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    /// Consume the wrapper, returning the inner socket
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+
+    fn reset(&mut self) {
+        self.state = State::Idle(Box::pin(time::sleep(self.config.interval)));
+    }
+
+    /// Try to send the pending probe
+    fn poll_probe(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        if self.pending.is_some() {
+            match Pin::new(&mut self.socket).poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    if let Some(probe) = self.pending.take() {
+                        Pin::new(&mut self.socket).start_send(probe)?;
+                        self.flush = true;
+                    }
+                }
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => return Ok(()),
+            }
+        }
+
+        if self.flush {
+            match Pin::new(&mut self.socket).poll_flush(cx) {
+                Poll::Ready(Ok(())) => self.flush = false,
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drive the timers. Returns `Ready` if the connection expired.
+    fn poll_timers(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            match &mut self.state {
+                State::Idle(sleep) => match sleep.as_mut().poll(cx) {
+                    Poll::Ready(()) => {
+                        self.pending = self.config.probe();
+                        self.state = State::Probing(Box::pin(time::sleep(self.config.timeout)));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                State::Probing(sleep) => match sleep.as_mut().poll(cx) {
+                    Poll::Ready(()) => self.state = State::Expired,
+                    Poll::Pending => return Poll::Pending,
+                },
+                State::Expired => return Poll::Ready(()),
+            }
+        }
+    }
+}
+
+impl<S> Sink<Message> for KeepAlive<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    type Error = Error;
+
+    #[inline]
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.socket).poll_ready(cx)
+    }
+
+    #[inline]
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        Pin::new(&mut self.socket).start_send(item)
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.socket).poll_close(cx)
+    }
+}
+
+impl<S> Stream for KeepAlive<S>
+where
+    S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+{
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let State::Expired = this.state {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(&mut this.socket).poll_next(cx) {
+            Poll::Ready(Some(Ok(msg))) => {
+                this.reset();
+                return Poll::Ready(Some(Ok(msg)));
+            }
+            Poll::Ready(res) => return Poll::Ready(res),
+            Poll::Pending => {}
+        }
+
+        if this.poll_timers(cx).is_ready() {
+            return Poll::Ready(Some(Err(Error::KeepAliveTimeout)));
+        }
+
+        if let Err(e) = this.poll_probe(cx) {
+            return Poll::Ready(Some(Err(e)));
+        }
+
+        Poll::Pending
+    }
+}
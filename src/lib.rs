@@ -7,46 +7,265 @@
 #![warn(clippy::large_futures)]
 #![cfg_attr(feature = "default", doc = include_str!("../README.md"))]
 
-#[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+use std::future::Future;
+#[cfg(not(target_arch = "wasm32"))]
 use std::net::SocketAddr;
 #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
-use std::path::{Path, PathBuf};
+use std::path::Path;
+#[cfg(all(any(unix, feature = "tor"), not(target_arch = "wasm32")))]
+use std::path::PathBuf;
 use std::time::Duration;
 
 pub use futures_util;
 pub use url::{self, Url};
 
+pub mod actor;
+pub mod broadcast;
+mod builder;
+mod coalesce;
+#[cfg(any(feature = "json", feature = "cbor", feature = "msgpack"))]
+pub mod codec;
+mod drain;
+pub mod error;
+pub mod event;
+pub mod extensions;
+pub mod fault;
+pub mod half;
+mod idle;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod keepalive;
+pub mod limits;
 pub mod message;
+pub mod middleware;
+#[cfg(feature = "test-util")]
+pub mod mock;
+pub mod mode;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod native;
+pub mod pool;
 pub mod prelude;
+pub mod priority;
+pub mod queue;
+pub mod reconnect;
+pub mod record;
+mod response;
+pub mod rpc;
+pub mod rtt;
+#[cfg(feature = "tower")]
+pub mod service;
+pub mod shutdown;
 mod socket;
+pub mod stats;
+mod time;
+pub mod timeouts;
 #[cfg(target_arch = "wasm32")]
 pub mod wasm;
+pub mod watchdog;
 
-pub use self::message::Message;
+pub use self::actor::{WsActor, WsReceiver, WsSender};
+pub use self::broadcast::{Broadcaster, ClientId};
+pub use self::builder::WebSocketBuilder;
+pub use self::coalesce::WriteCoalescingConfig;
+pub use self::drain::Drain;
+pub use self::error::{ErrorKind, ProtocolViolation};
+pub use self::event::{ConnectionEvent, ConnectionEvents, ConnectionObserver};
+pub use self::extensions::Extensions;
+pub use self::fault::{FaultInjector, Faults};
+pub use self::half::{RecvOnly, SendOnly};
+pub use self::idle::IdleTimeoutConfig;
+pub use self::keepalive::{KeepAlive, KeepAliveConfig};
+pub use self::limits::{Limit, Limits};
+pub use self::message::{Message, Utf8Policy};
+pub use self::middleware::{Action, Layer, Middleware};
+pub use self::mode::{ModeRouter, ModeSelector, OnionSelector};
+#[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
+pub use self::native::tor::{TorClientHandle, TorOptions};
+#[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+pub use self::native::CompressionConfig;
+#[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+pub use self::native::CookieJar;
+#[cfg(all(feature = "h2", not(target_arch = "wasm32")))]
+pub use self::native::H2Connection;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::native::{
+    AuthProvider, Authenticator, CustomTransport, Framer, IoStream, RawFramer, RequestHook,
+    Resolve, Resolver, Transport, WebSocketConfig,
+};
 #[cfg(not(target_arch = "wasm32"))]
-pub use self::native::Error;
+pub use self::native::{ConnectError, Error};
+#[cfg(all(
+    any(feature = "tls-rustls", feature = "tls-native"),
+    not(target_arch = "wasm32")
+))]
+pub use self::native::{TlsConfig, TlsInfo, TlsVersion};
+pub use self::pool::WsPool;
+pub use self::priority::{Priority, PrioritySink};
+pub use self::queue::{OverflowPolicy, SendQueue, SendQueueConfig};
+pub use self::record::{Recorder, Replay};
+pub use self::response::{HandshakeRejection, HandshakeResponse, MAX_REJECTION_BODY};
+pub use self::rpc::{Correlator, RpcClient, RpcDriver};
+pub use self::rtt::{RttEcho, RttMonitor};
+#[cfg(feature = "tower")]
+pub use self::service::{ConnectRequest, ConnectService};
+pub use self::shutdown::{ShutdownHandle, ShutdownToken};
 pub use self::socket::WebSocket;
+pub use self::stats::{ConnectionStats, StatsHandle};
+pub use self::timeouts::{Phase, Timeouts};
 #[cfg(target_arch = "wasm32")]
-pub use self::wasm::Error;
+pub use self::wasm::{ConnectError, Error};
+pub use self::watchdog::{Watchdog, WatchdogAlert, WatchdogConfig, WatchdogHandle};
 
+#[cfg(any(not(target_arch = "wasm32"), feature = "wasm-send"))]
+pub(crate) type BoxedFuture<'a, T> = futures_util::future::BoxFuture<'a, T>;
+#[cfg(all(target_arch = "wasm32", not(feature = "wasm-send")))]
+pub(crate) type BoxedFuture<'a, T> = futures_util::future::LocalBoxFuture<'a, T>;
+
+/// Connection mode
+///
+/// On WASM targets the browser opens the connection, so only [`ConnectionMode::Direct`] is available:
+/// the proxy and tor modes don't exist there, and requesting them fails at compile time
+/// instead of silently connecting without the expected privacy.
 #[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ConnectionMode {
     /// Direct
     #[default]
     Direct,
-    /// Custom proxy
+    /// Embedded tor client for the `.onion` hosts, direct for the others
+    ///
+    /// Without the `tor` feature, connecting to a `.onion` host fails with [`ConnectError::TorUnavailable`].
+    #[cfg(not(target_arch = "wasm32"))]
+    Auto,
+    /// SOCKS5 proxy
+    #[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+    Proxy {
+        /// Proxy address
+        addr: SocketAddr,
+        /// Proxy options
+        options: ProxyOptions,
+    },
+    /// External Tor daemon (SOCKS5 port)
+    ///
+    /// Unlike [`ConnectionMode::Proxy`], this is `.onion` aware:
+    /// onion addresses are validated, the DNS is never resolved locally
+    /// and the timeout for onion services is at least 2 mins.
     #[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
-    Proxy(SocketAddr),
+    TorSocks {
+        /// Tor SOCKS5 address
+        addr: SocketAddr,
+    },
+    /// HTTP proxy (`CONNECT` tunnel)
+    #[cfg(not(target_arch = "wasm32"))]
+    HttpProxy {
+        /// Proxy address
+        addr: SocketAddr,
+        /// Optional proxy authorization
+        auth: Option<ProxyAuth>,
+    },
+    /// Unix domain socket
+    ///
+    /// The URL is still used for the handshake request (ex. `ws://localhost/path`).
+    #[cfg(all(unix, not(target_arch = "wasm32")))]
+    UnixSocket {
+        /// Socket path
+        path: PathBuf,
+    },
+    /// Custom transport
+    ///
+    /// The TLS (for `wss` URLs) and WebSocket handshakes are layered on top of the streams opened by `transport`.
+    #[cfg(not(target_arch = "wasm32"))]
+    Custom {
+        /// Transport
+        transport: CustomTransport,
+    },
     /// Embedded tor client
     #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
     Tor {
         /// Path for cache and state data
         ///
         /// Mandatory for `android` and `ios` targets!
+        ///
+        /// Ignored if `client` is set.
         custom_path: Option<PathBuf>,
+        /// Already created tor client
+        ///
+        /// If `None`, a global client is bootstrapped on the first connection and then reused.
+        client: Option<TorClientHandle>,
+        /// Stream isolation and circuit options
+        options: TorOptions,
+    },
+}
+
+/// SOCKS5 proxy options
+#[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProxyOptions {
+    /// Resolve the hostname through the proxy (default: `true`)
+    ///
+    /// If `false`, the hostname is resolved locally and only the IP address is sent to the proxy:
+    /// the DNS query leaks outside the proxy.
+    pub remote_dns: bool,
+}
+
+#[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        Self { remote_dns: true }
+    }
+}
+
+/// Proxy authorization
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProxyAuth {
+    /// Basic authorization
+    Basic {
+        /// Username
+        username: String,
+        /// Password
+        password: String,
     },
+    /// Bearer token
+    Bearer(String),
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ProxyAuth {
+    /// Basic authorization
+    #[inline]
+    pub fn basic<U, P>(username: U, password: P) -> Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        Self::Basic {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Bearer token
+    #[inline]
+    pub fn bearer<T>(token: T) -> Self
+    where
+        T: Into<String>,
+    {
+        Self::Bearer(token.into())
+    }
+
+    /// Value of the `Proxy-Authorization` header
+    pub(crate) fn header_value(&self) -> String {
+        match self {
+            Self::Basic { username, password } => {
+                let credentials: String = format!("{username}:{password}");
+                format!(
+                    "Basic {}",
+                    native::http_proxy::base64_encode(credentials.as_bytes())
+                )
+            }
+            Self::Bearer(token) => format!("Bearer {token}"),
+        }
+    }
 }
 
 impl ConnectionMode {
@@ -56,11 +275,68 @@ impl ConnectionMode {
         Self::Direct
     }
 
-    /// Proxy
+    /// SOCKS5 proxy
+    ///
+    /// The hostname is resolved through the proxy. Check [`ProxyOptions`].
     #[inline]
     #[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
     pub fn proxy(addr: SocketAddr) -> Self {
-        Self::Proxy(addr)
+        Self::proxy_with_options(addr, ProxyOptions::default())
+    }
+
+    /// SOCKS5 proxy with custom options
+    #[inline]
+    #[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+    pub fn proxy_with_options(addr: SocketAddr, options: ProxyOptions) -> Self {
+        Self::Proxy { addr, options }
+    }
+
+    /// External Tor daemon, listening on the default SOCKS5 port (`127.0.0.1:9050`)
+    #[inline]
+    #[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+    pub fn tor_socks() -> Self {
+        Self::tor_socks_with_addr(SocketAddr::from(([127, 0, 0, 1], 9050)))
+    }
+
+    /// External Tor daemon, listening on a custom SOCKS5 address
+    #[inline]
+    #[cfg(all(feature = "socks", not(target_arch = "wasm32")))]
+    pub fn tor_socks_with_addr(addr: SocketAddr) -> Self {
+        Self::TorSocks { addr }
+    }
+
+    /// HTTP proxy (`CONNECT` tunnel)
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn http_proxy(addr: SocketAddr) -> Self {
+        Self::HttpProxy { addr, auth: None }
+    }
+
+    /// HTTP proxy (`CONNECT` tunnel) with authorization
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn http_proxy_with_auth(addr: SocketAddr, auth: ProxyAuth) -> Self {
+        Self::HttpProxy {
+            addr,
+            auth: Some(auth),
+        }
+    }
+
+    /// Unix domain socket
+    #[inline]
+    #[cfg(all(unix, not(target_arch = "wasm32")))]
+    pub fn unix_socket<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self::UnixSocket { path: path.into() }
+    }
+
+    /// Custom transport
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn custom(transport: CustomTransport) -> Self {
+        Self::Custom { transport }
     }
 
     /// Embedded tor client
@@ -70,7 +346,11 @@ impl ConnectionMode {
     #[inline]
     #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
     pub fn tor() -> Self {
-        Self::Tor { custom_path: None }
+        Self::Tor {
+            custom_path: None,
+            client: None,
+            options: TorOptions::default(),
+        }
     }
 
     /// Embedded tor client
@@ -84,7 +364,35 @@ impl ConnectionMode {
     {
         Self::Tor {
             custom_path: Some(data_path.as_ref().to_path_buf()),
+            client: None,
+            options: TorOptions::default(),
+        }
+    }
+
+    /// Embedded tor client
+    ///
+    /// Use an already created (and possibly bootstrapped) client,
+    /// to share it across many connections.
+    #[inline]
+    #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
+    pub fn tor_with_client(client: TorClientHandle) -> Self {
+        Self::Tor {
+            custom_path: None,
+            client: Some(client),
+            options: TorOptions::default(),
+        }
+    }
+
+    /// Set the tor stream options
+    ///
+    /// No effect if the mode isn't [`ConnectionMode::Tor`].
+    #[inline]
+    #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
+    pub fn with_tor_options(mut self, tor_options: TorOptions) -> Self {
+        if let Self::Tor { options, .. } = &mut self {
+            *options = tor_options;
         }
+        self
     }
 }
 
@@ -94,6 +402,70 @@ pub async fn connect(
     url: &Url,
     mode: &ConnectionMode,
     timeout: Duration,
-) -> Result<WebSocket, Error> {
+) -> Result<WebSocket, ConnectError> {
     WebSocket::connect(url, mode, timeout).await
 }
+
+/// Connect, aborting as soon as `cancel` completes
+///
+/// Check [`WebSocketBuilder::connect_until`] for more details.
+#[inline]
+pub async fn connect_until<C>(
+    url: &Url,
+    mode: &ConnectionMode,
+    timeout: Duration,
+    cancel: C,
+) -> Result<WebSocket, ConnectError>
+where
+    C: Future<Output = ()>,
+{
+    WebSocketBuilder::new()
+        .mode(mode.clone())
+        .timeout(timeout)
+        .connect_until(url, cancel)
+        .await
+}
+
+/// Race the connection to several URLs, returning the first successful one
+///
+/// Check [`WebSocketBuilder::connect_any`] for more details.
+#[inline]
+pub async fn connect_any(
+    urls: &[Url],
+    mode: &ConnectionMode,
+    timeout: Duration,
+) -> Result<(Url, WebSocket), ConnectError> {
+    WebSocketBuilder::new()
+        .mode(mode.clone())
+        .timeout(timeout)
+        .connect_any(urls, Duration::ZERO)
+        .await
+}
+
+/// Connect and return also the HTTP response of the handshake
+#[inline]
+pub async fn connect_with_response(
+    url: &Url,
+    mode: &ConnectionMode,
+    timeout: Duration,
+) -> Result<(WebSocket, HandshakeResponse), ConnectError> {
+    WebSocketBuilder::new()
+        .mode(mode.clone())
+        .timeout(timeout)
+        .connect_with_response(url)
+        .await
+}
+
+/// Connect and keep the connection alive
+///
+/// Check [`KeepAlive`] for more details.
+#[inline]
+pub async fn connect_with_keepalive(
+    url: &Url,
+    mode: &ConnectionMode,
+    timeout: Duration,
+    config: KeepAliveConfig,
+) -> Result<KeepAlive<WebSocket>, ConnectError> {
+    let socket: WebSocket = WebSocket::connect(url, mode, timeout).await?;
+    Ok(KeepAlive::new(socket, config))
+}
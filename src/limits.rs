@@ -0,0 +1,82 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Incoming message limits
+
+use std::fmt;
+
+/// Default max size of an incoming message (same of `tungstenite`)
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Limit exceeded by the peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Limit {
+    /// Max frames per message
+    FramesPerMessage,
+    /// Max buffered messages
+    BufferedMessages,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FramesPerMessage => write!(f, "frames per message"),
+            Self::BufferedMessages => write!(f, "buffered messages"),
+        }
+    }
+}
+
+/// Incoming message limits
+///
+/// Protect against peers that send oversized or endless messages.
+/// When a limit is exceeded, the stream yields [`Error::MessageTooLarge`](crate::Error::MessageTooLarge)
+/// or [`Error::LimitExceeded`](crate::Error::LimitExceeded) and the connection must be dropped.
+///
+/// `None` means no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Limits {
+    /// Max size of an incoming message, in bytes (default: 64 MiB)
+    ///
+    /// On native targets, the lower between this and [`WebSocketConfig::max_message_size`](crate::WebSocketConfig::max_message_size) is applied.
+    pub max_message_size: Option<usize>,
+    /// Max number of frames of a fragmented incoming message (default: none)
+    ///
+    /// Only enforced by [`FrameStream`](crate::native::frame::FrameStream):
+    /// `tungstenite` and the browsers reassemble the fragments internally,
+    /// so only the size of the reassembled message can be bounded there.
+    pub max_frames_per_message: Option<usize>,
+    /// Max number of received messages waiting to be read (default: none)
+    ///
+    /// Only enforced on WASM targets, where the browser pushes the messages as they arrive.
+    /// On native targets the messages are read on demand, leaving the unread data to the socket buffers.
+    pub max_buffered_messages: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+            max_frames_per_message: None,
+            max_buffered_messages: None,
+        }
+    }
+}
+
+impl Limits {
+    /// No limits
+    #[inline]
+    pub fn unlimited() -> Self {
+        Self {
+            max_message_size: None,
+            max_frames_per_message: None,
+            max_buffered_messages: None,
+        }
+    }
+
+    /// Check the size of a message
+    #[cfg(target_arch = "wasm32")]
+    #[inline]
+    pub(crate) fn exceeds_message_size(&self, size: usize) -> bool {
+        self.max_message_size.is_some_and(|max| size > max)
+    }
+}
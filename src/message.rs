@@ -1,59 +1,492 @@
 // Copyright (c) 2022-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
 use std::{fmt, str};
 
+pub use bytes::Bytes;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 #[cfg(not(target_arch = "wasm32"))]
-use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode as TungsteniteCloseCode;
 #[cfg(not(target_arch = "wasm32"))]
 use tokio_tungstenite::tungstenite::protocol::CloseFrame as TungsteniteCloseFrame;
 #[cfg(not(target_arch = "wasm32"))]
 use tokio_tungstenite::tungstenite::protocol::Message as TungsteniteMessage;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_tungstenite::tungstenite::Utf8Bytes as TungsteniteUtf8Bytes;
+
+/// UTF-8 string, cheap to clone
+///
+/// On native targets, the text frames are passed through without copying the payload.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Utf8Bytes(
+    #[cfg(not(target_arch = "wasm32"))] TungsteniteUtf8Bytes,
+    #[cfg(target_arch = "wasm32")] String,
+);
+
+impl Utf8Bytes {
+    /// Create from a static str
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub const fn from_static(s: &'static str) -> Self {
+        Self(TungsteniteUtf8Bytes::from_static(s))
+    }
+
+    /// Create from a static str
+    #[inline]
+    #[cfg(target_arch = "wasm32")]
+    pub fn from_static(s: &'static str) -> Self {
+        Self(String::from(s))
+    }
+
+    /// Get as str
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl Deref for Utf8Bytes {
+    type Target = str;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for Utf8Bytes {
+    #[inline]
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<[u8]> for Utf8Bytes {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self.as_str().as_bytes()
+    }
+}
+
+impl PartialOrd for Utf8Bytes {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Utf8Bytes {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl Hash for Utf8Bytes {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl PartialEq<str> for Utf8Bytes {
+    #[inline]
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Utf8Bytes {
+    #[inline]
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for Utf8Bytes {
+    #[inline]
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl fmt::Debug for Utf8Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Utf8Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<String> for Utf8Bytes {
+    #[inline]
+    fn from(s: String) -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            Self(s.into())
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            Self(s)
+        }
+    }
+}
+
+impl From<&str> for Utf8Bytes {
+    #[inline]
+    fn from(s: &str) -> Self {
+        Self(s.into())
+    }
+}
+
+impl From<&String> for Utf8Bytes {
+    #[inline]
+    fn from(s: &String) -> Self {
+        Self(s.as_str().into())
+    }
+}
+
+/// On native targets the payload is copied: prefer [`Utf8Bytes::as_str`] to inspect it.
+impl From<Utf8Bytes> for String {
+    #[inline]
+    fn from(s: Utf8Bytes) -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            s.as_str().to_string()
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            s.0
+        }
+    }
+}
+
+impl From<Utf8Bytes> for Bytes {
+    #[inline]
+    fn from(s: Utf8Bytes) -> Self {
+        s.0.into()
+    }
+}
+
+impl TryFrom<Bytes> for Utf8Bytes {
+    type Error = str::Utf8Error;
+
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            Ok(Self(TungsteniteUtf8Bytes::try_from(bytes)?))
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            Ok(Self(str::from_utf8(&bytes)?.to_string()))
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<TungsteniteUtf8Bytes> for Utf8Bytes {
+    #[inline]
+    fn from(s: TungsteniteUtf8Bytes) -> Self {
+        Self(s)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<Utf8Bytes> for TungsteniteUtf8Bytes {
+    #[inline]
+    fn from(s: Utf8Bytes) -> Self {
+        s.0
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Utf8Bytes {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Utf8Bytes {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer).map(Self::from)
+    }
+}
+
+/// Handling of the incoming text messages with invalid UTF-8
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Utf8Policy {
+    /// Yield [`Error::InvalidUtf8`](crate::Error::InvalidUtf8) (default, required by RFC 6455)
+    #[default]
+    Strict,
+    /// Replace the invalid sequences with `U+FFFD`
+    ///
+    /// Only on WASM targets: on native targets, the protocol always fails the connection.
+    Lossy,
+}
+
+/// Close code
+///
+/// The codes registered by [RFC 6455](https://www.rfc-editor.org/rfc/rfc6455#section-7.4.1)
+/// and the IANA registry, plus [`CloseCode::Other`] for the remaining ones.
+/// Compared by numeric value: `CloseCode::Other(1000)` is equal to [`CloseCode::Normal`].
+#[derive(Debug, Clone, Copy)]
+pub enum CloseCode {
+    /// `1000`: the purpose of the connection has been fulfilled
+    Normal,
+    /// `1001`: the endpoint is going away (ex. server shutdown or page navigation)
+    GoingAway,
+    /// `1002`: protocol error
+    ProtocolError,
+    /// `1003`: received a type of data that can't be accepted
+    UnsupportedData,
+    /// `1005`: no status code received (never sent in a close frame)
+    NoStatusReceived,
+    /// `1006`: connection dropped without a close frame (never sent in a close frame)
+    Abnormal,
+    /// `1007`: received data not consistent with the type of the message (ex. invalid UTF-8)
+    InvalidPayload,
+    /// `1008`: received a message that violates the policy of the endpoint
+    PolicyViolation,
+    /// `1009`: received a message too big to process
+    MessageTooBig,
+    /// `1010`: the server didn't negotiate a required extension
+    MandatoryExtension,
+    /// `1011`: unexpected condition on the server
+    InternalError,
+    /// `1012`: the server is restarting
+    ServiceRestart,
+    /// `1013`: temporary condition (ex. overload), try again later
+    TryAgainLater,
+    /// `1014`: the server, acting as gateway, received an invalid response
+    BadGateway,
+    /// `1015`: TLS handshake failure (never sent in a close frame)
+    TlsHandshake,
+    /// Any other code
+    Other(u16),
+}
+
+impl CloseCode {
+    /// Get the numeric code
+    pub fn as_u16(&self) -> u16 {
+        match self {
+            Self::Normal => 1000,
+            Self::GoingAway => 1001,
+            Self::ProtocolError => 1002,
+            Self::UnsupportedData => 1003,
+            Self::NoStatusReceived => 1005,
+            Self::Abnormal => 1006,
+            Self::InvalidPayload => 1007,
+            Self::PolicyViolation => 1008,
+            Self::MessageTooBig => 1009,
+            Self::MandatoryExtension => 1010,
+            Self::InternalError => 1011,
+            Self::ServiceRestart => 1012,
+            Self::TryAgainLater => 1013,
+            Self::BadGateway => 1014,
+            Self::TlsHandshake => 1015,
+            Self::Other(code) => *code,
+        }
+    }
+
+    /// Check if the code can be sent in a close frame
+    ///
+    /// Allowed: the registered codes (except `1005`, `1006` and `1015`),
+    /// the `3000-3999` range (libraries and frameworks) and the `4000-4999` range (private use).
+    /// The codes below `1000`, the reserved ones (`1004` and `1016-2999`) and the ones above `4999` are not allowed.
+    pub fn is_allowed(&self) -> bool {
+        matches!(self.as_u16(), 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+
+    /// Check if the code is reserved by the protocol, and not registered yet (`1004` and `1016-2999`)
+    #[inline]
+    pub fn is_reserved(&self) -> bool {
+        matches!(self.as_u16(), 1004 | 1016..=2999)
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1000 => Self::Normal,
+            1001 => Self::GoingAway,
+            1002 => Self::ProtocolError,
+            1003 => Self::UnsupportedData,
+            1005 => Self::NoStatusReceived,
+            1006 => Self::Abnormal,
+            1007 => Self::InvalidPayload,
+            1008 => Self::PolicyViolation,
+            1009 => Self::MessageTooBig,
+            1010 => Self::MandatoryExtension,
+            1011 => Self::InternalError,
+            1012 => Self::ServiceRestart,
+            1013 => Self::TryAgainLater,
+            1014 => Self::BadGateway,
+            1015 => Self::TlsHandshake,
+            code => Self::Other(code),
+        }
+    }
+}
+
+impl From<CloseCode> for u16 {
+    #[inline]
+    fn from(code: CloseCode) -> Self {
+        code.as_u16()
+    }
+}
+
+impl PartialEq for CloseCode {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.as_u16() == other.as_u16()
+    }
+}
+
+impl Eq for CloseCode {}
+
+impl PartialEq<u16> for CloseCode {
+    #[inline]
+    fn eq(&self, other: &u16) -> bool {
+        self.as_u16() == *other
+    }
+}
+
+impl PartialOrd for CloseCode {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CloseCode {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_u16().cmp(&other.as_u16())
+    }
+}
+
+impl Hash for CloseCode {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_u16().hash(state)
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_u16())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for CloseCode {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u16(self.as_u16())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for CloseCode {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        u16::deserialize(deserializer).map(Self::from)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<CloseCode> for TungsteniteCloseCode {
+    #[inline]
+    fn from(code: CloseCode) -> Self {
+        Self::from(code.as_u16())
+    }
+}
 
 #[cfg(not(target_arch = "wasm32"))]
+impl From<TungsteniteCloseCode> for CloseCode {
+    #[inline]
+    fn from(code: TungsteniteCloseCode) -> Self {
+        Self::from(u16::from(code))
+    }
+}
+
+/// Close frame
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct CloseFrame {
     /// The reason as a code.
-    pub code: u16,
+    pub code: CloseCode,
     /// The reason as text string.
-    pub reason: String,
+    pub reason: Utf8Bytes,
 }
 
 /// An enum representing the various forms of a WebSocket message.
+///
+/// The same type is used on both native and WASM targets.
+/// On WASM, the browser handles the control frames by itself:
+/// * sending a [`Message::Ping`] or a [`Message::Pong`] is a no-op;
+/// * sending a [`Message::Close`] closes the connection with the provided code and reason.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Message {
     /// A text WebSocket message
-    Text(String),
+    Text(Utf8Bytes),
     /// A binary WebSocket message
-    Binary(Vec<u8>),
+    Binary(Bytes),
     /// A ping message with the specified payload
     ///
     /// The payload here must have a length less than 125 bytes
-    #[cfg(not(target_arch = "wasm32"))]
-    Ping(Vec<u8>),
+    Ping(Bytes),
     /// A pong message with the specified payload
     ///
     /// The payload here must have a length less than 125 bytes
-    #[cfg(not(target_arch = "wasm32"))]
-    Pong(Vec<u8>),
+    Pong(Bytes),
     /// A close message with the optional close frame.
-    #[cfg(not(target_arch = "wasm32"))]
     Close(Option<CloseFrame>),
 }
 
 impl Message {
-    #[cfg(not(target_arch = "wasm32"))]
-    pub(crate) fn from_native(msg: TungsteniteMessage) -> Self {
-        match msg {
-            TungsteniteMessage::Text(text) => Self::Text(text.to_string()),
-            TungsteniteMessage::Binary(data) => Self::Binary(data.to_vec()),
-            TungsteniteMessage::Ping(data) => Self::Ping(data.to_vec()),
-            TungsteniteMessage::Pong(data) => Self::Pong(data.to_vec()),
-            TungsteniteMessage::Close(frame) => Self::Close(frame.map(|f| f.into())),
-            // SAFETY: from tungstenite docs: "you're not going to get this value while reading the message".
-            // SAFETY: this conversion is used only in Stream trait, so when reading the messages.
-            TungsteniteMessage::Frame(..) => unreachable!(),
-        }
+    /// Create a text message
+    #[inline]
+    pub fn text<S>(text: S) -> Self
+    where
+        S: Into<Utf8Bytes>,
+    {
+        Self::Text(text.into())
+    }
+
+    /// Create a binary message
+    #[inline]
+    pub fn binary<B>(data: B) -> Self
+    where
+        B: Into<Bytes>,
+    {
+        Self::Binary(data.into())
     }
 
     /// Get the length of the WebSocket message.
@@ -62,11 +495,8 @@ impl Message {
         match self {
             Self::Text(string) => string.len(),
             Self::Binary(data) => data.len(),
-            #[cfg(not(target_arch = "wasm32"))]
             Self::Ping(data) => data.len(),
-            #[cfg(not(target_arch = "wasm32"))]
             Self::Pong(data) => data.len(),
-            #[cfg(not(target_arch = "wasm32"))]
             Self::Close(data) => data.as_ref().map(|d| d.reason.len()).unwrap_or(0),
         }
     }
@@ -76,17 +506,66 @@ impl Message {
         self.len() == 0
     }
 
+    /// Check if it's a text message
+    #[inline]
+    pub fn is_text(&self) -> bool {
+        matches!(self, Self::Text(..))
+    }
+
+    /// Check if it's a binary message
+    #[inline]
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Self::Binary(..))
+    }
+
+    /// Check if it's a ping message
+    #[inline]
+    pub fn is_ping(&self) -> bool {
+        matches!(self, Self::Ping(..))
+    }
+
+    /// Check if it's a pong message
+    #[inline]
+    pub fn is_pong(&self) -> bool {
+        matches!(self, Self::Pong(..))
+    }
+
+    /// Check if it's a close message
+    #[inline]
+    pub fn is_close(&self) -> bool {
+        matches!(self, Self::Close(..))
+    }
+
+    /// Consume the message and get the payload as text, without copying it on native targets
+    ///
+    /// The binary payloads must be valid UTF-8.
+    pub fn into_text(self) -> Result<Utf8Bytes, str::Utf8Error> {
+        match self {
+            Self::Text(string) => Ok(string),
+            Self::Binary(data) | Self::Ping(data) | Self::Pong(data) => Utf8Bytes::try_from(data),
+            Self::Close(None) => Ok(Utf8Bytes::default()),
+            Self::Close(Some(frame)) => Ok(frame.reason),
+        }
+    }
+
+    /// Consume the message and get the payload as bytes, without copying it on native targets
+    pub fn into_data(self) -> Bytes {
+        match self {
+            Self::Text(string) => string.into(),
+            Self::Binary(data) | Self::Ping(data) | Self::Pong(data) => data,
+            Self::Close(None) => Bytes::new(),
+            Self::Close(Some(frame)) => frame.reason.into(),
+        }
+    }
+
     /// Attempt to get a &str from the WebSocket message,
     /// this will try to convert binary data to utf8.
     pub fn as_text(&self) -> Option<&str> {
         match self {
             Self::Text(string) => Some(string.as_str()),
             Self::Binary(data) => str::from_utf8(data).ok(),
-            #[cfg(not(target_arch = "wasm32"))]
             Self::Ping(data) | Self::Pong(data) => str::from_utf8(data).ok(),
-            #[cfg(not(target_arch = "wasm32"))]
             Self::Close(None) => Some(""),
-            #[cfg(not(target_arch = "wasm32"))]
             Self::Close(Some(frame)) => Some(&frame.reason),
         }
     }
@@ -102,11 +581,56 @@ impl fmt::Display for Message {
     }
 }
 
+impl From<Utf8Bytes> for Message {
+    #[inline]
+    fn from(text: Utf8Bytes) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<String> for Message {
+    #[inline]
+    fn from(text: String) -> Self {
+        Self::Text(text.into())
+    }
+}
+
+impl From<&str> for Message {
+    #[inline]
+    fn from(text: &str) -> Self {
+        Self::Text(text.into())
+    }
+}
+
+impl From<Bytes> for Message {
+    #[inline]
+    fn from(data: Bytes) -> Self {
+        Self::Binary(data)
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    #[inline]
+    fn from(data: Vec<u8>) -> Self {
+        Self::Binary(data.into())
+    }
+}
+
+impl TryFrom<Message> for String {
+    type Error = str::Utf8Error;
+
+    /// Check [`Message::into_text`]
+    #[inline]
+    fn try_from(msg: Message) -> Result<Self, Self::Error> {
+        msg.into_text().map(Self::from)
+    }
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 impl From<CloseFrame> for TungsteniteCloseFrame {
     fn from(frame: CloseFrame) -> Self {
         Self {
-            code: CloseCode::from(frame.code),
+            code: frame.code.into(),
             reason: frame.reason.into(),
         }
     }
@@ -117,20 +641,36 @@ impl From<Message> for TungsteniteMessage {
     fn from(msg: Message) -> Self {
         match msg {
             Message::Text(text) => Self::Text(text.into()),
-            Message::Binary(data) => Self::Binary(data.into()),
-            Message::Ping(data) => Self::Ping(data.into()),
-            Message::Pong(data) => Self::Pong(data.into()),
+            Message::Binary(data) => Self::Binary(data),
+            Message::Ping(data) => Self::Ping(data),
+            Message::Pong(data) => Self::Pong(data),
             Message::Close(frame) => Self::Close(frame.map(|f| f.into())),
         }
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+impl From<TungsteniteMessage> for Message {
+    fn from(msg: TungsteniteMessage) -> Self {
+        match msg {
+            TungsteniteMessage::Text(text) => Self::Text(text.into()),
+            TungsteniteMessage::Binary(data) => Self::Binary(data),
+            TungsteniteMessage::Ping(data) => Self::Ping(data),
+            TungsteniteMessage::Pong(data) => Self::Pong(data),
+            TungsteniteMessage::Close(frame) => Self::Close(frame.map(|f| f.into())),
+            // From tungstenite docs: "you're not going to get this value while reading the message".
+            // Anyway, expose the raw payload.
+            TungsteniteMessage::Frame(frame) => Self::Binary(frame.into_payload()),
+        }
+    }
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 impl From<TungsteniteCloseFrame> for CloseFrame {
     fn from(frame: TungsteniteCloseFrame) -> Self {
         Self {
             code: frame.code.into(),
-            reason: frame.reason.to_string(),
+            reason: frame.reason.into(),
         }
     }
 }
@@ -0,0 +1,282 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Message middleware
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::{ready, Sink, Stream};
+
+use crate::{Error, Message};
+
+/// What to do with an intercepted message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Forward the (possibly modified) message
+    Forward,
+    /// Drop the message
+    Drop,
+    /// Forward the message, followed by these ones
+    Inject(Vec<Message>),
+}
+
+/// Message middleware
+///
+/// Intercepts the outgoing and incoming messages, to transform (modifying them in place), drop or inject them.
+/// Useful for logging, redaction, compression, protocol shims, etc.
+///
+/// By default, all the messages are forwarded untouched.
+pub trait Middleware {
+    /// Intercept an outgoing message
+    #[inline]
+    fn on_send(&mut self, _msg: &mut Message) -> Action {
+        Action::Forward
+    }
+
+    /// Intercept an incoming message
+    #[inline]
+    fn on_receive(&mut self, _msg: &mut Message) -> Action {
+        Action::Forward
+    }
+}
+
+/// Middleware layer
+///
+/// Applies a [`Middleware`] to the messages of the inner socket.
+/// Layers can be stacked: the outermost sees the outgoing messages first and the incoming ones last.
+///
+/// The injected messages don't go through the middleware again.
+pub struct Layer<S, M> {
+    socket: S,
+    middleware: M,
+    /// Outgoing messages waiting for the inner sink
+    outgoing: VecDeque<Message>,
+    /// Injected incoming messages
+    incoming: VecDeque<Message>,
+}
+
+impl<S, M> Layer<S, M>
+where
+    M: Middleware,
+{
+    /// Wrap a socket
+    pub fn new(socket: S, middleware: M) -> Self {
+        Self {
+            socket,
+            middleware,
+            outgoing: VecDeque::new(),
+            incoming: VecDeque::new(),
+        }
+    }
+
+    /// Get a reference to the inner socket
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    /// Get a mutable reference to the inner socket
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    /// Get a reference to the middleware
+    #[inline]
+    pub fn middleware(&self) -> &M {
+        &self.middleware
+    }
+
+    /// Get a mutable reference to the middleware
+    #[inline]
+    pub fn middleware_mut(&mut self) -> &mut M {
+        &mut self.middleware
+    }
+
+    /// Consume the layer, returning the inner socket
+    ///
+    /// The pending messages are dropped.
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+}
+
+impl<S, M> Layer<S, M>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    /// Hand the outgoing messages to the inner sink
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while !self.outgoing.is_empty() {
+            ready!(Pin::new(&mut self.socket).poll_ready(cx))?;
+
+            if let Some(msg) = self.outgoing.pop_front() {
+                Pin::new(&mut self.socket).start_send(msg)?;
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S, M> Sink<Message> for Layer<S, M>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+    M: Middleware + Unpin,
+{
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_drain(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, mut item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        match this.middleware.on_send(&mut item) {
+            Action::Forward => this.outgoing.push_back(item),
+            Action::Drop => {}
+            Action::Inject(messages) => {
+                this.outgoing.push_back(item);
+                this.outgoing.extend(messages);
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.socket).poll_close(cx)
+    }
+}
+
+impl<S, M> Stream for Layer<S, M>
+where
+    S: Stream<Item = Result<Message, Error>> + Unpin,
+    M: Middleware + Unpin,
+{
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Some(msg) = this.incoming.pop_front() {
+            return Poll::Ready(Some(Ok(msg)));
+        }
+
+        loop {
+            match ready!(Pin::new(&mut this.socket).poll_next(cx)) {
+                Some(Ok(mut msg)) => match this.middleware.on_receive(&mut msg) {
+                    Action::Forward => return Poll::Ready(Some(Ok(msg))),
+                    Action::Drop => continue,
+                    Action::Inject(messages) => {
+                        this.incoming.extend(messages);
+                        return Poll::Ready(Some(Ok(msg)));
+                    }
+                },
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::native;
+
+    /// Redacts `secret`, drops `drop` and follows `more` with `extra`
+    #[derive(Default)]
+    struct Filter {
+        sent: usize,
+        received: usize,
+    }
+
+    impl Filter {
+        fn apply(msg: &mut Message) -> Action {
+            match msg.as_text() {
+                Some("secret") => {
+                    *msg = Message::text("***");
+                    Action::Forward
+                }
+                Some("drop") => Action::Drop,
+                Some("more") => Action::Inject(vec![Message::text("extra")]),
+                _ => Action::Forward,
+            }
+        }
+    }
+
+    impl Middleware for Filter {
+        fn on_send(&mut self, msg: &mut Message) -> Action {
+            self.sent += 1;
+            Self::apply(msg)
+        }
+
+        fn on_receive(&mut self, msg: &mut Message) -> Action {
+            self.received += 1;
+            Self::apply(msg)
+        }
+    }
+
+    async fn texts<S>(socket: &mut S, n: usize) -> Vec<String>
+    where
+        S: Stream<Item = Result<Message, Error>> + Unpin,
+    {
+        let mut texts: Vec<String> = Vec::with_capacity(n);
+        for _ in 0..n {
+            let msg: Message = socket.next().await.unwrap().unwrap();
+            texts.push(msg.as_text().unwrap().to_string());
+        }
+        texts
+    }
+
+    #[tokio::test]
+    async fn test_outgoing() {
+        let (client, mut server) = native::duplex().await;
+        let mut client = Layer::new(client, Filter::default());
+
+        for text in ["secret", "drop", "more", "end"] {
+            client.feed(Message::text(text)).await.unwrap();
+        }
+        client.flush().await.unwrap();
+
+        assert_eq!(texts(&mut server, 4).await, ["***", "more", "extra", "end"]);
+        assert_eq!(client.middleware().sent, 4);
+    }
+
+    #[tokio::test]
+    async fn test_incoming() {
+        let (client, mut server) = native::duplex().await;
+        let mut client = Layer::new(client, Filter::default());
+
+        for text in ["secret", "drop", "more", "end"] {
+            server.feed(Message::text(text)).await.unwrap();
+        }
+        server.flush().await.unwrap();
+
+        // The injected message isn't intercepted again
+        assert_eq!(texts(&mut client, 4).await, ["***", "more", "extra", "end"]);
+        assert_eq!(client.middleware().received, 4);
+    }
+
+    #[tokio::test]
+    async fn test_stacked() {
+        let (client, mut server) = native::duplex().await;
+        let mut client = Layer::new(Layer::new(client, Filter::default()), Filter::default());
+
+        // The messages injected by the outer layer go through the inner one
+        client.send(Message::text("more")).await.unwrap();
+        assert_eq!(texts(&mut server, 3).await, ["more", "extra", "extra"]);
+        assert_eq!(client.middleware().sent, 1);
+        assert_eq!(client.get_ref().middleware().sent, 2);
+    }
+}
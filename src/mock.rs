@@ -0,0 +1,318 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! In-memory mock connection, for testing
+//!
+//! [`pair`] returns a [`WebSocket`] backed by an in-memory [`MockWebSocket`],
+//! so the code under test doesn't need any change, and the [`MockPeer`] that controls the other side:
+//! inject the incoming messages, simulate latency, close frames and abrupt drops,
+//! and inspect the messages sent by the socket.
+//!
+//! Like a real connection, the pings are answered with a pong and the close frames are echoed.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures_util::{ready, Sink, Stream, StreamExt};
+
+use crate::message::CloseFrame;
+use crate::socket::InnerWebSocket;
+use crate::{time, BoxedFuture, Error, Message, WebSocket};
+
+enum Event {
+    Message(Message),
+    /// Abrupt connection drop, without closing handshake
+    Drop,
+}
+
+struct Incoming {
+    event: Event,
+    delay: Duration,
+}
+
+/// New mock connection
+///
+/// Returns the socket and the peer controlling the other side.
+pub fn pair() -> (WebSocket, MockPeer) {
+    let (in_tx, in_rx) = mpsc::unbounded();
+    let (out_tx, out_rx) = mpsc::unbounded();
+
+    let mock: MockWebSocket = MockWebSocket {
+        incoming: in_rx,
+        outgoing: out_tx,
+        delayed: None,
+        terminated: false,
+    };
+    let peer: MockPeer = MockPeer {
+        incoming: in_tx,
+        outgoing: out_rx,
+        latency: Duration::ZERO,
+    };
+
+    (WebSocket::new(InnerWebSocket::Mock(mock), None), peer)
+}
+
+/// In-memory mock socket
+///
+/// Check the [module docs](self) for more details.
+pub struct MockWebSocket {
+    incoming: UnboundedReceiver<Incoming>,
+    outgoing: UnboundedSender<Message>,
+    /// Event waiting for its delay to elapse
+    delayed: Option<(BoxedFuture<'static, ()>, Event)>,
+    /// The connection has been dropped or closed by the peer
+    terminated: bool,
+}
+
+impl MockWebSocket {
+    /// Check if the connection has been dropped or closed by the peer
+    #[inline]
+    pub fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+
+    fn deliver(&mut self, event: Event) -> Option<Result<Message, Error>> {
+        match event {
+            Event::Message(msg) => {
+                match &msg {
+                    // Answer like a real peer would
+                    Message::Ping(data) => {
+                        let _ = self.outgoing.unbounded_send(Message::Pong(data.clone()));
+                    }
+                    Message::Close(frame) => {
+                        let _ = self.outgoing.unbounded_send(Message::Close(frame.clone()));
+                        self.terminated = true;
+                    }
+                    _ => {}
+                }
+
+                Some(Ok(msg))
+            }
+            Event::Drop => {
+                self.terminated = true;
+                self.incoming.close();
+                self.outgoing.close_channel();
+                Some(Err(Error::not_connected()))
+            }
+        }
+    }
+}
+
+impl Sink<Message> for MockWebSocket {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.outgoing.is_closed() {
+            return Poll::Ready(Err(Error::not_connected()));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.outgoing
+            .unbounded_send(item)
+            .map_err(|_| Error::not_connected())
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.outgoing.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for MockWebSocket {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some((sleep, _)) = &mut self.delayed {
+                ready!(sleep.as_mut().poll(cx));
+
+                if let Some((_, event)) = self.delayed.take() {
+                    return Poll::Ready(self.deliver(event));
+                }
+            }
+
+            if self.terminated {
+                return Poll::Ready(None);
+            }
+
+            match ready!(self.incoming.poll_next_unpin(cx)) {
+                Some(Incoming { event, delay }) if delay.is_zero() => {
+                    return Poll::Ready(self.deliver(event))
+                }
+                Some(Incoming { event, delay }) => {
+                    self.delayed = Some((Box::pin(time::sleep(delay)), event));
+                }
+                // The peer has been dropped
+                None => {
+                    self.terminated = true;
+                    return Poll::Ready(None);
+                }
+            }
+        }
+    }
+}
+
+/// Peer of a [`MockWebSocket`]
+///
+/// Dropping the peer ends the stream of the socket.
+pub struct MockPeer {
+    incoming: UnboundedSender<Incoming>,
+    outgoing: UnboundedReceiver<Message>,
+    latency: Duration,
+}
+
+impl MockPeer {
+    /// Set the delay applied to the incoming messages (default: none)
+    ///
+    /// The messages are delivered in order: a delayed message holds back the following ones.
+    #[inline]
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = latency;
+    }
+
+    /// Inject an incoming message
+    #[inline]
+    pub fn send(&self, msg: Message) -> Result<(), Error> {
+        self.send_delayed(msg, self.latency)
+    }
+
+    /// Inject an incoming message, delivered after `delay`
+    pub fn send_delayed(&self, msg: Message, delay: Duration) -> Result<(), Error> {
+        self.push(Event::Message(msg), delay)
+    }
+
+    /// Send a close frame: the socket stream ends after yielding it
+    pub fn close(&self, frame: Option<CloseFrame>) -> Result<(), Error> {
+        self.send(Message::Close(frame))?;
+        self.incoming.close_channel();
+        Ok(())
+    }
+
+    /// Drop the connection abruptly, without closing handshake
+    ///
+    /// The socket stream yields an error and then ends. The following sends fail.
+    pub fn drop_connection(&self) -> Result<(), Error> {
+        self.push(Event::Drop, self.latency)?;
+        self.incoming.close_channel();
+        Ok(())
+    }
+
+    /// Wait for the next message sent by the socket
+    ///
+    /// Returns `None` when the socket has been closed or dropped.
+    #[inline]
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.outgoing.next().await
+    }
+
+    /// Get the next message sent by the socket, if already available
+    pub fn try_recv(&mut self) -> Option<Message> {
+        self.outgoing.try_next().ok().flatten()
+    }
+
+    fn push(&self, event: Event, delay: Duration) -> Result<(), Error> {
+        self.incoming
+            .unbounded_send(Incoming { event, delay })
+            .map_err(|_| Error::not_connected())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::SinkExt;
+
+    use super::*;
+    use crate::message::CloseCode;
+    use crate::ErrorKind;
+
+    #[tokio::test]
+    async fn test_exchange() {
+        let (mut socket, mut peer) = pair();
+
+        peer.send(Message::text("hello")).unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::text("hello")
+        );
+
+        socket.send(Message::text("world")).await.unwrap();
+        assert_eq!(peer.recv().await, Some(Message::text("world")));
+        assert_eq!(peer.try_recv(), None);
+
+        // Answered with a pong
+        peer.send(Message::Ping(vec![1].into())).unwrap();
+        peer.send(Message::text("after ping")).unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::text("after ping")
+        );
+        assert_eq!(peer.try_recv(), Some(Message::Pong(vec![1].into())));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latency() {
+        let (mut socket, mut peer) = pair();
+        peer.set_latency(Duration::from_millis(100));
+
+        let start = tokio::time::Instant::now();
+        peer.send(Message::text("a")).unwrap();
+        peer.send_delayed(Message::text("b"), Duration::ZERO)
+            .unwrap();
+
+        // Delivered in order: the delayed message holds back the following one
+        assert_eq!(socket.next().await.unwrap().unwrap(), Message::text("a"));
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        assert_eq!(socket.next().await.unwrap().unwrap(), Message::text("b"));
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let (mut socket, mut peer) = pair();
+
+        let frame = CloseFrame {
+            code: CloseCode::GoingAway,
+            reason: "bye".into(),
+        };
+        peer.close(Some(frame.clone())).unwrap();
+
+        // Echoed, then the stream ends
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::Close(Some(frame.clone()))
+        );
+        assert_eq!(peer.recv().await, Some(Message::Close(Some(frame))));
+        assert!(socket.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_drop_connection() {
+        let (mut socket, mut peer) = pair();
+        peer.drop_connection().unwrap();
+
+        let e: Error = socket.next().await.unwrap().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionLost);
+        assert!(socket.next().await.is_none());
+
+        // The following sends fail
+        assert!(socket.send(Message::text("lost")).await.is_err());
+        assert_eq!(peer.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_peer_dropped() {
+        let (mut socket, peer) = pair();
+        drop(peer);
+        assert!(socket.next().await.is_none());
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Per-URL connection mode
+
+use std::fmt;
+use std::sync::Arc;
+
+use url::Url;
+
+use crate::ConnectionMode;
+
+/// Connection mode selection
+///
+/// Implement it to connect each URL with its own mode (ex. clearnet relays direct, onion services via tor).
+pub trait ModeSelector: Send + Sync {
+    /// Get the mode to connect to `url` with
+    ///
+    /// `default` is the mode set in the builder.
+    fn select(&self, url: &Url, default: &ConnectionMode) -> ConnectionMode;
+}
+
+/// Route the `.onion` hosts to a dedicated mode, the others to the builder one
+///
+/// With the `tor` feature, the default routes the `.onion` hosts to the embedded tor client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnionSelector {
+    onion: ConnectionMode,
+}
+
+#[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
+impl Default for OnionSelector {
+    fn default() -> Self {
+        Self::new(ConnectionMode::Tor {
+            custom_path: None,
+            client: None,
+            options: Default::default(),
+        })
+    }
+}
+
+impl OnionSelector {
+    /// Route the `.onion` hosts to `onion`
+    #[inline]
+    pub fn new(onion: ConnectionMode) -> Self {
+        Self { onion }
+    }
+}
+
+impl ModeSelector for OnionSelector {
+    fn select(&self, url: &Url, default: &ConnectionMode) -> ConnectionMode {
+        match url.host_str() {
+            Some(host) if host.ends_with(".onion") => self.onion.clone(),
+            _ => default.clone(),
+        }
+    }
+}
+
+/// Connection mode router
+///
+/// Check [`WebSocketBuilder::mode_router`](crate::WebSocketBuilder::mode_router) for more details.
+///
+/// The router is cheap to clone: all the clones refer to the same selector.
+#[derive(Clone)]
+pub struct ModeRouter {
+    inner: Arc<dyn ModeSelector>,
+}
+
+impl fmt::Debug for ModeRouter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModeRouter").finish()
+    }
+}
+
+impl PartialEq for ModeRouter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for ModeRouter {}
+
+impl ModeRouter {
+    /// New router
+    #[inline]
+    pub fn new<S>(selector: S) -> Self
+    where
+        S: ModeSelector + 'static,
+    {
+        Self {
+            inner: Arc::new(selector),
+        }
+    }
+
+    /// New router from a callback
+    pub fn from_fn<F>(callback: F) -> Self
+    where
+        F: Fn(&Url, &ConnectionMode) -> ConnectionMode + Send + Sync + 'static,
+    {
+        Self::new(FnSelector(callback))
+    }
+
+    /// Route the `.onion` hosts to `onion`
+    ///
+    /// Check [`OnionSelector`].
+    #[inline]
+    pub fn onion(onion: ConnectionMode) -> Self {
+        Self::new(OnionSelector::new(onion))
+    }
+
+    #[inline]
+    pub(crate) fn select(&self, url: &Url, default: &ConnectionMode) -> ConnectionMode {
+        self.inner.select(url, default)
+    }
+}
+
+struct FnSelector<F>(F);
+
+impl<F> ModeSelector for FnSelector<F>
+where
+    F: Fn(&Url, &ConnectionMode) -> ConnectionMode + Send + Sync,
+{
+    fn select(&self, url: &Url, default: &ConnectionMode) -> ConnectionMode {
+        (self.0)(url, default)
+    }
+}
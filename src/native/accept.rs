@@ -0,0 +1,463 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Server-side handshake
+
+use std::fmt;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::handshake::server::{
+    Callback, ErrorResponse, Request, Response,
+};
+use tokio_tungstenite::tungstenite::http::header::{HeaderName, HeaderValue};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::WebSocketStream;
+
+use super::{ConnectError, WebSocketConfig};
+
+/// HTTP request of an incoming WebSocket handshake
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HandshakeRequest {
+    /// Request path
+    pub path: String,
+    /// Query string, if any
+    pub query: Option<String>,
+    /// HTTP headers
+    pub headers: Vec<(String, String)>,
+}
+
+impl HandshakeRequest {
+    /// Get the first value of an header (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl From<&Request> for HandshakeRequest {
+    fn from(req: &Request) -> Self {
+        Self {
+            path: req.uri().path().to_string(),
+            query: req.uri().query().map(|q| q.to_string()),
+            headers: req
+                .headers()
+                .iter()
+                .map(|(k, v)| {
+                    (
+                        k.as_str().to_string(),
+                        String::from_utf8_lossy(v.as_bytes()).to_string(),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Decision about an incoming WebSocket handshake
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AcceptDecision {
+    /// Accept the connection
+    Accept {
+        /// Additional response headers (ex. `Sec-WebSocket-Protocol`)
+        headers: Vec<(String, String)>,
+    },
+    /// Reject the connection
+    Reject {
+        /// HTTP status code
+        status: u16,
+        /// Optional response body
+        reason: Option<String>,
+    },
+}
+
+impl AcceptDecision {
+    /// Accept the connection
+    #[inline]
+    pub fn accept() -> Self {
+        Self::Accept {
+            headers: Vec::new(),
+        }
+    }
+
+    /// Reject the connection with a status code
+    #[inline]
+    pub fn reject(status: u16) -> Self {
+        Self::Reject {
+            status,
+            reason: None,
+        }
+    }
+
+    /// Reject the connection with a status code and a reason
+    #[inline]
+    pub fn reject_with_reason<S>(status: u16, reason: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self::Reject {
+            status,
+            reason: Some(reason.into()),
+        }
+    }
+
+    /// Add a response header
+    ///
+    /// Has no effect if the connection is rejected.
+    pub fn header<K, V>(mut self, name: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        if let Self::Accept { headers } = &mut self {
+            headers.push((name.into(), value.into()));
+        }
+        self
+    }
+}
+
+type OriginFn = dyn Fn(&str) -> bool + Send + Sync;
+
+/// Validation of the `Origin` and `Host` headers of the incoming handshakes
+///
+/// Protects from the cross-site WebSocket hijacking: the browsers always send the `Origin` of the page,
+/// while the other clients usually don't send it.
+/// The handshakes that don't match are rejected with `403 Forbidden`.
+///
+/// An empty allowlist allows any value.
+#[derive(Clone)]
+pub struct AcceptPolicy {
+    origins: Vec<String>,
+    hosts: Vec<String>,
+    allow_missing_origin: bool,
+    origin_fn: Option<Arc<OriginFn>>,
+}
+
+impl fmt::Debug for AcceptPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AcceptPolicy")
+            .field("origins", &self.origins)
+            .field("hosts", &self.hosts)
+            .field("allow_missing_origin", &self.allow_missing_origin)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for AcceptPolicy {
+    fn default() -> Self {
+        Self {
+            origins: Vec::new(),
+            hosts: Vec::new(),
+            allow_missing_origin: true,
+            origin_fn: None,
+        }
+    }
+}
+
+impl AcceptPolicy {
+    /// New policy, allowing any origin and host
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow an origin (ex. `https://example.com`, case-insensitive)
+    pub fn allow_origin<S>(mut self, origin: S) -> Self
+    where
+        S: Into<String>,
+    {
+        let origin: String = origin.into();
+        self.origins
+            .push(origin.trim_end_matches('/').to_ascii_lowercase());
+        self
+    }
+
+    /// Allow the origins not in the allowlist for which `callback` returns `true`
+    ///
+    /// Ex. to allow all the subdomains or origins loaded at runtime.
+    pub fn allow_origin_if<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.origin_fn = Some(Arc::new(callback));
+        self
+    }
+
+    /// Allow a host (ex. `example.com`, case-insensitive)
+    ///
+    /// If without port, any port is allowed.
+    pub fn allow_host<S>(mut self, host: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.hosts.push(host.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Allow the handshakes without the `Origin` header (default: true)
+    ///
+    /// The browsers always send it: disable to accept only the browsers.
+    #[inline]
+    pub fn allow_missing_origin(mut self, allow: bool) -> Self {
+        self.allow_missing_origin = allow;
+        self
+    }
+
+    /// Check if the handshake request is allowed
+    pub fn is_allowed(&self, req: &HandshakeRequest) -> bool {
+        self.is_origin_allowed(req.header("origin")) && self.is_host_allowed(req.header("host"))
+    }
+
+    /// Accept the handshake request or reject it with `403 Forbidden`
+    pub fn decide(&self, req: &HandshakeRequest) -> AcceptDecision {
+        if !self.is_origin_allowed(req.header("origin")) {
+            return AcceptDecision::reject_with_reason(403, "origin not allowed");
+        }
+
+        if !self.is_host_allowed(req.header("host")) {
+            return AcceptDecision::reject_with_reason(403, "host not allowed");
+        }
+
+        AcceptDecision::accept()
+    }
+
+    fn is_origin_allowed(&self, origin: Option<&str>) -> bool {
+        let origin: &str = match origin {
+            Some(origin) => origin.trim_end_matches('/'),
+            None => return self.allow_missing_origin,
+        };
+
+        if self.origins.is_empty() && self.origin_fn.is_none() {
+            return true;
+        }
+
+        self.origins.iter().any(|o| o.eq_ignore_ascii_case(origin))
+            || self.origin_fn.as_ref().is_some_and(|f| f(origin))
+    }
+
+    fn is_host_allowed(&self, host: Option<&str>) -> bool {
+        if self.hosts.is_empty() {
+            return true;
+        }
+
+        match host {
+            Some(host) => self.hosts.iter().any(|allowed| {
+                allowed.eq_ignore_ascii_case(host) || allowed.eq_ignore_ascii_case(strip_port(host))
+            }),
+            None => false,
+        }
+    }
+}
+
+/// Remove the port from the value of the `Host` header
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        // Not the last group of a bare IPv6 address (ex. `[::1]`)
+        Some((name, port)) if !host.ends_with(']') && port.bytes().all(|b| b.is_ascii_digit()) => {
+            name
+        }
+        _ => host,
+    }
+}
+
+fn error_response(status: StatusCode, reason: Option<String>) -> ErrorResponse {
+    let mut response: ErrorResponse = ErrorResponse::new(reason);
+    *response.status_mut() = status;
+    response
+}
+
+/// Handshake callback, applying the [`AcceptDecision`] to the response
+struct Decide<F>(F);
+
+impl<F> Callback for Decide<F>
+where
+    F: FnOnce(&HandshakeRequest) -> AcceptDecision,
+{
+    fn on_request(self, req: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+        let request: HandshakeRequest = HandshakeRequest::from(req);
+        match (self.0)(&request) {
+            AcceptDecision::Accept { headers } => {
+                for (name, value) in headers.into_iter() {
+                    let name = HeaderName::from_bytes(name.as_bytes());
+                    let value = HeaderValue::from_str(&value);
+                    match (name, value) {
+                        (Ok(name), Ok(value)) => {
+                            response.headers_mut().append(name, value);
+                        }
+                        _ => {
+                            return Err(error_response(
+                                StatusCode::INTERNAL_SERVER_ERROR,
+                                Some(String::from("invalid response header")),
+                            ))
+                        }
+                    }
+                }
+                Ok(response)
+            }
+            AcceptDecision::Reject { status, reason } => {
+                let status: StatusCode =
+                    StatusCode::from_u16(status).unwrap_or(StatusCode::FORBIDDEN);
+                Err(error_response(status, reason))
+            }
+        }
+    }
+}
+
+/// Accept an incoming connection, inspecting the handshake request with a callback
+///
+/// The callback decides whether to accept (optionally adding response headers) or reject the connection.
+#[inline]
+pub async fn accept_hdr<S, F>(
+    raw_stream: S,
+    callback: F,
+) -> Result<WebSocketStream<S>, ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(&HandshakeRequest) -> AcceptDecision + Unpin,
+{
+    accept_hdr_with_config(raw_stream, callback, None).await
+}
+
+/// Accept an incoming connection, inspecting the handshake request with a callback,
+/// with a custom [`WebSocketConfig`]
+///
+/// Check [`accept_hdr`] for more details.
+pub async fn accept_hdr_with_config<S, F>(
+    raw_stream: S,
+    callback: F,
+    config: Option<WebSocketConfig>,
+) -> Result<WebSocketStream<S>, ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(&HandshakeRequest) -> AcceptDecision + Unpin,
+{
+    let callback: Decide<F> = Decide(callback);
+    let config = config.map(Into::into);
+    Ok(tokio_tungstenite::accept_hdr_async_with_config(raw_stream, callback, config).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::Error as WsError;
+
+    use super::*;
+    use crate::native::WsServer;
+
+    fn request(origin: Option<&str>, host: Option<&str>) -> HandshakeRequest {
+        let mut headers: Vec<(String, String)> = Vec::new();
+        if let Some(origin) = origin {
+            headers.push((String::from("Origin"), origin.to_string()));
+        }
+        if let Some(host) = host {
+            headers.push((String::from("Host"), host.to_string()));
+        }
+        HandshakeRequest {
+            path: String::from("/"),
+            query: None,
+            headers,
+        }
+    }
+
+    #[test]
+    fn test_default_allows_all() {
+        let policy = AcceptPolicy::new();
+        assert!(policy.is_allowed(&request(None, None)));
+        assert!(policy.is_allowed(&request(Some("https://evil.com"), Some("example.com"))));
+    }
+
+    #[test]
+    fn test_origins() {
+        let policy = AcceptPolicy::new().allow_origin("https://Example.com/");
+        assert!(policy.is_allowed(&request(Some("https://example.com"), None)));
+        assert!(policy.is_allowed(&request(Some("HTTPS://EXAMPLE.COM/"), None)));
+        assert!(!policy.is_allowed(&request(Some("https://evil.com"), None)));
+        assert!(!policy.is_allowed(&request(Some("http://example.com"), None)));
+
+        // Non-browser clients
+        assert!(policy.is_allowed(&request(None, None)));
+        let policy = policy.allow_missing_origin(false);
+        assert!(!policy.is_allowed(&request(None, None)));
+    }
+
+    #[test]
+    fn test_origin_callback() {
+        let policy = AcceptPolicy::new()
+            .allow_origin("https://example.com")
+            .allow_origin_if(|origin| origin.ends_with(".example.com"));
+        assert!(policy.is_allowed(&request(Some("https://example.com"), None)));
+        assert!(policy.is_allowed(&request(Some("https://app.example.com"), None)));
+        assert!(!policy.is_allowed(&request(Some("https://example.org"), None)));
+    }
+
+    #[test]
+    fn test_hosts() {
+        let policy = AcceptPolicy::new()
+            .allow_host("Example.com")
+            .allow_host("[::1]:8080");
+        assert!(policy.is_allowed(&request(None, Some("example.com"))));
+        assert!(policy.is_allowed(&request(None, Some("example.com:443"))));
+        assert!(policy.is_allowed(&request(None, Some("[::1]:8080"))));
+        assert!(!policy.is_allowed(&request(None, Some("[::1]:9090"))));
+        assert!(!policy.is_allowed(&request(None, Some("evil.com"))));
+        assert!(!policy.is_allowed(&request(None, None)));
+    }
+
+    #[test]
+    fn test_strip_port() {
+        assert_eq!(strip_port("example.com:80"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:80"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+        assert_eq!(strip_port("example.com:abc"), "example.com:abc");
+    }
+
+    #[test]
+    fn test_decide() {
+        let policy = AcceptPolicy::new()
+            .allow_origin("https://example.com")
+            .allow_host("example.com");
+        assert_eq!(
+            policy.decide(&request(Some("https://example.com"), Some("example.com"))),
+            AcceptDecision::accept()
+        );
+        assert_eq!(
+            policy.decide(&request(Some("https://evil.com"), Some("example.com"))),
+            AcceptDecision::reject_with_reason(403, "origin not allowed")
+        );
+        assert_eq!(
+            policy.decide(&request(Some("https://example.com"), Some("evil.com"))),
+            AcceptDecision::reject_with_reason(403, "host not allowed")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_policy() {
+        let server: WsServer = WsServer::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .accept_policy(AcceptPolicy::new().allow_origin("https://example.com"));
+        let addr = server.local_addr().unwrap();
+
+        // Keep the accepted connections open
+        tokio::spawn(server.collect::<Vec<_>>());
+
+        let connect = |origin: &'static str| async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut req = format!("ws://{addr}").into_client_request().unwrap();
+            req.headers_mut()
+                .insert("origin", HeaderValue::from_static(origin));
+            tokio_tungstenite::client_async(req, stream).await
+        };
+
+        assert!(connect("https://example.com").await.is_ok());
+        match connect("https://evil.com").await {
+            Err(WsError::Http(response)) => assert_eq!(response.status(), StatusCode::FORBIDDEN),
+            res => panic!("unexpected result: {res:?}"),
+        }
+    }
+}
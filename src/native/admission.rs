@@ -0,0 +1,226 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection caps and handshake rate limiting of the server
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Connection limits of [`WsServer`](super::WsServer)
+///
+/// The connections are counted from the TCP accept until the socket is dropped,
+/// so the pending handshakes are included.
+///
+/// The connections over the limits are dropped right after the TCP accept, before the TLS and WebSocket handshakes.
+/// Without TLS, the response with the status code is written on a best-effort basis.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ServerLimits {
+    /// Max concurrent connections, rejected with `503 Service Unavailable` (default: unlimited)
+    pub max_connections: Option<usize>,
+    /// Max concurrent connections from the same IP, rejected with `429 Too Many Requests` (default: unlimited)
+    pub max_connections_per_ip: Option<usize>,
+    /// Max handshakes per second, rejected with `429 Too Many Requests` (default: unlimited)
+    pub max_handshakes_per_sec: Option<u32>,
+}
+
+#[derive(Default)]
+struct State {
+    active: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    window_start: Option<Instant>,
+    handshakes: u32,
+}
+
+/// Rejection of a connection over the limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Rejection {
+    pub(super) status: u16,
+    pub(super) reason: &'static str,
+}
+
+impl Rejection {
+    #[inline]
+    const fn new(status: u16, reason: &'static str) -> Self {
+        Self { status, reason }
+    }
+
+    /// Raw HTTP response
+    pub(super) fn response(&self) -> String {
+        let phrase: &str = match self.status {
+            429 => "Too Many Requests",
+            503 => "Service Unavailable",
+            _ => "Error",
+        };
+        format!(
+            "HTTP/1.1 {} {phrase}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            self.status,
+            self.reason.len(),
+            self.reason
+        )
+    }
+}
+
+/// Admission control of the incoming connections
+#[derive(Clone)]
+pub(super) struct Admission {
+    limits: ServerLimits,
+    state: Arc<Mutex<State>>,
+}
+
+impl Admission {
+    pub(super) fn new(limits: ServerLimits) -> Self {
+        Self {
+            limits,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Admit a new connection from `ip`
+    ///
+    /// If over a limit, returns the rejection.
+    pub(super) fn admit(&self, ip: IpAddr) -> Result<ConnectionPermit, Rejection> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(max) = self.limits.max_handshakes_per_sec {
+            let now: Instant = Instant::now();
+            match state.window_start {
+                Some(start) if now.duration_since(start) < RATE_WINDOW => {}
+                _ => {
+                    state.window_start = Some(now);
+                    state.handshakes = 0;
+                }
+            }
+
+            if state.handshakes >= max {
+                return Err(Rejection::new(429, "too many handshakes"));
+            }
+            state.handshakes += 1;
+        }
+
+        if let Some(max) = self.limits.max_connections {
+            if state.active >= max {
+                return Err(Rejection::new(503, "too many connections"));
+            }
+        }
+
+        let from_ip: usize = state.per_ip.get(&ip).copied().unwrap_or(0);
+        if let Some(max) = self.limits.max_connections_per_ip {
+            if from_ip >= max {
+                return Err(Rejection::new(
+                    429,
+                    "too many connections from the same address",
+                ));
+            }
+        }
+
+        state.active += 1;
+        state.per_ip.insert(ip, from_ip + 1);
+
+        Ok(ConnectionPermit {
+            ip,
+            state: self.state.clone(),
+        })
+    }
+}
+
+/// Slot of an admitted connection, released on drop
+pub(crate) struct ConnectionPermit {
+    ip: IpAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl fmt::Debug for ConnectionPermit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionPermit")
+            .field("ip", &self.ip)
+            .finish()
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.active = state.active.saturating_sub(1);
+
+        if let Some(count) = state.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const IP1: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const IP2: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let admission = Admission::new(ServerLimits {
+            max_connections: Some(2),
+            ..Default::default()
+        });
+
+        let p1 = admission.admit(IP1).unwrap();
+        let _p2 = admission.admit(IP2).unwrap();
+        assert_eq!(admission.admit(IP1).unwrap_err().status, 503);
+
+        // Released on drop
+        drop(p1);
+        assert!(admission.admit(IP1).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_per_ip() {
+        let admission = Admission::new(ServerLimits {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        });
+
+        let p1 = admission.admit(IP1).unwrap();
+        assert_eq!(admission.admit(IP1).unwrap_err().status, 429);
+        let _p2 = admission.admit(IP2).unwrap();
+
+        drop(p1);
+        let _p1 = admission.admit(IP1).unwrap();
+        assert_eq!(admission.state.lock().unwrap().per_ip.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_handshakes_per_sec() {
+        let admission = Admission::new(ServerLimits {
+            max_handshakes_per_sec: Some(2),
+            ..Default::default()
+        });
+
+        let _p1 = admission.admit(IP1).unwrap();
+        let _p2 = admission.admit(IP1).unwrap();
+        assert_eq!(admission.admit(IP2).unwrap_err().status, 429);
+
+        // New window
+        tokio::time::advance(RATE_WINDOW).await;
+        let _p3 = admission.admit(IP2).unwrap();
+    }
+
+    #[test]
+    fn test_rejection_response() {
+        let rejection = Rejection::new(429, "slow down");
+        assert_eq!(
+            rejection.response(),
+            "HTTP/1.1 429 Too Many Requests\r\nConnection: close\r\nContent-Length: 9\r\n\r\nslow down"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Handshake authorization
+
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+
+use super::{ConnectError, Request};
+
+/// Provider of the `Authorization` header of the handshake
+///
+/// Implement it to fetch or refresh the tokens that expire mid-session (ex. OAuth bearer tokens).
+pub trait AuthProvider: Send + Sync {
+    /// Get the value of the `Authorization` header (ex. `Bearer <token>`)
+    ///
+    /// Called before each connection attempt, so also before each reconnection.
+    /// `refresh` is `true` when the previous handshake has been rejected with `401`:
+    /// a cached token must not be reused.
+    fn authorization(&self, refresh: bool) -> BoxFuture<'_, Result<String, ConnectError>>;
+}
+
+/// Handshake authenticator
+///
+/// Check [`WebSocketBuilder::auth`](crate::WebSocketBuilder::auth) for more details.
+///
+/// The authenticator is cheap to clone: all the clones refer to the same provider.
+#[derive(Clone)]
+pub struct Authenticator {
+    inner: Arc<dyn AuthProvider>,
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authenticator").finish()
+    }
+}
+
+impl PartialEq for Authenticator {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for Authenticator {}
+
+impl Authenticator {
+    /// New authenticator
+    #[inline]
+    pub fn new<P>(provider: P) -> Self
+    where
+        P: AuthProvider + 'static,
+    {
+        Self {
+            inner: Arc::new(provider),
+        }
+    }
+
+    /// New authenticator from a callback, receiving the `refresh` flag
+    pub fn from_fn<F, Fut>(callback: F) -> Self
+    where
+        F: Fn(bool) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, ConnectError>> + Send + 'static,
+    {
+        Self::new(FnAuthProvider(callback))
+    }
+
+    /// Static `Bearer` token, never refreshed
+    pub fn bearer<S>(token: S) -> Self
+    where
+        S: Into<String>,
+    {
+        let header: String = format!("Bearer {}", token.into());
+        Self::from_fn(move |_| {
+            let header: String = header.clone();
+            async move { Ok(header) }
+        })
+    }
+
+    #[inline]
+    pub(super) async fn authorization(&self, refresh: bool) -> Result<String, ConnectError> {
+        self.inner.authorization(refresh).await
+    }
+}
+
+struct FnAuthProvider<F>(F);
+
+impl<F, Fut> AuthProvider for FnAuthProvider<F>
+where
+    F: Fn(bool) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<String, ConnectError>> + Send + 'static,
+{
+    fn authorization(&self, refresh: bool) -> BoxFuture<'_, Result<String, ConnectError>> {
+        Box::pin((self.0)(refresh))
+    }
+}
+
+/// Customization of the handshake request, right before it's sent
+///
+/// Check [`WebSocketBuilder::on_request`](crate::WebSocketBuilder::on_request) for more details.
+///
+/// The hook is cheap to clone: all the clones refer to the same callback.
+#[derive(Clone)]
+pub struct RequestHook {
+    inner: Arc<dyn Fn(Request) -> Request + Send + Sync>,
+}
+
+impl fmt::Debug for RequestHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestHook").finish()
+    }
+}
+
+impl PartialEq for RequestHook {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for RequestHook {}
+
+impl RequestHook {
+    /// New request hook
+    #[inline]
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(Request) -> Request + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(callback),
+        }
+    }
+
+    #[inline]
+    pub(super) fn call(&self, request: Request) -> Request {
+        (self.inner)(request)
+    }
+}
@@ -0,0 +1,41 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Runtime-agnostic connection
+//!
+//! Run the WebSocket over a stream opened by any async runtime (ex. `async-std` or `smol`),
+//! as long as it implements the `futures` I/O traits.
+//!
+//! No timer is used during the handshake: the caller is responsible to apply a timeout.
+//! The [`KeepAlive`](crate::KeepAlive) and [`ReconnectingWebSocket`](crate::reconnect::ReconnectingWebSocket)
+//! wrappers still require a `tokio` runtime.
+
+use futures_util::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
+use url::Url;
+
+use super::{build_request, handshake_without_timeout, protocol, ConnectError, IoStream};
+use crate::socket::{InnerWebSocket, WebSocket};
+use crate::{HandshakeResponse, WebSocketBuilder};
+
+/// Perform the TLS (if required) and WebSocket handshakes over an already opened `stream`
+///
+/// The [`ConnectionMode`](crate::ConnectionMode) and the timeout of the builder are ignored.
+pub async fn connect_with_stream<S>(
+    url: &Url,
+    stream: S,
+    builder: &WebSocketBuilder,
+) -> Result<(WebSocket, HandshakeResponse), ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let request: Request = build_request(url, builder, false).await?;
+    let stream: Compat<S> = stream.compat();
+    let stream: Box<dyn IoStream> = Box::new(stream);
+    let (stream, response) = handshake_without_timeout(url, request, stream, builder).await?;
+    Ok((
+        WebSocket::new(InnerWebSocket::Compat(stream), protocol(&response)),
+        HandshakeResponse::from(&response),
+    ))
+}
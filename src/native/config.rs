@@ -0,0 +1,62 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! WebSocket protocol config
+
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig as TungsteniteWebSocketConfig;
+
+/// WebSocket protocol config
+///
+/// Limits and buffering of a connection. The defaults are the same of `tungstenite`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WebSocketConfig {
+    /// Read buffer capacity (default: 128 KiB)
+    pub read_buffer_size: usize,
+    /// Target minimum size of the write buffer before flushing (default: 128 KiB)
+    ///
+    /// `0` means that every message is written immediately.
+    pub write_buffer_size: usize,
+    /// Max size of the write buffer (default: unlimited)
+    ///
+    /// Must be greater than [`WebSocketConfig::write_buffer_size`].
+    pub max_write_buffer_size: usize,
+    /// Max size of an incoming message (default: 64 MiB)
+    ///
+    /// `None` means no limit.
+    pub max_message_size: Option<usize>,
+    /// Max size of a single incoming frame (default: 16 MiB)
+    ///
+    /// `None` means no limit.
+    pub max_frame_size: Option<usize>,
+    /// Accept unmasked frames from the client (default: `false`)
+    ///
+    /// Server only. Not compliant with RFC 6455.
+    pub accept_unmasked_frames: bool,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        let config: TungsteniteWebSocketConfig = TungsteniteWebSocketConfig::default();
+        Self {
+            read_buffer_size: config.read_buffer_size,
+            write_buffer_size: config.write_buffer_size,
+            max_write_buffer_size: config.max_write_buffer_size,
+            max_message_size: config.max_message_size,
+            max_frame_size: config.max_frame_size,
+            accept_unmasked_frames: config.accept_unmasked_frames,
+        }
+    }
+}
+
+impl From<WebSocketConfig> for TungsteniteWebSocketConfig {
+    fn from(config: WebSocketConfig) -> Self {
+        let mut c: Self = Self::default();
+        c.read_buffer_size = config.read_buffer_size;
+        c.write_buffer_size = config.write_buffer_size;
+        c.max_write_buffer_size = config.max_write_buffer_size;
+        c.max_message_size = config.max_message_size;
+        c.max_frame_size = config.max_frame_size;
+        c.accept_unmasked_frames = config.accept_unmasked_frames;
+        c
+    }
+}
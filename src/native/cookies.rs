@@ -0,0 +1,411 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Cookie jar
+
+use std::cmp::Reverse;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use url::Url;
+
+#[derive(Debug, Clone)]
+struct Cookie {
+    name: String,
+    value: String,
+    /// Lowercase domain
+    domain: String,
+    /// Sent only to the exact `domain` (no `Domain` attribute)
+    host_only: bool,
+    path: String,
+    secure: bool,
+    /// `None` for session cookies
+    expires: Option<SystemTime>,
+}
+
+impl Cookie {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    fn matches(&self, host: &str, path: &str, secure: bool) -> bool {
+        let domain_match: bool = if self.host_only {
+            host == self.domain
+        } else {
+            domain_match(host, &self.domain)
+        };
+
+        domain_match && path_match(path, &self.path) && (secure || !self.secure)
+    }
+}
+
+/// Cookie jar, shared across connections
+///
+/// Captures the `Set-Cookie` headers of the handshake responses (also of the redirects and of the rejected handshakes)
+/// and sends the matching cookies in the `Cookie` header of the following handshakes.
+///
+/// The `Domain`, `Path`, `Secure`, `Max-Age` and `Expires` attributes are supported,
+/// but the public suffixes aren't checked: share a jar only across trusted servers.
+#[derive(Clone, Default)]
+pub struct CookieJar {
+    cookies: Arc<Mutex<Vec<Cookie>>>,
+}
+
+impl fmt::Debug for CookieJar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CookieJar")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl PartialEq for CookieJar {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.cookies, &other.cookies)
+    }
+}
+
+impl Eq for CookieJar {}
+
+impl CookieJar {
+    /// New empty jar
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store a cookie from a `Set-Cookie` header value, received from `url`
+    ///
+    /// Invalid cookies and cookies for other domains are ignored.
+    pub fn set(&self, set_cookie: &str, url: &Url) {
+        let Some(host) = url.host_str() else {
+            return;
+        };
+        let host: String = host.to_ascii_lowercase();
+        let now: SystemTime = SystemTime::now();
+
+        let Some(cookie) = parse(set_cookie, &host, url.path(), now) else {
+            return;
+        };
+
+        let mut cookies = self.cookies.lock().unwrap_or_else(|e| e.into_inner());
+        cookies.retain(|c| {
+            !(c.name == cookie.name && c.domain == cookie.domain && c.path == cookie.path)
+        });
+
+        // An expired cookie only deletes the stored one
+        if !cookie.is_expired(now) {
+            cookies.push(cookie);
+        }
+    }
+
+    /// Get the value of the `Cookie` header to send to `url`, if any cookie matches
+    pub fn header(&self, url: &Url) -> Option<String> {
+        let host: String = url.host_str()?.to_ascii_lowercase();
+        let secure: bool = matches!(url.scheme(), "wss" | "https");
+        let now: SystemTime = SystemTime::now();
+
+        let mut cookies = self.cookies.lock().unwrap_or_else(|e| e.into_inner());
+        cookies.retain(|c| !c.is_expired(now));
+
+        let mut matching: Vec<&Cookie> = cookies
+            .iter()
+            .filter(|c| c.matches(&host, url.path(), secure))
+            .collect();
+
+        if matching.is_empty() {
+            return None;
+        }
+
+        // Longer paths first (RFC 6265, section 5.4)
+        matching.sort_by_key(|c| Reverse(c.path.len()));
+
+        let header: Vec<String> = matching
+            .into_iter()
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect();
+        Some(header.join("; "))
+    }
+
+    /// Number of stored cookies (expired included)
+    pub fn len(&self) -> usize {
+        self.cookies.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Check if the jar is empty
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all the cookies
+    pub fn clear(&self) {
+        self.cookies
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
+
+/// Parse a `Set-Cookie` header value
+fn parse(set_cookie: &str, host: &str, request_path: &str, now: SystemTime) -> Option<Cookie> {
+    let mut parts = set_cookie.split(';');
+
+    let (name, value) = parts.next()?.split_once('=')?;
+    let name: &str = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut cookie: Cookie = Cookie {
+        name: name.to_string(),
+        value: value.trim().trim_matches('"').to_string(),
+        domain: host.to_string(),
+        host_only: true,
+        path: default_path(request_path),
+        secure: false,
+        expires: None,
+    };
+
+    let mut max_age: Option<SystemTime> = None;
+    let mut expires: Option<SystemTime> = None;
+
+    for attr in parts {
+        let (key, value) = match attr.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => (attr.trim(), ""),
+        };
+
+        if key.eq_ignore_ascii_case("domain") {
+            let domain: String = value.trim_start_matches('.').to_ascii_lowercase();
+            if !domain.is_empty() {
+                // Cookies for other domains are rejected
+                if !domain_match(host, &domain) {
+                    return None;
+                }
+
+                cookie.domain = domain;
+                cookie.host_only = false;
+            }
+        } else if key.eq_ignore_ascii_case("path") {
+            if value.starts_with('/') {
+                cookie.path = value.to_string();
+            }
+        } else if key.eq_ignore_ascii_case("secure") {
+            cookie.secure = true;
+        } else if key.eq_ignore_ascii_case("max-age") {
+            if let Ok(secs) = value.parse::<i64>() {
+                max_age = Some(match u64::try_from(secs) {
+                    Ok(secs) if secs > 0 => now
+                        .checked_add(Duration::from_secs(secs))
+                        .unwrap_or(now + Duration::from_secs(u32::MAX as u64)),
+                    _ => UNIX_EPOCH,
+                });
+            }
+        } else if key.eq_ignore_ascii_case("expires") {
+            expires = parse_http_date(value);
+        }
+    }
+
+    // `Max-Age` has precedence over `Expires`
+    cookie.expires = max_age.or(expires);
+
+    Some(cookie)
+}
+
+/// Default path of a cookie (RFC 6265, section 5.1.4)
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => String::from("/"),
+        Some(i) => path[..i].to_string(),
+    }
+}
+
+/// RFC 6265, section 5.1.3
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.ends_with(domain)
+            && host[..host.len() - domain.len()].ends_with('.')
+            && host.parse::<std::net::IpAddr>().is_err())
+}
+
+/// RFC 6265, section 5.1.4
+fn path_match(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+/// Parse an HTTP date (ex. `Sun, 06 Nov 1994 08:49:37 GMT` or `Sunday, 06-Nov-94 08:49:37 GMT`)
+fn parse_http_date(s: &str) -> Option<SystemTime> {
+    // Skip the weekday
+    let s: &str = s.split_once(',').map_or(s, |(_, date)| date).trim();
+
+    let mut fields = s.split([' ', '-']).filter(|f| !f.is_empty());
+    let day: u32 = fields.next()?.parse().ok()?;
+    let month: &str = fields.next()?;
+    let year: i64 = match fields.next()?.parse::<i64>().ok()? {
+        y @ 0..=69 => y + 2000,
+        y @ 70..=99 => y + 1900,
+        y => y,
+    };
+
+    // RFC 6265, section 5.1.1
+    if !(1601..=9999).contains(&year) {
+        return None;
+    }
+
+    let mut time = fields.next()?.split(':');
+    let hour: u64 = time.next()?.parse().ok()?;
+    let min: u64 = time.next()?.parse().ok()?;
+    let sec: u64 = time.next()?.parse().ok()?;
+
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    let month: u32 = MONTHS.iter().position(|m| {
+        month
+            .get(..3)
+            .is_some_and(|month| m.eq_ignore_ascii_case(month))
+    })? as u32
+        + 1;
+
+    if !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 {
+        return None;
+    }
+
+    let days: i64 = days_from_civil(year, month, day);
+    let secs: i64 = days
+        .checked_mul(86_400)?
+        .checked_add((hour * 3600 + min * 60 + sec) as i64)?;
+
+    match u64::try_from(secs) {
+        Ok(secs) => UNIX_EPOCH.checked_add(Duration::from_secs(secs)),
+        // Before the epoch: already expired
+        Err(..) => Some(UNIX_EPOCH),
+    }
+}
+
+/// Days since the Unix epoch of a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year: i64 = if month <= 2 { year - 1 } else { year };
+    let era: i64 = year.div_euclid(400);
+    let yoe: i64 = year - era * 400;
+    let month: i64 = i64::from(month);
+    let doy: i64 =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let doe: i64 = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let now: SystemTime = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        let cookie = parse(
+            "id=abc; Domain=.Example.com; Path=/ws; Secure; Max-Age=60",
+            "api.example.com",
+            "/ws/v1",
+            now,
+        )
+        .unwrap();
+        assert_eq!(cookie.name, "id");
+        assert_eq!(cookie.value, "abc");
+        assert_eq!(cookie.domain, "example.com");
+        assert!(!cookie.host_only);
+        assert_eq!(cookie.path, "/ws");
+        assert!(cookie.secure);
+        assert_eq!(cookie.expires, Some(now + Duration::from_secs(60)));
+
+        // Default domain and path
+        let cookie = parse("id=abc", "example.com", "/ws/v1", now).unwrap();
+        assert_eq!(cookie.domain, "example.com");
+        assert!(cookie.host_only);
+        assert_eq!(cookie.path, "/ws");
+        assert_eq!(cookie.expires, None);
+
+        // Other domain
+        assert!(parse("id=abc; Domain=other.com", "example.com", "/", now).is_none());
+
+        // No name
+        assert!(parse("=abc", "example.com", "/", now).is_none());
+        assert!(parse("abc", "example.com", "/", now).is_none());
+    }
+
+    #[test]
+    fn test_parse_expires() {
+        let now: SystemTime = UNIX_EPOCH;
+
+        let cookie = parse(
+            "id=abc; Expires=Sun, 06 Nov 1994 08:49:37 GMT",
+            "example.com",
+            "/",
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            cookie.expires,
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+
+        let cookie = parse(
+            "id=abc; Expires=Sunday, 06-Nov-94 08:49:37 GMT",
+            "example.com",
+            "/",
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            cookie.expires,
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+
+        // `Max-Age` has precedence
+        let cookie = parse(
+            "id=abc; Max-Age=10; Expires=Sun, 06 Nov 1994 08:49:37 GMT",
+            "example.com",
+            "/",
+            now,
+        )
+        .unwrap();
+        assert_eq!(cookie.expires, Some(UNIX_EPOCH + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_parse_http_date_invalid() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 99999999999999999 08:49:37 GMT"),
+            None
+        );
+        assert_eq!(parse_http_date("Sun, 06 Nov 1500 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Noé 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Né 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 32 Nov 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:49:37 GMT"), None);
+        assert_eq!(parse_http_date(""), None);
+    }
+
+    #[test]
+    fn test_domain_match() {
+        assert!(domain_match("example.com", "example.com"));
+        assert!(domain_match("api.example.com", "example.com"));
+        assert!(!domain_match("badexample.com", "example.com"));
+        assert!(!domain_match("example.com", "api.example.com"));
+        assert!(!domain_match("1.2.3.4", "2.3.4"));
+    }
+
+    #[test]
+    fn test_path_match() {
+        assert!(path_match("/ws", "/ws"));
+        assert!(path_match("/ws/v1", "/ws"));
+        assert!(path_match("/ws/v1", "/ws/"));
+        assert!(path_match("/ws", "/"));
+        assert!(!path_match("/wss", "/ws"));
+        assert!(!path_match("/", "/ws"));
+    }
+}
@@ -0,0 +1,1181 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Permessage-deflate (RFC 7692)
+//!
+//! `tungstenite` rejects the frames with the reserved bits set, so the compression is done below it:
+//! [`DeflateStream`] wraps the transport, inflates the incoming compressed frames
+//! and deflates the outgoing data frames, so `tungstenite` reads and writes only plain frames.
+//!
+//! The negotiated parameters are read from the handshake response, while it passes through the stream
+//! (read by the client, written by the server).
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BufMut, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use futures_util::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::client::{Request, Response};
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, SEC_WEBSOCKET_EXTENSIONS};
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use url::Url;
+
+#[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+use super::TlsInfo;
+use super::{tls, ws_config, ConnectError, Deadline, IoStream, WebSocketConfig};
+use crate::{Phase, WebSocketBuilder};
+
+/// Name of the extension
+const EXTENSION: &str = "permessage-deflate";
+/// Empty stored block ending a sync flush: removed from the compressed messages and appended back before inflating
+const TAIL: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+/// Max size of the handshake response head
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// Max size of a single read
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+/// Size of the processed outgoing data above which the writes wait for the transport
+const MAX_PENDING_WRITE: usize = 128 * 1024;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+
+/// Permessage-deflate config (RFC 7692)
+///
+/// The same config is used by the client
+/// ([`WebSocketBuilder::compression`](crate::WebSocketBuilder::compression))
+/// and by the server ([`WsServer::compression`](super::WsServer::compression)).
+///
+/// The LZ77 window is always 32 KiB: the offers that limit the window of the server are declined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CompressionConfig {
+    /// Compression level, from `0` (none) to `9` (best) (default: `6`)
+    pub level: u32,
+    /// Reset the compression context of the server after every message (default: `false`)
+    ///
+    /// Requested by the client or enforced by the server: lower memory usage, worse compression ratio.
+    pub server_no_context_takeover: bool,
+    /// Reset the compression context of the client after every message (default: `false`)
+    ///
+    /// Announced by the client or requested by the server: lower memory usage, worse compression ratio.
+    pub client_no_context_takeover: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            level: 6,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Offer of the client (`Sec-WebSocket-Extensions` request header)
+    fn offer(&self) -> String {
+        let mut offer: String = String::from(EXTENSION);
+        if self.server_no_context_takeover {
+            offer.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            offer.push_str("; client_no_context_takeover");
+        }
+        offer
+    }
+
+    /// Accept the first supported offer of the client, returning the `Sec-WebSocket-Extensions` response header
+    ///
+    /// `offers` are the values of the `Sec-WebSocket-Extensions` request headers.
+    pub(super) fn accept<'a, I>(&self, offers: I) -> Option<String>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let params: Params = offers
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .find_map(|offer| self.accept_offer(offer))?;
+
+        let mut response: String = String::from(EXTENSION);
+        if params.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if params.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        Some(response)
+    }
+
+    fn accept_offer(&self, offer: &str) -> Option<Params> {
+        let mut params = offer.split(';').map(str::trim);
+        if !params.next()?.eq_ignore_ascii_case(EXTENSION) {
+            return None;
+        }
+
+        let mut accepted: Params = Params {
+            server_no_context_takeover: self.server_no_context_takeover,
+            client_no_context_takeover: self.client_no_context_takeover,
+        };
+        let mut seen: Vec<&str> = Vec::new();
+
+        for param in params {
+            let (name, value) = split_param(param);
+
+            // Duplicated parameters make the offer invalid
+            if seen.iter().any(|s| s.eq_ignore_ascii_case(name)) {
+                return None;
+            }
+            seen.push(name);
+
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("server_no_context_takeover", None) => accepted.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) => accepted.client_no_context_takeover = true,
+                // The window of the compressor can't be reduced
+                ("server_max_window_bits", Some(bits)) if window_bits(bits) == Some(15) => {}
+                // The client can use any window: the inflater always has the largest one
+                ("client_max_window_bits", None) => {}
+                ("client_max_window_bits", Some(bits)) if window_bits(bits).is_some() => {}
+                _ => return None,
+            }
+        }
+
+        Some(accepted)
+    }
+}
+
+/// Negotiated parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Params {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+}
+
+impl Params {
+    /// Parse the `Sec-WebSocket-Extensions` headers of the handshake response
+    ///
+    /// Returns `None` if the extension isn't accepted,
+    /// and an error if it's accepted with parameters that the client didn't offer.
+    fn from_response<'a, I>(values: I) -> io::Result<Option<Self>>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut accepted: Option<Self> = None;
+
+        for extension in values.into_iter().flat_map(|value| value.split(',')) {
+            let mut params = extension.split(';').map(str::trim);
+            if !params
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case(EXTENSION))
+            {
+                continue;
+            }
+
+            if accepted.is_some() {
+                return Err(invalid_response());
+            }
+
+            let mut parsed: Self = Self {
+                server_no_context_takeover: false,
+                client_no_context_takeover: false,
+            };
+
+            for param in params {
+                match split_param(param) {
+                    (name, None) if name.eq_ignore_ascii_case("server_no_context_takeover") => {
+                        parsed.server_no_context_takeover = true;
+                    }
+                    (name, None) if name.eq_ignore_ascii_case("client_no_context_takeover") => {
+                        parsed.client_no_context_takeover = true;
+                    }
+                    // Any window of the server fits in the one of the inflater
+                    (name, Some(bits))
+                        if name.eq_ignore_ascii_case("server_max_window_bits")
+                            && window_bits(bits).is_some() => {}
+                    // Includes `client_max_window_bits`, not offered
+                    _ => return Err(invalid_response()),
+                }
+            }
+
+            accepted = Some(parsed);
+        }
+
+        Ok(accepted)
+    }
+}
+
+/// Split an extension parameter into name and value, removing the quotes of the value
+fn split_param(param: &str) -> (&str, Option<&str>) {
+    match param.split_once('=') {
+        Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+        None => (param, None),
+    }
+}
+
+/// Parse a LZ77 window size (`8` to `15` bits)
+fn window_bits(bits: &str) -> Option<u8> {
+    let bits: u8 = bits.parse().ok()?;
+    (8..=15).contains(&bits).then_some(bits)
+}
+
+#[inline]
+fn invalid_response() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "invalid permessage-deflate response",
+    )
+}
+
+#[inline]
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Perform the client WebSocket handshake over `conn` (after the TLS handshake, if required), offering the compression
+pub(super) async fn handshake(
+    url: &Url,
+    request: Request,
+    conn: Box<dyn IoStream>,
+    builder: &WebSocketBuilder,
+    deadline: &Deadline,
+) -> Result<(WebSocketStream<DeflateStream>, Response), ConnectError> {
+    let conn: MaybeTlsStream<Box<dyn IoStream>> =
+        deadline.run(Phase::Tls, tls(url, conn, builder)).await??;
+
+    #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+    let info: Option<TlsInfo> = super::tls::client_tls_info(&conn);
+
+    let stream: DeflateStream = DeflateStream::new(
+        Box::new(conn),
+        Role::Client,
+        builder.compression.unwrap_or_default(),
+        Some(ws_config(builder)),
+    );
+    #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+    let stream: DeflateStream = stream.with_tls_info(info);
+
+    handshake_stream(request, stream, builder, deadline).await
+}
+
+/// Perform the client WebSocket handshake over an already established `stream`, offering the compression
+pub(super) async fn handshake_stream(
+    mut request: Request,
+    stream: DeflateStream,
+    builder: &WebSocketBuilder,
+    deadline: &Deadline,
+) -> Result<(WebSocketStream<DeflateStream>, Response), ConnectError> {
+    let offer: String = builder.compression.unwrap_or_default().offer();
+    let value: HeaderValue = HeaderValue::from_str(&offer).map_err(WsError::from)?;
+    request
+        .headers_mut()
+        .append(SEC_WEBSOCKET_EXTENSIONS, value);
+
+    let fut = tokio_tungstenite::client_async_with_config(
+        request,
+        stream,
+        Some(ws_config(builder).into()),
+    );
+
+    // NOT REMOVE `Box::pin`!
+    // Use `Box::pin` to fix stack overflow on windows targets due to large `Future`
+    Ok(Box::pin(deadline.run(Phase::Handshake, fut)).await??)
+}
+
+/// Direction of the data through [`DeflateStream`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    /// Read from the transport
+    Incoming,
+    /// Written to the transport
+    Outgoing,
+}
+
+/// Transport compressing the frames, if the compression has been negotiated
+///
+/// Passes the bytes through until the end of the handshake response.
+pub(crate) struct DeflateStream {
+    inner: Box<dyn IoStream>,
+    role: Role,
+    config: CompressionConfig,
+    max_frame_size: Option<usize>,
+    max_message_size: Option<usize>,
+    #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+    tls: Option<TlsInfo>,
+    /// Handshake response head, while incomplete
+    head: Option<Vec<u8>>,
+    /// `None` if the compression hasn't been negotiated (yet)
+    codec: Option<Codec>,
+    /// Incoming data not processed yet
+    read_buf: BytesMut,
+    /// Processed incoming data, to be read by `tungstenite`
+    read_ready: BytesMut,
+    /// The transport has been closed
+    eof: bool,
+    /// Outgoing data not processed yet
+    write_buf: BytesMut,
+    /// Processed outgoing data, to be written to the transport
+    write_ready: BytesMut,
+}
+
+impl DeflateStream {
+    /// Wrap `inner`, before the WebSocket handshake
+    ///
+    /// The limits of `ws_config` bound the inflated frames and messages.
+    pub(crate) fn new(
+        inner: Box<dyn IoStream>,
+        role: Role,
+        config: CompressionConfig,
+        ws_config: Option<WebSocketConfig>,
+    ) -> Self {
+        let ws_config: WebSocketConfig = ws_config.unwrap_or_default();
+        Self {
+            inner,
+            role,
+            config,
+            max_frame_size: ws_config.max_frame_size,
+            max_message_size: ws_config.max_message_size,
+            #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+            tls: None,
+            head: Some(Vec::new()),
+            codec: None,
+            read_buf: BytesMut::new(),
+            read_ready: BytesMut::new(),
+            eof: false,
+            write_buf: BytesMut::new(),
+            write_ready: BytesMut::new(),
+        }
+    }
+
+    /// Keep the TLS session info of the wrapped transport
+    #[inline]
+    #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+    pub(crate) fn with_tls_info(mut self, info: Option<TlsInfo>) -> Self {
+        self.tls = info;
+        self
+    }
+
+    /// Get the TLS session info of the wrapped transport
+    #[inline]
+    #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+    pub(crate) fn tls_info(&self) -> Option<TlsInfo> {
+        self.tls.clone()
+    }
+
+    /// Check if the compression has been negotiated
+    #[inline]
+    pub(crate) fn is_compressed(&self) -> bool {
+        self.codec.is_some()
+    }
+
+    /// The handshake response passes through the reads (client) or the writes (server)
+    #[inline]
+    fn head_direction(&self) -> Direction {
+        match self.role {
+            Role::Client => Direction::Incoming,
+            Role::Server => Direction::Outgoing,
+        }
+    }
+
+    /// Move the handshake response head from `src` to `dst`, negotiating when complete
+    fn scan_head(&mut self, src: &mut BytesMut, dst: &mut BytesMut) -> io::Result<()> {
+        let Some(head) = &mut self.head else {
+            return Ok(());
+        };
+
+        // The end may be split across two chunks
+        let start: usize = head.len().saturating_sub(3);
+        let old_len: usize = head.len();
+        head.extend_from_slice(src);
+
+        let end: usize = match find(&head[start..], b"\r\n\r\n") {
+            Some(pos) => start + pos + 4,
+            None if head.len() > MAX_HEAD_SIZE => {
+                // Leave the oversized response to `tungstenite`, uncompressed
+                dst.extend_from_slice(&src.split());
+                self.head = None;
+                return Ok(());
+            }
+            None => {
+                dst.extend_from_slice(&src.split());
+                return Ok(());
+            }
+        };
+
+        dst.extend_from_slice(&src.split_to(end - old_len));
+        head.truncate(end);
+
+        let params: Option<Params> = response_params(head)?;
+        self.codec = params.map(|params| Codec::new(self.role, &self.config, params));
+        self.head = None;
+        Ok(())
+    }
+
+    /// Process the incoming data
+    fn process_read(&mut self) -> io::Result<()> {
+        let mut src: BytesMut = std::mem::take(&mut self.read_buf);
+        let mut dst: BytesMut = std::mem::take(&mut self.read_ready);
+
+        let res: io::Result<()> = self.process(&mut src, &mut dst, Direction::Incoming);
+
+        self.read_buf = src;
+        self.read_ready = dst;
+        res
+    }
+
+    /// Process the outgoing data
+    fn process_write(&mut self) -> io::Result<()> {
+        let mut src: BytesMut = std::mem::take(&mut self.write_buf);
+        let mut dst: BytesMut = std::mem::take(&mut self.write_ready);
+
+        let res: io::Result<()> = self.process(&mut src, &mut dst, Direction::Outgoing);
+
+        self.write_buf = src;
+        self.write_ready = dst;
+        res
+    }
+
+    /// Move the complete frames from `src` to `dst`, transforming them if the compression has been negotiated
+    ///
+    /// The handshake response is scanned only in its direction (check [`Self::head_direction`]).
+    fn process(
+        &mut self,
+        src: &mut BytesMut,
+        dst: &mut BytesMut,
+        direction: Direction,
+    ) -> io::Result<()> {
+        if self.head.is_some() {
+            if self.head_direction() != direction {
+                // The request
+                dst.extend_from_slice(&src.split());
+                return Ok(());
+            }
+
+            self.scan_head(src, dst)?;
+        }
+
+        while let Some(codec) = &mut self.codec {
+            let Some(header) = FrameHeader::parse(src) else {
+                return Ok(());
+            };
+
+            // Leave the oversized frames to `tungstenite`, that rejects them
+            if direction == Direction::Incoming
+                && self
+                    .max_frame_size
+                    .is_some_and(|max| header.len > max as u64)
+            {
+                self.codec = None;
+                break;
+            }
+
+            let size: usize = header.size + header.len as usize;
+            if src.len() < size {
+                return Ok(());
+            }
+
+            let frame: BytesMut = src.split_to(size);
+            if direction == Direction::Incoming {
+                codec.decode(&header, frame, dst, self.max_message_size)?;
+            } else {
+                codec.encode(&header, frame, dst)?;
+            }
+        }
+
+        dst.extend_from_slice(&src.split());
+        Ok(())
+    }
+
+    /// Write the processed outgoing data to the transport
+    fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_ready.is_empty() {
+            let n: usize = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_ready))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_ready.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for DeflateStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.read_ready.is_empty() {
+                let n: usize = this.read_ready.len().min(buf.remaining());
+                buf.put_slice(&this.read_ready.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.eof {
+                // Leave the truncated frame to `tungstenite`
+                if !this.read_buf.is_empty() {
+                    this.read_ready = this.read_buf.split();
+                    continue;
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut chunk = [0u8; READ_CHUNK_SIZE];
+            let mut chunk: ReadBuf<'_> = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+
+            if chunk.filled().is_empty() {
+                this.eof = true;
+                continue;
+            }
+
+            this.read_buf.extend_from_slice(chunk.filled());
+            this.process_read()?;
+        }
+    }
+}
+
+impl AsyncWrite for DeflateStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // Backpressure: the outgoing data is accepted while the pending one is below the threshold
+        if this.poll_write_ready(cx)?.is_pending() && this.write_ready.len() >= MAX_PENDING_WRITE {
+            return Poll::Pending;
+        }
+
+        this.write_buf.extend_from_slice(buf);
+        this.process_write()?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_ready(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_ready(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Get the negotiated parameters from the handshake response head
+fn response_params(head: &[u8]) -> io::Result<Option<Params>> {
+    let head: &str = std::str::from_utf8(head).map_err(invalid_data)?;
+    let mut lines = head.split("\r\n");
+
+    // Status line (ex. `HTTP/1.1 101 Switching Protocols`)
+    let switching: bool = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .is_some_and(|status| status == "101");
+    if !switching {
+        return Ok(None);
+    }
+
+    let values = lines.filter_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case(SEC_WEBSOCKET_EXTENSIONS.as_str())
+            .then(|| value.trim())
+    });
+    Params::from_response(values)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Header of a frame
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    /// Payload length
+    len: u64,
+    /// Header size
+    size: usize,
+}
+
+impl FrameHeader {
+    /// Parse a frame header (`None` if incomplete)
+    fn parse(buf: &[u8]) -> Option<Self> {
+        let first: u8 = *buf.first()?;
+        let second: u8 = *buf.get(1)?;
+
+        let (len, mut size) = match second & 0x7F {
+            126 => {
+                let b = buf.get(2..4)?;
+                (u16::from_be_bytes([b[0], b[1]]) as u64, 4)
+            }
+            127 => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(buf.get(2..10)?);
+                (u64::from_be_bytes(bytes), 10)
+            }
+            len => (len as u64, 2),
+        };
+
+        let mask: Option<[u8; 4]> = if second & 0x80 != 0 {
+            let b = buf.get(size..size + 4)?;
+            size += 4;
+            Some([b[0], b[1], b[2], b[3]])
+        } else {
+            None
+        };
+
+        Some(Self {
+            fin: first & 0x80 != 0,
+            rsv1: first & 0x40 != 0,
+            opcode: first & 0x0F,
+            mask,
+            len,
+            size,
+        })
+    }
+
+    /// Check if the frame carries (a part of) a data message
+    #[inline]
+    fn is_data(&self) -> bool {
+        matches!(self.opcode, OP_CONTINUATION | OP_TEXT | OP_BINARY)
+    }
+}
+
+/// Compression contexts of a connection
+struct Codec {
+    deflate: Compress,
+    inflate: Decompress,
+    /// Reset the compression context after every outgoing message
+    reset_deflate: bool,
+    /// Reset the decompression context after every incoming message
+    reset_inflate: bool,
+    /// The incoming message is compressed
+    inflating: bool,
+    /// Inflated size of the incoming message
+    inflated: usize,
+}
+
+impl Codec {
+    fn new(role: Role, config: &CompressionConfig, params: Params) -> Self {
+        let (reset_deflate, reset_inflate) = match role {
+            Role::Client => (
+                params.client_no_context_takeover || config.client_no_context_takeover,
+                params.server_no_context_takeover,
+            ),
+            Role::Server => (
+                params.server_no_context_takeover,
+                params.client_no_context_takeover,
+            ),
+        };
+
+        Self {
+            deflate: Compress::new(Compression::new(config.level.min(9)), false),
+            inflate: Decompress::new(false),
+            reset_deflate,
+            reset_inflate,
+            inflating: false,
+            inflated: 0,
+        }
+    }
+
+    /// Inflate an incoming frame
+    ///
+    /// The control frames and the invalid ones are left untouched: `tungstenite` handles them.
+    fn decode(
+        &mut self,
+        header: &FrameHeader,
+        frame: BytesMut,
+        dst: &mut BytesMut,
+        max_message_size: Option<usize>,
+    ) -> io::Result<()> {
+        let compressed: bool = match header.opcode {
+            OP_TEXT | OP_BINARY => {
+                self.inflating = header.rsv1;
+                self.inflated = 0;
+                header.rsv1
+            }
+            OP_CONTINUATION if !header.rsv1 => self.inflating,
+            _ => false,
+        };
+
+        if !compressed {
+            dst.extend_from_slice(&frame);
+            return Ok(());
+        }
+
+        let mut payload: Vec<u8> = frame[header.size..].to_vec();
+        if let Some(mask) = header.mask {
+            apply_mask(&mut payload, mask);
+        }
+        if header.fin {
+            payload.extend_from_slice(&TAIL);
+        }
+
+        // One byte over the limit is enough for `tungstenite` to reject the message
+        let limit: Option<usize> =
+            max_message_size.map(|max| max.saturating_sub(self.inflated).saturating_add(1));
+        let data: Vec<u8> = inflate(&mut self.inflate, &payload, limit)?;
+        self.inflated = self.inflated.saturating_add(data.len());
+
+        if header.fin {
+            self.inflating = false;
+            if self.reset_inflate {
+                self.inflate.reset(false);
+            }
+        }
+
+        write_frame(dst, header, false, &data);
+        Ok(())
+    }
+
+    /// Deflate an outgoing data frame
+    fn encode(
+        &mut self,
+        header: &FrameHeader,
+        frame: BytesMut,
+        dst: &mut BytesMut,
+    ) -> io::Result<()> {
+        if !header.is_data() {
+            dst.extend_from_slice(&frame);
+            return Ok(());
+        }
+
+        let mut payload: Vec<u8> = frame[header.size..].to_vec();
+        if let Some(mask) = header.mask {
+            apply_mask(&mut payload, mask);
+        }
+
+        let mut data: Vec<u8> = deflate(&mut self.deflate, &payload)?;
+        if header.fin {
+            if data.ends_with(&TAIL) {
+                data.truncate(data.len() - TAIL.len());
+            }
+            if self.reset_deflate {
+                self.deflate.reset();
+            }
+        }
+
+        // RSV1 marks the first frame of a compressed message
+        write_frame(dst, header, header.opcode != OP_CONTINUATION, &data);
+        Ok(())
+    }
+}
+
+/// Compress `input`, ending with a sync flush
+fn deflate(compress: &mut Compress, input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output: Vec<u8> = Vec::with_capacity(input.len() / 2 + 64);
+    let start: u64 = compress.total_in();
+
+    loop {
+        let consumed: usize = (compress.total_in() - start) as usize;
+        compress
+            .compress_vec(&input[consumed..], &mut output, FlushCompress::Sync)
+            .map_err(invalid_data)?;
+
+        // Done when all the input has been consumed without filling the output
+        let consumed: usize = (compress.total_in() - start) as usize;
+        if consumed == input.len() && output.len() < output.capacity() {
+            return Ok(output);
+        }
+
+        output.reserve(output.capacity());
+    }
+}
+
+/// Decompress `input`, stopping at `limit` bytes of output
+fn inflate(decompress: &mut Decompress, input: &[u8], limit: Option<usize>) -> io::Result<Vec<u8>> {
+    let capacity: usize = input.len().saturating_mul(2).max(64);
+    let mut output: Vec<u8> = Vec::with_capacity(limit.map_or(capacity, |l| capacity.min(l)));
+    let start: u64 = decompress.total_in();
+
+    loop {
+        let consumed: usize = (decompress.total_in() - start) as usize;
+        let status: Status = decompress
+            .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+            .map_err(invalid_data)?;
+
+        if let Some(limit) = limit {
+            if output.len() >= limit {
+                output.truncate(limit);
+                return Ok(output);
+            }
+        }
+
+        // The final block ends the context: the next message starts a new one
+        if status == Status::StreamEnd {
+            decompress.reset(false);
+            return Ok(output);
+        }
+
+        // Done when all the input has been consumed without filling the output
+        let consumed: usize = (decompress.total_in() - start) as usize;
+        if consumed == input.len() && output.len() < output.capacity() {
+            return Ok(output);
+        }
+
+        output.reserve(output.capacity().max(64));
+    }
+}
+
+/// Write a frame with the header of `original`, `payload` and RSV1 set if `rsv1`
+///
+/// The payload is masked with the same key of the original frame.
+fn write_frame(dst: &mut BytesMut, original: &FrameHeader, rsv1: bool, payload: &[u8]) {
+    dst.reserve(14 + payload.len());
+
+    let fin: u8 = if original.fin { 0x80 } else { 0 };
+    let rsv1: u8 = if rsv1 { 0x40 } else { 0 };
+    dst.put_u8(fin | rsv1 | original.opcode);
+
+    let masked: u8 = if original.mask.is_some() { 0x80 } else { 0 };
+    let len: usize = payload.len();
+    if len < 126 {
+        dst.put_u8(masked | len as u8);
+    } else if len <= u16::MAX as usize {
+        dst.put_u8(masked | 126);
+        dst.put_u16(len as u16);
+    } else {
+        dst.put_u8(masked | 127);
+        dst.put_u64(len as u64);
+    }
+
+    match original.mask {
+        Some(mask) => {
+            dst.put_slice(&mask);
+            let start: usize = dst.len();
+            dst.put_slice(payload);
+            apply_mask(&mut dst[start..], mask);
+        }
+        None => dst.put_slice(payload),
+    }
+}
+
+#[inline]
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::native::WsServer;
+    use crate::{Message, WebSocket};
+
+    #[test]
+    fn test_offer() {
+        assert_eq!(CompressionConfig::default().offer(), "permessage-deflate");
+
+        let config = CompressionConfig {
+            server_no_context_takeover: true,
+            client_no_context_takeover: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.offer(),
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover"
+        );
+    }
+
+    #[test]
+    fn test_accept() {
+        let config = CompressionConfig::default();
+
+        assert_eq!(
+            config.accept(["permessage-deflate; client_max_window_bits"]),
+            Some(String::from("permessage-deflate"))
+        );
+        assert_eq!(
+            config.accept(["x-custom, Permessage-Deflate; server_no_context_takeover"]),
+            Some(String::from(
+                "permessage-deflate; server_no_context_takeover"
+            ))
+        );
+        assert_eq!(
+            config.accept(["permessage-deflate; client_max_window_bits=\"10\""]),
+            Some(String::from("permessage-deflate"))
+        );
+
+        // The first supported offer wins
+        assert_eq!(
+            config.accept([
+                "permessage-deflate; server_max_window_bits=10",
+                "permessage-deflate; client_no_context_takeover"
+            ]),
+            Some(String::from(
+                "permessage-deflate; client_no_context_takeover"
+            ))
+        );
+
+        // Not supported or invalid
+        assert_eq!(config.accept(["x-custom"]), None);
+        assert_eq!(
+            config.accept(["permessage-deflate; server_max_window_bits=10"]),
+            None
+        );
+        assert_eq!(
+            config.accept([
+                "permessage-deflate; server_no_context_takeover; server_no_context_takeover"
+            ]),
+            None
+        );
+        assert_eq!(config.accept(["permessage-deflate; foo"]), None);
+        assert_eq!(config.accept(Vec::<&str>::new()), None);
+
+        // Enforced by the server
+        let config = CompressionConfig {
+            client_no_context_takeover: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.accept(["permessage-deflate"]),
+            Some(String::from(
+                "permessage-deflate; client_no_context_takeover"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_response() {
+        assert_eq!(Params::from_response(["x-custom"]).unwrap(), None);
+        assert_eq!(
+            Params::from_response(["permessage-deflate; server_no_context_takeover"]).unwrap(),
+            Some(Params {
+                server_no_context_takeover: true,
+                client_no_context_takeover: false,
+            })
+        );
+        assert_eq!(
+            Params::from_response(["permessage-deflate; server_max_window_bits=10"]).unwrap(),
+            Some(Params {
+                server_no_context_takeover: false,
+                client_no_context_takeover: false,
+            })
+        );
+
+        // Not offered by the client
+        assert!(Params::from_response(["permessage-deflate; client_max_window_bits=10"]).is_err());
+        assert!(Params::from_response(["permessage-deflate; foo"]).is_err());
+        assert!(Params::from_response(["permessage-deflate", "permessage-deflate"]).is_err());
+    }
+
+    #[test]
+    fn test_response_params() {
+        let head = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nsec-websocket-extensions: permessage-deflate\r\n\r\n";
+        assert!(response_params(head).unwrap().is_some());
+
+        let head = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+        assert!(response_params(head).unwrap().is_none());
+
+        let head =
+            b"HTTP/1.1 403 Forbidden\r\nSec-WebSocket-Extensions: permessage-deflate\r\n\r\n";
+        assert!(response_params(head).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_codec() {
+        let params = Params {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+        };
+        let mut client = Codec::new(Role::Client, &CompressionConfig::default(), params);
+        let mut server = Codec::new(Role::Server, &CompressionConfig::default(), params);
+
+        // Masked text frame, as written by the client
+        let payload: &[u8] = b"hello hello hello hello";
+        let header = FrameHeader {
+            fin: true,
+            rsv1: false,
+            opcode: OP_TEXT,
+            mask: Some([1, 2, 3, 4]),
+            len: payload.len() as u64,
+            size: 6,
+        };
+        let mut frame = BytesMut::new();
+        write_frame(&mut frame, &header, false, payload);
+
+        for _ in 0..2 {
+            let mut compressed = BytesMut::new();
+            client
+                .encode(&header, frame.clone(), &mut compressed)
+                .unwrap();
+            let parsed = FrameHeader::parse(&compressed).unwrap();
+            assert!(parsed.rsv1);
+            assert_eq!(parsed.mask, Some([1, 2, 3, 4]));
+
+            let mut plain = BytesMut::new();
+            server
+                .decode(&parsed, compressed, &mut plain, None)
+                .unwrap();
+            assert_eq!(plain, frame);
+        }
+    }
+
+    #[test]
+    fn test_control_frames_untouched() {
+        let params = Params {
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+        };
+        let mut codec = Codec::new(Role::Server, &CompressionConfig::default(), params);
+
+        // Unmasked ping
+        let frame = BytesMut::from(&[0x89, 0x02, b'h', b'i'][..]);
+        let header = FrameHeader::parse(&frame).unwrap();
+
+        let mut encoded = BytesMut::new();
+        codec.encode(&header, frame.clone(), &mut encoded).unwrap();
+        assert_eq!(encoded, frame);
+
+        let mut decoded = BytesMut::new();
+        codec
+            .decode(&header, frame.clone(), &mut decoded, None)
+            .unwrap();
+        assert_eq!(decoded, frame);
+    }
+
+    #[tokio::test]
+    async fn test_negotiated() {
+        let mut server: WsServer = WsServer::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .compression(CompressionConfig::default());
+        let url = Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap();
+
+        let accept = tokio::spawn(async move {
+            let (mut tx, mut rx, _) = server.next().await.unwrap().unwrap();
+            while let Some(Ok(msg)) = rx.next().await {
+                tx.send(msg).await.unwrap();
+            }
+        });
+
+        let (mut socket, response) = WebSocketBuilder::new()
+            .compression(CompressionConfig::default())
+            .connect_with_response(&url)
+            .await
+            .unwrap();
+        assert_eq!(response.extensions(), vec!["permessage-deflate"]);
+        assert!(socket.is_compressed());
+
+        let large: String = "abc".repeat(100_000);
+        for msg in [
+            Message::text("hello"),
+            Message::text(""),
+            Message::binary(vec![0u8; 70_000]),
+            Message::text(large),
+        ] {
+            socket.send(msg.clone()).await.unwrap();
+            assert_eq!(socket.next().await.unwrap().unwrap(), msg);
+        }
+
+        drop(socket);
+        accept.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_not_offered() {
+        let mut server: WsServer = WsServer::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .compression(CompressionConfig::default());
+        let url = Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap();
+
+        let accept = tokio::spawn(async move {
+            let (mut tx, mut rx, _) = server.next().await.unwrap().unwrap();
+            let msg: Message = rx.next().await.unwrap().unwrap();
+            tx.send(msg).await.unwrap();
+        });
+
+        let (mut socket, response) = WebSocketBuilder::new()
+            .connect_with_response(&url)
+            .await
+            .unwrap();
+        assert!(response.extensions().is_empty());
+
+        socket.send(Message::text("hello")).await.unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::text("hello")
+        );
+
+        accept.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_declined() {
+        let mut server: WsServer = WsServer::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap();
+
+        let accept = tokio::spawn(async move {
+            let (mut tx, mut rx, _) = server.next().await.unwrap().unwrap();
+            let msg: Message = rx.next().await.unwrap().unwrap();
+            tx.send(msg).await.unwrap();
+        });
+
+        let mut socket: WebSocket = WebSocketBuilder::new()
+            .compression(CompressionConfig::default())
+            .connect(&url)
+            .await
+            .unwrap();
+        assert!(!socket.is_compressed());
+
+        socket.send(Message::text("hello")).await.unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::text("hello")
+        );
+
+        accept.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_inflated_limit() {
+        let config = WebSocketConfig {
+            max_message_size: Some(1024),
+            ..Default::default()
+        };
+        let mut server: WsServer = WsServer::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .config(config)
+            .compression(CompressionConfig::default());
+        let url = Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap();
+
+        let accept = tokio::spawn(async move {
+            let (_tx, mut rx, _) = server.next().await.unwrap().unwrap();
+            rx.next().await.unwrap()
+        });
+
+        let mut socket: WebSocket = WebSocketBuilder::new()
+            .compression(CompressionConfig::default())
+            .connect(&url)
+            .await
+            .unwrap();
+
+        // Compressed well below the limit
+        socket.send(Message::binary(vec![0u8; 4096])).await.unwrap();
+
+        let res = accept.await.unwrap();
+        assert!(matches!(res, Err(crate::Error::MessageTooLarge { .. })));
+    }
+}
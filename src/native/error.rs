@@ -2,75 +2,486 @@
 // Distributed under the MIT software license
 
 use core::fmt;
+use std::io;
 
+use tokio_tungstenite::tungstenite::error::{CapacityError, ProtocolError};
 use tokio_tungstenite::tungstenite::Error as WsError;
-use url::ParseError;
+use url::{ParseError, Url};
 
+use super::http_proxy;
 #[cfg(feature = "tor")]
 use super::tor;
+use crate::error::{ErrorKind, ProtocolViolation};
+use crate::limits::Limit;
+use crate::message::CloseFrame;
+use crate::response::HandshakeRejection;
+use crate::timeouts::Phase;
 
+/// Error of an established connection
+///
+/// Yielded by the [`Stream`](futures_util::Stream) and the [`Sink`](futures_util::Sink) of the sockets,
+/// and by the adapters built on them (ex. the codecs and the correlator).
+/// The failures to connect are reported as [`ConnectError`].
 #[derive(Debug)]
 pub enum Error {
-    /// Ws error
-    Ws(WsError),
+    /// Ws error (I/O, TLS, etc.)
+    Ws(Box<WsError>),
+    /// The connection has been closed by the peer, with this close frame
+    ///
+    /// Returned when writing to a connection closed by the peer.
+    ClosedByPeer(CloseFrame),
+    /// WebSocket protocol violation
+    Protocol(ProtocolViolation),
+    /// HTTP/2 error
+    #[cfg(feature = "h2")]
+    H2(h2::Error),
+    /// The [`Framer`](crate::Framer) of the raw stream failed
+    Framing(Box<dyn std::error::Error + Send + Sync>),
+    /// The `POST` request carrying a message over the HTTP fallback failed
+    ///
+    /// Check [`WebSocketBuilder::http_fallback`](crate::WebSocketBuilder::http_fallback).
+    FallbackPost(Box<ConnectError>),
+    /// Not connected
+    NotConnected,
+    /// The closing handshake wasn't completed within the close timeout
+    CloseTimeout,
+    /// No messages received within the keepalive timeout
+    KeepAliveTimeout,
+    /// No frames received within the read-idle timeout
+    IdleTimeout,
+    /// The send queue is full
+    QueueFull,
+    /// Incoming message too large
+    MessageTooLarge {
+        /// Size of the message (may be partial)
+        size: usize,
+        /// Max allowed size
+        max_size: usize,
+    },
+    /// Incoming limit exceeded
+    LimitExceeded(Limit),
+    /// Text message with invalid UTF-8
+    InvalidUtf8,
+    /// The request id wasn't found by the correlator
+    MissingRequestId,
+    /// A request with the same id is already waiting for its response
+    DuplicateRequestId(String),
+    /// No response received within the request timeout
+    RequestTimeout,
+    /// JSON error
+    #[cfg(feature = "json")]
+    Json(serde_json::Error),
+    /// Codec error
+    #[cfg(any(feature = "cbor", feature = "msgpack"))]
+    Codec(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ws(e) => write!(f, "{e}"),
+            Self::ClosedByPeer(frame) => write!(
+                f,
+                "closed by peer: code={}, reason={}",
+                frame.code, frame.reason
+            ),
+            Self::Protocol(violation) => write!(f, "protocol violation: {violation}"),
+            #[cfg(feature = "h2")]
+            Self::H2(e) => write!(f, "h2: {e}"),
+            Self::Framing(e) => write!(f, "framing: {e}"),
+            Self::FallbackPost(e) => write!(f, "fallback post: {e}"),
+            Self::NotConnected => write!(f, "not connected"),
+            Self::CloseTimeout => write!(f, "closing handshake timeout"),
+            Self::KeepAliveTimeout => write!(f, "keepalive timeout"),
+            Self::IdleTimeout => write!(f, "idle timeout"),
+            Self::QueueFull => write!(f, "send queue full"),
+            Self::MessageTooLarge { size, max_size } => {
+                write!(f, "message too large: {size} > {max_size}")
+            }
+            Self::LimitExceeded(limit) => write!(f, "{limit} limit exceeded"),
+            Self::InvalidUtf8 => write!(f, "invalid UTF-8 in text message"),
+            Self::MissingRequestId => write!(f, "missing request id"),
+            Self::DuplicateRequestId(id) => write!(f, "duplicate request id: {id}"),
+            Self::RequestTimeout => write!(f, "request timeout"),
+            #[cfg(feature = "json")]
+            Self::Json(e) => write!(f, "{e}"),
+            #[cfg(any(feature = "cbor", feature = "msgpack"))]
+            Self::Codec(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<WsError> for Error {
+    // `Utf8` is a unit variant in tungstenite 0.26.1 and a tuple one in later 0.26 releases
+    #[allow(clippy::unneeded_struct_pattern)]
+    fn from(e: WsError) -> Self {
+        match e {
+            WsError::Capacity(CapacityError::MessageTooLong { size, max_size }) => {
+                Self::MessageTooLarge { size, max_size }
+            }
+            WsError::Utf8 { .. } => Self::InvalidUtf8,
+            WsError::Protocol(e) => Self::Protocol(e.into()),
+            e => Self::Ws(Box::new(e)),
+        }
+    }
+}
+
+impl From<ProtocolError> for ProtocolViolation {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::NonZeroReservedBits => Self::NonZeroReservedBits,
+            ProtocolError::UnmaskedFrameFromClient | ProtocolError::MaskedFrameFromServer => {
+                Self::InvalidMasking
+            }
+            ProtocolError::UnknownControlFrameType(opcode)
+            | ProtocolError::UnknownDataFrameType(opcode)
+            | ProtocolError::InvalidOpcode(opcode) => Self::UnknownOpcode(opcode),
+            ProtocolError::FragmentedControlFrame | ProtocolError::ControlFrameTooBig => {
+                Self::InvalidControlFrame
+            }
+            ProtocolError::UnexpectedContinueFrame | ProtocolError::ExpectedFragment(..) => {
+                Self::InvalidFragmentation
+            }
+            ProtocolError::InvalidCloseSequence => Self::InvalidCloseFrame,
+            ProtocolError::SendAfterClosing | ProtocolError::ReceivedAfterClosing => {
+                Self::DataAfterClose
+            }
+            ProtocolError::ResetWithoutClosingHandshake => Self::ResetWithoutClosingHandshake,
+            // The remaining ones are about the HTTP handshake
+            e => Self::Handshake(e.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+#[cfg(feature = "h2")]
+impl From<h2::Error> for Error {
+    fn from(e: h2::Error) -> Self {
+        Self::H2(e)
+    }
+}
+
+impl Error {
+    /// Get the error kind
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Ws(e) => ws_kind(e),
+            Self::ClosedByPeer(..) => ErrorKind::ClosedByPeer,
+            Self::Protocol(violation) => violation.kind(),
+            #[cfg(feature = "h2")]
+            Self::H2(e) => e.get_io().map_or(ErrorKind::Protocol, io_kind),
+            Self::Framing(..) => ErrorKind::InvalidData,
+            Self::FallbackPost(e) => e.kind(),
+            Self::CloseTimeout
+            | Self::KeepAliveTimeout
+            | Self::IdleTimeout
+            | Self::RequestTimeout => ErrorKind::Timeout,
+            Self::NotConnected => ErrorKind::ConnectionLost,
+            Self::QueueFull => ErrorKind::Other,
+            Self::MessageTooLarge { .. } | Self::LimitExceeded(..) => ErrorKind::Limit,
+            Self::InvalidUtf8 => ErrorKind::Protocol,
+            Self::MissingRequestId | Self::DuplicateRequestId(..) => ErrorKind::InvalidData,
+            #[cfg(feature = "json")]
+            Self::Json(..) => ErrorKind::InvalidData,
+            #[cfg(any(feature = "cbor", feature = "msgpack"))]
+            Self::Codec(..) => ErrorKind::InvalidData,
+        }
+    }
+
+    /// Check if retrying (i.e. reconnecting) may succeed
+    ///
+    /// Check [`ErrorKind::is_retryable`].
+    #[inline]
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+
+    /// Check if retrying will fail the same way
+    #[inline]
+    pub fn is_permanent(&self) -> bool {
+        self.kind().is_permanent()
+    }
+
+    /// New [`Error::Framing`], to be returned by a [`Framer`](crate::Framer)
+    #[inline]
+    pub fn framing<E>(e: E) -> Self
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Self::Framing(e.into())
+    }
+
+    #[inline]
+    pub(crate) fn not_connected() -> Self {
+        Self::NotConnected
+    }
+
+    #[inline]
+    #[cfg(any(feature = "cbor", feature = "msgpack"))]
+    pub(crate) fn codec<E>(e: E) -> Self
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        Self::Codec(Box::new(e))
+    }
+}
+
+/// Error establishing a connection
+///
+/// Returned by the connect methods (ex. [`WebSocketBuilder::connect`](crate::WebSocketBuilder::connect))
+/// and by the server handshakes: only the failures of the connection phases are here,
+/// the established connections yield [`Error`].
+#[derive(Debug)]
+pub enum ConnectError {
+    /// Ws error (I/O, TLS, URL, etc.)
+    Ws(Box<WsError>),
+    /// WebSocket protocol violation in the opening handshake
+    Protocol(ProtocolViolation),
     /// Socks error
     #[cfg(feature = "socks")]
     Socks(tokio_socks::Error),
     /// Tor error
     #[cfg(feature = "tor")]
     Tor(tor::Error),
+    /// `.onion` host with [`ConnectionMode::Auto`](crate::ConnectionMode::Auto), but the `tor` feature isn't enabled
+    TorUnavailable,
+    /// Invalid onion address
+    #[cfg(feature = "socks")]
+    InvalidOnion(String),
+    /// HTTP proxy error
+    HttpProxy(http_proxy::Error),
+    /// HTTP/2 error
+    #[cfg(feature = "h2")]
+    H2(h2::Error),
+    /// The server doesn't support WebSockets over HTTP/2 (RFC 8441)
+    #[cfg(feature = "h2")]
+    H2Unsupported,
+    /// WebTransport (HTTP/3) error
+    #[cfg(feature = "h3")]
+    H3(Box<dyn std::error::Error + Send + Sync>),
     /// Url parse error
     Url(ParseError),
-    /// Timeout
-    Timeout,
+    /// No URL to connect to
+    NoUrl,
+    /// The server rejected the HTTP handshake
+    HandshakeRejected(Box<HandshakeRejection>),
+    /// The redirects lead back to an already visited URL
+    RedirectLoop(Box<Url>),
+    /// The [`AuthProvider`](crate::AuthProvider) failed
+    Auth(Box<dyn std::error::Error + Send + Sync>),
+    /// The host couldn't be resolved
+    Dns(io::Error),
+    /// Timeout of a connection phase
+    Timeout(Phase),
+    /// The connection attempt has been cancelled
+    Cancelled,
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for ConnectError {}
 
-impl fmt::Display for Error {
+impl fmt::Display for ConnectError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Ws(e) => write!(f, "{e}"),
+            Self::Protocol(violation) => write!(f, "protocol violation: {violation}"),
             #[cfg(feature = "socks")]
             Self::Socks(e) => write!(f, "{e}"),
             #[cfg(feature = "tor")]
             Self::Tor(e) => write!(f, "{e}"),
+            Self::TorUnavailable => write!(f, "tor not available: enable the `tor` feature"),
+            #[cfg(feature = "socks")]
+            Self::InvalidOnion(host) => write!(f, "invalid onion address: {host}"),
+            Self::HttpProxy(e) => write!(f, "{e}"),
+            #[cfg(feature = "h2")]
+            Self::H2(e) => write!(f, "h2: {e}"),
+            #[cfg(feature = "h2")]
+            Self::H2Unsupported => write!(f, "websockets over HTTP/2 not supported by the server"),
+            #[cfg(feature = "h3")]
+            Self::H3(e) => write!(f, "h3: {e}"),
             Self::Url(e) => write!(f, "{e}"),
-            Self::Timeout => write!(f, "timeout"),
+            Self::NoUrl => write!(f, "no url to connect to"),
+            Self::HandshakeRejected(rejection) => {
+                write!(f, "handshake rejected: status={}", rejection.status)
+            }
+            Self::RedirectLoop(url) => write!(f, "redirect loop: {url}"),
+            Self::Auth(e) => write!(f, "auth: {e}"),
+            Self::Dns(e) => write!(f, "dns: {e}"),
+            Self::Timeout(phase) => write!(f, "{phase} timeout"),
+            Self::Cancelled => write!(f, "cancelled"),
         }
     }
 }
 
-impl From<WsError> for Error {
+impl From<WsError> for ConnectError {
     fn from(e: WsError) -> Self {
-        Self::Ws(e)
+        match e {
+            WsError::Protocol(e) => Self::Protocol(e.into()),
+            WsError::Http(response) => {
+                Self::HandshakeRejected(Box::new(HandshakeRejection::from(&response)))
+            }
+            e => Self::Ws(Box::new(e)),
+        }
     }
 }
 
 #[cfg(feature = "socks")]
-impl From<tokio_socks::Error> for Error {
+impl From<tokio_socks::Error> for ConnectError {
     fn from(e: tokio_socks::Error) -> Self {
         Self::Socks(e)
     }
 }
 
 #[cfg(feature = "tor")]
-impl From<tor::Error> for Error {
+impl From<tor::Error> for ConnectError {
     fn from(e: tor::Error) -> Self {
         Self::Tor(e)
     }
 }
 
-impl Error {
+#[cfg(feature = "h2")]
+impl From<h2::Error> for ConnectError {
+    fn from(e: h2::Error) -> Self {
+        Self::H2(e)
+    }
+}
+
+impl From<http_proxy::Error> for ConnectError {
+    fn from(e: http_proxy::Error) -> Self {
+        Self::HttpProxy(e)
+    }
+}
+
+impl ConnectError {
+    /// Get the error kind
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Ws(e) => ws_kind(e),
+            Self::Protocol(violation) => violation.kind(),
+            #[cfg(feature = "socks")]
+            Self::Socks(..) => ErrorKind::Proxy,
+            #[cfg(feature = "tor")]
+            Self::Tor(..) => ErrorKind::Proxy,
+            Self::TorUnavailable => ErrorKind::InvalidInput,
+            #[cfg(feature = "socks")]
+            Self::InvalidOnion(..) => ErrorKind::InvalidInput,
+            Self::HttpProxy(http_proxy::Error::Io(e)) => io_kind(e),
+            Self::HttpProxy(..) => ErrorKind::Proxy,
+            #[cfg(feature = "h2")]
+            Self::H2(e) => e.get_io().map_or(ErrorKind::Protocol, io_kind),
+            #[cfg(feature = "h2")]
+            Self::H2Unsupported => ErrorKind::Protocol,
+            #[cfg(feature = "h3")]
+            Self::H3(..) => ErrorKind::Other,
+            Self::Url(..) | Self::NoUrl => ErrorKind::InvalidInput,
+            Self::HandshakeRejected(rejection) => ErrorKind::HandshakeRejected(rejection.status),
+            Self::RedirectLoop(..) => ErrorKind::Protocol,
+            Self::Auth(..) => ErrorKind::Other,
+            Self::Dns(..) => ErrorKind::Dns,
+            Self::Timeout(..) => ErrorKind::Timeout,
+            Self::Cancelled => ErrorKind::Cancelled,
+        }
+    }
+
+    /// Check if retrying (i.e. reconnecting) may succeed
+    ///
+    /// Check [`ErrorKind::is_retryable`].
+    #[inline]
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+
+    /// Check if retrying will fail the same way
+    #[inline]
+    pub fn is_permanent(&self) -> bool {
+        self.kind().is_permanent()
+    }
+
+    /// New [`ConnectError::Auth`], to be returned by an [`AuthProvider`](crate::AuthProvider)
+    #[inline]
+    pub fn auth<E>(e: E) -> Self
+    where
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        Self::Auth(e.into())
+    }
+
     #[inline]
-    #[cfg(any(feature = "socks", feature = "tor"))]
     pub(super) fn empty_host() -> Self {
         Self::Url(ParseError::EmptyHost)
     }
 
     #[inline]
-    #[cfg(any(feature = "socks", feature = "tor"))]
     pub(super) fn invalid_port() -> Self {
         Self::Url(ParseError::InvalidPort)
     }
+
+    #[inline]
+    pub(super) fn unresolved_host() -> Self {
+        Self::Dns(io::Error::new(io::ErrorKind::NotFound, "host not resolved"))
+    }
+}
+
+// Check `impl From<WsError> for Error` and `impl From<WsError> for ConnectError`
+#[allow(clippy::unneeded_struct_pattern)]
+fn ws_kind(e: &WsError) -> ErrorKind {
+    match e {
+        WsError::ConnectionClosed | WsError::AlreadyClosed => ErrorKind::ClosedByPeer,
+        WsError::Io(e) => io_kind(e),
+        #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+        WsError::Tls(..) => ErrorKind::Tls,
+        WsError::Capacity(..) => ErrorKind::Limit,
+        WsError::Protocol(..) | WsError::Utf8 { .. } | WsError::AttackAttempt => {
+            ErrorKind::Protocol
+        }
+        WsError::Url(..) | WsError::HttpFormat(..) => ErrorKind::InvalidInput,
+        WsError::Http(response) => ErrorKind::HandshakeRejected(response.status().as_u16()),
+        _ => ErrorKind::Other,
+    }
+}
+
+/// `ENETUNREACH` and `EHOSTUNREACH`
+///
+/// `io::ErrorKind::NetworkUnreachable` and `io::ErrorKind::HostUnreachable` require rust 1.83.
+#[cfg(target_os = "linux")]
+const UNREACHABLE: [i32; 2] = [101, 113];
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+const UNREACHABLE: [i32; 2] = [51, 65];
+#[cfg(windows)]
+const UNREACHABLE: [i32; 2] = [10051, 10065];
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    windows
+)))]
+const UNREACHABLE: [i32; 0] = [];
+
+fn io_kind(e: &io::Error) -> ErrorKind {
+    if e.raw_os_error()
+        .is_some_and(|code| UNREACHABLE.contains(&code))
+    {
+        return ErrorKind::Unreachable;
+    }
+
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => ErrorKind::Refused,
+        io::ErrorKind::AddrNotAvailable | io::ErrorKind::NotFound => ErrorKind::Unreachable,
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::NotConnected => ErrorKind::ConnectionLost,
+        io::ErrorKind::TimedOut => ErrorKind::Timeout,
+        io::ErrorKind::InvalidData => ErrorKind::Protocol,
+        _ => ErrorKind::Other,
+    }
 }
@@ -0,0 +1,1246 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! HTTP fallback transport (Server-Sent Events + POST)
+//!
+//! Emulates the WebSocket when the upgrades are blocked by middleboxes:
+//! the messages of the server are received as Server-Sent Events of a `GET` request
+//! and each message of the client is sent as a `POST` request, to the same URL (with `http` or `https` scheme).
+//!
+//! The client side is enabled with [`WebSocketBuilder::http_fallback`](crate::WebSocketBuilder::http_fallback),
+//! the server side is implemented by [`FallbackSessions`], to be mounted on any HTTP server.
+//!
+//! # Protocol
+//!
+//! The requests of a connection share a random `sid` query parameter (the session ID).
+//!
+//! The client opens the session with a `GET` request with `Accept: text/event-stream`.
+//! The server answers with `200 OK` and `Content-Type: text/event-stream`, then streams the events:
+//!
+//! | Message | Event                                                                |
+//! |---------|----------------------------------------------------------------------|
+//! | Text    | `data` lines, without event type                                     |
+//! | Binary  | `binary` event, with the base64 encoded payload as `data`            |
+//! | Close   | `close` event, with `<code> <reason>` as `data` (empty if no frame)  |
+//!
+//! The comments (lines starting with `:`) are ignored: the server may send them as heartbeats.
+//! The server ends the stream after the `close` event.
+//!
+//! Each message of the client is the body of a `POST` request, with the content type:
+//!
+//! | Message | Content type                                                   |
+//! |---------|----------------------------------------------------------------|
+//! | Text    | `text/plain; charset=utf-8`                                    |
+//! | Binary  | `application/octet-stream`                                     |
+//! | Close   | `application/websocket-close`, with the close frame payload    |
+//!
+//! The close frame payload is the same of RFC 6455: the code (2 bytes, big-endian) followed by the reason, or empty.
+//! The server answers with `204 No Content`, or `404 Not Found` if the session doesn't exist.
+//!
+//! The pings and the pongs have no equivalent and aren't exchanged.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::{fmt, io};
+
+use bytes::{Buf, BytesMut};
+use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures_channel::oneshot;
+use futures_util::future::BoxFuture;
+use futures_util::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::client::{generate_key, Request};
+use tokio_tungstenite::tungstenite::http::header::{
+    CONNECTION, HOST, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::MaybeTlsStream;
+use url::{form_urlencoded, Position, Url};
+
+use super::http_proxy::base64_encode;
+use super::{build_request, open, tls, ws_config, ConnectError, Deadline, Error, IoStream};
+use crate::error::{ErrorKind, ProtocolViolation};
+use crate::message::{Bytes, CloseCode, CloseFrame, Utf8Bytes};
+use crate::socket::{InnerWebSocket, WebSocket};
+use crate::{HandshakeRejection, HandshakeResponse, Message, Phase, WebSocketBuilder};
+
+/// Max size of the response head
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Size of the read chunks
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Queued outgoing messages that trigger a flush before accepting a new one
+const MAX_QUEUED_MESSAGES: usize = 64;
+
+/// Content type of the close frames sent by the client
+const CLOSE_CONTENT_TYPE: &str = "application/websocket-close";
+
+/// Check if the WebSocket connection failed in a way that suggests a middlebox blocking the upgrade
+///
+/// The upgrade is blocked if the request is answered as a plain HTTP request (`200`),
+/// with `426 Upgrade Required` or `501 Not Implemented`,
+/// or if the connection is dropped or the response is garbled during the handshake.
+/// The other rejections (ex. `401`, `403` or `404`) come from the server and are returned.
+pub(crate) fn upgrade_blocked(e: &ConnectError) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::HandshakeRejected(200 | 426 | 501)
+            | ErrorKind::Protocol
+            | ErrorKind::ConnectionLost
+    )
+}
+
+/// Open the event stream of the fallback transport
+pub(super) async fn connect(
+    url: &Url,
+    builder: &WebSocketBuilder,
+    deadline: &Deadline,
+) -> Result<(WebSocket, HandshakeResponse), ConnectError> {
+    let mut url: Url = url.clone();
+    url.query_pairs_mut().append_pair("sid", &generate_key());
+
+    let (transport, peer_addr, deadline) = open(&url, builder, deadline.clone()).await?;
+    let mut conn: MaybeTlsStream<Box<dyn IoStream>> = deadline
+        .run(Phase::Tls, tls(&url, transport.boxed(), builder))
+        .await??;
+
+    let head: Vec<u8> = request_head(
+        "GET",
+        &url,
+        builder,
+        &[
+            ("Accept", "text/event-stream"),
+            ("Cache-Control", "no-cache"),
+        ],
+    )
+    .await?;
+
+    let (response, body) = Box::pin(deadline.run(Phase::Handshake, async {
+        conn.write_all(&head).await?;
+        conn.flush().await?;
+        read_response(&mut conn).await
+    }))
+    .await?
+    .map_err(WsError::Io)?;
+
+    if response.status != 200 {
+        return Err(ConnectError::HandshakeRejected(Box::new(
+            HandshakeRejection {
+                status: response.status,
+                headers: response.headers,
+                body: Vec::new(),
+            },
+        )));
+    }
+
+    if !response
+        .header("content-type")
+        .is_some_and(|t| t.starts_with("text/event-stream"))
+    {
+        return Err(ConnectError::Protocol(ProtocolViolation::Handshake(
+            String::from("the fallback response isn't an event stream"),
+        )));
+    }
+
+    let chunked: bool = response
+        .header("transfer-encoding")
+        .is_some_and(|t| t.to_ascii_lowercase().contains("chunked"));
+
+    let mut events: EventStream = EventStream {
+        io: Box::new(conn),
+        chunked,
+        chunk_remaining: None,
+        raw: body,
+        decoded: BytesMut::new(),
+        event: Vec::new(),
+        data: None,
+        max_size: ws_config(builder).max_message_size,
+        eof: false,
+    };
+    events
+        .decode()
+        .map_err(|e| ConnectError::Protocol(ProtocolViolation::Handshake(e.to_string())))?;
+
+    let socket: FallbackSocket = FallbackSocket {
+        url,
+        builder: builder.clone(),
+        events,
+        queue: VecDeque::new(),
+        sending: None,
+    };
+
+    Ok((
+        WebSocket::new(InnerWebSocket::Fallback(socket), None).with_peer_addr(peer_addr),
+        response,
+    ))
+}
+
+/// WebSocket emulated with Server-Sent Events + POST
+pub(crate) struct FallbackSocket {
+    /// URL with the `sid` query parameter
+    url: Url,
+    builder: WebSocketBuilder,
+    events: EventStream,
+    queue: VecDeque<Message>,
+    /// `POST` request in progress
+    sending: Option<BoxFuture<'static, Result<(), ConnectError>>>,
+}
+
+impl Sink<Message> for FallbackSocket {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.queue.len() >= MAX_QUEUED_MESSAGES {
+            ready!(self.poll_flush(cx))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        match item {
+            Message::Text(..) | Message::Binary(..) | Message::Close(..) => {
+                self.get_mut().queue.push_back(item)
+            }
+            // No equivalent over HTTP
+            Message::Ping(..) | Message::Pong(..) => {}
+        }
+
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(sending) = &mut this.sending {
+                let res = ready!(sending.as_mut().poll(cx));
+                this.sending = None;
+                res.map_err(|e| Error::FallbackPost(Box::new(e)))?;
+            }
+
+            match this.queue.pop_front() {
+                Some(message) => {
+                    this.sending = Some(Box::pin(post(
+                        this.url.clone(),
+                        this.builder.clone(),
+                        message,
+                    )));
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // The event stream is closed on drop
+        self.poll_flush(cx)
+    }
+}
+
+impl Stream for FallbackSocket {
+    type Item = Result<Message, Error>;
+
+    #[inline]
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().events.poll_event(cx)
+    }
+}
+
+/// Send a message with a `POST` request, on a new connection
+async fn post(url: Url, builder: WebSocketBuilder, message: Message) -> Result<(), ConnectError> {
+    let (content_type, body): (&str, Bytes) = match message {
+        Message::Text(text) => ("text/plain; charset=utf-8", Bytes::from(text.to_string())),
+        Message::Binary(data) => ("application/octet-stream", data),
+        Message::Close(frame) => (CLOSE_CONTENT_TYPE, close_payload(frame.as_ref())),
+        Message::Ping(..) | Message::Pong(..) => return Ok(()),
+    };
+    let content_length: String = body.len().to_string();
+
+    let (transport, _, deadline) = open(&url, &builder, Deadline::from(&builder)).await?;
+    let mut conn: MaybeTlsStream<Box<dyn IoStream>> = deadline
+        .run(Phase::Tls, tls(&url, transport.boxed(), &builder))
+        .await??;
+
+    let head: Vec<u8> = request_head(
+        "POST",
+        &url,
+        &builder,
+        &[
+            ("Content-Type", content_type),
+            ("Content-Length", &content_length),
+        ],
+    )
+    .await?;
+
+    let (response, _) = deadline
+        .run(Phase::Handshake, async {
+            conn.write_all(&head).await?;
+            conn.write_all(&body).await?;
+            conn.flush().await?;
+            read_response(&mut conn).await
+        })
+        .await?
+        .map_err(WsError::Io)?;
+
+    if !(200..300).contains(&response.status) {
+        return Err(ConnectError::HandshakeRejected(Box::new(
+            HandshakeRejection {
+                status: response.status,
+                headers: response.headers,
+                body: Vec::new(),
+            },
+        )));
+    }
+
+    Ok(())
+}
+
+/// Build the head of an HTTP/1.1 request, with the headers of the WebSocket handshake request
+async fn request_head(
+    method: &str,
+    url: &Url,
+    builder: &WebSocketBuilder,
+    extra: &[(&str, &str)],
+) -> Result<Vec<u8>, ConnectError> {
+    let request: Request = build_request(url, builder, false).await?;
+
+    let host: &str = url.host_str().ok_or_else(ConnectError::empty_host)?;
+    let target: &str = &url[Position::BeforePath..Position::AfterQuery];
+
+    let mut head: Vec<u8> = format!("{method} {target} HTTP/1.1\r\n").into_bytes();
+    match url.port() {
+        Some(port) => head.extend_from_slice(format!("Host: {host}:{port}\r\n").as_bytes()),
+        None => head.extend_from_slice(format!("Host: {host}\r\n").as_bytes()),
+    }
+    head.extend_from_slice(b"Connection: close\r\n");
+
+    for (name, value) in extra.iter() {
+        head.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+    }
+
+    for (name, value) in request.headers().iter() {
+        // WebSocket handshake headers
+        if [
+            HOST,
+            CONNECTION,
+            UPGRADE,
+            SEC_WEBSOCKET_KEY,
+            SEC_WEBSOCKET_VERSION,
+        ]
+        .contains(name)
+        {
+            continue;
+        }
+
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+
+    head.extend_from_slice(b"\r\n");
+    Ok(head)
+}
+
+/// Read the response head
+///
+/// Returns also the body bytes already read.
+async fn read_response<S>(conn: &mut S) -> io::Result<(HandshakeResponse, BytesMut)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf: BytesMut = BytesMut::new();
+    let mut chunk = [0u8; 1024];
+
+    let head: BytesMut = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head: BytesMut = buf.split_to(pos + 4);
+            break head;
+        }
+
+        if buf.len() >= MAX_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response head too large",
+            ));
+        }
+
+        let n: usize = conn.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid response head");
+
+    let head: &str = std::str::from_utf8(&head).map_err(|_| invalid())?;
+    let mut lines = head.split("\r\n");
+
+    // Parse status line (ex. `HTTP/1.1 200 OK`)
+    let mut parts = lines.next().ok_or_else(invalid)?.split_whitespace();
+    match parts.next() {
+        Some(version) if version.starts_with("HTTP/1.") => {}
+        _ => return Err(invalid()),
+    }
+    let status: u16 = parts
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)?;
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    Ok((HandshakeResponse { status, headers }, buf))
+}
+
+/// Sessions of the fallback transport, for the server side
+///
+/// Mount it on the HTTP server that serves the WebSocket endpoint, at the same path:
+///
+/// - a `GET` request with `Accept: text/event-stream` opens a session with [`FallbackSessions::open`],
+///   that returns the [`WebSocket`] of the connection and the [`EventBody`] of the response;
+/// - a `POST` request delivers its message with [`FallbackSessions::post`].
+///
+/// The session ID is the `sid` query parameter, check [`FallbackSessions::session_id`].
+/// The session is removed when its socket is dropped.
+///
+/// The handle is cheap to clone: all the clones refer to the same sessions.
+#[derive(Clone, Default)]
+pub struct FallbackSessions {
+    sessions: Arc<Mutex<HashMap<String, UnboundedSender<Message>>>>,
+}
+
+impl fmt::Debug for FallbackSessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FallbackSessions")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl FallbackSessions {
+    /// New empty sessions
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the session ID from the query string of a request URL
+    pub fn session_id(query: &str) -> Option<String> {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "sid")
+            .map(|(_, sid)| sid.into_owned())
+            .filter(|sid| !sid.is_empty())
+    }
+
+    /// Open a session, for a `GET` request
+    ///
+    /// Returns the socket of the session and the body of the `200 OK` response,
+    /// to be sent with `Content-Type: text/event-stream` and `Cache-Control: no-cache`.
+    ///
+    /// Returns `None` if the session already exists.
+    pub fn open(&self, sid: &str) -> Option<(WebSocket, EventBody)> {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+        let (events_tx, events_rx) = mpsc::unbounded();
+        let (dropped_tx, dropped_rx) = oneshot::channel();
+
+        {
+            let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+            if sessions.contains_key(sid) {
+                return None;
+            }
+            sessions.insert(sid.to_string(), incoming_tx);
+        }
+
+        let socket: FallbackServerSocket = FallbackServerSocket {
+            sid: sid.to_string(),
+            sessions: self.clone(),
+            incoming: incoming_rx,
+            events: Some(events_tx),
+            body_dropped: dropped_rx,
+            closed: false,
+        };
+        let body: EventBody = EventBody {
+            events: events_rx,
+            _dropped: dropped_tx,
+        };
+
+        Some((
+            WebSocket::new(InnerWebSocket::FallbackServer(socket), None),
+            body,
+        ))
+    }
+
+    /// Deliver the body of a `POST` request to its session
+    ///
+    /// Returns the status code of the response:
+    /// `204` if delivered, `400` if the body isn't a valid message or `404` if the session doesn't exist.
+    pub fn post(&self, sid: &str, content_type: Option<&str>, body: Bytes) -> u16 {
+        let content_type: &str = content_type.unwrap_or_default();
+        let message: Message = if content_type.starts_with("text/plain") {
+            match String::from_utf8(body.to_vec()) {
+                Ok(text) => Message::Text(Utf8Bytes::from(text)),
+                Err(..) => return 400,
+            }
+        } else if content_type.starts_with(CLOSE_CONTENT_TYPE) {
+            match parse_close_payload(&body) {
+                Ok(frame) => Message::Close(frame),
+                Err(..) => return 400,
+            }
+        } else {
+            Message::Binary(body)
+        };
+
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        match sessions.get(sid) {
+            Some(session) => match session.unbounded_send(message) {
+                Ok(()) => 204,
+                // The socket has been dropped
+                Err(..) => {
+                    sessions.remove(sid);
+                    404
+                }
+            },
+            None => 404,
+        }
+    }
+
+    /// Number of open sessions
+    pub fn len(&self) -> usize {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .len()
+    }
+
+    /// Check if there are no open sessions
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn remove(&self, sid: &str) {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(sid);
+    }
+}
+
+/// Body of the event stream response of a [`FallbackSessions`] session
+///
+/// A [`Stream`] of the encoded events, that ends after the `close` event or when the socket is dropped.
+/// Dropping it (ex. when the client disconnects) ends the stream of the socket.
+pub struct EventBody {
+    events: UnboundedReceiver<Bytes>,
+    _dropped: oneshot::Sender<()>,
+}
+
+impl fmt::Debug for EventBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventBody").finish_non_exhaustive()
+    }
+}
+
+impl Stream for EventBody {
+    type Item = Bytes;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+/// Server side of a fallback connection
+pub(crate) struct FallbackServerSocket {
+    sid: String,
+    sessions: FallbackSessions,
+    /// Messages of the `POST` requests
+    incoming: UnboundedReceiver<Message>,
+    /// Encoded events, `None` after the close event
+    events: Option<UnboundedSender<Bytes>>,
+    body_dropped: oneshot::Receiver<()>,
+    /// The close frame of the peer has been received
+    closed: bool,
+}
+
+impl Drop for FallbackServerSocket {
+    fn drop(&mut self) {
+        self.sessions.remove(&self.sid);
+    }
+}
+
+impl FallbackServerSocket {
+    fn send_event(&mut self, event: String) -> Result<(), Error> {
+        let events: &UnboundedSender<Bytes> = self
+            .events
+            .as_ref()
+            .ok_or_else(|| Error::from(WsError::AlreadyClosed))?;
+        events
+            .unbounded_send(Bytes::from(event))
+            .map_err(|_| Error::from(WsError::ConnectionClosed))
+    }
+}
+
+impl Sink<Message> for FallbackServerSocket {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match &self.events {
+            Some(..) => Poll::Ready(Ok(())),
+            None => Poll::Ready(Err(Error::from(WsError::AlreadyClosed))),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        match item {
+            Message::Text(text) => this.send_event(text_event(&text)),
+            Message::Binary(data) => {
+                this.send_event(format!("event: binary\ndata: {}\n\n", base64_encode(&data)))
+            }
+            // Heartbeat
+            Message::Ping(..) => this.send_event(String::from(": ping\n\n")),
+            Message::Pong(..) => Ok(()),
+            Message::Close(frame) => {
+                let res = this.send_event(format!(
+                    "event: close\ndata: {}\n\n",
+                    close_event(frame.as_ref())
+                ));
+                // End the event stream
+                this.events = None;
+                res
+            }
+        }
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().events = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for FallbackServerSocket {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.closed {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(&mut this.incoming).poll_next(cx) {
+            Poll::Ready(Some(Message::Close(frame))) => {
+                this.closed = true;
+
+                // Reply to the close frame, if not already closing
+                if this.events.is_some() {
+                    let _ = this.send_event(format!(
+                        "event: close\ndata: {}\n\n",
+                        close_event(frame.as_ref())
+                    ));
+                    this.events = None;
+                }
+
+                Poll::Ready(Some(Ok(Message::Close(frame))))
+            }
+            Poll::Ready(Some(msg)) => Poll::Ready(Some(Ok(msg))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => {
+                // The client disconnected from the event stream
+                if Pin::new(&mut this.body_dropped).poll(cx).is_ready() {
+                    this.closed = true;
+                    return Poll::Ready(None);
+                }
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Encode a text message as an event
+///
+/// The texts with carriage returns, that the event streams treat as line terminators, are base64 encoded `text` events.
+fn text_event(text: &str) -> String {
+    if text.contains('\r') {
+        return format!("event: text\ndata: {}\n\n", base64_encode(text.as_bytes()));
+    }
+
+    let mut event: String = String::with_capacity(text.len() + 16);
+    for line in text.split('\n') {
+        event.push_str("data: ");
+        event.push_str(line);
+        event.push('\n');
+    }
+    event.push('\n');
+    event
+}
+
+/// Incoming Server-Sent Events
+struct EventStream {
+    io: Box<dyn IoStream>,
+    /// The body uses the chunked transfer encoding
+    chunked: bool,
+    /// Bytes left in the current chunk (`None` while waiting the chunk size)
+    chunk_remaining: Option<usize>,
+    /// Body bytes, with the transfer encoding
+    raw: BytesMut,
+    /// Body bytes, without the transfer encoding
+    decoded: BytesMut,
+    /// Type of the current event
+    event: Vec<u8>,
+    /// Data of the current event
+    data: Option<Vec<u8>>,
+    /// Max size of an incoming message
+    max_size: Option<usize>,
+    eof: bool,
+}
+
+impl EventStream {
+    fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Message, Error>>> {
+        loop {
+            while let Some(line) = self.next_line() {
+                if let Some(res) = self.process_line(&line) {
+                    return Poll::Ready(Some(res));
+                }
+            }
+
+            if let Some(max_size) = self.max_size {
+                let size: usize = self.raw.len()
+                    + self.decoded.len()
+                    + self.data.as_ref().map_or(0, |data| data.len());
+                if size > max_size {
+                    return Poll::Ready(Some(Err(Error::MessageTooLarge { size, max_size })));
+                }
+            }
+
+            if self.eof {
+                return Poll::Ready(None);
+            }
+
+            let mut chunk = [0u8; READ_CHUNK_SIZE];
+            let mut buf: ReadBuf<'_> = ReadBuf::new(&mut chunk);
+            if let Err(e) = ready!(Pin::new(&mut self.io).poll_read(cx, &mut buf)) {
+                return Poll::Ready(Some(Err(io_error(e))));
+            }
+
+            if buf.filled().is_empty() {
+                self.eof = true;
+            } else {
+                self.raw.extend_from_slice(buf.filled());
+
+                if let Err(e) = self.decode() {
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+
+    /// Move the body bytes from `raw` to `decoded`, removing the chunked transfer encoding
+    fn decode(&mut self) -> Result<(), Error> {
+        if !self.chunked {
+            let raw: BytesMut = self.raw.split();
+            self.decoded.extend_from_slice(&raw);
+            return Ok(());
+        }
+
+        loop {
+            match self.chunk_remaining {
+                // CRLF after the chunk data
+                Some(0) => {
+                    if self.raw.len() < 2 {
+                        return Ok(());
+                    }
+
+                    self.raw.advance(2);
+                    self.chunk_remaining = None;
+                }
+                Some(remaining) => {
+                    if self.raw.is_empty() {
+                        return Ok(());
+                    }
+
+                    let len: usize = remaining.min(self.raw.len());
+                    let data: BytesMut = self.raw.split_to(len);
+                    self.decoded.extend_from_slice(&data);
+                    self.chunk_remaining = Some(remaining - len);
+                }
+                None => {
+                    let Some(pos) = self.raw.windows(2).position(|w| w == b"\r\n") else {
+                        return Ok(());
+                    };
+
+                    let line: BytesMut = self.raw.split_to(pos + 2);
+                    let size: usize = std::str::from_utf8(&line[..pos])
+                        .ok()
+                        .and_then(|line| line.split(';').next())
+                        .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+                        .ok_or_else(|| Error::framing("invalid chunk size"))?;
+
+                    // Last chunk
+                    if size == 0 {
+                        self.eof = true;
+                        return Ok(());
+                    }
+
+                    self.chunk_remaining = Some(size);
+                }
+            }
+        }
+    }
+
+    /// Take the next complete line, without the line terminator
+    fn next_line(&mut self) -> Option<BytesMut> {
+        let pos: usize = self.decoded.iter().position(|b| *b == b'\n')?;
+        let mut line: BytesMut = self.decoded.split_to(pos + 1);
+        line.truncate(pos);
+        if line.ends_with(b"\r") {
+            line.truncate(pos - 1);
+        }
+        Some(line)
+    }
+
+    /// Process a line of the event stream, returning the message of the dispatched event, if any
+    fn process_line(&mut self, line: &[u8]) -> Option<Result<Message, Error>> {
+        // An empty line dispatches the event
+        if line.is_empty() {
+            let event: Vec<u8> = std::mem::take(&mut self.event);
+            let data: Vec<u8> = self.data.take()?;
+
+            return Some(if event == b"binary" {
+                base64_decode(&data)
+                    .map(|data| Message::Binary(Bytes::from(data)))
+                    .ok_or_else(|| Error::framing("invalid base64 in binary event"))
+            } else if event == b"text" {
+                base64_decode(&data)
+                    .ok_or_else(|| Error::framing("invalid base64 in text event"))
+                    .and_then(|data| String::from_utf8(data).map_err(|_| Error::InvalidUtf8))
+                    .map(|text| Message::Text(text.into()))
+            } else if event == b"close" {
+                // The server ends the stream after the close event
+                self.eof = true;
+                self.decoded.clear();
+                parse_close_event(&data).map(Message::Close)
+            } else {
+                String::from_utf8(data)
+                    .map(|text| Message::Text(text.into()))
+                    .map_err(|_| Error::InvalidUtf8)
+            });
+        }
+
+        // Comment (ex. heartbeat)
+        if line.starts_with(b":") {
+            return None;
+        }
+
+        let (field, value): (&[u8], &[u8]) = match line.iter().position(|b| *b == b':') {
+            Some(pos) => {
+                let value: &[u8] = &line[pos + 1..];
+                (&line[..pos], value.strip_prefix(b" ").unwrap_or(value))
+            }
+            None => (line, &[]),
+        };
+
+        match field {
+            b"data" => {
+                let data: &mut Vec<u8> = self.data.get_or_insert_with(Vec::new);
+                if !data.is_empty() {
+                    data.push(b'\n');
+                }
+                data.extend_from_slice(value);
+            }
+            b"event" => self.event = value.to_vec(),
+            _ => {}
+        }
+
+        None
+    }
+}
+
+/// Close frame payload (RFC 6455): code and reason, or empty
+fn close_payload(frame: Option<&CloseFrame>) -> Bytes {
+    match frame {
+        Some(frame) => {
+            let mut payload: Vec<u8> = Vec::with_capacity(2 + frame.reason.len());
+            payload.extend_from_slice(&frame.code.as_u16().to_be_bytes());
+            payload.extend_from_slice(frame.reason.as_bytes());
+            Bytes::from(payload)
+        }
+        None => Bytes::new(),
+    }
+}
+
+/// Parse a close frame payload (RFC 6455)
+fn parse_close_payload(payload: &[u8]) -> Result<Option<CloseFrame>, Error> {
+    match payload {
+        [] => Ok(None),
+        [hi, lo, reason @ ..] => {
+            let reason: String =
+                String::from_utf8(reason.to_vec()).map_err(|_| Error::InvalidUtf8)?;
+            Ok(Some(CloseFrame {
+                code: CloseCode::from(u16::from_be_bytes([*hi, *lo])),
+                reason: Utf8Bytes::from(reason),
+            }))
+        }
+        [_] => Err(Error::framing("close payload without the code")),
+    }
+}
+
+/// Close event data: `<code> <reason>`, or empty
+fn close_event(frame: Option<&CloseFrame>) -> String {
+    match frame {
+        Some(frame) => format!("{} {}", frame.code.as_u16(), frame.reason.as_str()),
+        None => String::new(),
+    }
+}
+
+/// Parse the data of a close event
+fn parse_close_event(data: &[u8]) -> Result<Option<CloseFrame>, Error> {
+    let data: &str = std::str::from_utf8(data).map_err(|_| Error::InvalidUtf8)?;
+    if data.is_empty() {
+        return Ok(None);
+    }
+
+    let (code, reason) = data.split_once(' ').unwrap_or((data, ""));
+    let code: u16 = code
+        .parse()
+        .map_err(|_| Error::framing("invalid code in close event"))?;
+    Ok(Some(CloseFrame {
+        code: CloseCode::from(code),
+        reason: Utf8Bytes::from(reason.to_string()),
+    }))
+}
+
+/// Standard base64 decoding (padding optional, whitespaces ignored)
+fn base64_decode(input: &[u8]) -> Option<Vec<u8>> {
+    let mut output: Vec<u8> = Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+
+    for c in input.iter() {
+        let value: u32 = match c {
+            b'A'..=b'Z' => (c - b'A') as u32,
+            b'a'..=b'z' => (c - b'a' + 26) as u32,
+            b'0'..=b'9' => (c - b'0' + 52) as u32,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' | b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => return None,
+        };
+
+        acc = (acc << 6) | value;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            output.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    Some(output)
+}
+
+#[inline]
+fn io_error(e: io::Error) -> Error {
+    Error::from(WsError::Io(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    fn rejected(status: u16) -> ConnectError {
+        ConnectError::HandshakeRejected(Box::new(HandshakeRejection {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }))
+    }
+
+    #[test]
+    fn test_upgrade_blocked() {
+        assert!(upgrade_blocked(&rejected(200)));
+        assert!(upgrade_blocked(&rejected(426)));
+        assert!(upgrade_blocked(&rejected(501)));
+        assert!(!upgrade_blocked(&rejected(401)));
+        assert!(!upgrade_blocked(&rejected(403)));
+        assert!(!upgrade_blocked(&rejected(404)));
+    }
+
+    #[test]
+    fn test_session_id() {
+        assert_eq!(
+            FallbackSessions::session_id("a=1&sid=abc%3D%3D"),
+            Some(String::from("abc=="))
+        );
+        assert_eq!(FallbackSessions::session_id("sid="), None);
+        assert_eq!(FallbackSessions::session_id("a=1"), None);
+    }
+
+    #[test]
+    fn test_close_payload() {
+        let frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: Utf8Bytes::from("bye"),
+        };
+        let payload: Bytes = close_payload(Some(&frame));
+        assert_eq!(parse_close_payload(&payload).unwrap(), Some(frame.clone()));
+        assert_eq!(parse_close_payload(&[]).unwrap(), None);
+        assert!(parse_close_payload(&[3]).is_err());
+
+        let data: String = close_event(Some(&frame));
+        assert_eq!(data, "1000 bye");
+        assert_eq!(parse_close_event(data.as_bytes()).unwrap(), Some(frame));
+        assert_eq!(parse_close_event(b"").unwrap(), None);
+    }
+
+    /// Decode the events of the server socket with the client event stream
+    #[tokio::test]
+    async fn test_events_round_trip() {
+        let sessions = FallbackSessions::new();
+        let (mut server, mut body) = sessions.open("sid").unwrap();
+        assert!(sessions.open("sid").is_none());
+
+        let (client_io, mut server_io) = tokio::io::duplex(64 * 1024);
+        let mut events = EventStream {
+            io: Box::new(client_io),
+            chunked: false,
+            chunk_remaining: None,
+            raw: BytesMut::new(),
+            decoded: BytesMut::new(),
+            event: Vec::new(),
+            data: None,
+            max_size: None,
+            eof: false,
+        };
+
+        let messages: Vec<Message> = vec![
+            Message::Text(Utf8Bytes::from("hello")),
+            Message::Text(Utf8Bytes::from("multi\nline\n")),
+            Message::Text(Utf8Bytes::from("carriage\r\nreturn\r")),
+            Message::Binary(Bytes::from_static(&[0, 1, 2, 255])),
+            Message::Ping(Bytes::new()),
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::GoingAway,
+                reason: Utf8Bytes::from("restart"),
+            })),
+        ];
+        for msg in messages.iter() {
+            server.feed(msg.clone()).await.unwrap();
+        }
+        server.flush().await.unwrap();
+
+        // The body ends after the close event
+        while let Some(event) = body.next().await {
+            server_io.write_all(&event).await.unwrap();
+        }
+
+        let mut received: Vec<Message> = Vec::new();
+        while let Some(msg) = futures_util::future::poll_fn(|cx| events.poll_event(cx)).await {
+            received.push(msg.unwrap());
+        }
+
+        let expected: Vec<Message> = messages.into_iter().filter(|msg| !msg.is_ping()).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn test_sessions_post() {
+        let sessions = FallbackSessions::new();
+        let (mut server, mut body) = sessions.open("sid").unwrap();
+
+        assert_eq!(
+            sessions.post("sid", Some("text/plain"), Bytes::from_static(b"hi")),
+            204
+        );
+        assert_eq!(
+            sessions.post(
+                "sid",
+                Some("application/octet-stream"),
+                Bytes::from_static(&[1])
+            ),
+            204
+        );
+        assert_eq!(
+            sessions.post("sid", Some("text/plain"), Bytes::from_static(&[0xff])),
+            400
+        );
+        assert_eq!(sessions.post("other", None, Bytes::new()), 404);
+
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Text(Utf8Bytes::from("hi"))
+        );
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Binary(Bytes::from_static(&[1]))
+        );
+
+        // The close frame of the client is echoed and ends the event stream
+        let payload: Bytes = close_payload(Some(&CloseFrame {
+            code: CloseCode::Normal,
+            reason: Utf8Bytes::from(""),
+        }));
+        assert_eq!(sessions.post("sid", Some(CLOSE_CONTENT_TYPE), payload), 204);
+        assert!(server.next().await.unwrap().unwrap().is_close());
+        assert!(server.next().await.is_none());
+        assert_eq!(
+            body.next().await.unwrap(),
+            Bytes::from_static(b"event: close\ndata: 1000 \n\n")
+        );
+        assert!(body.next().await.is_none());
+
+        // Removed on drop
+        drop(server);
+        assert!(sessions.is_empty());
+    }
+
+    /// Minimal HTTP server that blocks the upgrades and serves the fallback sessions
+    async fn serve(
+        stream: TcpStream,
+        sessions: FallbackSessions,
+        echo: mpsc::UnboundedSender<WebSocket>,
+    ) {
+        let mut stream = BufReader::new(stream);
+
+        let mut request_line = String::new();
+        stream.read_line(&mut request_line).await.unwrap();
+        let mut parts = request_line.split_whitespace();
+        let method: String = parts.next().unwrap().to_string();
+        let target: String = parts.next().unwrap().to_string();
+
+        let mut headers: Vec<(String, String)> = Vec::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let line: &str = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.clone())
+        };
+
+        // Upgrade stripped by a middlebox: answered as a plain request
+        if header("upgrade").is_some() {
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            return;
+        }
+
+        let query: &str = target.split_once('?').map_or("", |(_, q)| q);
+        let sid: String = FallbackSessions::session_id(query).unwrap();
+
+        if method == "GET" {
+            let (socket, mut body) = sessions.open(&sid).unwrap();
+            echo.unbounded_send(socket).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            while let Some(event) = body.next().await {
+                if stream.write_all(&event).await.is_err() {
+                    break;
+                }
+            }
+        } else {
+            let len: usize = header("content-length").unwrap().parse().unwrap();
+            let mut payload = vec![0; len];
+            stream.read_exact(&mut payload).await.unwrap();
+            let status: u16 = sessions.post(
+                &sid,
+                header("content-type").as_deref(),
+                Bytes::from(payload),
+            );
+            stream
+                .write_all(format!("HTTP/1.1 {status} OK\r\nContent-Length: 0\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fallback_end_to_end() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sessions = FallbackSessions::new();
+        let (echo_tx, mut echo_rx) = mpsc::unbounded();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream, sessions.clone(), echo_tx.clone()));
+            }
+        });
+
+        // Echo server
+        tokio::spawn(async move {
+            let mut socket: WebSocket = echo_rx.next().await.unwrap();
+            while let Some(Ok(msg)) = socket.next().await {
+                if msg.is_close() {
+                    break;
+                }
+                socket.send(msg).await.unwrap();
+            }
+        });
+
+        let url = Url::parse(&format!("ws://{addr}/ws")).unwrap();
+        let mut socket: WebSocket = WebSocketBuilder::new()
+            .http_fallback(true)
+            .connect(&url)
+            .await
+            .unwrap();
+
+        socket
+            .send(Message::Text(Utf8Bytes::from("hello")))
+            .await
+            .unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::Text(Utf8Bytes::from("hello"))
+        );
+
+        socket
+            .send(Message::Binary(Bytes::from_static(&[1, 2, 3])))
+            .await
+            .unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::Binary(Bytes::from_static(&[1, 2, 3]))
+        );
+
+        // Completed by the close event of the server, before the timeout
+        socket
+            .close_with_timeout(CloseCode::Normal, "", std::time::Duration::from_secs(5))
+            .await
+            .unwrap();
+    }
+}
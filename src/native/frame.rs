@@ -0,0 +1,582 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Frame-level access
+//!
+//! Process the incoming messages incrementally, as the data arrives,
+//! without buffering the entire reassembled payload in memory.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_tungstenite::tungstenite::error::ProtocolError;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::http::header::{SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::MaybeTlsStream;
+use url::Url;
+
+use super::{build_request, open, tls, ConnectError, Deadline, Error, IoStream};
+use crate::message::{CloseCode, CloseFrame};
+use crate::{
+    HandshakeRejection, HandshakeResponse, Limit, Limits, Message, Phase, WebSocketBuilder,
+};
+
+/// Max size of the handshake response head
+const MAX_RESPONSE_SIZE: usize = 16 * 1024;
+/// Max size of a single read
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+/// Max payload size of a control frame
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+
+/// Fragment kind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FragmentKind {
+    /// Text message
+    Text,
+    /// Binary message
+    Binary,
+    /// Ping
+    Ping,
+    /// Pong
+    Pong,
+    /// Close
+    Close,
+}
+
+impl FragmentKind {
+    #[inline]
+    fn is_control(&self) -> bool {
+        matches!(self, Self::Ping | Self::Pong | Self::Close)
+    }
+}
+
+/// Chunk of an incoming message
+///
+/// The control messages (ping, pong and close) are never split.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Fragment {
+    /// Kind of the message
+    pub kind: FragmentKind,
+    /// First chunk of the message
+    pub first: bool,
+    /// Last chunk of the message
+    pub last: bool,
+    /// Payload chunk
+    ///
+    /// For text messages, a chunk may end in the middle of a UTF-8 character.
+    pub data: Bytes,
+}
+
+impl Fragment {
+    /// Parse the close frame, if this is a close message with a status code
+    pub fn close_frame(&self) -> Option<CloseFrame> {
+        if self.kind != FragmentKind::Close || self.data.len() < 2 {
+            return None;
+        }
+
+        let code: CloseCode = u16::from_be_bytes([self.data[0], self.data[1]]).into();
+        let reason = self.data.slice(2..).try_into().ok()?;
+        Some(CloseFrame { code, reason })
+    }
+}
+
+/// Frame being received
+struct Frame {
+    kind: FragmentKind,
+    fin: bool,
+    /// Payload not received yet
+    remaining: u64,
+    /// No chunks of the message delivered yet
+    first: bool,
+}
+
+/// Frame header
+struct Header {
+    fin: bool,
+    opcode: u8,
+    len: u64,
+}
+
+/// Frame-level WebSocket
+///
+/// The [`Stream`] yields the incoming messages as [`Fragment`]s, as soon as the data arrives.
+/// The [`Sink`] sends every [`Message`] as a single frame.
+///
+/// No automatic replies: the pings must be answered with [`Message::Pong`]
+/// and the closing handshake must be completed by the application.
+///
+/// The [`Limits`] of the builder are enforced on the announced frame sizes,
+/// so an oversized message is rejected before its payload is received.
+pub struct FrameStream {
+    stream: MaybeTlsStream<Box<dyn IoStream>>,
+    protocol: Option<String>,
+    peer_addr: Option<SocketAddr>,
+    read_buf: BytesMut,
+    /// Frame being received
+    frame: Option<Frame>,
+    /// Kind of the fragmented message being received
+    message: Option<FragmentKind>,
+    /// Frames of the message being received
+    frames: usize,
+    /// Size of the message being received
+    size: usize,
+    limits: Limits,
+    write_buf: Vec<u8>,
+    /// Bytes of `write_buf` already written
+    written: usize,
+}
+
+/// Connect, with frame-level access to the incoming messages
+///
+/// Check [`FrameStream`] for more details.
+pub async fn connect(
+    url: &Url,
+    builder: &WebSocketBuilder,
+) -> Result<(FrameStream, HandshakeResponse), ConnectError> {
+    let request: Request = build_request(url, builder, false).await?;
+    let (transport, peer_addr, deadline) = open(url, builder, Deadline::from(builder)).await?;
+
+    let conn: Box<dyn IoStream> = transport.boxed();
+    let mut stream: MaybeTlsStream<Box<dyn IoStream>> =
+        deadline.run(Phase::Tls, tls(url, conn, builder)).await??;
+    let response: HandshakeResponse = deadline
+        .run(Phase::Handshake, handshake(&request, &mut stream))
+        .await??;
+
+    let protocol: Option<String> = response
+        .header("Sec-WebSocket-Protocol")
+        .map(|p| p.to_string());
+
+    Ok((
+        FrameStream {
+            stream,
+            protocol,
+            peer_addr,
+            read_buf: BytesMut::new(),
+            frame: None,
+            message: None,
+            frames: 0,
+            size: 0,
+            limits: builder.limits,
+            write_buf: Vec::new(),
+            written: 0,
+        },
+        response,
+    ))
+}
+
+/// Perform the WebSocket handshake
+///
+/// The response is read byte by byte, to not consume any frame.
+async fn handshake<S>(request: &Request, stream: &mut S) -> Result<HandshakeResponse, ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let key: &[u8] = request
+        .headers()
+        .get(SEC_WEBSOCKET_KEY)
+        .ok_or(WsError::Protocol(ProtocolError::MissingSecWebSocketKey))?
+        .as_bytes();
+
+    // Build request
+    let path: &str = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let mut head: Vec<u8> = format!("GET {path} HTTP/1.1\r\n").into_bytes();
+    for (name, value) in request.headers() {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+
+    stream.write_all(&head).await.map_err(WsError::Io)?;
+    stream.flush().await.map_err(WsError::Io)?;
+
+    // Read response head
+    let mut head: Vec<u8> = Vec::with_capacity(256);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_RESPONSE_SIZE {
+            return Err(invalid_data("handshake response too large"));
+        }
+
+        let byte: u8 = stream.read_u8().await.map_err(WsError::Io)?;
+        head.push(byte);
+    }
+
+    // Parse response head (ex. `HTTP/1.1 101 Switching Protocols`)
+    let head: &str = std::str::from_utf8(&head)
+        .map_err(|_| invalid_data::<ConnectError, _>("invalid handshake response"))?;
+    let mut lines = head.lines();
+    let status: u16 = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid_data::<ConnectError, _>("invalid handshake response"))?;
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    // The body isn't read
+    if status != 101 {
+        return Err(ConnectError::HandshakeRejected(Box::new(
+            HandshakeRejection {
+                status,
+                headers,
+                body: Vec::new(),
+            },
+        )));
+    }
+
+    let response: HandshakeResponse = HandshakeResponse { status, headers };
+
+    if response.header(SEC_WEBSOCKET_ACCEPT.as_str()) != Some(derive_accept_key(key).as_str()) {
+        return Err(ConnectError::from(WsError::Protocol(
+            ProtocolError::SecWebSocketAcceptKeyMismatch,
+        )));
+    }
+
+    Ok(response)
+}
+
+impl FrameStream {
+    /// Get the subprotocol accepted by the server, if any
+    #[inline]
+    pub fn negotiated_protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Get the address of the remote peer
+    ///
+    /// Check [`WebSocket::peer_addr`](crate::WebSocket::peer_addr).
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Start receiving a frame
+    fn start_frame(&mut self, header: Header) -> Result<Frame, Error> {
+        let (kind, first) = match header.opcode {
+            0x0 => {
+                let kind: FragmentKind = self
+                    .message
+                    .ok_or(WsError::Protocol(ProtocolError::UnexpectedContinueFrame))?;
+                (kind, false)
+            }
+            0x1 | 0x2 => {
+                if self.message.is_some() {
+                    return Err(invalid_data("expected a continuation frame"));
+                }
+
+                let kind: FragmentKind = if header.opcode == 0x1 {
+                    FragmentKind::Text
+                } else {
+                    FragmentKind::Binary
+                };
+                (kind, true)
+            }
+            0x8 => (FragmentKind::Close, true),
+            0x9 => (FragmentKind::Ping, true),
+            0xA => (FragmentKind::Pong, true),
+            opcode @ 0x3..=0x7 => {
+                return Err(Error::from(WsError::Protocol(
+                    ProtocolError::UnknownDataFrameType(opcode),
+                )))
+            }
+            opcode => {
+                return Err(Error::from(WsError::Protocol(
+                    ProtocolError::UnknownControlFrameType(opcode),
+                )))
+            }
+        };
+
+        if kind.is_control() {
+            if !header.fin {
+                return Err(Error::from(WsError::Protocol(
+                    ProtocolError::FragmentedControlFrame,
+                )));
+            }
+
+            if header.len > MAX_CONTROL_PAYLOAD {
+                return Err(Error::from(WsError::Protocol(
+                    ProtocolError::ControlFrameTooBig,
+                )));
+            }
+        } else {
+            self.check_limits(first, header.len)?;
+
+            // Control frames can be interleaved with the fragments of a message
+            self.message = if header.fin { None } else { Some(kind) };
+        }
+
+        Ok(Frame {
+            kind,
+            fin: header.fin,
+            remaining: header.len,
+            first,
+        })
+    }
+
+    /// Account a data frame of `len` bytes to the message being received
+    fn check_limits(&mut self, first: bool, len: u64) -> Result<(), Error> {
+        if first {
+            self.frames = 0;
+            self.size = 0;
+        }
+
+        self.frames += 1;
+        self.size = self
+            .size
+            .saturating_add(usize::try_from(len).unwrap_or(usize::MAX));
+
+        if let Some(max_size) = self.limits.max_message_size {
+            if self.size > max_size {
+                return Err(Error::MessageTooLarge {
+                    size: self.size,
+                    max_size,
+                });
+            }
+        }
+
+        if let Some(max) = self.limits.max_frames_per_message {
+            if self.frames > max {
+                return Err(Error::LimitExceeded(Limit::FramesPerMessage));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read more data. Returns `0` at the end of the stream.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize, Error>> {
+        let mut buf = [0u8; READ_CHUNK_SIZE];
+        let mut read_buf: ReadBuf<'_> = ReadBuf::new(&mut buf);
+        ready!(Pin::new(&mut self.stream).poll_read(cx, &mut read_buf)).map_err(WsError::Io)?;
+        self.read_buf.extend_from_slice(read_buf.filled());
+        Poll::Ready(Ok(read_buf.filled().len()))
+    }
+
+    fn poll_fragment(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Fragment>, Error>> {
+        loop {
+            match &mut self.frame {
+                Some(frame) => {
+                    let available: u64 = self.read_buf.len() as u64;
+
+                    // Deliver what is available (the control frames only when complete)
+                    let complete: bool = available >= frame.remaining;
+                    if complete || (available > 0 && !frame.kind.is_control()) {
+                        let len: usize = frame.remaining.min(available) as usize;
+                        let data: Bytes = self.read_buf.split_to(len).freeze();
+                        frame.remaining -= len as u64;
+
+                        let fragment: Fragment = Fragment {
+                            kind: frame.kind,
+                            first: frame.first,
+                            last: frame.remaining == 0 && frame.fin,
+                            data,
+                        };
+                        frame.first = false;
+
+                        if frame.remaining == 0 {
+                            // The next frame of a fragmented message doesn't start it
+                            self.frame = None;
+                        }
+
+                        return Poll::Ready(Ok(Some(fragment)));
+                    }
+                }
+                None => {
+                    if let Some((header, size)) = parse_header(&self.read_buf)? {
+                        self.read_buf.advance(size);
+                        self.frame = Some(self.start_frame(header)?);
+                        continue;
+                    }
+                }
+            }
+
+            if ready!(self.poll_fill(cx))? == 0 {
+                if self.frame.is_some() || !self.read_buf.is_empty() {
+                    return Poll::Ready(Err(Error::from(WsError::Io(io::Error::from(
+                        io::ErrorKind::UnexpectedEof,
+                    )))));
+                }
+
+                return Poll::Ready(Ok(None));
+            }
+        }
+    }
+
+    /// Write the buffered frames
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while self.written < self.write_buf.len() {
+            let n: usize =
+                ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.written..]))
+                    .map_err(WsError::Io)?;
+
+            if n == 0 {
+                return Poll::Ready(Err(Error::from(WsError::Io(io::Error::from(
+                    io::ErrorKind::WriteZero,
+                )))));
+            }
+
+            self.written += n;
+        }
+
+        self.write_buf.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for FrameStream {
+    type Item = Result<Fragment, Error>;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_fragment(cx).map(Result::transpose)
+    }
+}
+
+impl Sink<Message> for FrameStream {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_write_buf(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        encode(item, &mut self.write_buf);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_write_buf(cx))?;
+        Pin::new(&mut self.stream)
+            .poll_flush(cx)
+            .map_err(|e| Error::from(WsError::Io(e)))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_write_buf(cx))?;
+        Pin::new(&mut self.stream)
+            .poll_shutdown(cx)
+            .map_err(|e| Error::from(WsError::Io(e)))
+    }
+}
+
+/// Parse a frame header, returning it with its size (`None` if incomplete)
+fn parse_header(buf: &[u8]) -> Result<Option<(Header, usize)>, Error> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin: bool = buf[0] & 0x80 != 0;
+    let rsv: u8 = buf[0] & 0x70;
+    let opcode: u8 = buf[0] & 0x0F;
+    let masked: bool = buf[1] & 0x80 != 0;
+
+    if rsv != 0 {
+        return Err(Error::from(WsError::Protocol(
+            ProtocolError::NonZeroReservedBits,
+        )));
+    }
+
+    if masked {
+        return Err(Error::from(WsError::Protocol(
+            ProtocolError::MaskedFrameFromServer,
+        )));
+    }
+
+    let (len, size) = match buf[1] & 0x7F {
+        126 => match buf.get(2..4) {
+            Some(b) => (u16::from_be_bytes([b[0], b[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10) {
+            Some(b) => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(b);
+                (u64::from_be_bytes(bytes), 10)
+            }
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+
+    Ok(Some((Header { fin, opcode, len }, size)))
+}
+
+/// Encode a message as a single masked frame
+fn encode(msg: Message, buf: &mut Vec<u8>) {
+    let (opcode, payload): (u8, Bytes) = match msg {
+        Message::Text(text) => (0x1, Bytes::from(text)),
+        Message::Binary(data) => (0x2, data),
+        Message::Close(frame) => {
+            let payload: Bytes = match frame {
+                Some(frame) => {
+                    let mut payload: Vec<u8> = frame.code.as_u16().to_be_bytes().to_vec();
+                    payload.extend_from_slice(frame.reason.as_bytes());
+                    Bytes::from(payload)
+                }
+                None => Bytes::new(),
+            };
+            (0x8, payload)
+        }
+        Message::Ping(data) => (0x9, data),
+        Message::Pong(data) => (0xA, data),
+    };
+
+    // FIN + opcode
+    buf.push(0x80 | opcode);
+
+    // MASK + length
+    let len: usize = payload.len();
+    if len < 126 {
+        buf.push(0x80 | len as u8);
+    } else if len <= u16::MAX as usize {
+        buf.push(0x80 | 126);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(0x80 | 127);
+        buf.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    let mask: [u8; 4] = mask_key();
+    buf.extend_from_slice(&mask);
+    buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+}
+
+/// Masking key of a client frame
+///
+/// Not cryptographically secure, but unpredictable enough for the purpose of the masking
+/// (prevent cache poisoning of the intermediaries).
+fn mask_key() -> [u8; 4] {
+    let hash: u64 = RandomState::new().build_hasher().finish();
+    (hash as u32).to_be_bytes()
+}
+
+#[inline]
+fn invalid_data<T, E>(error: E) -> T
+where
+    T: From<WsError>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    T::from(WsError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        error,
+    )))
+}
@@ -0,0 +1,83 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Happy Eyeballs (RFC 8305)
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::pin;
+
+use futures_util::future::{self, Either};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time;
+
+use super::tcp;
+use crate::WebSocketBuilder;
+
+/// Connect to the first reachable address
+///
+/// The attempts are started one after the other, alternating the address families,
+/// without waiting for the previous ones to fail: a new attempt is started
+/// every [`WebSocketBuilder::connection_attempt_delay`] or as soon as an attempt fails.
+/// The first established connection wins and the other attempts are dropped.
+pub(super) async fn connect(
+    addrs: &[SocketAddr],
+    builder: &WebSocketBuilder,
+) -> io::Result<TcpStream> {
+    let mut pending = interleave(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut error: Option<io::Error> = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(tcp::connect(addr, builder)),
+                None => {
+                    return Err(error.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+                    }))
+                }
+            }
+        }
+
+        let delay = pin!(time::sleep(builder.connection_attempt_delay));
+        let result = match future::select(attempts.next(), delay).await {
+            Either::Left((result, _)) => result,
+            Either::Right(..) => None,
+        };
+
+        match result {
+            Some(Ok(stream)) => return Ok(stream),
+            Some(Err(e)) => error = Some(e),
+            None => {}
+        }
+
+        // Start the next attempt: the previous one failed or the delay elapsed
+        if let Some(addr) = pending.next() {
+            attempts.push(tcp::connect(addr, builder));
+        }
+    }
+}
+
+/// Sort the addresses alternating the families, starting with the family of the first one
+fn interleave(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_is_ipv6: bool = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .iter()
+        .copied()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut sorted: Vec<SocketAddr> = Vec::with_capacity(addrs.len());
+
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    sorted
+}
@@ -0,0 +1,331 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! WebSockets over HTTP/2 (RFC 8441)
+//!
+//! The WebSockets are opened with the extended `CONNECT` method,
+//! as streams multiplexed on a single HTTP/2 connection.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use futures_util::ready;
+use h2::client::{self, SendRequest};
+use h2::ext::Protocol;
+use h2::{Ping, RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(not(any(feature = "tls-rustls", feature = "tls-native")))]
+use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::tungstenite::handshake::client::{Request, Response};
+use tokio_tungstenite::tungstenite::http::header::{CONNECTION, HOST, SEC_WEBSOCKET_KEY, UPGRADE};
+use tokio_tungstenite::tungstenite::http::{Method, StatusCode, Uri, Version};
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use url::{Position, Url};
+
+#[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+use super::TlsConfig;
+use super::{build_request, open, protocol, ws_config, ConnectError, Deadline, IoStream};
+use crate::socket::{InnerWebSocket, WebSocket};
+use crate::{HandshakeRejection, HandshakeResponse, Phase, WebSocketBuilder};
+
+/// HTTP/2 connection, multiplexing the WebSockets opened with
+/// [`WebSocketBuilder::connect_h2`](crate::WebSocketBuilder::connect_h2)
+///
+/// The connection is driven by a background task, until the handle (and its clones) and all the WebSockets are dropped.
+///
+/// The handle is cheap to clone: all the clones refer to the same connection.
+#[derive(Clone)]
+pub struct H2Connection {
+    send: SendRequest<Bytes>,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl fmt::Debug for H2Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("H2Connection")
+            .field("peer_addr", &self.peer_addr)
+            .finish()
+    }
+}
+
+impl H2Connection {
+    /// Open an HTTP/2 connection to the host of `url`
+    ///
+    /// The connection mode, the TLS config and the timeouts of the `builder` are used.
+    /// For `wss` URLs HTTP/2 is negotiated with ALPN, for `ws` URLs it's used with prior knowledge (h2c).
+    ///
+    /// Returns [`ConnectError::H2Unsupported`] if the server doesn't support the extended `CONNECT` method.
+    pub async fn connect(url: &Url, builder: &WebSocketBuilder) -> Result<Self, ConnectError> {
+        let builder = builder.for_url(url);
+
+        let (transport, peer_addr, deadline) =
+            open(url, &builder, Deadline::from(&builder)).await?;
+        let conn: MaybeTlsStream<Box<dyn IoStream>> = deadline
+            .run(Phase::Tls, tls(url, transport.boxed(), &builder))
+            .await??;
+
+        // Use `Box::pin` to fix stack overflow on windows targets due to large `Future`
+        let send: SendRequest<Bytes> =
+            Box::pin(deadline.run(Phase::Handshake, handshake(conn))).await??;
+
+        Ok(Self { send, peer_addr })
+    }
+
+    /// Peer address of the connection, if known
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+}
+
+/// Perform the TLS handshake over `conn`, negotiating HTTP/2, if required by the URL scheme
+#[cfg_attr(
+    not(any(feature = "tls-rustls", feature = "tls-native")),
+    allow(unused_variables)
+)]
+async fn tls(
+    url: &Url,
+    conn: Box<dyn IoStream>,
+    builder: &WebSocketBuilder,
+) -> Result<MaybeTlsStream<Box<dyn IoStream>>, ConnectError> {
+    if url.scheme() != "wss" {
+        return Ok(MaybeTlsStream::Plain(conn));
+    }
+
+    #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+    {
+        let host: &str = url.host_str().ok_or_else(ConnectError::empty_host)?;
+        let (stream, h2) = match &builder.tls {
+            Some(tls) => tls.wrap_stream_h2(host, conn).await?,
+            None => TlsConfig::default().wrap_stream_h2(host, conn).await?,
+        };
+
+        if !h2 {
+            return Err(ConnectError::H2Unsupported);
+        }
+
+        Ok(stream)
+    }
+
+    #[cfg(not(any(feature = "tls-rustls", feature = "tls-native")))]
+    {
+        Err(ConnectError::from(WsError::Url(
+            UrlError::TlsFeatureNotEnabled,
+        )))
+    }
+}
+
+/// Perform the HTTP/2 handshake and spawn the connection task
+async fn handshake(
+    conn: MaybeTlsStream<Box<dyn IoStream>>,
+) -> Result<SendRequest<Bytes>, ConnectError> {
+    let (send, mut connection) = client::handshake(conn).await?;
+    let ping_pong = connection.ping_pong();
+
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+
+    // The server SETTINGS precede the PONG:
+    // once received, the support of the extended `CONNECT` is known.
+    if let Some(mut ping_pong) = ping_pong {
+        ping_pong.ping(Ping::opaque()).await?;
+    }
+
+    if !send.is_extended_connect_protocol_enabled() {
+        return Err(ConnectError::H2Unsupported);
+    }
+
+    Ok(send)
+}
+
+/// Open a WebSocket as a new stream of `connection`
+///
+/// The redirects aren't followed.
+pub(crate) async fn connect(
+    connection: &H2Connection,
+    url: &Url,
+    builder: &WebSocketBuilder,
+) -> Result<(WebSocket, HandshakeResponse), ConnectError> {
+    let request: Request = extended_connect(url, builder).await?;
+    let deadline: Deadline = Deadline::from(builder);
+
+    let (stream, response) = Box::pin(deadline.run(
+        Phase::Handshake,
+        send_request(connection.send.clone(), request),
+    ))
+    .await??;
+
+    let stream: Box<dyn IoStream> = Box::new(stream);
+    let stream = WebSocketStream::from_raw_socket(
+        MaybeTlsStream::Plain(stream),
+        Role::Client,
+        Some(ws_config(builder).into()),
+    )
+    .await;
+
+    Ok((
+        WebSocket::new(InnerWebSocket::Custom(stream), protocol(&response))
+            .with_peer_addr(connection.peer_addr),
+        HandshakeResponse::from(&response),
+    ))
+}
+
+/// Build the extended `CONNECT` request, with the headers of the HTTP/1.1 handshake request
+async fn extended_connect(url: &Url, builder: &WebSocketBuilder) -> Result<Request, ConnectError> {
+    let (parts, ()) = build_request(url, builder, false).await?.into_parts();
+
+    let host: &str = url.host_str().ok_or_else(ConnectError::empty_host)?;
+    let scheme: &str = if url.scheme() == "wss" {
+        "https"
+    } else {
+        "http"
+    };
+    let authority: String = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+    let uri: Uri = format!(
+        "{scheme}://{authority}{}",
+        &url[Position::BeforePath..Position::AfterQuery]
+    )
+    .parse()
+    .map_err(WsError::from)?;
+
+    let mut request: Request = Request::new(());
+    *request.method_mut() = Method::CONNECT;
+    *request.uri_mut() = uri;
+    *request.version_mut() = Version::HTTP_2;
+    *request.headers_mut() = parts.headers;
+    request
+        .extensions_mut()
+        .insert(Protocol::from_static("websocket"));
+
+    // Connection-specific headers are forbidden in HTTP/2
+    for name in [HOST, CONNECTION, UPGRADE, SEC_WEBSOCKET_KEY] {
+        request.headers_mut().remove(name);
+    }
+
+    Ok(request)
+}
+
+async fn send_request(
+    send: SendRequest<Bytes>,
+    request: Request,
+) -> Result<(H2Stream, Response), ConnectError> {
+    let mut send: SendRequest<Bytes> = send.ready().await?;
+    let (response, stream) = send.send_request(request, false)?;
+
+    let (parts, recv) = response.await?.into_parts();
+    let response: Response = Response::from_parts(parts, None);
+
+    if response.status() != StatusCode::OK {
+        return Err(ConnectError::HandshakeRejected(Box::new(
+            HandshakeRejection::from(&response),
+        )));
+    }
+
+    Ok((
+        H2Stream {
+            send: stream,
+            recv,
+            buf: Bytes::new(),
+            closed: false,
+        },
+        response,
+    ))
+}
+
+/// HTTP/2 stream of a WebSocket
+struct H2Stream {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    /// Received data not read yet
+    buf: Bytes,
+    /// The end of stream has been sent
+    closed: bool,
+}
+
+impl AsyncRead for H2Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.buf.is_empty() {
+            match ready!(this.recv.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    // Give back the flow control window
+                    let _ = this.recv.flow_control().release_capacity(data.len());
+                    this.buf = data;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+                // End of stream
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len: usize = this.buf.len().min(buf.remaining());
+        buf.put_slice(&this.buf[..len]);
+        this.buf.advance(len);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for H2Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        this.send.reserve_capacity(buf.len());
+
+        match ready!(this.send.poll_capacity(cx)) {
+            Some(Ok(capacity)) => {
+                let len: usize = capacity.min(buf.len());
+                this.send
+                    .send_data(Bytes::copy_from_slice(&buf[..len]), false)
+                    .map_err(io_error)?;
+                Poll::Ready(Ok(len))
+            }
+            Some(Err(e)) => Poll::Ready(Err(io_error(e))),
+            None => Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Flushed by the connection task
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.closed {
+            this.closed = true;
+            this.send.send_data(Bytes::new(), true).map_err(io_error)?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn io_error(e: h2::Error) -> io::Error {
+    let kind: io::ErrorKind = e.get_io().map_or(io::ErrorKind::Other, io::Error::kind);
+    io::Error::new(kind, e)
+}
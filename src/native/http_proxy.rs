@@ -0,0 +1,136 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! HTTP proxy (CONNECT tunnel)
+
+use std::net::SocketAddr;
+use std::{fmt, io};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::tcp;
+use crate::{ProxyAuth, WebSocketBuilder};
+
+/// Max size of the proxy response head
+const MAX_RESPONSE_SIZE: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    /// I/O error
+    Io(io::Error),
+    /// Malformed proxy response
+    InvalidResponse,
+    /// Proxy response head too large
+    ResponseTooLarge,
+    /// The proxy refused to open the tunnel
+    Status(u16),
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::InvalidResponse => write!(f, "invalid proxy response"),
+            Self::ResponseTooLarge => write!(f, "proxy response too large"),
+            Self::Status(status) => write!(f, "proxy refused to connect: status={status}"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+pub(crate) struct TcpHttpProxyStream;
+
+impl TcpHttpProxyStream {
+    /// Connect to `host:port` through the HTTP proxy, using the `CONNECT` method
+    pub async fn connect(
+        proxy: SocketAddr,
+        host: &str,
+        port: u16,
+        auth: Option<&ProxyAuth>,
+        builder: &WebSocketBuilder,
+    ) -> Result<TcpStream, Error> {
+        let mut stream: TcpStream = tcp::connect(proxy, builder).await?;
+
+        // Build request
+        let mut request: String =
+            format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+        if let Some(auth) = auth {
+            request.push_str(&format!("Proxy-Authorization: {}\r\n", auth.header_value()));
+        }
+        request.push_str("\r\n");
+
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        // Read the response head byte by byte, to not consume any data of the tunnel
+        let mut head: Vec<u8> = Vec::with_capacity(256);
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_RESPONSE_SIZE {
+                return Err(Error::ResponseTooLarge);
+            }
+
+            let byte: u8 = stream.read_u8().await?;
+            head.push(byte);
+        }
+
+        // Parse status line (ex. `HTTP/1.1 200 Connection established`)
+        let head: &str = std::str::from_utf8(&head).map_err(|_| Error::InvalidResponse)?;
+        let status_line: &str = head.lines().next().ok_or(Error::InvalidResponse)?;
+        let mut parts = status_line.split_whitespace();
+
+        match parts.next() {
+            Some(version) if version.starts_with("HTTP/1.") => {}
+            _ => return Err(Error::InvalidResponse),
+        }
+
+        let status: u16 = parts
+            .next()
+            .and_then(|s| s.parse().ok())
+            .ok_or(Error::InvalidResponse)?;
+
+        if !(200..300).contains(&status) {
+            return Err(Error::Status(status));
+        }
+
+        Ok(stream)
+    }
+}
+
+/// Standard base64 encoding (with padding)
+pub(crate) fn base64_encode(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output: String = String::with_capacity(input.len() * 4 / 3 + 4);
+
+    for chunk in input.chunks(3) {
+        let b0: u32 = chunk[0] as u32;
+        let b1: u32 = chunk.get(1).copied().unwrap_or(0) as u32;
+        let b2: u32 = chunk.get(2).copied().unwrap_or(0) as u32;
+        let n: u32 = (b0 << 16) | (b1 << 8) | b2;
+
+        output.push(ALPHABET[(n >> 18) as usize & 63] as char);
+        output.push(ALPHABET[(n >> 12) as usize & 63] as char);
+
+        if chunk.len() > 1 {
+            output.push(ALPHABET[(n >> 6) as usize & 63] as char);
+        } else {
+            output.push('=');
+        }
+
+        if chunk.len() > 2 {
+            output.push(ALPHABET[n as usize & 63] as char);
+        } else {
+            output.push('=');
+        }
+    }
+
+    output
+}
@@ -3,8 +3,10 @@
 
 //! Native
 
-#[cfg(feature = "socks")]
+use std::future::Future;
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
 #[cfg(feature = "tor")]
 use std::path::PathBuf;
 use std::time::Duration;
@@ -12,107 +14,879 @@ use std::time::Duration;
 #[cfg(feature = "tor")]
 use arti_client::DataStream;
 use tokio::io::{AsyncRead, AsyncWrite};
-#[cfg(feature = "socks")]
-use tokio::net::TcpStream;
-use tokio::time;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::{self, Instant};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+#[cfg(not(any(feature = "tls-rustls", feature = "tls-native")))]
+use tokio_tungstenite::tungstenite::error::UrlError;
+pub use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+#[cfg(feature = "cookies")]
+use tokio_tungstenite::tungstenite::http::header::COOKIE;
+use tokio_tungstenite::tungstenite::http::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, SEC_WEBSOCKET_EXTENSIONS,
+    SEC_WEBSOCKET_PROTOCOL,
+};
 use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Error as WsError;
 pub use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::MaybeTlsStream;
 pub use tokio_tungstenite::WebSocketStream;
-use url::Url;
+use url::{Origin, Url};
 
+mod accept;
+pub(crate) mod admission;
+mod auth;
+#[cfg(feature = "futures-io")]
+pub mod compat;
+mod config;
+#[cfg(feature = "cookies")]
+mod cookies;
+#[cfg(feature = "deflate")]
+pub(crate) mod deflate;
 mod error;
+pub mod fallback;
+pub mod frame;
+mod happy_eyeballs;
+#[cfg(feature = "h2")]
+pub mod http2;
+pub mod http_proxy;
+pub mod raw;
+mod resolver;
+pub mod server;
 #[cfg(feature = "socks")]
 mod socks;
+mod tcp;
+#[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+pub(crate) mod tls;
 #[cfg(feature = "tor")]
 pub mod tor;
+mod transport;
+pub mod typed;
+#[cfg(any(
+    feature = "hyper",
+    feature = "axum",
+    feature = "actix-web",
+    feature = "warp"
+))]
+pub mod upgrade;
+#[cfg(feature = "h3")]
+pub mod webtransport;
 
-pub use self::error::Error;
+pub use self::accept::{
+    accept_hdr, accept_hdr_with_config, AcceptDecision, AcceptPolicy, HandshakeRequest,
+};
+pub use self::admission::ServerLimits;
+pub use self::auth::{AuthProvider, Authenticator, RequestHook};
+pub use self::config::WebSocketConfig;
+#[cfg(feature = "cookies")]
+pub use self::cookies::CookieJar;
+#[cfg(feature = "deflate")]
+pub use self::deflate::CompressionConfig;
+pub use self::error::{ConnectError, Error};
+pub use self::fallback::{EventBody, FallbackSessions};
+#[cfg(feature = "h2")]
+pub use self::http2::H2Connection;
+use self::http_proxy::TcpHttpProxyStream;
+pub use self::raw::{Framer, RawFramer};
+pub use self::resolver::{Resolve, Resolver};
+pub use self::server::{PeerInfo, WsServer};
 #[cfg(feature = "socks")]
 use self::socks::TcpSocks5Stream;
-use crate::socket::WebSocket;
-use crate::ConnectionMode;
+pub use self::transport::{CustomTransport, Transport};
+pub use self::typed::{TcpWebSocket, TypedWebSocket};
+
+#[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+pub use self::tls::{ServerTlsStream, TlsAcceptor, TlsConfig, TlsInfo, TlsVersion};
+#[cfg(feature = "tor")]
+use self::tor::{TorClientHandle, TorOptions};
+use crate::socket::{InnerWebSocket, WebSocket, WsStream};
+#[cfg(feature = "socks")]
+use crate::ProxyOptions;
+use crate::{
+    ConnectionMode, HandshakeRejection, HandshakeResponse, Phase, ProxyAuth, Timeouts,
+    WebSocketBuilder,
+};
+
+/// Type-erased I/O stream
+///
+/// Implemented by every `AsyncRead + AsyncWrite + Send + Unpin + 'static` type.
+pub trait IoStream: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T> IoStream for T where T: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+/// Size of the in-memory pipe of [`duplex`]
+const DUPLEX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Min timeout for connecting to an onion service through the Tor daemon
+#[cfg(feature = "socks")]
+const TOR_SOCKS_ONION_MIN_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[inline]
+pub async fn connect(url: &Url, builder: &WebSocketBuilder) -> Result<WebSocket, ConnectError> {
+    let (socket, _) = connect_with_response(url, builder).await?;
+    Ok(socket)
+}
+
+pub async fn connect_with_response(
+    url: &Url,
+    builder: &WebSocketBuilder,
+) -> Result<(WebSocket, HandshakeResponse), ConnectError> {
+    let origin: Origin = url.origin();
+    let mut url: Url = url.clone();
+    let mut visited: Vec<Url> = Vec::new();
+    let mut refresh: bool = false;
 
-pub async fn connect(
+    // The credentials aren't sent to the other origins
+    let mut stripped: Option<WebSocketBuilder> = None;
+
+    // Shared by the redirects and the retries
+    let deadline: Deadline = Deadline::from(builder);
+
+    loop {
+        let current: &WebSocketBuilder = if url.origin() == origin {
+            builder
+        } else {
+            stripped.get_or_insert_with(|| without_credentials(builder))
+        };
+
+        let res = connect_once(&url, current, refresh, &deadline).await;
+
+        #[cfg(feature = "cookies")]
+        store_cookies(&url, current, &res);
+
+        let location: Option<Url> = match &res {
+            Err(ConnectError::HandshakeRejected(rejection))
+                if visited.len() < builder.max_redirects =>
+            {
+                redirect_location(&url, rejection)
+            }
+            _ => None,
+        };
+
+        match res {
+            // Retry once with a refreshed authorization
+            Err(ConnectError::HandshakeRejected(rejection))
+                if rejection.status == 401
+                    && current.auth.is_some()
+                    && current.auth_refresh
+                    && !refresh =>
+            {
+                refresh = true;
+            }
+            Err(ConnectError::HandshakeRejected(..)) if location.is_some() => {
+                let location: Url = location.expect("checked in the guard");
+
+                visited.push(url);
+
+                if visited.contains(&location) {
+                    return Err(ConnectError::RedirectLoop(Box::new(location)));
+                }
+
+                url = location;
+            }
+            Err(e)
+                if current.http_fallback
+                    && current.raw.is_none()
+                    && fallback::upgrade_blocked(&e) =>
+            {
+                return Box::pin(fallback::connect(&url, current, &deadline)).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Clone the builder without the credentials (the [`AuthProvider`], the [`RequestHook`] and the sensitive custom headers),
+/// for the cross-origin redirects
+fn without_credentials(builder: &WebSocketBuilder) -> WebSocketBuilder {
+    let mut builder: WebSocketBuilder = builder.clone();
+    builder.headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case("authorization")
+            && !name.eq_ignore_ascii_case("proxy-authorization")
+            && !name.eq_ignore_ascii_case("cookie")
+    });
+    builder.auth = None;
+    builder.request_hook = None;
+    builder
+}
+
+/// Capture the `Set-Cookie` headers of the handshake response, also if rejected
+#[cfg(feature = "cookies")]
+fn store_cookies(
     url: &Url,
-    mode: &ConnectionMode,
-    timeout: Duration,
-) -> Result<WebSocket, Error> {
-    match mode {
-        ConnectionMode::Direct => connect_direct(url, timeout).await,
+    builder: &WebSocketBuilder,
+    res: &Result<(WebSocket, HandshakeResponse), ConnectError>,
+) {
+    let Some(jar) = &builder.cookie_jar else {
+        return;
+    };
+
+    let headers: &[(String, String)] = match res {
+        Ok((_, response)) => &response.headers,
+        Err(ConnectError::HandshakeRejected(rejection)) => &rejection.headers,
+        Err(..) => return,
+    };
+
+    for (name, value) in headers.iter() {
+        if name.eq_ignore_ascii_case("set-cookie") {
+            jar.set(value, url);
+        }
+    }
+}
+
+/// Get the target of a redirect, with the scheme mapped to `ws` or `wss`
+///
+/// Returns `None` if the rejection isn't a redirect or if it downgrades `wss` to `ws`.
+fn redirect_location(url: &Url, rejection: &HandshakeRejection) -> Option<Url> {
+    if !matches!(rejection.status, 301 | 302 | 307 | 308) {
+        return None;
+    }
+
+    // Relative locations are resolved against the current URL
+    let mut location: Url = url.join(rejection.header("location")?).ok()?;
+
+    let scheme: &str = match location.scheme() {
+        "http" | "ws" => "ws",
+        "https" | "wss" => "wss",
+        _ => return None,
+    };
+
+    // Refuse the downgrades from `wss` to `ws`
+    if url.scheme() == "wss" && scheme == "ws" {
+        return None;
+    }
+    location.set_scheme(scheme).ok()?;
+
+    Some(location)
+}
+
+async fn connect_once(
+    url: &Url,
+    builder: &WebSocketBuilder,
+    refresh: bool,
+    deadline: &Deadline,
+) -> Result<(WebSocket, HandshakeResponse), ConnectError> {
+    if let Some(framer) = &builder.raw {
+        return raw::connect(url, framer, builder, deadline).await;
+    }
+
+    let request: Request = build_request(url, builder, refresh).await?;
+    let (transport, peer_addr, deadline) = open(url, builder, deadline.clone()).await?;
+
+    let (inner, response) = match transport {
+        #[cfg(feature = "deflate")]
+        transport if builder.compression.is_some() => {
+            let (stream, response) = Box::pin(deflate::handshake(
+                url,
+                request,
+                transport.boxed(),
+                builder,
+                &deadline,
+            ))
+            .await?;
+            (InnerWebSocket::Deflate(stream), response)
+        }
+        RawStream::Tcp(conn) => {
+            let (stream, response) = handshake(url, request, conn, builder, &deadline).await?;
+            (InnerWebSocket::Tokio(stream), response)
+        }
+        #[cfg(unix)]
+        RawStream::Unix(conn) => {
+            let (stream, response) = handshake(url, request, conn, builder, &deadline).await?;
+            (InnerWebSocket::Unix(stream), response)
+        }
+        #[cfg(feature = "tor")]
+        RawStream::Tor(conn) => {
+            let (stream, response) = handshake(url, request, conn, builder, &deadline).await?;
+            (InnerWebSocket::Tor(stream), response)
+        }
+        RawStream::Custom(conn) => {
+            let (stream, response) = handshake(url, request, conn, builder, &deadline).await?;
+            (InnerWebSocket::Custom(stream), response)
+        }
+    };
+
+    Ok((
+        WebSocket::new(inner, protocol(&response)).with_peer_addr(peer_addr),
+        HandshakeResponse::from(&response),
+    ))
+}
+
+/// Connection to the server (or to the proxy tunnel), before the TLS and WebSocket handshakes
+enum RawStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "tor")]
+    Tor(DataStream),
+    Custom(Box<dyn IoStream>),
+}
+
+impl RawStream {
+    /// Erase the stream type
+    fn boxed(self) -> Box<dyn IoStream> {
+        match self {
+            Self::Tcp(conn) => Box::new(conn),
+            #[cfg(unix)]
+            Self::Unix(conn) => Box::new(conn),
+            #[cfg(feature = "tor")]
+            Self::Tor(conn) => Box::new(conn),
+            Self::Custom(conn) => conn,
+        }
+    }
+}
+
+/// Open the transport of the connection mode, within `deadline`
+///
+/// Returns also the peer address and the deadline of the next phases.
+async fn open(
+    url: &Url,
+    builder: &WebSocketBuilder,
+    deadline: Deadline,
+) -> Result<(RawStream, Option<SocketAddr>, Deadline), ConnectError> {
+    match &builder.mode {
+        ConnectionMode::Direct => open_direct(url, builder, deadline).await,
+        ConnectionMode::Auto => open_auto(url, builder, deadline).await,
+        #[cfg(feature = "socks")]
+        ConnectionMode::Proxy { addr, options } => {
+            open_proxy(url, *addr, options, builder, deadline).await
+        }
         #[cfg(feature = "socks")]
-        ConnectionMode::Proxy(proxy) => connect_proxy(url, *proxy, timeout).await,
+        ConnectionMode::TorSocks { addr } => open_tor_socks(url, *addr, builder, deadline).await,
+        ConnectionMode::HttpProxy { addr, auth } => {
+            open_http_proxy(url, *addr, auth.as_ref(), builder, deadline).await
+        }
+        #[cfg(unix)]
+        ConnectionMode::UnixSocket { path } => open_unix(path, deadline).await,
+        ConnectionMode::Custom { transport } => open_custom(url, transport, deadline).await,
         #[cfg(feature = "tor")]
-        ConnectionMode::Tor { custom_path } => {
-            connect_tor(url, timeout, custom_path.as_ref()).await
+        ConnectionMode::Tor {
+            custom_path,
+            client,
+            options,
+        } => {
+            open_tor(
+                url,
+                custom_path.as_ref(),
+                client.as_ref(),
+                options,
+                deadline,
+            )
+            .await
+        }
+    }
+}
+
+/// Subprotocol accepted by the server, if any
+fn protocol(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|p| p.to_str().ok())
+        .map(|p| p.to_string())
+}
+
+/// Build the handshake request, including the custom headers
+///
+/// `refresh` is passed to the [`AuthProvider`], if any.
+async fn build_request(
+    url: &Url,
+    builder: &WebSocketBuilder,
+    refresh: bool,
+) -> Result<Request, ConnectError> {
+    let mut request: Request = url.as_str().into_client_request()?;
+
+    let headers: &mut HeaderMap = request.headers_mut();
+    for (name, value) in builder.headers.iter() {
+        let name: HeaderName = HeaderName::from_bytes(name.as_bytes()).map_err(WsError::from)?;
+        let value: HeaderValue = HeaderValue::from_str(value).map_err(WsError::from)?;
+        headers.append(name, value);
+    }
+
+    if let Some(auth) = &builder.auth {
+        let authorization: String = auth.authorization(refresh).await?;
+        let value: HeaderValue = HeaderValue::from_str(&authorization).map_err(WsError::from)?;
+        headers.insert(AUTHORIZATION, value);
+    }
+
+    #[cfg(feature = "cookies")]
+    if let Some(cookies) = builder.cookie_jar.as_ref().and_then(|jar| jar.header(url)) {
+        let value: HeaderValue = HeaderValue::from_str(&cookies).map_err(WsError::from)?;
+        headers.append(COOKIE, value);
+    }
+
+    if !builder.protocols.is_empty() {
+        let protocols: String = builder.protocols.join(", ");
+        let value: HeaderValue = HeaderValue::from_str(&protocols).map_err(WsError::from)?;
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, value);
+    }
+
+    if !builder.ws_extensions.is_empty() {
+        let extensions: String = builder.ws_extensions.join(", ");
+        let value: HeaderValue = HeaderValue::from_str(&extensions).map_err(WsError::from)?;
+        headers.insert(SEC_WEBSOCKET_EXTENSIONS, value);
+    }
+
+    if let Some(hook) = &builder.request_hook {
+        request = hook.call(request);
+    }
+
+    Ok(request)
+}
+
+/// Connection deadline
+///
+/// Bounds each phase with its own timeout and with the overall timeout.
+#[derive(Clone)]
+struct Deadline {
+    /// `None` if the overall timeout overflows
+    at: Option<Instant>,
+    timeouts: Timeouts,
+}
+
+impl Deadline {
+    fn new(timeout: Duration, timeouts: Timeouts) -> Self {
+        Self {
+            at: Instant::now().checked_add(timeout),
+            timeouts,
+        }
+    }
+
+    /// Extend the overall timeout and the TCP phase timeout to at least `min` from now
+    #[cfg(feature = "socks")]
+    fn at_least(&self, min: Duration) -> Self {
+        let mut timeouts: Timeouts = self.timeouts;
+        timeouts.tcp = timeouts.tcp.map(|t| t.max(min));
+
+        let at: Option<Instant> = match (self.at, Instant::now().checked_add(min)) {
+            (Some(at), Some(min_at)) => Some(at.max(min_at)),
+            _ => None,
+        };
+
+        Self { at, timeouts }
+    }
+
+    /// Run a phase
+    async fn run<F>(&self, phase: Phase, future: F) -> Result<F::Output, ConnectError>
+    where
+        F: Future,
+    {
+        let phase_at: Option<Instant> = self
+            .timeouts
+            .get(phase)
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        let at: Option<Instant> = match (self.at, phase_at) {
+            (Some(at), Some(phase_at)) => Some(at.min(phase_at)),
+            (at, phase_at) => at.or(phase_at),
+        };
+
+        match at {
+            Some(at) => time::timeout_at(at, future)
+                .await
+                .map_err(|_| ConnectError::Timeout(phase)),
+            None => Ok(future.await),
         }
     }
 }
 
-async fn connect_direct(url: &Url, timeout: Duration) -> Result<WebSocket, Error> {
+impl From<&WebSocketBuilder> for Deadline {
+    fn from(builder: &WebSocketBuilder) -> Self {
+        Self::new(builder.timeout, builder.timeouts)
+    }
+}
+
+/// Resolve `host`, with the custom resolver (if any) or the system one
+async fn resolve(
+    host: &str,
+    port: u16,
+    builder: &WebSocketBuilder,
+    deadline: &Deadline,
+) -> Result<Vec<SocketAddr>, ConnectError> {
+    // Strip brackets of IPv6 addresses
+    if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    let addrs: Vec<SocketAddr> = match &builder.resolver {
+        Some(resolver) => deadline
+            .run(Phase::Dns, resolver.resolve(host, port))
+            .await?
+            .map_err(ConnectError::Dns)?,
+        None => deadline
+            .run(Phase::Dns, lookup_host((host, port)))
+            .await?
+            .map_err(ConnectError::Dns)?
+            .collect(),
+    };
+
+    if addrs.is_empty() {
+        return Err(ConnectError::unresolved_host());
+    }
+
+    Ok(addrs)
+}
+
+/// WebSocket protocol config, with the [`Limits`](crate::Limits) applied
+fn ws_config(builder: &WebSocketBuilder) -> WebSocketConfig {
+    let mut config: WebSocketConfig = builder.config.unwrap_or_default();
+
+    if let Some(max) = builder.limits.max_message_size {
+        config.max_message_size = Some(config.max_message_size.map_or(max, |m| m.min(max)));
+    }
+
+    config
+}
+
+/// Perform the TLS (if required) and WebSocket handshakes over `conn`
+async fn handshake<S>(
+    url: &Url,
+    request: Request,
+    conn: S,
+    builder: &WebSocketBuilder,
+    deadline: &Deadline,
+) -> Result<(WsStream<S>, Response), ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let conn: MaybeTlsStream<S> = deadline.run(Phase::Tls, tls(url, conn, builder)).await??;
+
+    let fut =
+        tokio_tungstenite::client_async_with_config(request, conn, Some(ws_config(builder).into()));
+
     // NOT REMOVE `Box::pin`!
     // Use `Box::pin` to fix stack overflow on windows targets due to large `Future`
-    let (stream, _) = Box::pin(time::timeout(
-        timeout,
-        tokio_tungstenite::connect_async(url.as_str()),
-    ))
-    .await
-    .map_err(|_| Error::Timeout)??;
-    Ok(WebSocket::Tokio(stream))
+    Ok(Box::pin(deadline.run(Phase::Handshake, fut)).await??)
+}
+
+/// Perform the TLS (if required) and WebSocket handshakes over `conn`, without timeout
+#[cfg(feature = "futures-io")]
+async fn handshake_without_timeout<S>(
+    url: &Url,
+    request: Request,
+    conn: S,
+    builder: &WebSocketBuilder,
+) -> Result<(WsStream<S>, Response), ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let conn: MaybeTlsStream<S> = tls(url, conn, builder).await?;
+    Ok(
+        tokio_tungstenite::client_async_with_config(request, conn, Some(ws_config(builder).into()))
+            .await?,
+    )
+}
+
+/// Perform the TLS handshake over `conn`, if required by the URL scheme (`wss`, or `tls` for the raw streams)
+#[cfg_attr(
+    not(any(feature = "tls-rustls", feature = "tls-native")),
+    allow(unused_variables)
+)]
+async fn tls<S>(
+    url: &Url,
+    conn: S,
+    builder: &WebSocketBuilder,
+) -> Result<MaybeTlsStream<S>, ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if !matches!(url.scheme(), "wss" | "tls") {
+        return Ok(MaybeTlsStream::Plain(conn));
+    }
+
+    #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+    {
+        let host: &str = url.host_str().ok_or_else(ConnectError::empty_host)?;
+        let stream: MaybeTlsStream<S> = match &builder.tls {
+            Some(tls) => tls.wrap_stream(host, conn).await?,
+            None => TlsConfig::default().wrap_stream(host, conn).await?,
+        };
+        Ok(stream)
+    }
+
+    #[cfg(not(any(feature = "tls-rustls", feature = "tls-native")))]
+    {
+        Err(ConnectError::from(WsError::Url(
+            UrlError::TlsFeatureNotEnabled,
+        )))
+    }
+}
+
+async fn open_direct(
+    url: &Url,
+    builder: &WebSocketBuilder,
+    deadline: Deadline,
+) -> Result<(RawStream, Option<SocketAddr>, Deadline), ConnectError> {
+    let host: &str = url.host_str().ok_or_else(ConnectError::empty_host)?;
+    let port: u16 = url
+        .port_or_known_default()
+        .ok_or_else(ConnectError::invalid_port)?;
+
+    let mut addrs: Vec<SocketAddr> = resolve(host, port, builder, &deadline).await?;
+
+    // A socket bound to a local address can connect only to the same address family
+    if let Some(local_addr) = builder.local_addr {
+        addrs.retain(|addr| addr.is_ipv4() == local_addr.is_ipv4());
+    }
+
+    let conn: TcpStream = deadline
+        .run(Phase::Tcp, happy_eyeballs::connect(&addrs, builder))
+        .await?
+        .map_err(WsError::Io)?;
+    let peer_addr: Option<SocketAddr> = conn.peer_addr().ok();
+    Ok((RawStream::Tcp(conn), peer_addr, deadline))
 }
 
 #[cfg(feature = "socks")]
-async fn connect_proxy(
+async fn open_proxy(
     url: &Url,
     proxy: SocketAddr,
-    timeout: Duration,
-) -> Result<WebSocket, Error> {
-    let host: &str = url.host_str().ok_or_else(Error::empty_host)?;
+    options: &ProxyOptions,
+    builder: &WebSocketBuilder,
+    deadline: Deadline,
+) -> Result<(RawStream, Option<SocketAddr>, Deadline), ConnectError> {
+    let host: &str = url.host_str().ok_or_else(ConnectError::empty_host)?;
     let port: u16 = url
         .port_or_known_default()
-        .ok_or_else(Error::invalid_port)?;
-    let addr: String = format!("{host}:{port}");
+        .ok_or_else(ConnectError::invalid_port)?;
 
-    let conn: TcpStream = TcpSocks5Stream::connect(proxy, addr).await?;
-    // NOT REMOVE `Box::pin`!
-    // Use `Box::pin` to fix stack overflow on windows targets due to large `Future`
-    let (stream, _) = Box::pin(time::timeout(
-        timeout,
-        tokio_tungstenite::client_async_tls(url.as_str(), conn),
-    ))
-    .await
-    .map_err(|_| Error::Timeout)??;
-    Ok(WebSocket::Tokio(stream))
+    let conn: TcpStream = if options.remote_dns {
+        // The domain is sent to the proxy, that resolves it
+        deadline
+            .run(
+                Phase::Tcp,
+                TcpSocks5Stream::connect(proxy, (host, port), builder),
+            )
+            .await??
+    } else {
+        let addr: SocketAddr = resolve(host, port, builder, &deadline).await?[0];
+        deadline
+            .run(Phase::Tcp, TcpSocks5Stream::connect(proxy, addr, builder))
+            .await??
+    };
+    let peer_addr: Option<SocketAddr> = conn.peer_addr().ok();
+    Ok((RawStream::Tcp(conn), peer_addr, deadline))
+}
+
+#[cfg(feature = "socks")]
+async fn open_tor_socks(
+    url: &Url,
+    proxy: SocketAddr,
+    builder: &WebSocketBuilder,
+    deadline: Deadline,
+) -> Result<(RawStream, Option<SocketAddr>, Deadline), ConnectError> {
+    let host: &str = url.host_str().ok_or_else(ConnectError::empty_host)?;
+    let port: u16 = url
+        .port_or_known_default()
+        .ok_or_else(ConnectError::invalid_port)?;
+
+    // Building a circuit to an onion service is slow
+    let deadline: Deadline = if host.ends_with(".onion") {
+        if !socks::is_valid_onion(host) {
+            return Err(ConnectError::InvalidOnion(host.to_string()));
+        }
+
+        deadline.at_least(TOR_SOCKS_ONION_MIN_TIMEOUT)
+    } else {
+        deadline
+    };
+
+    // Never resolve locally: the domain is sent to the Tor daemon
+    let conn: TcpStream = deadline
+        .run(
+            Phase::Tcp,
+            TcpSocks5Stream::connect(proxy, (host, port), builder),
+        )
+        .await??;
+    Ok((RawStream::Tcp(conn), Some(proxy), deadline))
+}
+
+async fn open_http_proxy(
+    url: &Url,
+    proxy: SocketAddr,
+    auth: Option<&ProxyAuth>,
+    builder: &WebSocketBuilder,
+    deadline: Deadline,
+) -> Result<(RawStream, Option<SocketAddr>, Deadline), ConnectError> {
+    let host: &str = url.host_str().ok_or_else(ConnectError::empty_host)?;
+    let port: u16 = url
+        .port_or_known_default()
+        .ok_or_else(ConnectError::invalid_port)?;
+
+    let conn: TcpStream = deadline
+        .run(
+            Phase::Tcp,
+            TcpHttpProxyStream::connect(proxy, host, port, auth, builder),
+        )
+        .await??;
+    let peer_addr: Option<SocketAddr> = conn.peer_addr().ok();
+    Ok((RawStream::Tcp(conn), peer_addr, deadline))
+}
+
+#[cfg(unix)]
+async fn open_unix(
+    path: &Path,
+    deadline: Deadline,
+) -> Result<(RawStream, Option<SocketAddr>, Deadline), ConnectError> {
+    let conn: UnixStream = deadline
+        .run(Phase::Tcp, UnixStream::connect(path))
+        .await?
+        .map_err(WsError::Io)?;
+    Ok((RawStream::Unix(conn), None, deadline))
+}
+
+async fn open_custom(
+    url: &Url,
+    transport: &CustomTransport,
+    deadline: Deadline,
+) -> Result<(RawStream, Option<SocketAddr>, Deadline), ConnectError> {
+    let host: &str = url.host_str().ok_or_else(ConnectError::empty_host)?;
+    let port: u16 = url
+        .port_or_known_default()
+        .ok_or_else(ConnectError::invalid_port)?;
+
+    let conn: Box<dyn IoStream> = deadline
+        .run(Phase::Tcp, transport.connect(host, port))
+        .await?
+        .map_err(WsError::Io)?;
+    Ok((RawStream::Custom(conn), None, deadline))
+}
+
+/// Open with the embedded tor client for the `.onion` hosts, direct for the others
+async fn open_auto(
+    url: &Url,
+    builder: &WebSocketBuilder,
+    deadline: Deadline,
+) -> Result<(RawStream, Option<SocketAddr>, Deadline), ConnectError> {
+    let is_onion: bool = url.host_str().is_some_and(|host| host.ends_with(".onion"));
+
+    if !is_onion {
+        return open_direct(url, builder, deadline).await;
+    }
+
+    #[cfg(feature = "tor")]
+    {
+        open_tor(url, None, None, &TorOptions::default(), deadline).await
+    }
+
+    #[cfg(not(feature = "tor"))]
+    {
+        Err(ConnectError::TorUnavailable)
+    }
 }
 
 #[cfg(feature = "tor")]
-async fn connect_tor(
+async fn open_tor(
     url: &Url,
-    timeout: Duration,
     custom_path: Option<&PathBuf>,
-) -> Result<WebSocket, Error> {
-    let host: &str = url.host_str().ok_or_else(Error::empty_host)?;
+    client: Option<&TorClientHandle>,
+    options: &TorOptions,
+    deadline: Deadline,
+) -> Result<(RawStream, Option<SocketAddr>, Deadline), ConnectError> {
+    let host: &str = url.host_str().ok_or_else(ConnectError::empty_host)?;
     let port: u16 = url
         .port_or_known_default()
-        .ok_or_else(Error::invalid_port)?;
+        .ok_or_else(ConnectError::invalid_port)?;
 
-    let conn: DataStream = tor::connect(host, port, custom_path).await?;
-    // NOT REMOVE `Box::pin`!
-    // Use `Box::pin` to fix stack overflow on windows targets due to large `Future`
-    let (stream, _) = Box::pin(time::timeout(
-        timeout,
-        tokio_tungstenite::client_async_tls(url.as_str(), conn),
-    ))
-    .await
-    .map_err(|_| Error::Timeout)??;
-    Ok(WebSocket::Tor(stream))
+    // The circuit building isn't bounded (the client may need to bootstrap)
+    let conn: DataStream = tor::connect(host, port, custom_path, client, options).await?;
+    Ok((RawStream::Tor(conn), None, deadline))
+}
+
+#[inline]
+pub async fn accept<S>(raw_stream: S) -> Result<WebSocketStream<S>, ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    accept_with_config(raw_stream, None).await
 }
 
+/// Accept an incoming connection with a custom [`WebSocketConfig`]
 #[inline]
-pub async fn accept<S>(raw_stream: S) -> Result<WebSocketStream<S>, Error>
+pub async fn accept_with_config<S>(
+    raw_stream: S,
+    config: Option<WebSocketConfig>,
+) -> Result<WebSocketStream<S>, ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    Ok(tokio_tungstenite::accept_async_with_config(raw_stream, config.map(Into::into)).await?)
+}
+
+/// Accept an incoming connection over TLS (`wss://`)
+#[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+pub async fn accept_tls<S>(
+    raw_stream: S,
+    acceptor: &TlsAcceptor,
+) -> Result<WebSocketStream<ServerTlsStream<S>>, ConnectError>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    Ok(tokio_tungstenite::accept_async(raw_stream).await?)
+    let stream: ServerTlsStream<S> = acceptor.accept(raw_stream).await?;
+    accept(stream).await
+}
+
+/// Perform only the client WebSocket handshake over an already established `stream` (plain or TLS)
+///
+/// Counterpart of [`take_upgraded`] for the client role.
+/// Check [`WebSocketBuilder::client_from_stream`] for more details.
+#[inline]
+pub async fn client_from_stream<S>(
+    url: &Url,
+    stream: S,
+) -> Result<(WebSocket, HandshakeResponse), ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    WebSocketBuilder::new()
+        .client_from_stream(url, stream)
+        .await
+}
+
+/// Perform the client WebSocket handshake over `stream`, without TLS
+pub(crate) async fn client_handshake<S>(
+    url: &Url,
+    stream: S,
+    builder: &WebSocketBuilder,
+) -> Result<(WebSocket, HandshakeResponse), ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let request: Request = build_request(url, builder, false).await?;
+    let stream: Box<dyn IoStream> = Box::new(stream);
+    let deadline: Deadline = Deadline::from(builder);
+
+    #[cfg(feature = "deflate")]
+    if let Some(compression) = builder.compression {
+        let stream = deflate::DeflateStream::new(
+            stream,
+            Role::Client,
+            compression,
+            Some(ws_config(builder)),
+        );
+        let (stream, response) = Box::pin(deflate::handshake_stream(
+            request, stream, builder, &deadline,
+        ))
+        .await?;
+        return Ok((
+            WebSocket::new(InnerWebSocket::Deflate(stream), protocol(&response)),
+            HandshakeResponse::from(&response),
+        ));
+    }
+
+    let fut = tokio_tungstenite::client_async_with_config(
+        request,
+        MaybeTlsStream::Plain(stream),
+        Some(ws_config(builder).into()),
+    );
+    let (stream, response) = Box::pin(deadline.run(Phase::Handshake, fut)).await??;
+
+    Ok((
+        WebSocket::new(InnerWebSocket::Custom(stream), protocol(&response)),
+        HandshakeResponse::from(&response),
+    ))
 }
 
 /// Take an already upgraded websocket connection
@@ -123,5 +897,128 @@ pub async fn take_upgraded<S>(raw_stream: S) -> WebSocketStream<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    WebSocketStream::from_raw_socket(raw_stream, Role::Server, None).await
+    take_upgraded_with_config(raw_stream, None).await
+}
+
+/// Take an already upgraded websocket connection, with a custom [`WebSocketConfig`]
+#[inline]
+pub async fn take_upgraded_with_config<S>(
+    raw_stream: S,
+    config: Option<WebSocketConfig>,
+) -> WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    WebSocketStream::from_raw_socket(raw_stream, Role::Server, config.map(Into::into)).await
+}
+
+/// New in-process connection pair, without binding any port
+///
+/// Returns the client and the server sockets, connected through an in-memory pipe
+/// with the full WebSocket framing (masking, fragmentation, control frames).
+/// Useful to test protocol logic end-to-end.
+#[inline]
+pub async fn duplex() -> (WebSocket, WebSocket) {
+    duplex_with_config(None).await
+}
+
+/// New in-process connection pair, with a custom [`WebSocketConfig`]
+///
+/// Check [`duplex`] for more details.
+pub async fn duplex_with_config(config: Option<WebSocketConfig>) -> (WebSocket, WebSocket) {
+    let (client, server) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+    let client = WebSocketStream::from_raw_socket(client, Role::Client, config.map(Into::into));
+    let server = WebSocketStream::from_raw_socket(server, Role::Server, config.map(Into::into));
+    let (client, server) = futures_util::future::join(client, server).await;
+    (
+        WebSocket::new(InnerWebSocket::Duplex(client), None),
+        WebSocket::new(InnerWebSocket::Duplex(server), None),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirect(location: &str) -> HandshakeRejection {
+        HandshakeRejection {
+            status: 302,
+            headers: vec![(String::from("Location"), location.to_string())],
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_redirect_location() {
+        let url = Url::parse("wss://example.com/ws").unwrap();
+
+        assert_eq!(
+            redirect_location(&url, &redirect("https://example.org/ws")),
+            Some(Url::parse("wss://example.org/ws").unwrap())
+        );
+        assert_eq!(
+            redirect_location(&url, &redirect("/other")),
+            Some(Url::parse("wss://example.com/other").unwrap())
+        );
+
+        // Downgrade
+        assert_eq!(
+            redirect_location(&url, &redirect("ws://example.com/ws")),
+            None
+        );
+        assert_eq!(
+            redirect_location(&url, &redirect("http://example.com/ws")),
+            None
+        );
+
+        // Not a redirect
+        let mut rejection = redirect("wss://example.org/ws");
+        rejection.status = 403;
+        assert_eq!(redirect_location(&url, &rejection), None);
+    }
+
+    #[test]
+    fn test_without_credentials() {
+        let builder = WebSocketBuilder::new()
+            .header("Authorization", "Bearer token")
+            .header("cookie", "a=b")
+            .header("User-Agent", "test")
+            .on_request(|request| request);
+        let builder = without_credentials(&builder);
+        assert_eq!(
+            builder.headers,
+            vec![(String::from("User-Agent"), String::from("test"))]
+        );
+        assert!(builder.auth.is_none());
+        assert!(builder.request_hook.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_redirects_share_deadline() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        // Every hop is slow and redirects to a new path
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}/0", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            for hop in 1..=10 {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = conn.read(&mut buf).await.unwrap();
+                time::sleep(Duration::from_millis(400)).await;
+                let response =
+                    format!("HTTP/1.1 302 Found\r\nLocation: /{hop}\r\nContent-Length: 0\r\n\r\n");
+                conn.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let builder = WebSocketBuilder::new()
+            .timeout(Duration::from_secs(1))
+            .follow_redirects(10);
+        let start = Instant::now();
+        let res = Box::pin(connect(&url, &builder)).await;
+        assert!(matches!(res, Err(ConnectError::Timeout(Phase::Handshake))));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
 }
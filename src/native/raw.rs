@@ -0,0 +1,475 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Raw TCP framing, without the WebSocket handshake
+//!
+//! For the servers exposing plain TCP protocols (ex. newline-delimited JSON):
+//! the messages are split by a [`Framer`] instead of the WebSocket framing.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BytesMut};
+use futures_util::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::MaybeTlsStream;
+use url::Url;
+
+use super::{open, tls, ws_config, ConnectError, Deadline, Error, IoStream};
+use crate::message::Bytes;
+use crate::socket::{InnerWebSocket, WebSocket};
+use crate::{HandshakeResponse, Message, Phase, WebSocketBuilder};
+
+/// Size of the read chunks
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Buffered outgoing bytes that trigger a flush before accepting a new message
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Max buffers of a vectored write
+const MAX_IO_SLICES: usize = 64;
+
+/// Message framing over a raw stream
+pub trait Framer: Send + Sync {
+    /// Decode a message from the start of `src`, consuming its bytes
+    ///
+    /// Returns `None` if more bytes are needed.
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Message>, Error>;
+
+    /// Encode a text or binary `message`, appending it to `dst`
+    fn encode(&self, message: Message, dst: &mut BytesMut) -> Result<(), Error>;
+}
+
+/// Newline-delimited messages
+///
+/// Lines are decoded as text messages, without the trailing `\n` (or `\r\n`).
+/// Text and binary messages are encoded followed by `\n`, so they must not contain it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineFramer;
+
+impl Framer for LineFramer {
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Message>, Error> {
+        let Some(pos) = src.iter().position(|b| *b == b'\n') else {
+            return Ok(None);
+        };
+
+        let mut line: BytesMut = src.split_to(pos + 1);
+        line.truncate(pos);
+        if line.ends_with(b"\r") {
+            line.truncate(pos - 1);
+        }
+
+        let text: String = String::from_utf8(line.to_vec()).map_err(|_| Error::InvalidUtf8)?;
+        Ok(Some(Message::Text(text.into())))
+    }
+
+    fn encode(&self, message: Message, dst: &mut BytesMut) -> Result<(), Error> {
+        let payload: &[u8] = match &message {
+            Message::Text(text) => text.as_ref(),
+            Message::Binary(data) => data,
+            _ => return Ok(()),
+        };
+
+        if payload.contains(&b'\n') {
+            return Err(Error::framing("newline in a newline-delimited message"));
+        }
+
+        dst.extend_from_slice(payload);
+        dst.extend_from_slice(b"\n");
+        Ok(())
+    }
+}
+
+/// Length-prefixed messages
+///
+/// Each message is prefixed by its length, as a big-endian `u32`, and decoded as binary message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LengthPrefixedFramer;
+
+impl Framer for LengthPrefixedFramer {
+    fn decode(&self, src: &mut BytesMut) -> Result<Option<Message>, Error> {
+        let Some(prefix) = src.get(..4) else {
+            return Ok(None);
+        };
+
+        let len: usize = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+        if src.len() < 4 + len {
+            return Ok(None);
+        }
+
+        src.advance(4);
+        let data: Bytes = src.split_to(len).freeze();
+        Ok(Some(Message::Binary(data)))
+    }
+
+    fn encode(&self, message: Message, dst: &mut BytesMut) -> Result<(), Error> {
+        let payload: &[u8] = match &message {
+            Message::Text(text) => text.as_ref(),
+            Message::Binary(data) => data,
+            _ => return Ok(()),
+        };
+
+        let len: u32 = u32::try_from(payload.len())
+            .map_err(|_| Error::framing("message longer than u32::MAX"))?;
+        dst.extend_from_slice(&len.to_be_bytes());
+        dst.extend_from_slice(payload);
+        Ok(())
+    }
+}
+
+/// Raw stream framer
+///
+/// Check [`WebSocketBuilder::raw`](crate::WebSocketBuilder::raw) for more details.
+///
+/// The framer is cheap to clone: all the clones refer to the same [`Framer`].
+#[derive(Clone)]
+pub struct RawFramer {
+    inner: Arc<dyn Framer>,
+}
+
+impl fmt::Debug for RawFramer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawFramer").finish()
+    }
+}
+
+impl PartialEq for RawFramer {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for RawFramer {}
+
+impl RawFramer {
+    /// New raw framer
+    #[inline]
+    pub fn new<F>(framer: F) -> Self
+    where
+        F: Framer + 'static,
+    {
+        Self {
+            inner: Arc::new(framer),
+        }
+    }
+
+    /// Newline-delimited messages
+    ///
+    /// Check [`LineFramer`].
+    #[inline]
+    pub fn lines() -> Self {
+        Self::new(LineFramer)
+    }
+
+    /// Length-prefixed messages
+    ///
+    /// Check [`LengthPrefixedFramer`].
+    #[inline]
+    pub fn length_prefixed() -> Self {
+        Self::new(LengthPrefixedFramer)
+    }
+}
+
+/// Open the transport of the connection mode and the TLS connection (for `wss` and `tls` URLs),
+/// skipping the WebSocket handshake
+pub(super) async fn connect(
+    url: &Url,
+    framer: &RawFramer,
+    builder: &WebSocketBuilder,
+    deadline: &Deadline,
+) -> Result<(WebSocket, HandshakeResponse), ConnectError> {
+    let (transport, peer_addr, deadline) = open(url, builder, deadline.clone()).await?;
+    let conn: MaybeTlsStream<Box<dyn IoStream>> = deadline
+        .run(Phase::Tls, tls(url, transport.boxed(), builder))
+        .await??;
+
+    let socket: RawSocket = RawSocket {
+        io: Box::new(conn),
+        framer: framer.clone(),
+        max_size: ws_config(builder).max_message_size,
+        read_buf: BytesMut::new(),
+        write_bufs: VecDeque::new(),
+        queued: 0,
+        eof: false,
+    };
+
+    Ok((
+        WebSocket::new(InnerWebSocket::Raw(socket), None).with_peer_addr(peer_addr),
+        HandshakeResponse {
+            status: 0,
+            headers: Vec::new(),
+        },
+    ))
+}
+
+/// Messages over a raw stream, split by a [`Framer`]
+pub(crate) struct RawSocket {
+    io: Box<dyn IoStream>,
+    framer: RawFramer,
+    /// Max size of an incoming message
+    max_size: Option<usize>,
+    read_buf: BytesMut,
+    /// Encoded outgoing messages, one buffer each
+    write_bufs: VecDeque<Bytes>,
+    /// Bytes of `write_bufs`
+    queued: usize,
+    /// The peer closed the stream
+    eof: bool,
+}
+
+impl RawSocket {
+    /// Write the buffered messages, together with a vectored write if supported by the stream
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while let Some(front) = self.write_bufs.front() {
+            let io = Pin::new(&mut self.io);
+            let n: usize = if io.is_write_vectored() {
+                let slices: Vec<IoSlice<'_>> = self
+                    .write_bufs
+                    .iter()
+                    .take(MAX_IO_SLICES)
+                    .map(|buf| IoSlice::new(buf))
+                    .collect();
+                ready!(io.poll_write_vectored(cx, &slices))
+            } else {
+                ready!(io.poll_write(cx, front))
+            }
+            .map_err(io_error)?;
+
+            if n == 0 {
+                return Poll::Ready(Err(io_error(io::Error::from(io::ErrorKind::WriteZero))));
+            }
+
+            self.advance(n);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Drop the first `n` written bytes
+    fn advance(&mut self, mut n: usize) {
+        self.queued -= n;
+
+        while let Some(front) = self.write_bufs.front_mut() {
+            if n < front.len() {
+                front.advance(n);
+                return;
+            }
+
+            n -= front.len();
+            self.write_bufs.pop_front();
+        }
+    }
+}
+
+impl Sink<Message> for RawSocket {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+
+        if this.queued >= WRITE_BUFFER_SIZE {
+            ready!(this.poll_write_buf(cx))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+
+        match item {
+            Message::Text(..) | Message::Binary(..) => {
+                let mut buf: BytesMut = BytesMut::new();
+                this.framer.inner.encode(item, &mut buf)?;
+
+                if !buf.is_empty() {
+                    this.queued += buf.len();
+                    this.write_bufs.push_back(buf.freeze());
+                }
+
+                Ok(())
+            }
+            // No equivalent over a raw stream
+            Message::Ping(..) | Message::Pong(..) | Message::Close(..) => Ok(()),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Poll::Ready(ready!(Pin::new(&mut this.io).poll_flush(cx)).map_err(io_error))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Poll::Ready(ready!(Pin::new(&mut this.io).poll_shutdown(cx)).map_err(io_error))
+    }
+}
+
+impl Stream for RawSocket {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            match this.framer.inner.decode(&mut this.read_buf) {
+                Ok(Some(message)) => return Poll::Ready(Some(Ok(message))),
+                Ok(None) => {}
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+
+            if let Some(max_size) = this.max_size {
+                if this.read_buf.len() > max_size {
+                    return Poll::Ready(Some(Err(Error::MessageTooLarge {
+                        size: this.read_buf.len(),
+                        max_size,
+                    })));
+                }
+            }
+
+            if this.eof {
+                return Poll::Ready(None);
+            }
+
+            let mut chunk = [0u8; READ_CHUNK_SIZE];
+            let mut buf: ReadBuf<'_> = ReadBuf::new(&mut chunk);
+            if let Err(e) = ready!(Pin::new(&mut this.io).poll_read(cx, &mut buf)) {
+                return Poll::Ready(Some(Err(io_error(e))));
+            }
+
+            if buf.filled().is_empty() {
+                this.eof = true;
+            } else {
+                this.read_buf.extend_from_slice(buf.filled());
+            }
+        }
+    }
+}
+
+#[inline]
+fn io_error(e: io::Error) -> Error {
+    Error::from(WsError::Io(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures_util::SinkExt;
+
+    use super::*;
+
+    /// Stream writing at most `max` bytes per call
+    struct Partial {
+        written: Arc<Mutex<Vec<u8>>>,
+        writes: Arc<Mutex<usize>>,
+        max: usize,
+        vectored: bool,
+    }
+
+    impl AsyncRead for Partial {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for Partial {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            let mut written = self.written.lock().unwrap_or_else(|e| e.into_inner());
+            let mut n: usize = 0;
+            for buf in bufs {
+                let len: usize = buf.len().min(self.max - n);
+                written.extend_from_slice(&buf[..len]);
+                n += len;
+            }
+            *self.writes.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+            Poll::Ready(Ok(n))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            self.vectored
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Feed the messages and flush once, returning the written bytes and the number of writes
+    async fn write(messages: &[&str], max: usize, vectored: bool) -> (String, usize) {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let writes = Arc::new(Mutex::new(0));
+        let mut socket = RawSocket {
+            io: Box::new(Partial {
+                written: written.clone(),
+                writes: writes.clone(),
+                max,
+                vectored,
+            }),
+            framer: RawFramer::new(LineFramer),
+            max_size: None,
+            read_buf: BytesMut::new(),
+            write_bufs: VecDeque::new(),
+            queued: 0,
+            eof: false,
+        };
+
+        for msg in messages {
+            socket.feed(Message::text(*msg)).await.unwrap();
+        }
+        socket.flush().await.unwrap();
+        assert_eq!(socket.queued, 0);
+
+        let written = written.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let writes = *writes.lock().unwrap_or_else(|e| e.into_inner());
+        (String::from_utf8(written).unwrap(), writes)
+    }
+
+    #[tokio::test]
+    async fn test_vectored_write() {
+        // A single write for all the messages
+        assert_eq!(
+            write(&["a", "bc", "def"], usize::MAX, true).await,
+            (String::from("a\nbc\ndef\n"), 1)
+        );
+
+        // Partial writes resume in the middle of a message
+        assert_eq!(
+            write(&["a", "bc", "def"], 3, true).await,
+            (String::from("a\nbc\ndef\n"), 3)
+        );
+
+        // One message per write
+        assert_eq!(
+            write(&["a", "bc", "def"], usize::MAX, false).await,
+            (String::from("a\nbc\ndef\n"), 3)
+        );
+    }
+}
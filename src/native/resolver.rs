@@ -0,0 +1,113 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! DNS resolver
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+#[cfg(feature = "hickory-dns")]
+use hickory_resolver::TokioAsyncResolver;
+
+/// DNS resolution
+///
+/// Implement it to resolve the hostnames with DoH/DoT, pin the IPs, apply split-horizon DNS, etc.
+pub trait Resolve: Send + Sync {
+    /// Resolve `host` to the socket addresses to connect to
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>>;
+}
+
+#[cfg(feature = "hickory-dns")]
+impl Resolve for TokioAsyncResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move {
+            let lookup = self
+                .lookup_ip(host)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            Ok(lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+        })
+    }
+}
+
+/// Custom DNS resolver
+///
+/// Replaces the system resolver when the hostname is resolved locally:
+/// it's not used when the DNS is resolved by the proxy or by tor.
+///
+/// With the `hickory-dns` feature, a `hickory_resolver::TokioAsyncResolver` can be used.
+///
+/// The resolver is cheap to clone: all the clones refer to the same instance.
+#[derive(Clone)]
+pub struct Resolver {
+    inner: Arc<dyn Resolve>,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Resolver").finish()
+    }
+}
+
+impl PartialEq for Resolver {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for Resolver {}
+
+impl Resolver {
+    /// New custom resolver
+    #[inline]
+    pub fn new<R>(resolver: R) -> Self
+    where
+        R: Resolve + 'static,
+    {
+        Self {
+            inner: Arc::new(resolver),
+        }
+    }
+
+    /// New custom resolver from a callback
+    pub fn from_fn<F, Fut>(callback: F) -> Self
+    where
+        F: Fn(String, u16) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'static,
+    {
+        Self::new(FnResolver(callback))
+    }
+
+    #[inline]
+    pub(super) async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self.inner.resolve(host, port).await
+    }
+}
+
+struct FnResolver<F>(F);
+
+impl<F, Fut> Resolve for FnResolver<F>
+where
+    F: Fn(String, u16) -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'static,
+{
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Vec<SocketAddr>>> {
+        Box::pin((self.0)(host.to_string(), port))
+    }
+}
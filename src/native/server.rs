@@ -0,0 +1,394 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! WebSocket server
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use std::{fmt, io};
+
+use futures_util::stream::{FuturesUnordered, SplitSink, SplitStream};
+use futures_util::Stream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::time;
+#[cfg(feature = "deflate")]
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::WebSocketStream;
+
+use super::admission::{Admission, ConnectionPermit, Rejection};
+#[cfg(feature = "deflate")]
+use super::deflate::DeflateStream;
+#[cfg(feature = "deflate")]
+use super::CompressionConfig;
+#[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+use super::TlsAcceptor;
+use super::{
+    accept_hdr_with_config, accept_with_config, AcceptDecision, AcceptPolicy, ConnectError,
+    HandshakeRequest, ServerLimits, WebSocketConfig,
+};
+use crate::socket::InnerWebSocket;
+use crate::{BoxedFuture, Message, Phase, ShutdownToken, WebSocket};
+
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Callback = dyn Fn(&HandshakeRequest) -> AcceptDecision + Send + Sync;
+
+type Handshake = BoxedFuture<'static, Result<(WebSocket, PeerInfo), ConnectError>>;
+
+/// Incoming connection: message sink, message stream and peer info
+pub type Connection = (
+    SplitSink<WebSocket, Message>,
+    SplitStream<WebSocket>,
+    PeerInfo,
+);
+
+/// Info about the peer of an accepted connection
+///
+/// Also inserted in the [`Extensions`](crate::Extensions) of the socket.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PeerInfo {
+    /// Remote address
+    pub remote_addr: SocketAddr,
+    /// End-entity certificate presented by the client (DER encoded)
+    ///
+    /// Only if the TLS acceptor requests the client certificates (mTLS).
+    pub tls_peer_cert: Option<Vec<u8>>,
+    /// Server name requested by the client (SNI)
+    ///
+    /// Not available with `tls-native`.
+    pub sni: Option<String>,
+    /// Negotiated ALPN protocol
+    ///
+    /// Not available with `tls-native`.
+    pub alpn: Option<Vec<u8>>,
+}
+
+impl PeerInfo {
+    /// Info of a plain TCP connection
+    #[inline]
+    pub(super) fn new(remote_addr: SocketAddr) -> Self {
+        Self {
+            remote_addr,
+            tls_peer_cert: None,
+            sni: None,
+            alpn: None,
+        }
+    }
+}
+
+/// WebSocket server
+///
+/// Accepts the incoming TCP connections and performs the WebSocket upgrade.
+/// The [`Stream`] yields every upgraded [`Connection`]:
+/// a failed handshake yields an error, without stopping the server.
+///
+/// The handshakes are performed concurrently, so slow clients don't block the others.
+///
+/// Use `WsServer::tls` to serve `wss://`.
+///
+/// The `permessage-deflate` offers of the clients are declined,
+/// unless the compression is enabled with `WsServer::compression`.
+pub struct WsServer {
+    listener: TcpListener,
+    timeout: Duration,
+    callback: Option<Arc<Callback>>,
+    policy: Option<AcceptPolicy>,
+    admission: Option<Admission>,
+    config: Option<WebSocketConfig>,
+    #[cfg(feature = "deflate")]
+    compression: Option<CompressionConfig>,
+    #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+    tls: Option<TlsAcceptor>,
+    shutdown: Option<ShutdownToken>,
+    handshakes: FuturesUnordered<Handshake>,
+}
+
+impl fmt::Debug for WsServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WsServer")
+            .field("listener", &self.listener)
+            .field("timeout", &self.timeout)
+            .field("handshakes", &self.handshakes.len())
+            .finish()
+    }
+}
+
+impl WsServer {
+    /// Bind a new server
+    pub async fn bind<A>(addr: A) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+    {
+        let listener: TcpListener = TcpListener::bind(addr).await?;
+        Ok(Self::from_listener(listener))
+    }
+
+    /// New server from an already bound listener
+    pub fn from_listener(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            callback: None,
+            policy: None,
+            admission: None,
+            config: None,
+            #[cfg(feature = "deflate")]
+            compression: None,
+            #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+            tls: None,
+            shutdown: None,
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+
+    /// Set the max time allowed to complete the WebSocket handshake (default: 30 secs)
+    #[inline]
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Inspect every handshake request, deciding whether to accept or reject it
+    ///
+    /// Check [`accept_hdr`].
+    pub fn on_request<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&HandshakeRequest) -> AcceptDecision + Send + Sync + 'static,
+    {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Validate the `Origin` and `Host` headers of every handshake request
+    ///
+    /// Checked before the [`WsServer::on_request`] callback.
+    #[inline]
+    pub fn accept_policy(mut self, policy: AcceptPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Cap the concurrent connections and rate limit the handshakes
+    ///
+    /// The connections over the limits are dropped before the TLS and WebSocket handshakes.
+    #[inline]
+    pub fn limits(mut self, limits: ServerLimits) -> Self {
+        self.admission = Some(Admission::new(limits));
+        self
+    }
+
+    /// Set the WebSocket protocol config of the accepted connections
+    #[inline]
+    pub fn config(mut self, config: WebSocketConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Accept the permessage-deflate offers of the clients (RFC 7692)
+    ///
+    /// The first supported offer is accepted: the no context takeover parameters of `config`
+    /// are enforced, also if not requested by the client.
+    /// The [`WebSocketConfig`] max sizes apply to the decompressed messages.
+    #[inline]
+    #[cfg(feature = "deflate")]
+    pub fn compression(mut self, config: CompressionConfig) -> Self {
+        self.compression = Some(config);
+        self
+    }
+
+    /// Terminate TLS (`wss://`) before the WebSocket handshake
+    #[inline]
+    #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Register the accepted connections to a shutdown coordinator
+    ///
+    /// Check [`ShutdownHandle`](crate::ShutdownHandle) for more details.
+    #[inline]
+    pub fn shutdown_token(mut self, token: ShutdownToken) -> Self {
+        self.shutdown = Some(token);
+        self
+    }
+
+    /// Get the local address of the listener
+    #[inline]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Drop a connection over the limits, without TLS or WebSocket handshake
+    ///
+    /// Without TLS, the HTTP response is written if the socket buffer has room for it.
+    fn reject(&self, stream: TcpStream, rejection: Rejection) {
+        #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+        if self.tls.is_some() {
+            return;
+        }
+
+        let _ = stream.try_write(rejection.response().as_bytes());
+    }
+
+    fn handshake(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        permit: Option<ConnectionPermit>,
+    ) -> Handshake {
+        let timeout: Duration = self.timeout;
+        let callback: Option<Arc<Callback>> = self.callback.clone();
+        let policy: Option<AcceptPolicy> = self.policy.clone();
+        let config: Option<WebSocketConfig> = self.config;
+        #[cfg(feature = "deflate")]
+        let compression: Option<CompressionConfig> = self.compression;
+        #[cfg(feature = "deflate")]
+        let callback: Option<Arc<Callback>> = match compression {
+            Some(compression) => Some(negotiate(compression, callback)),
+            None => callback,
+        };
+        #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+        let tls: Option<TlsAcceptor> = self.tls.clone();
+        let shutdown: Option<ShutdownToken> = self.shutdown.clone();
+        Box::pin(async move {
+            let fut = async move {
+                #[cfg(any(feature = "tls-rustls", feature = "tls-native"))]
+                if let Some(tls) = tls {
+                    let stream = tls.accept(stream).await?;
+                    let peer: PeerInfo = super::tls::peer_info(addr, &stream);
+
+                    #[cfg(feature = "deflate")]
+                    if let Some(compression) = compression {
+                        let info = super::tls::server_tls_info(&stream);
+                        let stream: DeflateStream =
+                            DeflateStream::new(Box::new(stream), Role::Server, compression, config)
+                                .with_tls_info(Some(info));
+                        let stream = upgrade(stream, policy, callback, config).await?;
+                        return Ok((InnerWebSocket::Deflate(stream), peer));
+                    }
+
+                    let stream = upgrade(stream, policy, callback, config).await?;
+                    return Ok((InnerWebSocket::ServerTls(stream), peer));
+                }
+
+                #[cfg(feature = "deflate")]
+                if let Some(compression) = compression {
+                    let stream: DeflateStream =
+                        DeflateStream::new(Box::new(stream), Role::Server, compression, config);
+                    let stream = upgrade(stream, policy, callback, config).await?;
+                    return Ok((InnerWebSocket::Deflate(stream), PeerInfo::new(addr)));
+                }
+
+                let stream: WebSocketStream<TcpStream> =
+                    upgrade(stream, policy, callback, config).await?;
+                Ok::<_, ConnectError>((InnerWebSocket::Server(stream), PeerInfo::new(addr)))
+            };
+            let (inner, peer): (InnerWebSocket, PeerInfo) = time::timeout(timeout, fut)
+                .await
+                .map_err(|_| ConnectError::Timeout(Phase::Handshake))??;
+            let socket: WebSocket = WebSocket::new(inner, None)
+                .with_peer_addr(Some(addr))
+                .with_shutdown(shutdown.as_ref())
+                .with_permit(permit);
+            socket.extensions().insert(peer.clone());
+            Ok((socket, peer))
+        })
+    }
+}
+
+/// Perform the WebSocket handshake
+async fn upgrade<S>(
+    stream: S,
+    policy: Option<AcceptPolicy>,
+    callback: Option<Arc<Callback>>,
+    config: Option<WebSocketConfig>,
+) -> Result<WebSocketStream<S>, ConnectError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if policy.is_none() && callback.is_none() {
+        return accept_with_config(stream, config).await;
+    }
+
+    let decide = move |req: &HandshakeRequest| {
+        if let Some(policy) = &policy {
+            let decision: AcceptDecision = policy.decide(req);
+            if let AcceptDecision::Reject { .. } = decision {
+                return decision;
+            }
+        }
+
+        match &callback {
+            Some(callback) => callback(req),
+            None => AcceptDecision::accept(),
+        }
+    };
+    accept_hdr_with_config(stream, decide, config).await
+}
+
+/// Wrap the callback, answering the permessage-deflate offers of the accepted handshakes
+#[cfg(feature = "deflate")]
+fn negotiate(config: CompressionConfig, callback: Option<Arc<Callback>>) -> Arc<Callback> {
+    Arc::new(move |req: &HandshakeRequest| {
+        let decision: AcceptDecision = match &callback {
+            Some(callback) => callback(req),
+            None => AcceptDecision::accept(),
+        };
+
+        let offers = req
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("sec-websocket-extensions"))
+            .map(|(_, value)| value.as_str());
+        match config.accept(offers) {
+            Some(extension) => decision.header("Sec-WebSocket-Extensions", extension),
+            None => decision,
+        }
+    })
+}
+
+impl Stream for WsServer {
+    type Item = Result<Connection, ConnectError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        // Accept all the pending TCP connections
+        loop {
+            match this.listener.poll_accept(cx) {
+                Poll::Ready(Ok((stream, addr))) => {
+                    let permit: Option<ConnectionPermit> = match &this.admission {
+                        Some(admission) => match admission.admit(addr.ip()) {
+                            Ok(permit) => Some(permit),
+                            Err(rejection) => {
+                                this.reject(stream, rejection);
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    let handshake: Handshake = this.handshake(stream, addr, permit);
+                    this.handshakes.push(handshake);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(WsError::Io(e).into()))),
+                Poll::Pending => break,
+            }
+        }
+
+        match Pin::new(&mut this.handshakes).poll_next(cx) {
+            Poll::Ready(Some(Ok((socket, peer)))) => {
+                let (tx, rx) = socket.split();
+                Poll::Ready(Some(Ok((tx, rx, peer))))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            // No pending handshakes: the listener will wake up the task
+            Poll::Ready(None) | Poll::Pending => Poll::Pending,
+        }
+    }
+}
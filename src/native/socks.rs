@@ -9,14 +9,41 @@ use tokio::net::TcpStream;
 use tokio_socks::tcp::Socks5Stream;
 use tokio_socks::IntoTargetAddr;
 
+use super::tcp;
+use crate::WebSocketBuilder;
+
 pub(crate) struct TcpSocks5Stream;
 
 impl TcpSocks5Stream {
-    #[inline]
     pub async fn connect<'a>(
         proxy: SocketAddr,
         dest: impl IntoTargetAddr<'a>,
+        builder: &WebSocketBuilder,
     ) -> Result<TcpStream, tokio_socks::Error> {
-        Ok(Socks5Stream::connect(proxy, dest).await?.into_inner())
+        let stream: TcpStream = tcp::connect(proxy, builder)
+            .await
+            .map_err(tokio_socks::Error::Io)?;
+        Ok(Socks5Stream::connect_with_socket(stream, dest)
+            .await?
+            .into_inner())
     }
 }
+
+/// Check if `host` is a valid v3 onion address (subdomains allowed)
+///
+/// The checksum is not verified: the Tor daemon will reject it anyway.
+pub(crate) fn is_valid_onion(host: &str) -> bool {
+    let name: &str = match host.strip_suffix(".onion") {
+        Some(name) => name,
+        None => return false,
+    };
+
+    // Skip subdomains
+    let id: &str = name.rsplit('.').next().unwrap_or(name);
+
+    id.len() == 56
+        && id.ends_with('d') // version 3
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || (b'2'..=b'7').contains(&b))
+}
@@ -0,0 +1,33 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! TCP
+
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::WebSocketBuilder;
+
+/// Open a TCP connection to `addr`
+///
+/// The socket is bound to the local address and to the network interface of the builder, if set.
+pub(super) async fn connect(addr: SocketAddr, builder: &WebSocketBuilder) -> io::Result<TcpStream> {
+    let socket: TcpSocket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+
+    if let Some(local_addr) = builder.local_addr {
+        socket.bind(local_addr)?;
+    }
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    if let Some(interface) = &builder.interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+
+    socket.connect(addr).await
+}
@@ -0,0 +1,606 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! TLS
+
+use std::fmt;
+use std::net::SocketAddr;
+#[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+use std::sync::Arc;
+
+#[cfg(feature = "tls-native")]
+use native_tls::{
+    Certificate, Identity, TlsAcceptor as NativeTlsAcceptor, TlsConnector as NativeTlsConnector,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+#[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+#[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+#[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+use tokio_rustls::rustls::{
+    ClientConfig, CommonState, DigitallySignedStruct, Error as RustlsError, ProtocolVersion,
+    RootCertStore, ServerConfig, SignatureScheme,
+};
+use tokio_tungstenite::tungstenite::error::TlsError;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::MaybeTlsStream;
+
+use super::server::PeerInfo;
+use super::ConnectError;
+
+/// TLS stream of an accepted connection
+#[cfg(feature = "tls-native")]
+pub type ServerTlsStream<S> = tokio_native_tls::TlsStream<S>;
+/// TLS stream of an accepted connection
+#[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+pub type ServerTlsStream<S> = tokio_rustls::server::TlsStream<S>;
+
+/// Negotiated TLS protocol version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TlsVersion {
+    /// TLS 1.2
+    Tls12,
+    /// TLS 1.3
+    Tls13,
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tls12 => write!(f, "TLSv1.2"),
+            Self::Tls13 => write!(f, "TLSv1.3"),
+        }
+    }
+}
+
+/// Info about the TLS session of a connection
+///
+/// Check [`WebSocket::tls_info`](crate::WebSocket::tls_info).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TlsInfo {
+    /// Negotiated protocol version
+    ///
+    /// Not available with `tls-native`.
+    pub version: Option<TlsVersion>,
+    /// Negotiated cipher suite (ex. `TLS13_AES_256_GCM_SHA384`)
+    ///
+    /// Not available with `tls-native`.
+    pub cipher_suite: Option<String>,
+    /// Certificate chain presented by the peer, end-entity first (DER encoded)
+    ///
+    /// With `tls-native` only the end-entity certificate is available.
+    pub peer_certificates: Vec<Vec<u8>>,
+    /// Negotiated ALPN protocol
+    pub alpn: Option<Vec<u8>>,
+}
+
+/// Custom TLS configuration
+///
+/// Applied to the TLS backend selected with the crate features:
+/// `tls-native` (if enabled) or `tls-rustls`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// Additional trusted root certificates (DER)
+    root_certs: Vec<Vec<u8>>,
+    /// Trust the built-in root certificates
+    builtin_roots: bool,
+    /// Client certificate chain and private key (DER), for mTLS
+    client_auth: Option<(Vec<Vec<u8>>, Vec<u8>)>,
+    /// Skip the server certificate verification
+    danger_accept_invalid_certs: bool,
+    /// Override the SNI hostname
+    server_name: Option<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            root_certs: Vec::new(),
+            builtin_roots: true,
+            client_auth: None,
+            danger_accept_invalid_certs: false,
+            server_name: None,
+        }
+    }
+}
+
+impl TlsConfig {
+    /// New default TLS config
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a trusted root certificate (DER encoded)
+    #[inline]
+    pub fn add_root_cert<T>(mut self, der: T) -> Self
+    where
+        T: Into<Vec<u8>>,
+    {
+        self.root_certs.push(der.into());
+        self
+    }
+
+    /// Trust the built-in root certificates (default: `true`)
+    ///
+    /// These are the Mozilla root certificates for `rustls` and the system ones for `native-tls`.
+    /// Disable it to trust **only** the certificates added with [`TlsConfig::add_root_cert`].
+    #[inline]
+    pub fn builtin_roots(mut self, enable: bool) -> Self {
+        self.builtin_roots = enable;
+        self
+    }
+
+    /// Set the client certificate chain and private key (DER encoded), for mTLS
+    ///
+    /// The key must be in PKCS#8 format (`rustls` supports also PKCS#1 and SEC1).
+    pub fn client_auth<T, K>(mut self, cert_chain: Vec<T>, key: K) -> Self
+    where
+        T: Into<Vec<u8>>,
+        K: Into<Vec<u8>>,
+    {
+        let cert_chain: Vec<Vec<u8>> = cert_chain.into_iter().map(|c| c.into()).collect();
+        self.client_auth = Some((cert_chain, key.into()));
+        self
+    }
+
+    /// Skip the verification of the server certificate (default: `false`)
+    ///
+    /// **Dangerous**: use it only for testing!
+    #[inline]
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.danger_accept_invalid_certs = accept;
+        self
+    }
+
+    /// Override the hostname used for SNI and certificate verification
+    ///
+    /// By default, the host of the URL is used.
+    #[inline]
+    pub fn server_name<S>(mut self, name: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    #[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+    fn rustls_config(&self) -> Result<ClientConfig, RustlsError> {
+        let provider: Arc<CryptoProvider> = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let builder = if self.danger_accept_invalid_certs {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoCertVerification(provider)))
+        } else {
+            let mut roots: RootCertStore = RootCertStore::empty();
+
+            if self.builtin_roots {
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            }
+
+            for der in self.root_certs.iter() {
+                roots.add(CertificateDer::from(der.as_slice()))?;
+            }
+
+            builder.with_root_certificates(roots)
+        };
+
+        match &self.client_auth {
+            Some((chain, key)) => {
+                let chain: Vec<CertificateDer<'static>> = chain
+                    .iter()
+                    .map(|c| CertificateDer::from(c.clone()))
+                    .collect();
+                let key: PrivateKeyDer<'static> = PrivateKeyDer::try_from(key.as_slice())
+                    .map_err(|e| RustlsError::General(e.to_string()))?
+                    .clone_key();
+                builder.with_client_auth_cert(chain, key)
+            }
+            None => Ok(builder.with_no_client_auth()),
+        }
+    }
+
+    #[cfg(feature = "tls-native")]
+    #[cfg_attr(not(feature = "h2"), allow(unused_variables))]
+    fn native_connector(&self, alpn: &[&str]) -> Result<NativeTlsConnector, native_tls::Error> {
+        let mut builder = NativeTlsConnector::builder();
+
+        #[cfg(feature = "h2")]
+        if !alpn.is_empty() {
+            builder.request_alpns(alpn);
+        }
+
+        builder.disable_built_in_roots(!self.builtin_roots);
+
+        for der in self.root_certs.iter() {
+            builder.add_root_certificate(Certificate::from_der(der)?);
+        }
+
+        if let Some((chain, key)) = &self.client_auth {
+            let chain: String = chain.iter().map(|c| pem("CERTIFICATE", c)).collect();
+            let key: String = pem("PRIVATE KEY", key);
+            builder.identity(Identity::from_pkcs8(chain.as_bytes(), key.as_bytes())?);
+        }
+
+        builder.danger_accept_invalid_certs(self.danger_accept_invalid_certs);
+
+        builder.build()
+    }
+
+    /// `rustls` config for QUIC, offering HTTP/3 with ALPN
+    #[cfg(all(feature = "h3", feature = "tls-rustls", not(feature = "tls-native")))]
+    pub(super) fn quic_config(&self) -> Result<ClientConfig, ConnectError> {
+        let mut config: ClientConfig = self
+            .rustls_config()
+            .map_err(|e| WsError::Tls(TlsError::Rustls(e)))?;
+        config.alpn_protocols = vec![b"h3".to_vec()];
+        Ok(config)
+    }
+
+    /// Perform the TLS handshake over `stream`
+    #[inline]
+    pub(super) async fn wrap_stream<S>(
+        &self,
+        host: &str,
+        stream: S,
+    ) -> Result<MaybeTlsStream<S>, ConnectError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.connect(host, stream, &[]).await
+    }
+
+    /// Perform the TLS handshake over `stream`, offering HTTP/2 with ALPN
+    ///
+    /// Returns also if the server selected HTTP/2.
+    #[cfg(feature = "h2")]
+    pub(super) async fn wrap_stream_h2<S>(
+        &self,
+        host: &str,
+        stream: S,
+    ) -> Result<(MaybeTlsStream<S>, bool), ConnectError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream: MaybeTlsStream<S> = self.connect(host, stream, &["h2"]).await?;
+
+        let h2: bool = match &stream {
+            #[cfg(feature = "tls-native")]
+            MaybeTlsStream::NativeTls(s) => matches!(
+                s.get_ref().negotiated_alpn(),
+                Ok(Some(protocol)) if protocol == b"h2"
+            ),
+            #[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+            MaybeTlsStream::Rustls(s) => s.get_ref().1.alpn_protocol() == Some(&b"h2"[..]),
+            _ => false,
+        };
+
+        Ok((stream, h2))
+    }
+
+    /// Perform the TLS handshake over `stream`, offering the `alpn` protocols (if any)
+    async fn connect<S>(
+        &self,
+        host: &str,
+        stream: S,
+        alpn: &[&str],
+    ) -> Result<MaybeTlsStream<S>, ConnectError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // Strip brackets of IPv6 addresses
+        let host: &str = self
+            .server_name
+            .as_deref()
+            .unwrap_or_else(|| host.trim_start_matches('[').trim_end_matches(']'));
+
+        #[cfg(feature = "tls-native")]
+        {
+            let connector: NativeTlsConnector = self
+                .native_connector(alpn)
+                .map_err(|e| WsError::Tls(TlsError::Native(e)))?;
+            let connector = tokio_native_tls::TlsConnector::from(connector);
+            let stream = connector
+                .connect(host, stream)
+                .await
+                .map_err(|e| WsError::Tls(TlsError::Native(e)))?;
+            Ok(MaybeTlsStream::NativeTls(stream))
+        }
+
+        #[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+        {
+            let mut config: ClientConfig = self
+                .rustls_config()
+                .map_err(|e| WsError::Tls(TlsError::Rustls(e)))?;
+            config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+            let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+            let domain: ServerName<'static> = ServerName::try_from(host.to_string())
+                .map_err(|_| WsError::Tls(TlsError::InvalidDnsName))?;
+            let stream = connector
+                .connect(domain, stream)
+                .await
+                .map_err(WsError::Io)?;
+            Ok(MaybeTlsStream::Rustls(stream))
+        }
+    }
+}
+
+/// TLS acceptor, for the server side
+///
+/// Uses the TLS backend selected with the crate features:
+/// `tls-native` (if enabled) or `tls-rustls`.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    #[cfg(feature = "tls-native")]
+    inner: tokio_native_tls::TlsAcceptor,
+    #[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+    inner: tokio_rustls::TlsAcceptor,
+}
+
+impl fmt::Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsAcceptor").finish_non_exhaustive()
+    }
+}
+
+impl TlsAcceptor {
+    /// New TLS acceptor from the certificate chain and the private key (DER encoded)
+    ///
+    /// The key must be in PKCS#8 format (`rustls` supports also PKCS#1 and SEC1).
+    pub fn new<T, K>(cert_chain: Vec<T>, key: K) -> Result<Self, ConnectError>
+    where
+        T: Into<Vec<u8>>,
+        K: Into<Vec<u8>>,
+    {
+        let cert_chain: Vec<Vec<u8>> = cert_chain.into_iter().map(|c| c.into()).collect();
+        let key: Vec<u8> = key.into();
+
+        #[cfg(feature = "tls-native")]
+        {
+            let chain: String = cert_chain.iter().map(|c| pem("CERTIFICATE", c)).collect();
+            let key: String = pem("PRIVATE KEY", &key);
+            let acceptor: NativeTlsAcceptor =
+                Identity::from_pkcs8(chain.as_bytes(), key.as_bytes())
+                    .and_then(NativeTlsAcceptor::new)
+                    .map_err(|e| WsError::Tls(TlsError::Native(e)))?;
+            Ok(Self {
+                inner: tokio_native_tls::TlsAcceptor::from(acceptor),
+            })
+        }
+
+        #[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+        {
+            let config: ServerConfig = rustls_server_config(cert_chain, key)
+                .map_err(|e| WsError::Tls(TlsError::Rustls(e)))?;
+            Ok(Self {
+                inner: tokio_rustls::TlsAcceptor::from(Arc::new(config)),
+            })
+        }
+    }
+
+    /// New TLS acceptor from a custom `rustls` server config
+    ///
+    /// Ex. with a client certificate verifier, to get the [`PeerInfo::tls_peer_cert`] (mTLS).
+    #[inline]
+    #[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+    pub fn from_rustls_config(config: Arc<ServerConfig>) -> Self {
+        Self {
+            inner: tokio_rustls::TlsAcceptor::from(config),
+        }
+    }
+
+    /// Perform the TLS handshake over an incoming `stream`
+    pub(super) async fn accept<S>(&self, stream: S) -> Result<ServerTlsStream<S>, ConnectError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        #[cfg(feature = "tls-native")]
+        {
+            Ok(self
+                .inner
+                .accept(stream)
+                .await
+                .map_err(|e| WsError::Tls(TlsError::Native(e)))?)
+        }
+
+        #[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+        {
+            Ok(self.inner.accept(stream).await.map_err(WsError::Io)?)
+        }
+    }
+}
+
+/// Info about the peer of a TLS connection
+pub(super) fn peer_info<S>(remote_addr: SocketAddr, stream: &ServerTlsStream<S>) -> PeerInfo
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut peer: PeerInfo = PeerInfo::new(remote_addr);
+
+    #[cfg(feature = "tls-native")]
+    {
+        peer.tls_peer_cert = stream
+            .get_ref()
+            .peer_certificate()
+            .ok()
+            .flatten()
+            .and_then(|cert| cert.to_der().ok());
+    }
+
+    #[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+    {
+        let (_, conn) = stream.get_ref();
+        peer.tls_peer_cert = conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(|cert| cert.to_vec());
+        peer.sni = conn.server_name().map(String::from);
+        peer.alpn = conn.alpn_protocol().map(<[u8]>::to_vec);
+    }
+
+    peer
+}
+
+/// Get the TLS session info of a client connection
+///
+/// Returns `None` if the connection isn't encrypted.
+pub(crate) fn client_tls_info<S>(stream: &MaybeTlsStream<S>) -> Option<TlsInfo>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match stream {
+        #[cfg(feature = "tls-native")]
+        MaybeTlsStream::NativeTls(s) => Some(native_tls_info(s.get_ref())),
+        #[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+        MaybeTlsStream::Rustls(s) => Some(rustls_info(s.get_ref().1)),
+        _ => None,
+    }
+}
+
+/// Get the TLS session info of an accepted connection
+pub(crate) fn server_tls_info<S>(stream: &ServerTlsStream<S>) -> TlsInfo
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    #[cfg(feature = "tls-native")]
+    {
+        native_tls_info(stream.get_ref())
+    }
+
+    #[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+    {
+        rustls_info(stream.get_ref().1)
+    }
+}
+
+#[cfg(feature = "tls-native")]
+fn native_tls_info<S>(stream: &tokio_native_tls::TlsStream<S>) -> TlsInfo
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stream = stream.get_ref();
+    TlsInfo {
+        version: None,
+        cipher_suite: None,
+        peer_certificates: stream
+            .peer_certificate()
+            .ok()
+            .flatten()
+            .and_then(|cert| cert.to_der().ok())
+            .into_iter()
+            .collect(),
+        #[cfg(feature = "h2")]
+        alpn: stream.negotiated_alpn().ok().flatten(),
+        #[cfg(not(feature = "h2"))]
+        alpn: None,
+    }
+}
+
+#[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+fn rustls_info(conn: &CommonState) -> TlsInfo {
+    TlsInfo {
+        version: match conn.protocol_version() {
+            Some(ProtocolVersion::TLSv1_2) => Some(TlsVersion::Tls12),
+            Some(ProtocolVersion::TLSv1_3) => Some(TlsVersion::Tls13),
+            _ => None,
+        },
+        cipher_suite: conn
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite())),
+        peer_certificates: conn
+            .peer_certificates()
+            .map(|certs| certs.iter().map(|cert| cert.to_vec()).collect())
+            .unwrap_or_default(),
+        alpn: conn.alpn_protocol().map(<[u8]>::to_vec),
+    }
+}
+
+#[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+fn rustls_server_config(
+    cert_chain: Vec<Vec<u8>>,
+    key: Vec<u8>,
+) -> Result<ServerConfig, RustlsError> {
+    let provider: Arc<CryptoProvider> = Arc::new(crypto::ring::default_provider());
+    let chain: Vec<CertificateDer<'static>> =
+        cert_chain.into_iter().map(CertificateDer::from).collect();
+    let key: PrivateKeyDer<'static> =
+        PrivateKeyDer::try_from(key).map_err(|e| RustlsError::General(e.to_string()))?;
+    ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+}
+
+/// Encode DER as PEM
+#[cfg(feature = "tls-native")]
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded: String = super::http_proxy::base64_encode(der);
+    let mut pem: String = format!("-----BEGIN {label}-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        // Base64 is always ASCII
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+/// Accept any server certificate
+#[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+#[derive(Debug)]
+struct NoCertVerification(Arc<CryptoProvider>);
+
+#[cfg(all(feature = "tls-rustls", not(feature = "tls-native")))]
+impl ServerCertVerifier for NoCertVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, RustlsError> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -3,20 +3,33 @@
 
 //! Tor
 
+use std::cmp::Ordering;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 #[cfg(feature = "tor-launch-service")]
 use std::net::SocketAddr;
 use std::path::PathBuf;
-#[cfg(feature = "tor-launch-service")]
+#[cfg(any(feature = "tor-geoip", feature = "tor-onion-auth"))]
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[cfg(feature = "tor-launch-service")]
 use arti_client::config::onion_service::OnionServiceConfigBuilder;
 use arti_client::config::{CfgPath, ConfigBuildError, TorClientConfigBuilder};
-use arti_client::{DataStream, TorClient, TorClientConfig};
+use arti_client::status::{BootstrapEvents, BootstrapStatus};
+use arti_client::{DataStream, StreamPrefs, TorClient, TorClientConfig};
+use futures_util::stream::BoxStream;
 #[cfg(feature = "tor-launch-service")]
 use futures_util::task::{SpawnError, SpawnExt};
+use futures_util::StreamExt;
 use tokio::sync::OnceCell;
+use tokio::time;
+#[cfg(feature = "tor-geoip")]
+use tor_geoip::CountryCode;
+#[cfg(feature = "tor-onion-auth")]
+use tor_hscrypto::pk::{HsClientDescEncSecretKey, HsId};
 #[cfg(feature = "tor-launch-service")]
 use tor_hsrproxy::config::{
     Encapsulation, ProxyAction, ProxyConfigBuilder, ProxyConfigError, ProxyPattern, ProxyRule,
@@ -26,9 +39,14 @@ use tor_hsrproxy::config::{
 use tor_hsrproxy::OnionServiceReverseProxy;
 #[cfg(feature = "tor-launch-service")]
 use tor_hsservice::{HsNickname, InvalidNickname, OnionServiceConfig, RunningOnionService};
+#[cfg(feature = "tor-onion-auth")]
+use tor_keymgr::KeystoreSelector;
+#[cfg(feature = "tor-onion-auth")]
+use tor_llcrypto::pk::curve25519::StaticSecret;
 use tor_rtcompat::PreferredRuntime;
 
-static TOR_CLIENT: OnceCell<TorClient<PreferredRuntime>> = OnceCell::const_new();
+static TOR_CLIENT: OnceCell<TorClientHandle> = OnceCell::const_new();
+static ISOLATION_TOKEN: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug)]
 pub enum Error {
@@ -45,6 +63,19 @@ pub enum Error {
     /// Spawn error
     #[cfg(feature = "tor-launch-service")]
     Spawn(SpawnError),
+    /// Bootstrap timeout
+    Timeout,
+    /// Not an onion address, while onion-only is enforced
+    NotOnion(String),
+    /// Unknown exit country code
+    #[cfg(feature = "tor-geoip")]
+    UnknownCountry(String),
+    /// Invalid onion address
+    #[cfg(feature = "tor-onion-auth")]
+    InvalidOnionAddress(String),
+    /// Invalid client authorization key
+    #[cfg(feature = "tor-onion-auth")]
+    InvalidClientAuthKey,
 }
 
 impl std::error::Error for Error {}
@@ -60,6 +91,14 @@ impl fmt::Display for Error {
             Self::InvalidNickname(e) => write!(f, "{e}"),
             #[cfg(feature = "tor-launch-service")]
             Self::Spawn(e) => write!(f, "{e}"),
+            Self::Timeout => write!(f, "bootstrap timeout"),
+            Self::NotOnion(host) => write!(f, "not an onion address: {host}"),
+            #[cfg(feature = "tor-geoip")]
+            Self::UnknownCountry(code) => write!(f, "unknown country code: {code}"),
+            #[cfg(feature = "tor-onion-auth")]
+            Self::InvalidOnionAddress(addr) => write!(f, "invalid onion address: {addr}"),
+            #[cfg(feature = "tor-onion-auth")]
+            Self::InvalidClientAuthKey => write!(f, "invalid client authorization key"),
         }
     }
 }
@@ -97,9 +136,309 @@ impl From<SpawnError> for Error {
     }
 }
 
-async fn init_tor_client(
-    custom_path: Option<&PathBuf>,
-) -> Result<TorClient<PreferredRuntime>, Error> {
+/// Tor stream options
+///
+/// Applied to every connection made with [`ConnectionMode::Tor`](crate::ConnectionMode::Tor).
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TorOptions {
+    /// Isolation token
+    isolation: Option<u64>,
+    /// Preferred exit country (ISO 3166-1 alpha-2)
+    #[cfg(feature = "tor-geoip")]
+    exit_country: Option<String>,
+    /// Allow only onion addresses
+    onion_only: bool,
+}
+
+impl TorOptions {
+    /// New default options
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Isolate the connections from the ones with a different token
+    ///
+    /// Connections with different tokens never share a circuit.
+    /// Use [`TorOptions::new_isolation_token`] to get a unique token.
+    #[inline]
+    pub fn isolation(mut self, token: u64) -> Self {
+        self.isolation = Some(token);
+        self
+    }
+
+    /// Get a new unique isolation token
+    #[inline]
+    pub fn new_isolation_token() -> u64 {
+        ISOLATION_TOKEN.fetch_add(1, AtomicOrdering::Relaxed)
+    }
+
+    /// Prefer exit relays in a country (ISO 3166-1 alpha-2 code, ex. `DE`)
+    ///
+    /// Has no effect on onion services.
+    #[inline]
+    #[cfg(feature = "tor-geoip")]
+    pub fn exit_country<S>(mut self, code: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.exit_country = Some(code.into());
+        self
+    }
+
+    /// Allow only onion addresses
+    ///
+    /// Connections to clearnet hosts fail with [`Error::NotOnion`].
+    #[inline]
+    pub fn onion_only(mut self, enable: bool) -> Self {
+        self.onion_only = enable;
+        self
+    }
+
+    fn stream_prefs(&self, host: &str) -> Result<StreamPrefs, Error> {
+        let is_onion: bool = host.ends_with(".onion");
+
+        if self.onion_only && !is_onion {
+            return Err(Error::NotOnion(host.to_string()));
+        }
+
+        let mut prefs: StreamPrefs = StreamPrefs::new();
+
+        if let Some(token) = self.isolation {
+            prefs.set_isolation(token);
+        }
+
+        #[cfg(feature = "tor-geoip")]
+        if let Some(code) = &self.exit_country {
+            if !is_onion {
+                let country: CountryCode =
+                    CountryCode::from_str(code).map_err(|_| Error::UnknownCountry(code.clone()))?;
+                prefs.exit_country(country);
+            }
+        }
+
+        Ok(prefs)
+    }
+}
+
+/// Bootstrap progress
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BootstrapProgress {
+    /// Completion, from `0` to `100`
+    pub percent: u8,
+    /// Description of the current phase
+    pub phase: String,
+    /// Ready to connect
+    pub ready: bool,
+    /// Description of what is blocking the bootstrap, if anything
+    pub blocked: Option<String>,
+}
+
+impl From<BootstrapStatus> for BootstrapProgress {
+    fn from(status: BootstrapStatus) -> Self {
+        let frac: f32 = status.as_frac().clamp(0.0, 1.0);
+        Self {
+            percent: (frac * 100.0).round() as u8,
+            phase: status.to_string(),
+            ready: status.ready_for_traffic(),
+            blocked: status.blocked().map(|b| b.to_string()),
+        }
+    }
+}
+
+/// Tor client handle
+///
+/// Bootstrap a tor client once and share it across many connections
+/// with [`ConnectionMode::tor_with_client`](crate::ConnectionMode::tor_with_client).
+///
+/// The handle is cheap to clone: all the clones refer to the same client.
+#[derive(Clone)]
+pub struct TorClientHandle {
+    client: Arc<TorClient<PreferredRuntime>>,
+}
+
+impl fmt::Debug for TorClientHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TorClientHandle").finish()
+    }
+}
+
+impl PartialEq for TorClientHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.client, &other.client)
+    }
+}
+
+impl Eq for TorClientHandle {}
+
+impl PartialOrd for TorClientHandle {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TorClientHandle {
+    fn cmp(&self, other: &Self) -> Ordering {
+        Arc::as_ptr(&self.client).cmp(&Arc::as_ptr(&other.client))
+    }
+}
+
+impl Hash for TorClientHandle {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.client).hash(state)
+    }
+}
+
+impl From<TorClient<PreferredRuntime>> for TorClientHandle {
+    fn from(client: TorClient<PreferredRuntime>) -> Self {
+        Self {
+            client: Arc::new(client),
+        }
+    }
+}
+
+impl TorClientHandle {
+    /// Create and bootstrap a new tor client
+    ///
+    /// Check [`TorClientHandle::unbootstrapped`] to report the bootstrap progress.
+    pub async fn bootstrap(custom_path: Option<&PathBuf>) -> Result<Self, Error> {
+        let client: Self = Self::unbootstrapped(custom_path)?;
+        client.client.bootstrap().await?;
+        Ok(client)
+    }
+
+    /// Create a new tor client, without bootstrapping it
+    ///
+    /// Subscribe to the progress with [`TorClientHandle::bootstrap_progress`]
+    /// and then call [`TorClientHandle::ensure_bootstrapped`].
+    /// The client is bootstrapped anyway on the first connection.
+    pub fn unbootstrapped(custom_path: Option<&PathBuf>) -> Result<Self, Error> {
+        let config: TorClientConfig = build_config(custom_path)?;
+        let client: TorClient<PreferredRuntime> = TorClient::builder()
+            .config(config)
+            .create_unbootstrapped()?;
+        Ok(Self::from(client))
+    }
+
+    /// Bootstrap the client, if not already bootstrapped
+    ///
+    /// Returns [`Error::Timeout`] if not completed within `timeout`.
+    pub async fn ensure_bootstrapped(&self, timeout: Duration) -> Result<(), Error> {
+        time::timeout(timeout, self.client.bootstrap())
+            .await
+            .map_err(|_| Error::Timeout)??;
+        Ok(())
+    }
+
+    /// Current bootstrap progress
+    #[inline]
+    pub fn bootstrap_status(&self) -> BootstrapProgress {
+        self.client.bootstrap_status().into()
+    }
+
+    /// Subscribe to the bootstrap progress
+    ///
+    /// The stream yields every progress change and never ends while the client is alive.
+    pub fn bootstrap_progress(&self) -> BoxStream<'static, BootstrapProgress> {
+        let events: BootstrapEvents = self.client.bootstrap_events();
+        events.map(BootstrapProgress::from).boxed()
+    }
+
+    /// Add the client authorization key of a restricted onion service
+    ///
+    /// The key is stored in the keystore of the client and used for all the connections to `onion`.
+    #[cfg(feature = "tor-onion-auth")]
+    pub fn add_onion_client_auth(
+        &self,
+        onion: &str,
+        key: &OnionClientAuthKey,
+    ) -> Result<(), Error> {
+        let hsid: HsId =
+            HsId::from_str(onion).map_err(|_| Error::InvalidOnionAddress(onion.to_string()))?;
+        let secret: HsClientDescEncSecretKey = StaticSecret::from(key.0).into();
+        self.client
+            .insert_service_discovery_key(KeystoreSelector::Primary, hsid, secret)?;
+        Ok(())
+    }
+
+    /// Get the inner tor client
+    #[inline]
+    pub fn client(&self) -> &TorClient<PreferredRuntime> {
+        &self.client
+    }
+}
+
+/// Client authorization key (x25519 secret key) of a restricted onion service
+#[cfg(feature = "tor-onion-auth")]
+#[derive(Clone, PartialEq, Eq)]
+pub struct OnionClientAuthKey([u8; 32]);
+
+#[cfg(feature = "tor-onion-auth")]
+impl fmt::Debug for OnionClientAuthKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OnionClientAuthKey")
+            .field(&"<sensitive>")
+            .finish()
+    }
+}
+
+#[cfg(feature = "tor-onion-auth")]
+impl From<[u8; 32]> for OnionClientAuthKey {
+    fn from(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+#[cfg(feature = "tor-onion-auth")]
+impl OnionClientAuthKey {
+    /// Parse a base32 encoded key
+    ///
+    /// Accepts both the bare key and the C tor `.auth_private` line format
+    /// (`<onion-address>:descriptor:x25519:<base32-key>`).
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let key: &str = match s.trim().rsplit_once(':') {
+            Some((prefix, key)) if prefix.ends_with(":descriptor:x25519") => key,
+            Some(..) => return Err(Error::InvalidClientAuthKey),
+            None => s.trim(),
+        };
+
+        let bytes: Vec<u8> = base32_decode(key).ok_or(Error::InvalidClientAuthKey)?;
+        let key: [u8; 32] = bytes.try_into().map_err(|_| Error::InvalidClientAuthKey)?;
+        Ok(Self(key))
+    }
+}
+
+/// Decode RFC 4648 base32 (case-insensitive, optional padding)
+#[cfg(feature = "tor-onion-auth")]
+fn base32_decode(input: &str) -> Option<Vec<u8>> {
+    let input: &str = input.trim_end_matches('=');
+    let mut output: Vec<u8> = Vec::with_capacity(input.len() * 5 / 8);
+
+    let mut buffer: u32 = 0;
+    let mut bits: u32 = 0;
+
+    for c in input.bytes() {
+        let value: u8 = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Some(output)
+}
+
+fn build_config(custom_path: Option<&PathBuf>) -> Result<TorClientConfig, Error> {
     // Construct default Tor Client config
     let mut config = TorClientConfigBuilder::default();
 
@@ -118,29 +457,49 @@ async fn init_tor_client(
         config.storage().cache_dir(cache_dir).state_dir(state_dir);
     }
 
-    let config: TorClientConfig = config.build()?;
-    Ok(TorClient::builder()
-        .config(config)
-        .create_bootstrapped()
-        .await?)
+    Ok(config.build()?)
 }
 
-/// Get or init tor client
-async fn get_tor_client<'a>(
+/// Get or init the global tor client
+///
+/// The global client is used by [`ConnectionMode::Tor`](crate::ConnectionMode::Tor) when no client is provided.
+/// It's created without bootstrapping it: check [`TorClientHandle::unbootstrapped`].
+///
+/// The `custom_path` is used only at the first call.
+pub async fn global_client(custom_path: Option<&PathBuf>) -> Result<TorClientHandle, Error> {
+    let client: &TorClientHandle = TOR_CLIENT
+        .get_or_try_init(|| async { TorClientHandle::unbootstrapped(custom_path) })
+        .await?;
+    Ok(client.clone())
+}
+
+/// Bootstrap the global tor client, if not already bootstrapped
+///
+/// Check [`global_client`] and [`TorClientHandle::ensure_bootstrapped`].
+pub async fn ensure_bootstrapped(
     custom_path: Option<&PathBuf>,
-) -> Result<&'a TorClient<PreferredRuntime>, Error> {
-    TOR_CLIENT
-        .get_or_try_init(|| async { init_tor_client(custom_path).await })
-        .await
+    timeout: Duration,
+) -> Result<(), Error> {
+    let client: TorClientHandle = global_client(custom_path).await?;
+    client.ensure_bootstrapped(timeout).await
 }
 
 pub(super) async fn connect(
     domain: &str,
     port: u16,
     custom_path: Option<&PathBuf>,
+    client: Option<&TorClientHandle>,
+    options: &TorOptions,
 ) -> Result<DataStream, Error> {
-    let client: &TorClient<PreferredRuntime> = get_tor_client(custom_path).await?;
-    Ok(client.connect((domain, port)).await?)
+    let prefs: StreamPrefs = options.stream_prefs(domain)?;
+    let client: TorClientHandle = match client {
+        Some(client) => client.clone(),
+        None => global_client(custom_path).await?,
+    };
+    Ok(client
+        .client()
+        .connect_with_prefs((domain, port), &prefs)
+        .await?)
 }
 
 /// Launch onion service and forward requests from `hiddenservice.onion:<port>` to [`SocketAddr`].
@@ -155,7 +514,9 @@ where
     S: Into<String>,
 {
     // Get tor client
-    let client: &TorClient<PreferredRuntime> = get_tor_client(custom_path).await?;
+    let client: TorClientHandle = global_client(custom_path).await?;
+    let client: &TorClient<PreferredRuntime> = client.client();
+    client.bootstrap().await?;
 
     // Configure proxy
     let mut config: ProxyConfigBuilder = ProxyConfigBuilder::default();
@@ -0,0 +1,121 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Custom transport
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+
+use super::IoStream;
+
+/// Custom transport
+///
+/// Implement it to open the connections with your own streams (VPN SDKs, QUIC streams, test harnesses, etc.):
+/// the TLS (for `wss` URLs) and WebSocket handshakes are layered on top.
+pub trait Transport: Send + Sync {
+    /// Open a stream to `host` and `port` of the URL
+    fn connect<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Box<dyn IoStream>>>;
+}
+
+/// Custom transport handle
+///
+/// Check [`ConnectionMode::Custom`](crate::ConnectionMode::Custom).
+///
+/// The handle is cheap to clone: all the clones refer to the same transport.
+#[derive(Clone)]
+pub struct CustomTransport {
+    inner: Arc<dyn Transport>,
+}
+
+impl fmt::Debug for CustomTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustomTransport").finish()
+    }
+}
+
+impl PartialEq for CustomTransport {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl Eq for CustomTransport {}
+
+impl PartialOrd for CustomTransport {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CustomTransport {
+    fn cmp(&self, other: &Self) -> Ordering {
+        Arc::as_ptr(&self.inner)
+            .cast::<()>()
+            .cmp(&Arc::as_ptr(&other.inner).cast::<()>())
+    }
+}
+
+impl Hash for CustomTransport {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.inner).cast::<()>().hash(state)
+    }
+}
+
+impl CustomTransport {
+    /// New custom transport
+    #[inline]
+    pub fn new<T>(transport: T) -> Self
+    where
+        T: Transport + 'static,
+    {
+        Self {
+            inner: Arc::new(transport),
+        }
+    }
+
+    /// New custom transport from a callback
+    pub fn from_fn<F, Fut, S>(callback: F) -> Self
+    where
+        F: Fn(String, u16) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<S>> + Send + 'static,
+        S: IoStream,
+    {
+        Self::new(FnTransport(callback))
+    }
+
+    #[inline]
+    pub(super) async fn connect(&self, host: &str, port: u16) -> io::Result<Box<dyn IoStream>> {
+        self.inner.connect(host, port).await
+    }
+}
+
+struct FnTransport<F>(F);
+
+impl<F, Fut, S> Transport for FnTransport<F>
+where
+    F: Fn(String, u16) -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<S>> + Send + 'static,
+    S: IoStream,
+{
+    fn connect<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, io::Result<Box<dyn IoStream>>> {
+        let future = (self.0)(host.to_string(), port);
+        Box::pin(async move {
+            let stream: Box<dyn IoStream> = Box::new(future.await?);
+            Ok(stream)
+        })
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! WebSocket with a concrete stream type
+//!
+//! [`WebSocket`](crate::WebSocket) is an enum of all the supported streams and keeps the stats,
+//! the RTT and the idle bookkeeping of every message.
+//! [`TypedWebSocket`] wraps a single tungstenite stream instead,
+//! so the compiler can see (and inline) the whole path of a message.
+//!
+//! It's a plain connection: no auto-pong, keepalive, idle timeout, write coalescing, compression,
+//! connection events or stats. Check the `socket` bench for the per-message difference.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use url::Url;
+
+use super::{build_request, handshake, open, ConnectError, Deadline, Error, RawStream};
+use crate::message::Message;
+use crate::{HandshakeResponse, WebSocketBuilder};
+
+/// [`TypedWebSocket`] over TCP, plain or TLS
+pub type TcpWebSocket = TypedWebSocket<MaybeTlsStream<TcpStream>>;
+
+/// WebSocket over the concrete stream `S`
+///
+/// Check the [module](self) docs for what it doesn't do compared to [`WebSocket`](crate::WebSocket).
+pub struct TypedWebSocket<S> {
+    inner: WebSocketStream<S>,
+}
+
+impl<S> TypedWebSocket<S> {
+    /// Wrap an already established tungstenite stream
+    #[inline]
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        Self { inner }
+    }
+
+    /// Get a reference to the tungstenite stream
+    #[inline]
+    pub fn get_ref(&self) -> &WebSocketStream<S> {
+        &self.inner
+    }
+
+    /// Unwrap the tungstenite stream
+    #[inline]
+    pub fn into_inner(self) -> WebSocketStream<S> {
+        self.inner
+    }
+}
+
+impl<S> Sink<Message> for TypedWebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = Error;
+
+    #[inline]
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx).map_err(Into::into)
+    }
+
+    #[inline]
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        Pin::new(&mut self.inner)
+            .start_send(item.into())
+            .map_err(Into::into)
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(Into::into)
+    }
+
+    #[inline]
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(Into::into)
+    }
+}
+
+impl<S> Stream for TypedWebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Message, Error>;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner)
+            .poll_next(cx)
+            .map(|i| i.map(|res| res.map(Message::from).map_err(Into::into)))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Connect over TCP, with the TCP-based connection modes
+///
+/// The unix socket, the custom transports, the embedded Tor client and the raw streams
+/// return an error. The redirects aren't followed.
+pub async fn connect_typed(
+    url: &Url,
+    builder: &WebSocketBuilder,
+) -> Result<(TcpWebSocket, HandshakeResponse), ConnectError> {
+    if builder.raw.is_some() {
+        return Err(unsupported());
+    }
+
+    let deadline: Deadline = Deadline::from(builder);
+    let request = build_request(url, builder, false).await?;
+    let (transport, _, deadline) = open(url, builder, deadline).await?;
+
+    let RawStream::Tcp(conn) = transport else {
+        return Err(unsupported());
+    };
+
+    let (stream, response) = handshake(url, request, conn, builder, &deadline).await?;
+    Ok((
+        TypedWebSocket::new(stream),
+        HandshakeResponse::from(&response),
+    ))
+}
+
+fn unsupported() -> ConnectError {
+    ConnectError::from(WsError::Io(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the connection mode isn't carried over TCP",
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::native::WsServer;
+    #[cfg(unix)]
+    use crate::ConnectionMode;
+
+    #[tokio::test]
+    async fn test_connect_typed() {
+        let mut server: WsServer = WsServer::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap();
+
+        let accept = tokio::spawn(async move {
+            let (mut tx, mut rx, _) = server.next().await.unwrap().unwrap();
+            let msg: Message = rx.next().await.unwrap().unwrap();
+            tx.send(msg).await.unwrap();
+        });
+
+        let (mut socket, _) = connect_typed(&url, &WebSocketBuilder::new()).await.unwrap();
+        socket.send(Message::text("hello")).await.unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            Message::text("hello")
+        );
+
+        accept.await.unwrap();
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn test_not_tcp() {
+        let path = std::env::temp_dir().join(format!("wsocket-typed-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        let builder = WebSocketBuilder::new().mode(ConnectionMode::unix_socket(&path));
+        let url = Url::parse("ws://localhost").unwrap();
+        match connect_typed(&url, &builder).await {
+            Err(ConnectError::Ws(e)) => {
+                assert!(matches!(*e, WsError::Io(ref e) if e.kind() == io::ErrorKind::Unsupported))
+            }
+            _ => panic!("expected an unsupported error"),
+        }
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
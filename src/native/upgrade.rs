@@ -0,0 +1,403 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Adapters for the WebSockets upgraded by HTTP servers
+//!
+//! Unlike [`take_upgraded`](super::take_upgraded), they use the crate [`Message`](crate::Message) type,
+//! so the message handling logic can be shared by the servers and the clients.
+
+#[cfg(feature = "actix-web")]
+use std::fmt;
+#[cfg(feature = "actix-web")]
+use std::future::Future;
+#[cfg(any(feature = "axum", feature = "actix-web", feature = "warp"))]
+use std::io;
+#[cfg(any(feature = "axum", feature = "actix-web", feature = "warp"))]
+use std::pin::Pin;
+#[cfg(any(feature = "axum", feature = "actix-web", feature = "warp"))]
+use std::task::{Context, Poll};
+
+#[cfg(feature = "actix-web")]
+use actix_ws::{
+    AggregatedMessage, AggregatedMessageStream, CloseCode, CloseReason, Closed, MessageStream,
+    ProtocolError, Session,
+};
+#[cfg(feature = "axum")]
+use axum::extract::ws::{
+    CloseFrame as AxumCloseFrame, Message as AxumMessage, WebSocket as AxumWebSocket,
+};
+#[cfg(feature = "actix-web")]
+use futures_util::future::BoxFuture;
+#[cfg(feature = "actix-web")]
+use futures_util::ready;
+#[cfg(any(feature = "axum", feature = "actix-web", feature = "warp"))]
+use futures_util::{Sink, Stream};
+#[cfg(feature = "hyper")]
+use hyper::upgrade::Upgraded;
+#[cfg(feature = "hyper")]
+use hyper_util::rt::TokioIo;
+#[cfg(feature = "hyper")]
+use tokio_tungstenite::tungstenite::protocol::Role;
+#[cfg(any(feature = "axum", feature = "actix-web", feature = "warp"))]
+use tokio_tungstenite::tungstenite::Error as WsError;
+#[cfg(feature = "hyper")]
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+#[cfg(feature = "warp")]
+use warp::ws::{Message as WarpMessage, WebSocket as WarpWebSocket};
+
+#[cfg(any(feature = "axum", feature = "actix-web", feature = "warp"))]
+use super::Error;
+#[cfg(feature = "hyper")]
+use super::{IoStream, WebSocketConfig};
+#[cfg(feature = "warp")]
+use crate::message::Bytes;
+#[cfg(any(feature = "axum", feature = "actix-web", feature = "warp"))]
+use crate::message::{CloseFrame, Message};
+use crate::socket::{InnerWebSocket, WebSocket};
+
+/// Take a connection upgraded by [hyper](https://docs.rs/hyper)
+///
+/// The handshake (subprotocol included) must be already completed by the server.
+#[inline]
+#[cfg(feature = "hyper")]
+pub async fn from_hyper(upgraded: Upgraded) -> WebSocket {
+    from_hyper_with_config(upgraded, None).await
+}
+
+/// Take a connection upgraded by [hyper](https://docs.rs/hyper), with a custom [`WebSocketConfig`]
+#[cfg(feature = "hyper")]
+pub async fn from_hyper_with_config(
+    upgraded: Upgraded,
+    config: Option<WebSocketConfig>,
+) -> WebSocket {
+    let stream: Box<dyn IoStream> = Box::new(TokioIo::new(upgraded));
+    let stream = WebSocketStream::from_raw_socket(
+        MaybeTlsStream::Plain(stream),
+        Role::Server,
+        config.map(Into::into),
+    )
+    .await;
+    WebSocket::new(InnerWebSocket::Custom(stream), None)
+}
+
+/// Take a WebSocket accepted by [axum](https://docs.rs/axum)
+///
+/// The subprotocol selected by the server, if any, is kept.
+#[cfg(feature = "axum")]
+pub fn from_axum(socket: AxumWebSocket) -> WebSocket {
+    let protocol: Option<String> = socket
+        .protocol()
+        .and_then(|p| p.to_str().ok())
+        .map(|p| p.to_string());
+    WebSocket::new(InnerWebSocket::Axum(AxumSocket(socket)), protocol)
+}
+
+/// WebSocket accepted by axum, with the crate message and error types
+#[cfg(feature = "axum")]
+pub(crate) struct AxumSocket(AxumWebSocket);
+
+#[cfg(feature = "axum")]
+impl Sink<Message> for AxumSocket {
+    type Error = Error;
+
+    #[inline]
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.0).poll_ready(cx).map_err(axum_error)
+    }
+
+    #[inline]
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        Pin::new(&mut self.0)
+            .start_send(to_axum(item))
+            .map_err(axum_error)
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.0).poll_flush(cx).map_err(axum_error)
+    }
+
+    #[inline]
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.0).poll_close(cx).map_err(axum_error)
+    }
+}
+
+#[cfg(feature = "axum")]
+impl Stream for AxumSocket {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0)
+            .poll_next(cx)
+            .map(|i| i.map(|res| res.map(from_axum_message).map_err(axum_error)))
+    }
+}
+
+#[cfg(feature = "axum")]
+fn to_axum(message: Message) -> AxumMessage {
+    match message {
+        Message::Text(text) => AxumMessage::Text(text.to_string().into()),
+        Message::Binary(data) => AxumMessage::Binary(data),
+        Message::Ping(data) => AxumMessage::Ping(data),
+        Message::Pong(data) => AxumMessage::Pong(data),
+        Message::Close(frame) => AxumMessage::Close(frame.map(|frame| AxumCloseFrame {
+            code: frame.code.as_u16(),
+            reason: frame.reason.to_string().into(),
+        })),
+    }
+}
+
+#[cfg(feature = "axum")]
+fn from_axum_message(message: AxumMessage) -> Message {
+    match message {
+        AxumMessage::Text(text) => Message::Text(text.as_str().into()),
+        AxumMessage::Binary(data) => Message::Binary(data),
+        AxumMessage::Ping(data) => Message::Ping(data),
+        AxumMessage::Pong(data) => Message::Pong(data),
+        AxumMessage::Close(frame) => Message::Close(frame.map(|frame| CloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason.as_str().into(),
+        })),
+    }
+}
+
+/// Recover the WebSocket error wrapped by axum, if any
+#[cfg(feature = "axum")]
+fn axum_error(e: axum::Error) -> Error {
+    match e.into_inner().downcast::<WsError>() {
+        Ok(e) => Error::from(*e),
+        Err(e) => Error::from(WsError::Io(io::Error::new(io::ErrorKind::Other, e))),
+    }
+}
+
+/// Take a WebSocket accepted by [warp](https://docs.rs/warp)
+#[cfg(feature = "warp")]
+pub fn from_warp(socket: WarpWebSocket) -> WebSocket {
+    WebSocket::new(InnerWebSocket::Warp(WarpSocket(socket)), None)
+}
+
+/// WebSocket accepted by warp, with the crate message and error types
+#[cfg(feature = "warp")]
+pub(crate) struct WarpSocket(WarpWebSocket);
+
+#[cfg(feature = "warp")]
+impl Sink<Message> for WarpSocket {
+    type Error = Error;
+
+    #[inline]
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.0).poll_ready(cx).map_err(warp_error)
+    }
+
+    #[inline]
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        Pin::new(&mut self.0)
+            .start_send(to_warp(item))
+            .map_err(warp_error)
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.0).poll_flush(cx).map_err(warp_error)
+    }
+
+    #[inline]
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.0).poll_close(cx).map_err(warp_error)
+    }
+}
+
+#[cfg(feature = "warp")]
+impl Stream for WarpSocket {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0)
+            .poll_next(cx)
+            .map(|i| i.map(|res| res.map(from_warp_message).map_err(warp_error)))
+    }
+}
+
+#[cfg(feature = "warp")]
+fn to_warp(message: Message) -> WarpMessage {
+    match message {
+        Message::Text(text) => WarpMessage::text(text.to_string()),
+        Message::Binary(data) => WarpMessage::binary(data.to_vec()),
+        Message::Ping(data) => WarpMessage::ping(data.to_vec()),
+        Message::Pong(data) => WarpMessage::pong(data.to_vec()),
+        Message::Close(Some(frame)) => {
+            WarpMessage::close_with(frame.code.as_u16(), frame.reason.to_string())
+        }
+        Message::Close(None) => WarpMessage::close(),
+    }
+}
+
+#[cfg(feature = "warp")]
+fn from_warp_message(message: WarpMessage) -> Message {
+    if message.is_close() {
+        return Message::Close(message.close_frame().map(|(code, reason)| CloseFrame {
+            code: code.into(),
+            reason: reason.into(),
+        }));
+    }
+
+    if let Ok(text) = message.to_str() {
+        return Message::Text(text.into());
+    }
+
+    let is_ping: bool = message.is_ping();
+    let is_pong: bool = message.is_pong();
+    let data: Bytes = Bytes::from(message.into_bytes());
+    if is_ping {
+        Message::Ping(data)
+    } else if is_pong {
+        Message::Pong(data)
+    } else {
+        Message::Binary(data)
+    }
+}
+
+#[cfg(feature = "warp")]
+fn warp_error(e: warp::Error) -> Error {
+    Error::from(WsError::Io(io::Error::new(io::ErrorKind::Other, e)))
+}
+
+/// Take a WebSocket accepted by [actix-web](https://docs.rs/actix-web), with [actix-ws](https://docs.rs/actix-ws)
+///
+/// The continuation frames are aggregated into whole messages.
+#[cfg(feature = "actix-web")]
+pub fn from_actix(session: Session, stream: MessageStream) -> ActixWebSocket {
+    ActixWebSocket {
+        session,
+        stream: stream.aggregate_continuations(),
+        pending: None,
+        closed: false,
+    }
+}
+
+/// WebSocket accepted by actix-web, with the crate message and error types
+///
+/// Unlike the other adapters it isn't a [`WebSocket`],
+/// since the actix-web streams can't be sent to other threads.
+#[cfg(feature = "actix-web")]
+pub struct ActixWebSocket {
+    session: Session,
+    stream: AggregatedMessageStream,
+    /// Message being sent
+    pending: Option<BoxFuture<'static, Result<(), Closed>>>,
+    /// The close frame has been sent
+    closed: bool,
+}
+
+#[cfg(feature = "actix-web")]
+impl fmt::Debug for ActixWebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ActixWebSocket")
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+#[cfg(feature = "actix-web")]
+impl ActixWebSocket {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if let Some(pending) = &mut self.pending {
+            let res: Result<(), Closed> = ready!(pending.as_mut().poll(cx));
+            self.pending = None;
+            res.map_err(|Closed| Error::from(WsError::AlreadyClosed))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "actix-web")]
+impl Sink<Message> for ActixWebSocket {
+    type Error = Error;
+
+    #[inline]
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let mut session: Session = this.session.clone();
+
+        let pending: BoxFuture<'static, Result<(), Closed>> = match item {
+            Message::Text(text) => Box::pin(async move { session.text(text.to_string()).await }),
+            Message::Binary(data) => Box::pin(async move { session.binary(data).await }),
+            Message::Ping(data) => Box::pin(async move { session.ping(&data).await }),
+            Message::Pong(data) => Box::pin(async move { session.pong(&data).await }),
+            Message::Close(frame) => {
+                this.closed = true;
+                Box::pin(session.close(frame.map(to_actix_close)))
+            }
+        };
+
+        this.pending = Some(pending);
+        Ok(())
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+
+        if !this.closed {
+            this.closed = true;
+            let session: Session = this.session.clone();
+            this.pending = Some(Box::pin(async move {
+                // Already closed by the peer
+                let _ = session.close(None).await;
+                Ok(())
+            }));
+            ready!(this.poll_pending(cx))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "actix-web")]
+impl Stream for ActixWebSocket {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.stream)
+            .poll_next(cx)
+            .map(|i| i.map(|res| res.map(from_actix_message).map_err(actix_error)))
+    }
+}
+
+#[cfg(feature = "actix-web")]
+fn to_actix_close(frame: CloseFrame) -> CloseReason {
+    CloseReason {
+        code: CloseCode::from(frame.code.as_u16()),
+        description: Some(frame.reason.to_string()),
+    }
+}
+
+#[cfg(feature = "actix-web")]
+fn from_actix_message(message: AggregatedMessage) -> Message {
+    match message {
+        AggregatedMessage::Text(text) => Message::Text(text.to_string().into()),
+        AggregatedMessage::Binary(data) => Message::Binary(data),
+        AggregatedMessage::Ping(data) => Message::Ping(data),
+        AggregatedMessage::Pong(data) => Message::Pong(data),
+        AggregatedMessage::Close(reason) => Message::Close(reason.map(|reason| CloseFrame {
+            code: u16::from(reason.code).into(),
+            reason: reason.description.unwrap_or_default().into(),
+        })),
+    }
+}
+
+#[cfg(feature = "actix-web")]
+fn actix_error(e: ProtocolError) -> Error {
+    Error::from(WsError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))
+}
@@ -0,0 +1,187 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! WebSockets over WebTransport (HTTP/3, experimental)
+//!
+//! The WebSocket frames are exchanged over a bidirectional stream of a WebTransport session,
+//! so the server must do the same (ex. with [`take_upgraded`](super::take_upgraded) over the accepted stream).
+//! QUIC avoids the head-of-line blocking of TCP, for latency-sensitive workloads.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::header::{
+    CONNECTION, HOST, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use url::Url;
+use wtransport::endpoint::ConnectOptions;
+use wtransport::{ClientConfig, Connection, Endpoint, RecvStream, SendStream};
+
+use super::{build_request, ws_config, ConnectError, Deadline, IoStream};
+use crate::socket::{InnerWebSocket, WebSocket};
+use crate::{ConnectionMode, HandshakeResponse, Phase, WebSocketBuilder};
+
+/// Open a WebTransport session and a WebSocket over its first bidirectional stream
+pub(crate) async fn connect(
+    url: &Url,
+    builder: &WebSocketBuilder,
+) -> Result<(WebSocket, HandshakeResponse), ConnectError> {
+    // QUIC can't be tunneled through the proxies: never fall back to a direct connection
+    if builder.mode != ConnectionMode::Direct {
+        return Err(ConnectError::H3(
+            "only the direct connection mode is supported over QUIC".into(),
+        ));
+    }
+
+    let options: ConnectOptions = options(url, builder).await?;
+    let config: ClientConfig = client_config(builder)?;
+    let deadline: Deadline = Deadline::from(builder);
+
+    let endpoint = Endpoint::client(config).map_err(|e| ConnectError::H3(e.into()))?;
+    let connection: Connection = Box::pin(deadline.run(Phase::Tls, endpoint.connect(options)))
+        .await?
+        .map_err(|e| ConnectError::H3(e.into()))?;
+    let peer_addr = connection.remote_address();
+
+    let (send, recv) = deadline
+        .run(Phase::Handshake, async {
+            connection
+                .open_bi()
+                .await
+                .map_err(|e| ConnectError::H3(e.into()))?
+                .await
+                .map_err(|e| ConnectError::H3(e.into()))
+        })
+        .await??;
+
+    let stream: Box<dyn IoStream> = Box::new(BiStream {
+        send,
+        recv,
+        _connection: connection,
+    });
+    let stream = WebSocketStream::from_raw_socket(
+        MaybeTlsStream::Plain(stream),
+        Role::Client,
+        Some(ws_config(builder).into()),
+    )
+    .await;
+
+    Ok((
+        WebSocket::new(InnerWebSocket::Custom(stream), None).with_peer_addr(Some(peer_addr)),
+        HandshakeResponse {
+            status: 200,
+            headers: Vec::new(),
+        },
+    ))
+}
+
+/// Session request, with the `https` URL and the headers of the HTTP/1.1 handshake request
+async fn options(url: &Url, builder: &WebSocketBuilder) -> Result<ConnectOptions, ConnectError> {
+    let mut session_url: Url = url.clone();
+    match url.scheme() {
+        "wss" | "https" => {
+            let _ = session_url.set_scheme("https");
+        }
+        _ => {
+            return Err(ConnectError::from(WsError::Url(
+                UrlError::UnsupportedUrlScheme,
+            )))
+        }
+    }
+
+    // The handshake request requires a `ws` or `wss` URL
+    let mut ws_url: Url = url.clone();
+    let _ = ws_url.set_scheme("wss");
+    let request: Request = build_request(&ws_url, builder, false).await?;
+
+    let mut options = ConnectOptions::builder(session_url.as_str());
+    for (name, value) in request.headers().iter() {
+        // Not meaningful for a WebTransport session
+        if [
+            HOST,
+            CONNECTION,
+            UPGRADE,
+            SEC_WEBSOCKET_KEY,
+            SEC_WEBSOCKET_VERSION,
+        ]
+        .contains(name)
+        {
+            continue;
+        }
+
+        let value: &str = value.to_str().map_err(WsError::from)?;
+        options = options.add_header(name.as_str(), value);
+    }
+
+    Ok(options.build())
+}
+
+/// QUIC client config, with the TLS config of the builder (if supported by the TLS backend)
+#[cfg_attr(feature = "tls-native", allow(unused_variables))]
+fn client_config(builder: &WebSocketBuilder) -> Result<ClientConfig, ConnectError> {
+    #[cfg(not(feature = "tls-native"))]
+    {
+        let tls = builder.tls.clone().unwrap_or_default().quic_config()?;
+        Ok(ClientConfig::builder()
+            .with_bind_default()
+            .with_custom_tls(tls)
+            .build())
+    }
+
+    // The `native-tls` config can't be used by QUIC
+    #[cfg(feature = "tls-native")]
+    {
+        Ok(ClientConfig::builder()
+            .with_bind_default()
+            .with_native_certs()
+            .build())
+    }
+}
+
+/// Bidirectional stream of a WebTransport session
+///
+/// Keeps the session open.
+struct BiStream {
+    send: SendStream,
+    recv: RecvStream,
+    _connection: Connection,
+}
+
+impl AsyncRead for BiStream {
+    #[inline]
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for BiStream {
+    #[inline]
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
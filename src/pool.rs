@@ -0,0 +1,311 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection pool
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::future;
+use futures_util::{SinkExt, Stream};
+use url::Url;
+
+use crate::reconnect::{ExponentialBackoff, ReconnectingWebSocket};
+use crate::{Error, Extensions, Message, WebSocketBuilder};
+
+/// Pool of connections to multiple URLs
+///
+/// Every URL is connected with a [`ReconnectingWebSocket`], established in background,
+/// so each connection is re-established independently.
+///
+/// The [`Stream`] yields the incoming messages of all the connections, tagged by source URL,
+/// and drives the (re)connections: it must be polled for the pool to make progress.
+/// The connections that gave up reconnecting are removed. The stream ends when the pool is empty.
+pub struct WsPool {
+    builder: WebSocketBuilder,
+    backoff: ExponentialBackoff,
+    sockets: Vec<ReconnectingWebSocket>,
+    /// Next socket for the round-robin sends
+    next_send: usize,
+    /// First socket to poll, for fairness
+    next_poll: usize,
+}
+
+impl WsPool {
+    /// New empty pool
+    ///
+    /// All the connections are established with the same `builder` and `backoff` policy.
+    pub fn new(builder: WebSocketBuilder, backoff: ExponentialBackoff) -> Self {
+        Self {
+            builder,
+            backoff,
+            sockets: Vec::new(),
+            next_send: 0,
+            next_poll: 0,
+        }
+    }
+
+    /// Add a URL to the pool, with a new empty [`Extensions`]
+    ///
+    /// Returns `false` if already in the pool.
+    #[inline]
+    pub fn add(&mut self, url: Url) -> bool {
+        self.add_with_extensions(url, Extensions::new())
+    }
+
+    /// Add a URL to the pool, attaching `extensions` to its connection
+    ///
+    /// The extensions of the pool builder, if any, are ignored.
+    ///
+    /// Returns `false` if already in the pool.
+    pub fn add_with_extensions(&mut self, url: Url, extensions: Extensions) -> bool {
+        if self.contains(&url) {
+            return false;
+        }
+
+        self.sockets.push(ReconnectingWebSocket::new(
+            url,
+            self.builder.clone().extensions(extensions),
+            self.backoff,
+        ));
+        true
+    }
+
+    /// Remove a URL from the pool, dropping its connection
+    ///
+    /// Returns `false` if not in the pool.
+    pub fn remove(&mut self, url: &Url) -> bool {
+        let len: usize = self.sockets.len();
+        self.sockets.retain(|s| s.url() != url);
+        self.sockets.len() != len
+    }
+
+    /// Check if a URL is in the pool
+    #[inline]
+    pub fn contains(&self, url: &Url) -> bool {
+        self.sockets.iter().any(|s| s.url() == url)
+    }
+
+    /// Get the URLs of the pool
+    pub fn urls(&self) -> impl Iterator<Item = &Url> {
+        self.sockets.iter().map(|s| s.url())
+    }
+
+    /// Get the connection of a URL
+    #[inline]
+    pub fn get(&self, url: &Url) -> Option<&ReconnectingWebSocket> {
+        self.sockets.iter().find(|s| s.url() == url)
+    }
+
+    /// Get the [`Extensions`] of a URL
+    #[inline]
+    pub fn extensions(&self, url: &Url) -> Option<&Extensions> {
+        self.get(url).map(|s| s.extensions())
+    }
+
+    /// Number of URLs in the pool
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.sockets.len()
+    }
+
+    /// Check if the pool is empty
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.sockets.is_empty()
+    }
+
+    /// Number of currently connected URLs
+    pub fn connected(&self) -> usize {
+        self.sockets.iter().filter(|s| s.is_connected()).count()
+    }
+
+    /// Send a message to a URL
+    ///
+    /// Waits for the connection to be re-established, if broken.
+    pub async fn send_to(&mut self, url: &Url, msg: Message) -> Result<(), Error> {
+        match self.sockets.iter_mut().find(|s| s.url() == url) {
+            Some(socket) => socket.send(msg).await,
+            None => Err(Error::not_connected()),
+        }
+    }
+
+    /// Send a message to the next connected URL, in round-robin order
+    ///
+    /// Returns the URL the message has been sent to.
+    pub async fn send_round_robin(&mut self, msg: Message) -> Result<Url, Error> {
+        let len: usize = self.sockets.len();
+
+        for i in 0..len {
+            let index: usize = (self.next_send + i) % len;
+            let socket: &mut ReconnectingWebSocket = &mut self.sockets[index];
+
+            if socket.is_connected() {
+                self.next_send = (index + 1) % len;
+                socket.send(msg).await?;
+                return Ok(socket.url().clone());
+            }
+        }
+
+        Err(Error::not_connected())
+    }
+
+    /// Send a message to all the connected URLs, concurrently
+    ///
+    /// Returns the number of URLs the message has been sent to.
+    pub async fn broadcast(&mut self, msg: Message) -> usize {
+        let sends = self
+            .sockets
+            .iter_mut()
+            .filter(|s| s.is_connected())
+            .map(|s| s.send(msg.clone()));
+
+        future::join_all(sends)
+            .await
+            .into_iter()
+            .filter(Result::is_ok)
+            .count()
+    }
+}
+
+impl Stream for WsPool {
+    type Item = (Url, Message);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let len: usize = this.sockets.len();
+        let mut terminated: Vec<usize> = Vec::new();
+        let mut item: Option<(Url, Message)> = None;
+
+        for i in 0..len {
+            let index: usize = (this.next_poll + i) % len;
+            let socket: &mut ReconnectingWebSocket = &mut this.sockets[index];
+
+            match Pin::new(&mut *socket).poll_next(cx) {
+                Poll::Ready(Some(Ok(msg))) => {
+                    // Start from the next socket on the next poll
+                    this.next_poll = (index + 1) % len;
+                    item = Some((socket.url().clone(), msg));
+                    break;
+                }
+                // The broken connections are re-established without yielding errors
+                Poll::Ready(Some(Err(..))) => cx.waker().wake_by_ref(),
+                Poll::Ready(None) => terminated.push(index),
+                Poll::Pending => {}
+            }
+        }
+
+        // Remove the connections that gave up
+        if !terminated.is_empty() {
+            terminated.sort_unstable();
+            for index in terminated.into_iter().rev() {
+                this.sockets.remove(index);
+            }
+            this.next_poll = 0;
+            this.next_send = 0;
+        }
+
+        match item {
+            Some(item) => Poll::Ready(Some(item)),
+            None if this.sockets.is_empty() => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::time::Duration;
+
+    use futures_channel::mpsc;
+    use futures_util::StreamExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::native::WsServer;
+
+    /// Server greeting its only client with `name` and forwarding the received messages
+    async fn server(name: &'static str) -> (Url, mpsc::UnboundedReceiver<Message>) {
+        let mut server: WsServer = WsServer::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap();
+        let (received, rx) = mpsc::unbounded();
+
+        tokio::spawn(async move {
+            let (mut tx, mut rx, _) = server.next().await.unwrap().unwrap();
+            tx.send(Message::text(name)).await.unwrap();
+            while let Some(Ok(msg)) = rx.next().await {
+                received.unbounded_send(msg).unwrap();
+            }
+        });
+
+        (url, rx)
+    }
+
+    fn backoff() -> ExponentialBackoff {
+        ExponentialBackoff {
+            initial_delay: Duration::from_millis(10),
+            max_attempts: Some(2),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tagged_messages() {
+        let (a, mut received_a) = server("a").await;
+        let (b, mut received_b) = server("b").await;
+
+        let mut pool = WsPool::new(WebSocketBuilder::new(), backoff());
+        assert!(pool.add(a.clone()));
+        assert!(pool.add(b.clone()));
+        assert!(!pool.add(a.clone()));
+        assert_eq!(pool.len(), 2);
+
+        let mut greetings: Vec<(Url, Message)> =
+            vec![pool.next().await.unwrap(), pool.next().await.unwrap()];
+        greetings.sort_by_key(|x| x.0.port());
+        let mut expected = vec![
+            (a.clone(), Message::text("a")),
+            (b.clone(), Message::text("b")),
+        ];
+        expected.sort_by_key(|x| x.0.port());
+        assert_eq!(greetings, expected);
+        assert_eq!(pool.connected(), 2);
+
+        // Sent to every URL
+        assert_eq!(pool.broadcast(Message::text("all")).await, 2);
+        assert_eq!(received_a.next().await, Some(Message::text("all")));
+        assert_eq!(received_b.next().await, Some(Message::text("all")));
+
+        // Sent to one URL
+        pool.send_to(&b, Message::text("only b")).await.unwrap();
+        assert_eq!(received_b.next().await, Some(Message::text("only b")));
+
+        // Alternated between the URLs
+        let first: Url = pool.send_round_robin(Message::text("rr")).await.unwrap();
+        let second: Url = pool.send_round_robin(Message::text("rr")).await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(received_a.next().await, Some(Message::text("rr")));
+        assert_eq!(received_b.next().await, Some(Message::text("rr")));
+
+        assert!(pool.remove(&a));
+        assert!(!pool.remove(&a));
+        assert_eq!(pool.urls().collect::<Vec<_>>(), [&b]);
+        assert!(pool.send_to(&a, Message::text("gone")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_gave_up_removed() {
+        // Nothing listening
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+
+        let mut pool = WsPool::new(WebSocketBuilder::new(), backoff());
+        pool.add(closed.clone());
+        assert!(pool.contains(&closed));
+
+        // The stream ends when the last connection gives up
+        assert_eq!(pool.next().await, None);
+        assert!(pool.is_empty());
+    }
+}
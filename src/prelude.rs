@@ -8,7 +8,12 @@
 #![allow(ambiguous_glob_reexports)]
 #![doc(hidden)]
 
+#[cfg(any(feature = "json", feature = "cbor", feature = "msgpack"))]
+pub use crate::codec::{CodecSinkExt, CodecStreamExt};
+#[cfg(feature = "json")]
+pub use crate::json::{JsonSinkExt, JsonStreamExt};
 pub use crate::message::*;
 #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
 pub use crate::native::tor::{self, *};
+pub use crate::reconnect::*;
 pub use crate::*;
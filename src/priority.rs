@@ -0,0 +1,305 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Priority queue for outgoing messages
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::{ready, Sink, Stream};
+
+use crate::{Error, Message};
+
+/// Priority class of an outgoing message
+///
+/// Sorted from the most to the least urgent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Control messages (ex. ping, pong and close)
+    Control,
+    /// Small time-critical messages
+    Realtime,
+    /// Large or not time-critical messages
+    Bulk,
+}
+
+impl Priority {
+    const LEN: usize = 3;
+
+    /// Default priority of a message: [`Priority::Control`] for ping, pong and close,
+    /// [`Priority::Realtime`] for text and [`Priority::Bulk`] for binary messages
+    pub fn of(msg: &Message) -> Self {
+        match msg {
+            Message::Text(..) => Self::Realtime,
+            Message::Binary(..) => Self::Bulk,
+            Message::Ping(..) | Message::Pong(..) | Message::Close(..) => Self::Control,
+        }
+    }
+
+    #[inline]
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Priority sink
+///
+/// Buffers the outgoing messages in priority classes and hands them to the inner sink
+/// from the most to the least urgent class (FIFO within the same class),
+/// so queued bulk messages don't delay the time-critical ones.
+///
+/// Send `(Priority, Message)` to choose the class or a plain [`Message`] to use [`Priority::of`].
+///
+/// The queue is unbounded: messages are written to the socket when it's ready
+/// and a message already handed to the socket is never preempted.
+/// The [`Stream`] is passed through.
+pub struct PrioritySink<S> {
+    socket: S,
+    queues: [VecDeque<Message>; Priority::LEN],
+}
+
+impl<S> PrioritySink<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    /// Wrap a socket
+    pub fn new(socket: S) -> Self {
+        Self {
+            socket,
+            queues: Default::default(),
+        }
+    }
+
+    /// Get a reference to the inner socket
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    /// Get a mutable reference to the inner socket
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    /// Consume the wrapper, returning the inner socket
+    ///
+    /// The queued messages are dropped.
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+
+    /// Number of queued messages of a priority class
+    #[inline]
+    pub fn queued_len(&self, priority: Priority) -> usize {
+        self.queues[priority.index()].len()
+    }
+
+    /// Check if there are no queued messages
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+
+    /// Hand the queued messages to the inner sink, most urgent first
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while !self.is_empty() {
+            ready!(Pin::new(&mut self.socket).poll_ready(cx))?;
+
+            // Pick the message only when the socket is ready: a more urgent one may have been queued
+            if let Some(msg) = self.queues.iter_mut().find_map(VecDeque::pop_front) {
+                Pin::new(&mut self.socket).start_send(msg)?;
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> Sink<(Priority, Message)> for PrioritySink<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Unbounded: just make progress with the queued messages
+        if let Poll::Ready(Err(e)) = self.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        (priority, msg): (Priority, Message),
+    ) -> Result<(), Self::Error> {
+        self.queues[priority.index()].push_back(msg);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.socket).poll_close(cx)
+    }
+}
+
+impl<S> Sink<Message> for PrioritySink<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    type Error = Error;
+
+    #[inline]
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<(Priority, Message)>::poll_ready(self, cx)
+    }
+
+    #[inline]
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        Sink::<(Priority, Message)>::start_send(self, (Priority::of(&item), item))
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<(Priority, Message)>::poll_flush(self, cx)
+    }
+
+    #[inline]
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::<(Priority, Message)>::poll_close(self, cx)
+    }
+}
+
+impl<S> Stream for PrioritySink<S>
+where
+    S: Stream<Item = Result<Message, Error>> + Unpin,
+{
+    type Item = Result<Message, Error>;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.socket).poll_next(cx)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.socket.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::task::noop_waker_ref;
+
+    use super::*;
+
+    /// Sink that accepts the messages only when `ready`
+    #[derive(Default)]
+    struct Stalled {
+        ready: bool,
+        sent: Vec<Message>,
+    }
+
+    impl Sink<Message> for Stalled {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            if self.ready {
+                Poll::Ready(Ok(()))
+            } else {
+                Poll::Pending
+            }
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Error> {
+            self.sent.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            self.poll_ready(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            self.poll_ready(cx)
+        }
+    }
+
+    fn feed<T>(sink: &mut PrioritySink<Stalled>, item: T)
+    where
+        PrioritySink<Stalled>: Sink<T, Error = Error>,
+    {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(matches!(
+            Pin::new(&mut *sink).poll_ready(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        Pin::new(sink).start_send(item).unwrap();
+    }
+
+    fn flush(sink: &mut PrioritySink<Stalled>) -> Poll<Result<(), Error>> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        Sink::<Message>::poll_flush(Pin::new(sink), &mut cx)
+    }
+
+    #[test]
+    fn test_order() {
+        let mut sink = PrioritySink::new(Stalled::default());
+        feed(&mut sink, Message::binary(vec![1]));
+        feed(&mut sink, Message::text("a"));
+        feed(&mut sink, Message::Ping(vec![2].into()));
+        feed(&mut sink, (Priority::Bulk, Message::text("b")));
+        feed(&mut sink, Message::text("c"));
+
+        // Buffered until the socket is ready
+        assert!(flush(&mut sink).is_pending());
+        assert_eq!(sink.queued_len(Priority::Control), 1);
+        assert_eq!(sink.queued_len(Priority::Realtime), 2);
+        assert_eq!(sink.queued_len(Priority::Bulk), 2);
+
+        sink.get_mut().ready = true;
+        assert!(matches!(flush(&mut sink), Poll::Ready(Ok(()))));
+        assert!(sink.is_empty());
+        assert_eq!(
+            sink.into_inner().sent,
+            [
+                Message::Ping(vec![2].into()),
+                Message::text("a"),
+                Message::text("c"),
+                Message::binary(vec![1]),
+                Message::text("b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_urgent_preempts_queued() {
+        let mut sink = PrioritySink::new(Stalled::default());
+        feed(&mut sink, Message::binary(vec![1]));
+        feed(&mut sink, Message::binary(vec![2]));
+
+        // Queued after the bulk messages, written first
+        feed(&mut sink, Message::text("urgent"));
+        sink.get_mut().ready = true;
+        feed(&mut sink, Message::binary(vec![3]));
+
+        assert_eq!(
+            sink.get_ref().sent,
+            [
+                Message::text("urgent"),
+                Message::binary(vec![1]),
+                Message::binary(vec![2]),
+            ]
+        );
+        assert_eq!(sink.queued_len(Priority::Bulk), 1);
+    }
+}
@@ -0,0 +1,400 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Bounded send queue
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::{ready, Sink, Stream};
+
+use crate::{Error, Message, StatsHandle};
+
+/// What to do when a message is sent and the queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OverflowPolicy {
+    /// Wait until there is room in the queue
+    #[default]
+    Block,
+    /// Drop the oldest queued messages to make room
+    DropOldest,
+    /// Drop the new message
+    DropNewest,
+    /// Return [`Error::QueueFull`]
+    Error,
+}
+
+/// Send queue config
+///
+/// A message is always accepted if the queue is empty, even if larger than the limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SendQueueConfig {
+    /// Max number of queued messages (default: 1024)
+    pub max_len: usize,
+    /// Max size of the queued messages, in bytes (default: 16 MiB)
+    pub max_bytes: Option<usize>,
+    /// Overflow policy (default: [`OverflowPolicy::Block`])
+    pub policy: OverflowPolicy,
+}
+
+impl Default for SendQueueConfig {
+    fn default() -> Self {
+        Self {
+            max_len: 1024,
+            max_bytes: Some(16 * 1024 * 1024),
+            policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+/// Bounded send queue
+///
+/// Buffers the outgoing messages up to the limits of [`SendQueueConfig`],
+/// applying the [`OverflowPolicy`] when they are reached,
+/// so a slow peer can't cause an unbounded memory growth.
+///
+/// The queued messages are written to the socket when it's ready. The [`Stream`] is passed through.
+pub struct SendQueue<S> {
+    socket: S,
+    config: SendQueueConfig,
+    queue: VecDeque<Message>,
+    /// Size of the queued messages
+    bytes: usize,
+    /// Number of dropped messages
+    dropped: u64,
+    stats: Option<StatsHandle>,
+}
+
+impl<S> SendQueue<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    /// Wrap a socket
+    pub fn new(socket: S, config: SendQueueConfig) -> Self {
+        Self {
+            socket,
+            config,
+            queue: VecDeque::new(),
+            bytes: 0,
+            dropped: 0,
+            stats: None,
+        }
+    }
+
+    /// Report the size of the queued messages to `stats`
+    ///
+    /// Check [`ConnectionStats::queued_bytes`](crate::ConnectionStats::queued_bytes).
+    pub fn with_stats(mut self, stats: StatsHandle) -> Self {
+        stats.set_queued_bytes(self.bytes);
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Get a reference to the inner socket
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    /// Get a mutable reference to the inner socket
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    /// Consume the wrapper, returning the inner socket
+    ///
+    /// The queued messages are dropped.
+    pub fn into_inner(self) -> S {
+        if let Some(stats) = &self.stats {
+            stats.set_queued_bytes(0);
+        }
+        self.socket
+    }
+
+    /// Number of queued messages
+    #[inline]
+    pub fn queued_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Size of the queued messages, in bytes
+    #[inline]
+    pub fn queued_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Number of messages dropped by the [`OverflowPolicy`]
+    #[inline]
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Check if a message of `len` bytes doesn't fit in the queue
+    fn is_full(&self, len: usize) -> bool {
+        // A message is always accepted by an empty queue
+        if self.queue.is_empty() {
+            return false;
+        }
+
+        self.queue.len() >= self.config.max_len
+            || self
+                .config
+                .max_bytes
+                .is_some_and(|max| self.bytes.saturating_add(len) > max)
+    }
+
+    fn push(&mut self, msg: Message) {
+        self.bytes += msg.len();
+        self.queue.push_back(msg);
+        self.report();
+    }
+
+    fn pop(&mut self) -> Option<Message> {
+        let msg: Message = self.queue.pop_front()?;
+        self.bytes -= msg.len();
+        self.report();
+        Some(msg)
+    }
+
+    #[inline]
+    fn report(&self) {
+        if let Some(stats) = &self.stats {
+            stats.set_queued_bytes(self.bytes);
+        }
+    }
+
+    /// Write the queued messages to the inner sink
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while !self.queue.is_empty() {
+            ready!(Pin::new(&mut self.socket).poll_ready(cx))?;
+
+            if let Some(msg) = self.pop() {
+                Pin::new(&mut self.socket).start_send(msg)?;
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> Sink<Message> for SendQueue<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Make progress with the queued messages
+        if let Poll::Ready(Err(e)) = self.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        // The waker has been registered by the inner sink, that isn't ready
+        if self.config.policy == OverflowPolicy::Block && self.is_full(0) {
+            return Poll::Pending;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let len: usize = item.len();
+
+        if self.is_full(len) {
+            match self.config.policy {
+                // Checked in `poll_ready`: only the size limit may be exceeded
+                OverflowPolicy::Block => {}
+                OverflowPolicy::DropOldest => {
+                    while self.is_full(len) {
+                        self.pop();
+                        self.dropped += 1;
+                    }
+                }
+                OverflowPolicy::DropNewest => {
+                    self.dropped += 1;
+                    return Ok(());
+                }
+                OverflowPolicy::Error => return Err(Error::QueueFull),
+            }
+        }
+
+        self.push(item);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.socket).poll_close(cx)
+    }
+}
+
+impl<S> Stream for SendQueue<S>
+where
+    S: Stream<Item = Result<Message, Error>> + Unpin,
+{
+    type Item = Result<Message, Error>;
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.socket).poll_next(cx)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.socket.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::task::noop_waker_ref;
+
+    use super::*;
+
+    /// Sink that accepts the messages only when `ready`
+    #[derive(Default)]
+    struct Stalled {
+        ready: bool,
+        sent: Vec<Message>,
+    }
+
+    impl Sink<Message> for Stalled {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            if self.ready {
+                Poll::Ready(Ok(()))
+            } else {
+                Poll::Pending
+            }
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Error> {
+            self.sent.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            self.poll_ready(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            self.poll_ready(cx)
+        }
+    }
+
+    fn queue(
+        max_len: usize,
+        max_bytes: Option<usize>,
+        policy: OverflowPolicy,
+    ) -> SendQueue<Stalled> {
+        SendQueue::new(
+            Stalled::default(),
+            SendQueueConfig {
+                max_len,
+                max_bytes,
+                policy,
+            },
+        )
+    }
+
+    /// Send without waiting: returns `None` if the queue isn't ready
+    fn try_send(queue: &mut SendQueue<Stalled>, msg: &str) -> Option<Result<(), Error>> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        match Pin::new(&mut *queue).poll_ready(&mut cx) {
+            Poll::Ready(Ok(())) => Some(Pin::new(queue).start_send(Message::text(msg))),
+            Poll::Ready(Err(e)) => Some(Err(e)),
+            Poll::Pending => None,
+        }
+    }
+
+    fn queued(queue: &SendQueue<Stalled>) -> Vec<&str> {
+        queue.queue.iter().filter_map(Message::as_text).collect()
+    }
+
+    #[test]
+    fn test_block() {
+        let mut queue = queue(2, None, OverflowPolicy::Block);
+        assert!(try_send(&mut queue, "a").unwrap().is_ok());
+        assert!(try_send(&mut queue, "b").unwrap().is_ok());
+        assert!(try_send(&mut queue, "c").is_none());
+        assert_eq!(queued(&queue), ["a", "b"]);
+
+        // Drained when the socket is ready
+        queue.get_mut().ready = true;
+        assert!(try_send(&mut queue, "c").unwrap().is_ok());
+        assert_eq!(queued(&queue), ["c"]);
+        assert_eq!(queue.get_ref().sent.len(), 2);
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let mut queue = queue(2, None, OverflowPolicy::DropOldest);
+        for msg in ["a", "b", "c", "d"] {
+            assert!(try_send(&mut queue, msg).unwrap().is_ok());
+        }
+        assert_eq!(queued(&queue), ["c", "d"]);
+        assert_eq!(queue.dropped(), 2);
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let mut queue = queue(2, None, OverflowPolicy::DropNewest);
+        for msg in ["a", "b", "c", "d"] {
+            assert!(try_send(&mut queue, msg).unwrap().is_ok());
+        }
+        assert_eq!(queued(&queue), ["a", "b"]);
+        assert_eq!(queue.dropped(), 2);
+    }
+
+    #[test]
+    fn test_error() {
+        let mut queue = queue(2, None, OverflowPolicy::Error);
+        assert!(try_send(&mut queue, "a").unwrap().is_ok());
+        assert!(try_send(&mut queue, "b").unwrap().is_ok());
+        assert!(matches!(
+            try_send(&mut queue, "c").unwrap(),
+            Err(Error::QueueFull)
+        ));
+        assert_eq!(queued(&queue), ["a", "b"]);
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn test_max_bytes() {
+        let mut queue = queue(usize::MAX, Some(4), OverflowPolicy::DropOldest);
+
+        // Always accepted by the empty queue
+        assert!(try_send(&mut queue, "large").unwrap().is_ok());
+        assert_eq!(queue.queued_bytes(), 5);
+
+        assert!(try_send(&mut queue, "ab").unwrap().is_ok());
+        assert!(try_send(&mut queue, "cd").unwrap().is_ok());
+        assert_eq!(queued(&queue), ["ab", "cd"]);
+        assert_eq!(queue.queued_bytes(), 4);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = StatsHandle::default();
+        let mut queue = queue(2, None, OverflowPolicy::Block).with_stats(stats.clone());
+        assert!(try_send(&mut queue, "abc").unwrap().is_ok());
+        assert_eq!(stats.snapshot().queued_bytes, 3);
+
+        queue.get_mut().ready = true;
+        assert!(try_send(&mut queue, "d").unwrap().is_ok());
+        assert_eq!(stats.snapshot().queued_bytes, 1);
+
+        queue.into_inner();
+        assert_eq!(stats.snapshot().queued_bytes, 0);
+    }
+}
@@ -0,0 +1,531 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Automatic reconnection
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures_util::{ready, Sink, Stream};
+use url::Url;
+
+use crate::stats::StatsHandle;
+use crate::{
+    time, BoxedFuture, ConnectError, Error, Extensions, Message, WebSocket, WebSocketBuilder,
+};
+
+/// Exponential backoff policy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExponentialBackoff {
+    /// Delay before the first reconnection attempt
+    pub initial_delay: Duration,
+    /// Max delay between two reconnection attempts
+    pub max_delay: Duration,
+    /// Factor applied to the delay after every failed attempt
+    pub multiplier: f64,
+    /// Max number of consecutive failed attempts before giving up (`None` means unlimited)
+    pub max_attempts: Option<u32>,
+    /// Max fraction of the delay randomly removed from it, in `0.0..=1.0` (default: `0.0`)
+    ///
+    /// Spreads the reconnections of many clients dropped at the same time.
+    pub jitter: f64,
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            max_attempts: None,
+            jitter: 0.0,
+        }
+    }
+}
+
+impl ExponentialBackoff {
+    /// Get the delay for the attempt number `attempt` (starting from `1`)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp: i32 = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let secs: f64 = self.initial_delay.as_secs_f64() * self.multiplier.powi(exp);
+        if secs.is_finite() && secs < self.max_delay.as_secs_f64() {
+            Duration::from_secs_f64(secs)
+        } else {
+            self.max_delay
+        }
+    }
+
+    /// Get the delay for the attempt number `attempt`, minus the jitter
+    ///
+    /// `random` must be in `0.0..1.0`.
+    fn jittered_delay(&self, attempt: u32, random: f64) -> Duration {
+        let delay: Duration = self.delay(attempt);
+        let jitter: f64 = self.jitter.clamp(0.0, 1.0) * random;
+        delay.mul_f64(1.0 - jitter)
+    }
+
+    /// Check if another attempt is allowed
+    #[inline]
+    fn is_allowed(&self, attempt: u32) -> bool {
+        match self.max_attempts {
+            Some(max) => attempt <= max,
+            None => true,
+        }
+    }
+}
+
+/// Random number in `0.0..1.0`
+///
+/// Not uniform enough for anything else than spreading the reconnections.
+fn random() -> f64 {
+    let hash: u64 = RandomState::new().build_hasher().finish();
+    // 53 random bits
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Reconnection event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ReconnectEvent {
+    /// The connection has been lost
+    Disconnected,
+    /// Waiting `delay` before the reconnection attempt number `attempt`
+    Reconnecting {
+        /// Attempt number (starting from `1`)
+        attempt: u32,
+        /// Delay before the attempt
+        delay: Duration,
+    },
+    /// Reconnected after `attempts` attempts
+    Reconnected {
+        /// Number of attempts
+        attempts: u32,
+    },
+    /// Max attempts reached or permanent error (check [`ConnectError::is_permanent`]):
+    /// the connection will not be re-established
+    GaveUp,
+}
+
+enum State {
+    Connected(Box<WebSocket>),
+    Waiting {
+        attempt: u32,
+        sleep: BoxedFuture<'static, ()>,
+    },
+    Connecting {
+        attempt: u32,
+        future: BoxedFuture<'static, Result<WebSocket, ConnectError>>,
+    },
+    Terminated,
+}
+
+/// Use the same extensions for all the connections
+fn shared_extensions(builder: WebSocketBuilder) -> (WebSocketBuilder, StatsHandle) {
+    let extensions: Extensions = builder.extensions.clone().unwrap_or_default();
+    let stats: StatsHandle = StatsHandle::default().with_extensions(extensions.clone());
+    (builder.extensions(extensions), stats)
+}
+
+/// WebSocket that transparently re-establishes dropped connections
+///
+/// The connection is re-established with the same [`WebSocketBuilder`] (so same mode, timeout, headers, etc.).
+///
+/// Messages sent while the connection is broken are **not** buffered:
+/// the [`Sink`] waits until the connection is re-established before accepting new messages.
+///
+/// The [`Stream`] yields the error that dropped the connection (if any) before reconnecting:
+/// it ends only when the reconnection is given up or the socket is closed.
+pub struct ReconnectingWebSocket {
+    url: Url,
+    builder: WebSocketBuilder,
+    backoff: ExponentialBackoff,
+    state: State,
+    observers: Vec<UnboundedSender<ReconnectEvent>>,
+    /// Stats of all the connections
+    stats: StatsHandle,
+    /// Connected at least once
+    connected_once: bool,
+}
+
+impl ReconnectingWebSocket {
+    /// Connect
+    ///
+    /// The first connection attempt is not retried: if fails, the error is returned.
+    pub async fn connect(
+        url: Url,
+        builder: WebSocketBuilder,
+        backoff: ExponentialBackoff,
+    ) -> Result<Self, ConnectError> {
+        let (builder, stats) = shared_extensions(builder);
+        let socket: WebSocket = builder.connect(&url).await?.with_stats(stats.clone());
+        Ok(Self {
+            url,
+            builder,
+            backoff,
+            state: State::Connected(Box::new(socket)),
+            observers: Vec::new(),
+            stats,
+            connected_once: true,
+        })
+    }
+
+    /// New lazy connection
+    ///
+    /// Unlike [`ReconnectingWebSocket::connect`], the connection is established in background
+    /// when the socket is first polled and the first attempt is retried with the backoff policy.
+    pub fn new(url: Url, builder: WebSocketBuilder, backoff: ExponentialBackoff) -> Self {
+        let (builder, stats) = shared_extensions(builder);
+        let future = {
+            let url: Url = url.clone();
+            let builder: WebSocketBuilder = builder.clone();
+            Box::pin(async move { builder.connect(&url).await })
+        };
+
+        Self {
+            url,
+            builder,
+            backoff,
+            state: State::Connecting { attempt: 1, future },
+            observers: Vec::new(),
+            stats,
+            connected_once: false,
+        }
+    }
+
+    /// Get the URL
+    #[inline]
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Get the [`Extensions`], shared by all the connections
+    #[inline]
+    pub fn extensions(&self) -> &Extensions {
+        self.stats.extensions()
+    }
+
+    /// Subscribe to reconnection events
+    pub fn events(&mut self) -> UnboundedReceiver<ReconnectEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.observers.push(tx);
+        rx
+    }
+
+    /// Check if currently connected
+    #[inline]
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, State::Connected(..))
+    }
+
+    /// Get the current connection, if connected
+    #[inline]
+    pub fn get_ref(&self) -> Option<&WebSocket> {
+        match &self.state {
+            State::Connected(socket) => Some(socket.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// Get the stats handle, shared by all the connections
+    ///
+    /// Check [`StatsHandle`] for more details.
+    #[inline]
+    pub fn stats(&self) -> StatsHandle {
+        self.stats.clone()
+    }
+
+    fn notify(&mut self, event: ReconnectEvent) {
+        self.observers.retain(|tx| tx.unbounded_send(event).is_ok());
+    }
+
+    /// Schedule the reconnection attempt number `attempt`
+    fn schedule(&mut self, attempt: u32) {
+        if !self.backoff.is_allowed(attempt) {
+            self.state = State::Terminated;
+            self.notify(ReconnectEvent::GaveUp);
+            return;
+        }
+
+        let delay: Duration = self.backoff.jittered_delay(attempt, random());
+        self.state = State::Waiting {
+            attempt,
+            sleep: Box::pin(time::sleep(delay)),
+        };
+        self.notify(ReconnectEvent::Reconnecting { attempt, delay });
+    }
+
+    /// Drop the current connection and start reconnecting
+    fn disconnected(&mut self) {
+        self.notify(ReconnectEvent::Disconnected);
+        self.schedule(1);
+    }
+
+    /// Drive the reconnection until connected.
+    ///
+    /// Returns `Ready(false)` if terminated.
+    fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        loop {
+            match &mut self.state {
+                State::Connected(..) => return Poll::Ready(true),
+                State::Waiting { attempt, sleep } => {
+                    ready!(sleep.as_mut().poll(cx));
+
+                    let attempt: u32 = *attempt;
+                    let url: Url = self.url.clone();
+                    let builder: WebSocketBuilder = self.builder.clone();
+                    self.state = State::Connecting {
+                        attempt,
+                        future: Box::pin(async move { builder.connect(&url).await }),
+                    };
+                }
+                State::Connecting { attempt, future } => {
+                    let attempt: u32 = *attempt;
+                    match ready!(future.as_mut().poll(cx)) {
+                        Ok(socket) => {
+                            let socket: WebSocket = socket.with_stats(self.stats.clone());
+                            self.state = State::Connected(Box::new(socket));
+
+                            // The first connection of a lazy socket isn't a reconnection
+                            if self.connected_once {
+                                self.stats.on_reconnected();
+                                self.notify(ReconnectEvent::Reconnected { attempts: attempt });
+                            }
+                            self.connected_once = true;
+                        }
+                        // Retrying can't help
+                        Err(e) if e.is_permanent() => {
+                            self.state = State::Terminated;
+                            self.notify(ReconnectEvent::GaveUp);
+                        }
+                        Err(..) => self.schedule(attempt.saturating_add(1)),
+                    }
+                }
+                State::Terminated => return Poll::Ready(false),
+            }
+        }
+    }
+}
+
+impl Sink<Message> for ReconnectingWebSocket {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        loop {
+            if !ready!(this.poll_connected(cx)) {
+                return Poll::Ready(Err(Error::not_connected()));
+            }
+
+            if let State::Connected(socket) = &mut this.state {
+                match ready!(Pin::new(socket.as_mut()).poll_ready(cx)) {
+                    Ok(()) => return Poll::Ready(Ok(())),
+                    Err(..) => this.disconnected(),
+                }
+            }
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        match &mut this.state {
+            State::Connected(socket) => match Pin::new(socket.as_mut()).start_send(item) {
+                Ok(()) => Ok(()),
+                Err(e) => {
+                    this.disconnected();
+                    Err(e)
+                }
+            },
+            _ => Err(Error::not_connected()),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        match &mut this.state {
+            State::Connected(socket) => match ready!(Pin::new(socket.as_mut()).poll_flush(cx)) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(e) => {
+                    this.disconnected();
+                    Poll::Ready(Err(e))
+                }
+            },
+            // Nothing buffered
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        let res = match &mut this.state {
+            State::Connected(socket) => ready!(Pin::new(socket.as_mut()).poll_close(cx)),
+            _ => Ok(()),
+        };
+
+        // Closed by the user: not reconnect
+        this.state = State::Terminated;
+
+        Poll::Ready(res)
+    }
+}
+
+impl Stream for ReconnectingWebSocket {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if !ready!(this.poll_connected(cx)) {
+                return Poll::Ready(None);
+            }
+
+            if let State::Connected(socket) = &mut this.state {
+                match ready!(Pin::new(socket.as_mut()).poll_next(cx)) {
+                    Some(Ok(msg)) => return Poll::Ready(Some(Ok(msg))),
+                    Some(Err(e)) => {
+                        this.disconnected();
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    None => this.disconnected(),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::native::WsServer;
+    use crate::ErrorKind;
+
+    #[test]
+    fn test_delay_growth() {
+        let backoff = ExponentialBackoff {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            max_attempts: None,
+            jitter: 0.0,
+        };
+
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(200));
+        assert_eq!(backoff.delay(3), Duration::from_millis(400));
+        assert_eq!(backoff.delay(8), Duration::from_millis(12_800));
+    }
+
+    #[test]
+    fn test_delay_cap() {
+        let backoff = ExponentialBackoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            ..Default::default()
+        };
+
+        assert_eq!(backoff.delay(4), Duration::from_secs(8));
+        assert_eq!(backoff.delay(5), Duration::from_secs(10));
+        assert_eq!(backoff.delay(100), Duration::from_secs(10));
+
+        // Overflow of the exponent or of the delay
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(10));
+        let huge = ExponentialBackoff {
+            multiplier: f64::MAX,
+            ..backoff
+        };
+        assert_eq!(huge.delay(3), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_jitter_bounds() {
+        let backoff = ExponentialBackoff {
+            initial_delay: Duration::from_secs(4),
+            jitter: 0.5,
+            ..Default::default()
+        };
+
+        assert_eq!(backoff.jittered_delay(1, 0.0), Duration::from_secs(4));
+        assert_eq!(backoff.jittered_delay(1, 0.5), Duration::from_secs(3));
+
+        for _ in 0..1_000 {
+            let random: f64 = random();
+            assert!((0.0..1.0).contains(&random));
+
+            let delay: Duration = backoff.jittered_delay(2, random);
+            assert!(delay > Duration::from_secs(4));
+            assert!(delay <= Duration::from_secs(8));
+        }
+
+        // Clamped to the whole delay
+        let full = ExponentialBackoff {
+            jitter: 2.0,
+            ..backoff
+        };
+        assert!(full.jittered_delay(1, 0.999) <= Duration::from_secs(4));
+        assert_eq!(
+            ExponentialBackoff::default().jittered_delay(1, 0.999),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_error() {
+        let mut server: WsServer = WsServer::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap();
+
+        let backoff = ExponentialBackoff {
+            initial_delay: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let (socket, conn) = futures_util::future::join(
+            Box::pin(ReconnectingWebSocket::connect(
+                url,
+                WebSocketBuilder::new(),
+                backoff,
+            )),
+            server.next(),
+        )
+        .await;
+        let mut socket: ReconnectingWebSocket = socket.unwrap();
+        let mut events = socket.events();
+
+        // Dropped without the closing handshake
+        drop(conn);
+        let e: Error = socket.next().await.unwrap().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionLost);
+        assert_eq!(events.next().await, Some(ReconnectEvent::Disconnected));
+
+        let (msg, conn) = futures_util::future::join(socket.next(), async {
+            let (mut tx, _rx, _) = server.next().await.unwrap().unwrap();
+            tx.send(Message::text("hello")).await.unwrap();
+            tx
+        })
+        .await;
+        assert_eq!(msg.unwrap().unwrap().as_text(), Some("hello"));
+        assert!(matches!(
+            events.next().await,
+            Some(ReconnectEvent::Reconnecting { attempt: 1, .. })
+        ));
+        assert_eq!(
+            events.next().await,
+            Some(ReconnectEvent::Reconnected { attempts: 1 })
+        );
+        drop(conn);
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let backoff = ExponentialBackoff {
+            max_attempts: Some(3),
+            ..Default::default()
+        };
+        assert!(backoff.is_allowed(3));
+        assert!(!backoff.is_allowed(4));
+        assert!(ExponentialBackoff::default().is_allowed(u32::MAX));
+    }
+}
@@ -0,0 +1,488 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Session record and replay
+//!
+//! The [`Recorder`] logs all the messages of a socket, with their timestamps, to any [`Write`]r (ex. a file);
+//! the [`Replay`] plays a recorded session back as a socket, to reproduce the bugs reported from the field.
+//!
+//! # Format
+//!
+//! A sequence of length-prefixed records, all the integers are big-endian:
+//!
+//! | Field       | Size | Description                                          |
+//! |-------------|------|------------------------------------------------------|
+//! | direction   | 1    | `0` sent, `1` received                               |
+//! | kind        | 1    | `0` text, `1` binary, `2` ping, `3` pong, `4` close  |
+//! | timestamp   | 8    | Microseconds since the start of the recording        |
+//! | length      | 4    | Length of the payload                                |
+//! | payload     | len  | Message payload (close: code + reason, if any)       |
+
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::{ready, Sink, Stream};
+
+use crate::message::{Bytes, CloseFrame, Utf8Bytes};
+use crate::time::{self, Instant};
+use crate::{BoxedFuture, Error, Message};
+
+/// Message direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Direction {
+    /// Sent by the socket
+    Sent,
+    /// Received by the socket
+    Received,
+}
+
+/// Recorded message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Direction
+    pub direction: Direction,
+    /// Time elapsed since the start of the recording
+    pub timestamp: Duration,
+    /// Message
+    pub message: Message,
+}
+
+impl Record {
+    /// Write the record
+    pub fn write_to<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: Write + ?Sized,
+    {
+        let direction: u8 = match self.direction {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        };
+        let (kind, payload): (u8, Vec<u8>) = match &self.message {
+            Message::Text(text) => (0, text.as_bytes().to_vec()),
+            Message::Binary(data) => (1, data.to_vec()),
+            Message::Ping(data) => (2, data.to_vec()),
+            Message::Pong(data) => (3, data.to_vec()),
+            Message::Close(None) => (4, Vec::new()),
+            Message::Close(Some(frame)) => {
+                let mut payload: Vec<u8> = Vec::with_capacity(2 + frame.reason.len());
+                payload.extend_from_slice(&frame.code.as_u16().to_be_bytes());
+                payload.extend_from_slice(frame.reason.as_bytes());
+                (4, payload)
+            }
+        };
+        let timestamp: u64 = u64::try_from(self.timestamp.as_micros()).unwrap_or(u64::MAX);
+        let len: u32 = u32::try_from(payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+
+        writer.write_all(&[direction, kind])?;
+        writer.write_all(&timestamp.to_be_bytes())?;
+        writer.write_all(&len.to_be_bytes())?;
+        writer.write_all(&payload)
+    }
+
+    /// Read a record
+    ///
+    /// Returns `None` at the end of the input.
+    pub fn read_from<R>(reader: &mut R) -> io::Result<Option<Self>>
+    where
+        R: Read + ?Sized,
+    {
+        let mut header: [u8; 14] = [0; 14];
+
+        // Clean end of input only before the first byte of a record
+        match reader.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => reader.read_exact(&mut header[1..])?,
+        }
+
+        let direction: Direction = match header[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            _ => return Err(invalid_data("invalid direction")),
+        };
+        let mut timestamp: [u8; 8] = [0; 8];
+        timestamp.copy_from_slice(&header[2..10]);
+        let mut len: [u8; 4] = [0; 4];
+        len.copy_from_slice(&header[10..14]);
+
+        // Don't trust the length to allocate the buffer
+        let len: u64 = u64::from(u32::from_be_bytes(len));
+        let mut payload: Vec<u8> = Vec::new();
+        reader.take(len).read_to_end(&mut payload)?;
+        if payload.len() as u64 != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated payload",
+            ));
+        }
+
+        let message: Message = match header[1] {
+            0 => Message::Text(utf8(payload)?),
+            1 => Message::Binary(Bytes::from(payload)),
+            2 => Message::Ping(Bytes::from(payload)),
+            3 => Message::Pong(Bytes::from(payload)),
+            4 if payload.is_empty() => Message::Close(None),
+            4 if payload.len() >= 2 => Message::Close(Some(CloseFrame {
+                code: u16::from_be_bytes([payload[0], payload[1]]).into(),
+                reason: utf8(payload[2..].to_vec())?,
+            })),
+            4 => return Err(invalid_data("close payload without the code")),
+            _ => return Err(invalid_data("invalid message kind")),
+        };
+
+        Ok(Some(Self {
+            direction,
+            timestamp: Duration::from_micros(u64::from_be_bytes(timestamp)),
+            message,
+        }))
+    }
+
+    /// Read all the records
+    pub fn read_all<R>(reader: &mut R) -> io::Result<Vec<Self>>
+    where
+        R: Read + ?Sized,
+    {
+        let mut records: Vec<Self> = Vec::new();
+        while let Some(record) = Self::read_from(reader)? {
+            records.push(record);
+        }
+        Ok(records)
+    }
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn utf8(payload: Vec<u8>) -> io::Result<Utf8Bytes> {
+    String::from_utf8(payload)
+        .map(Utf8Bytes::from)
+        .map_err(|_| invalid_data("invalid UTF-8 text"))
+}
+
+/// Session recorder
+///
+/// Logs all the messages sent and received through the inner socket to `writer`.
+/// The writes are blocking: use a buffered writer (ex. [`BufWriter`](std::io::BufWriter)).
+///
+/// A write error doesn't affect the connection: the recording stops and the error can be retrieved
+/// with [`Recorder::take_error`].
+pub struct Recorder<S, W> {
+    socket: S,
+    writer: Option<W>,
+    start: Instant,
+    error: Option<io::Error>,
+}
+
+impl<S, W> Recorder<S, W>
+where
+    W: Write,
+{
+    /// Wrap a socket
+    pub fn new(socket: S, writer: W) -> Self {
+        Self {
+            socket,
+            writer: Some(writer),
+            start: Instant::now(),
+            error: None,
+        }
+    }
+
+    /// Get a reference to the inner socket
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    /// Get a mutable reference to the inner socket
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+
+    /// Take the write error that stopped the recording, if any
+    #[inline]
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Consume the recorder, returning the inner socket and the writer (`None` if the recording failed)
+    ///
+    /// The writer isn't flushed.
+    #[inline]
+    pub fn into_inner(self) -> (S, Option<W>) {
+        (self.socket, self.writer)
+    }
+
+    fn record(&mut self, direction: Direction, message: &Message) {
+        let Some(writer) = &mut self.writer else {
+            return;
+        };
+
+        let record: Record = Record {
+            direction,
+            timestamp: self.start.elapsed(),
+            message: message.clone(),
+        };
+
+        if let Err(e) = record.write_to(writer) {
+            self.writer = None;
+            self.error = Some(e);
+        }
+    }
+
+    fn flush_writer(&mut self) {
+        if let Some(writer) = &mut self.writer {
+            if let Err(e) = writer.flush() {
+                self.writer = None;
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+impl<S, W> Sink<Message> for Recorder<S, W>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+    W: Write + Unpin,
+{
+    type Error = Error;
+
+    #[inline]
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.socket).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        this.record(Direction::Sent, &item);
+        Pin::new(&mut this.socket).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.flush_writer();
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.flush_writer();
+        Pin::new(&mut self.socket).poll_close(cx)
+    }
+}
+
+impl<S, W> Stream for Recorder<S, W>
+where
+    S: Stream<Item = Result<Message, Error>> + Unpin,
+    W: Write + Unpin,
+{
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let item = ready!(Pin::new(&mut this.socket).poll_next(cx));
+
+        match &item {
+            Some(Ok(msg)) => this.record(Direction::Received, msg),
+            // Make the recording available as soon as the session ends
+            None => this.flush_writer(),
+            Some(Err(..)) => {}
+        }
+
+        Poll::Ready(item)
+    }
+}
+
+/// Replay of a recorded session
+///
+/// The [`Stream`] yields the received messages, with the original timing (unless disabled with [`Replay::timing`]).
+/// The [`Sink`] accepts and discards any message: the sent ones can be inspected with [`Replay::sent`].
+/// The stream ends after the last recorded message.
+pub struct Replay {
+    /// Received messages, in reverse order
+    received: Vec<Record>,
+    sent: Vec<Message>,
+    timing: bool,
+    start: Option<Instant>,
+    sleep: Option<BoxedFuture<'static, ()>>,
+}
+
+impl Replay {
+    /// New replay of the records
+    pub fn new(records: Vec<Record>) -> Self {
+        let mut received: Vec<Record> = records
+            .into_iter()
+            .filter(|r| r.direction == Direction::Received)
+            .collect();
+        received.reverse();
+
+        Self {
+            received,
+            sent: Vec::new(),
+            timing: true,
+            start: None,
+            sleep: None,
+        }
+    }
+
+    /// New replay of the records read from `reader`
+    ///
+    /// Check [`Record::read_all`].
+    #[inline]
+    pub fn from_reader<R>(reader: &mut R) -> io::Result<Self>
+    where
+        R: Read + ?Sized,
+    {
+        Ok(Self::new(Record::read_all(reader)?))
+    }
+
+    /// Reproduce the original timing (default: `true`)
+    ///
+    /// When disabled, the messages are yielded as fast as they are read.
+    #[inline]
+    pub fn timing(mut self, enabled: bool) -> Self {
+        self.timing = enabled;
+        self
+    }
+
+    /// Get the messages sent to the replay
+    #[inline]
+    pub fn sent(&self) -> &[Message] {
+        &self.sent
+    }
+
+    /// Number of messages still to be yielded
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.received.len()
+    }
+}
+
+impl Sink<Message> for Replay {
+    type Error = Error;
+
+    #[inline]
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.sent.push(item);
+        Ok(())
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for Replay {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        let Some(next) = this.received.last() else {
+            return Poll::Ready(None);
+        };
+
+        if this.timing {
+            // The timeline starts at the first poll
+            let start: Instant = *this.start.get_or_insert_with(Instant::now);
+
+            if this.sleep.is_none() {
+                let elapsed: Duration = start.elapsed();
+                if next.timestamp > elapsed {
+                    this.sleep = Some(Box::pin(time::sleep(next.timestamp - elapsed)));
+                }
+            }
+
+            if let Some(sleep) = &mut this.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+        }
+
+        Poll::Ready(this.received.pop().map(|r| Ok(r.message)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::CloseCode;
+
+    #[test]
+    fn test_round_trip() {
+        let records: Vec<Record> = vec![
+            Record {
+                direction: Direction::Sent,
+                timestamp: Duration::from_micros(0),
+                message: Message::Text(Utf8Bytes::from("hello")),
+            },
+            Record {
+                direction: Direction::Received,
+                timestamp: Duration::from_micros(1_500),
+                message: Message::Binary(Bytes::from_static(&[1, 2, 3])),
+            },
+            Record {
+                direction: Direction::Sent,
+                timestamp: Duration::from_millis(2),
+                message: Message::Ping(Bytes::from_static(b"ping")),
+            },
+            Record {
+                direction: Direction::Received,
+                timestamp: Duration::from_millis(3),
+                message: Message::Pong(Bytes::new()),
+            },
+            Record {
+                direction: Direction::Sent,
+                timestamp: Duration::from_secs(4),
+                message: Message::Close(Some(CloseFrame {
+                    code: CloseCode::GoingAway,
+                    reason: Utf8Bytes::from("bye"),
+                })),
+            },
+            Record {
+                direction: Direction::Received,
+                timestamp: Duration::from_secs(5),
+                message: Message::Close(None),
+            },
+        ];
+
+        let mut buf: Vec<u8> = Vec::new();
+        for record in records.iter() {
+            record.write_to(&mut buf).unwrap();
+        }
+
+        assert_eq!(Record::read_all(&mut buf.as_slice()).unwrap(), records);
+    }
+
+    #[test]
+    fn test_read_invalid() {
+        // Close payload of 1 byte
+        let mut buf: Vec<u8> = vec![0, 4];
+        buf.extend_from_slice(&0u64.to_be_bytes());
+        buf.extend_from_slice(&1u32.to_be_bytes());
+        buf.push(0);
+        let err = Record::read_from(&mut buf.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "close payload without the code");
+
+        // Huge length, truncated payload
+        let mut buf: Vec<u8> = vec![0, 1];
+        buf.extend_from_slice(&0u64.to_be_bytes());
+        buf.extend_from_slice(&u32::MAX.to_be_bytes());
+        buf.extend_from_slice(&[1, 2, 3]);
+        let err = Record::read_from(&mut buf.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
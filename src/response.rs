@@ -0,0 +1,123 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Handshake response
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+#[cfg(not(target_arch = "wasm32"))]
+use tokio_tungstenite::tungstenite::http::{HeaderMap, Response as HttpResponse};
+
+/// Max size of [`HandshakeRejection::body`]
+pub const MAX_REJECTION_BODY: usize = 1024;
+
+/// HTTP response of the WebSocket handshake
+///
+/// On WASM targets the browser doesn't expose the handshake response,
+/// so the `headers` are always empty.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HandshakeResponse {
+    /// HTTP status code
+    pub status: u16,
+    /// HTTP headers
+    pub headers: Vec<(String, String)>,
+}
+
+impl HandshakeResponse {
+    /// Get the first value of an header (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Get the extensions accepted by the server (`Sec-WebSocket-Extensions`), with their parameters
+    ///
+    /// Ex. `permessage-deflate; client_max_window_bits=10`.
+    pub fn extensions(&self) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("sec-websocket-extensions"))
+            .flat_map(|(_, v)| v.split(','))
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .collect()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<&Response> for HandshakeResponse {
+    fn from(response: &Response) -> Self {
+        Self {
+            status: response.status().as_u16(),
+            headers: headers(response.headers()),
+        }
+    }
+}
+
+/// HTTP response of a rejected WebSocket handshake (ex. `401` or `429`)
+///
+/// Check [`ConnectError::HandshakeRejected`](crate::ConnectError::HandshakeRejected).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HandshakeRejection {
+    /// HTTP status code
+    pub status: u16,
+    /// HTTP headers
+    pub headers: Vec<(String, String)>,
+    /// Body, truncated to [`MAX_REJECTION_BODY`] bytes
+    pub body: Vec<u8>,
+}
+
+impl HandshakeRejection {
+    /// Get the first value of an header (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Get the `Retry-After` header, if expressed in seconds
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.header("retry-after")?
+            .trim()
+            .parse()
+            .ok()
+            .map(Duration::from_secs)
+    }
+
+    /// Get the body as text
+    #[inline]
+    pub fn body_text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<&HttpResponse<Option<Vec<u8>>>> for HandshakeRejection {
+    fn from(response: &HttpResponse<Option<Vec<u8>>>) -> Self {
+        let body: &[u8] = response.body().as_deref().unwrap_or_default();
+        Self {
+            status: response.status().as_u16(),
+            headers: headers(response.headers()),
+            body: body[..body.len().min(MAX_REJECTION_BODY)].to_vec(),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(k, v)| {
+            (
+                k.as_str().to_string(),
+                String::from_utf8_lossy(v.as_bytes()).to_string(),
+            )
+        })
+        .collect()
+}
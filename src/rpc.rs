@@ -0,0 +1,429 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Request/response correlation
+//!
+//! Multiplex many request/response exchanges over one connection.
+//!
+//! [`RpcClient::new`] returns the client, cheap to clone, and the [`RpcDriver`],
+//! that owns the socket and must be polled (ex. in a dedicated task) to make progress.
+//! The driver yields the incoming messages that aren't responses (ex. notifications).
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures_channel::oneshot;
+use futures_util::{Sink, Stream};
+
+use crate::{time, Error, Message};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Request/response correlation
+///
+/// Any `Fn(&Message) -> Option<String>` is a correlator that uses the same id
+/// for requests and responses (ex. the `id` field of JSON-RPC).
+pub trait Correlator: Send + Sync {
+    /// Id of an outgoing request
+    fn request_id(&self, msg: &Message) -> Option<String>;
+
+    /// Id of the request an incoming message responds to (`None` if it isn't a response)
+    fn response_id(&self, msg: &Message) -> Option<String>;
+}
+
+impl<F> Correlator for F
+where
+    F: Fn(&Message) -> Option<String> + Send + Sync,
+{
+    #[inline]
+    fn request_id(&self, msg: &Message) -> Option<String> {
+        self(msg)
+    }
+
+    #[inline]
+    fn response_id(&self, msg: &Message) -> Option<String> {
+        self(msg)
+    }
+}
+
+/// Response channels of the pending requests, by request id
+#[derive(Default)]
+struct Pending(Mutex<HashMap<String, oneshot::Sender<Message>>>);
+
+impl Pending {
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, oneshot::Sender<Message>>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Removes the request from the pending ones when the caller stops waiting (ex. timeout)
+struct Waiter {
+    pending: Arc<Pending>,
+    id: String,
+    rx: oneshot::Receiver<Message>,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        let mut pending = self.pending.lock();
+
+        // Already removed by the response, the id may have been reused since
+        if pending
+            .get(&self.id)
+            .is_some_and(|tx| tx.is_connected_to(&self.rx))
+        {
+            pending.remove(&self.id);
+        }
+    }
+}
+
+/// RPC client
+///
+/// Check the [module docs](self) for more details.
+#[derive(Clone)]
+pub struct RpcClient {
+    tx: UnboundedSender<Message>,
+    correlator: Arc<dyn Correlator>,
+    pending: Arc<Pending>,
+    timeout: Duration,
+}
+
+impl RpcClient {
+    /// New RPC client over `socket`
+    ///
+    /// The [`RpcDriver`] must be polled to send the requests and receive the responses.
+    pub fn new<S, C>(socket: S, correlator: C) -> (Self, RpcDriver<S>)
+    where
+        C: Correlator + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded();
+        let correlator: Arc<dyn Correlator> = Arc::new(correlator);
+        let pending: Arc<Pending> = Arc::new(Pending::default());
+
+        let client: Self = Self {
+            tx,
+            correlator: correlator.clone(),
+            pending: pending.clone(),
+            timeout: DEFAULT_TIMEOUT,
+        };
+        let driver: RpcDriver<S> = RpcDriver {
+            socket,
+            correlator,
+            requests: Some(rx),
+            pending,
+            outgoing: None,
+            flush: false,
+        };
+
+        (client, driver)
+    }
+
+    /// Set the default request timeout (default: 30 secs)
+    #[inline]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send a request and wait for its response
+    #[inline]
+    pub async fn call(&self, msg: Message) -> Result<Message, Error> {
+        self.call_with_timeout(msg, self.timeout).await
+    }
+
+    /// Send a request and wait for its response, with a custom timeout
+    ///
+    /// Returns [`Error::MissingRequestId`] if the correlator doesn't find the request id,
+    /// [`Error::DuplicateRequestId`] if a request with the same id is still pending
+    /// and [`Error::RequestTimeout`] if the response isn't received within `timeout`.
+    pub async fn call_with_timeout(
+        &self,
+        msg: Message,
+        timeout: Duration,
+    ) -> Result<Message, Error> {
+        let id: String = self
+            .correlator
+            .request_id(&msg)
+            .ok_or(Error::MissingRequestId)?;
+
+        let (tx, rx) = oneshot::channel();
+
+        {
+            let mut pending = self.pending.lock();
+            if pending.contains_key(&id) {
+                return Err(Error::DuplicateRequestId(id));
+            }
+            pending.insert(id.clone(), tx);
+        }
+
+        let mut waiter: Waiter = Waiter {
+            pending: self.pending.clone(),
+            id,
+            rx,
+        };
+
+        self.tx
+            .unbounded_send(msg)
+            .map_err(|_| Error::not_connected())?;
+
+        match time::timeout(timeout, &mut waiter.rx).await {
+            Some(Ok(response)) => Ok(response),
+            // The driver has been dropped or the connection closed
+            Some(Err(oneshot::Canceled)) => Err(Error::not_connected()),
+            None => Err(Error::RequestTimeout),
+        }
+    }
+
+    /// Send a message without waiting for a response
+    pub fn send(&self, msg: Message) -> Result<(), Error> {
+        self.tx
+            .unbounded_send(msg)
+            .map_err(|_| Error::not_connected())
+    }
+}
+
+/// RPC driver
+///
+/// Sends the requests and routes the responses to the callers.
+/// The [`Stream`] yields the incoming messages that aren't responses to pending requests.
+///
+/// When the stream ends, the pending requests fail.
+pub struct RpcDriver<S> {
+    socket: S,
+    correlator: Arc<dyn Correlator>,
+    /// `None` when all the clients have been dropped
+    requests: Option<UnboundedReceiver<Message>>,
+    /// Shared with the clients
+    pending: Arc<Pending>,
+    /// Message waiting for the socket to be ready
+    outgoing: Option<Message>,
+    /// Messages sent but not flushed yet
+    flush: bool,
+}
+
+impl<S> RpcDriver<S>
+where
+    S: Sink<Message, Error = Error> + Unpin,
+{
+    /// Get a reference to the inner socket
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    /// Number of requests waiting for the response
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.pending.lock().len()
+    }
+
+    /// Send the queued requests, as long as the socket is ready
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Result<(), Error> {
+        loop {
+            if let Some(msg) = self.outgoing.take() {
+                match Pin::new(&mut self.socket).poll_ready(cx) {
+                    Poll::Ready(Ok(())) => {
+                        Pin::new(&mut self.socket).start_send(msg)?;
+                        self.flush = true;
+                    }
+                    Poll::Ready(Err(e)) => return Err(e),
+                    Poll::Pending => {
+                        self.outgoing = Some(msg);
+                        break;
+                    }
+                }
+            }
+
+            let Some(requests) = &mut self.requests else {
+                break;
+            };
+
+            match Pin::new(requests).poll_next(cx) {
+                Poll::Ready(Some(msg)) => {
+                    self.outgoing = Some(msg);
+                }
+                Poll::Ready(None) => {
+                    self.requests = None;
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        if self.flush {
+            match Pin::new(&mut self.socket).poll_flush(cx) {
+                Poll::Ready(Ok(())) => self.flush = false,
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<S> Stream for RpcDriver<S>
+where
+    S: Sink<Message, Error = Error> + Stream<Item = Result<Message, Error>> + Unpin,
+{
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Err(e) = this.poll_send(cx) {
+            return Poll::Ready(Some(Err(e)));
+        }
+
+        loop {
+            match Pin::new(&mut this.socket).poll_next(cx) {
+                Poll::Ready(Some(Ok(msg))) => {
+                    let tx: Option<oneshot::Sender<Message>> = this
+                        .correlator
+                        .response_id(&msg)
+                        .and_then(|id| this.pending.lock().remove(&id));
+
+                    match tx {
+                        Some(tx) => {
+                            // The caller may have given up in the meantime
+                            let _ = tx.send(msg);
+                        }
+                        None => return Poll::Ready(Some(Ok(msg))),
+                    }
+                }
+                Poll::Ready(None) => {
+                    // Fail the pending requests
+                    this.pending.lock().clear();
+                    return Poll::Ready(None);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<S> Drop for RpcDriver<S> {
+    fn drop(&mut self) {
+        // Fail the pending requests
+        self.pending.lock().clear();
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::native;
+    use crate::WebSocket;
+
+    /// The id is the text before the first `:`
+    fn correlator(msg: &Message) -> Option<String> {
+        let text: &str = msg.as_text()?;
+        let (id, _) = text.split_once(':')?;
+        Some(id.to_string())
+    }
+
+    async fn setup() -> (RpcClient, WebSocket) {
+        let (client, server) = native::duplex().await;
+        let (client, mut driver) = RpcClient::new(client, correlator);
+
+        // Poll the driver, forwarding the notifications
+        tokio::spawn(async move {
+            while let Some(msg) = driver.next().await {
+                let msg: Message = msg.unwrap();
+                assert_eq!(msg.as_text(), Some("notification"));
+            }
+        });
+
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_correlation() {
+        let (client, mut server) = setup().await;
+
+        let a = client.call(Message::text("a:ping"));
+        let b = client.call(Message::text("b:ping"));
+        let responder = async {
+            let mut requests: Vec<Message> = Vec::new();
+            for _ in 0..2 {
+                requests.push(server.next().await.unwrap().unwrap());
+            }
+
+            // Answer in reverse order, with a notification in between
+            server.send(Message::text("notification")).await.unwrap();
+            for request in requests.into_iter().rev() {
+                let id: String = correlator(&request).unwrap();
+                server
+                    .send(Message::text(format!("{id}:pong")))
+                    .await
+                    .unwrap();
+            }
+        };
+
+        let (a, b, ()) = futures_util::future::join3(a, b, responder).await;
+        assert_eq!(a.unwrap().as_text(), Some("a:pong"));
+        assert_eq!(b.unwrap().as_text(), Some("b:pong"));
+        assert!(client.pending.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_missing_request_id() {
+        let (client, _server) = setup().await;
+        assert!(matches!(
+            client.call(Message::text("no id")).await,
+            Err(Error::MissingRequestId)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_request_id() {
+        let (client, mut server) = setup().await;
+
+        let first = client.call(Message::text("a:1"));
+        let second = async {
+            // Wait for the first request to be pending
+            let request: Message = server.next().await.unwrap().unwrap();
+            assert_eq!(request.as_text(), Some("a:1"));
+
+            let res = client.call(Message::text("a:2")).await;
+            assert!(matches!(res, Err(Error::DuplicateRequestId(id)) if id == "a"));
+
+            server.send(Message::text("a:done")).await.unwrap();
+        };
+
+        let (first, ()) = futures_util::future::join(first, second).await;
+        assert_eq!(first.unwrap().as_text(), Some("a:done"));
+
+        // The id can be reused once answered
+        let again = client.call(Message::text("a:3"));
+        let responder = async {
+            server.next().await.unwrap().unwrap();
+            server.send(Message::text("a:again")).await.unwrap();
+        };
+        let (again, ()) = futures_util::future::join(again, responder).await;
+        assert_eq!(again.unwrap().as_text(), Some("a:again"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout_prunes_pending() {
+        let (client, _server) = setup().await;
+
+        let res = client
+            .call_with_timeout(Message::text("a:1"), Duration::from_secs(1))
+            .await;
+        assert!(matches!(res, Err(Error::RequestTimeout)));
+        assert!(client.pending.lock().is_empty());
+
+        // Cancelled by the caller
+        let call = client.call(Message::text("b:1"));
+        assert!(time::timeout(Duration::from_secs(1), call).await.is_none());
+        assert!(client.pending.lock().is_empty());
+    }
+}
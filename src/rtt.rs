@@ -0,0 +1,181 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Round-trip time measurement
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::message::Bytes;
+use crate::time::Instant;
+use crate::Message;
+
+/// Max number of probes waiting for the reply
+const MAX_PENDING: usize = 16;
+
+type EncodeFn = dyn Fn(u64) -> Message + Send + Sync;
+type DecodeFn = dyn Fn(&Message) -> Option<u64> + Send + Sync;
+
+/// Application-level echo
+///
+/// Used to measure the RTT when the ping frames aren't available (WASM targets)
+/// or the peer doesn't answer them: the server must echo the probe back.
+///
+/// The hook is cheap to clone: all the clones refer to the same callbacks.
+#[derive(Clone)]
+pub struct RttEcho {
+    encode: Arc<EncodeFn>,
+    decode: Arc<DecodeFn>,
+}
+
+impl fmt::Debug for RttEcho {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RttEcho").finish()
+    }
+}
+
+impl PartialEq for RttEcho {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.encode, &other.encode) && Arc::ptr_eq(&self.decode, &other.decode)
+    }
+}
+
+impl Eq for RttEcho {}
+
+impl RttEcho {
+    /// New echo hook
+    ///
+    /// `encode` builds the probe carrying a nonce, `decode` extracts the nonce from the echoed reply
+    /// (`None` if the message isn't a reply).
+    pub fn new<E, D>(encode: E, decode: D) -> Self
+    where
+        E: Fn(u64) -> Message + Send + Sync + 'static,
+        D: Fn(&Message) -> Option<u64> + Send + Sync + 'static,
+    {
+        Self {
+            encode: Arc::new(encode),
+            decode: Arc::new(decode),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    next_nonce: u64,
+    /// Probes waiting for the reply
+    pending: VecDeque<(u64, Instant)>,
+    latest: Option<Duration>,
+    smoothed: Option<Duration>,
+}
+
+impl State {
+    fn on_reply(&mut self, nonce: u64) {
+        let pos: Option<usize> = self.pending.iter().position(|(n, _)| *n == nonce);
+
+        if let Some(pos) = pos {
+            // The older probes will never be answered
+            let (_, sent) = self
+                .pending
+                .drain(..=pos)
+                .next_back()
+                .expect("pos is in range");
+            let sample: Duration = sent.elapsed();
+
+            // Exponentially weighted moving average (RFC 6298)
+            self.smoothed = Some(match self.smoothed {
+                Some(smoothed) => (smoothed * 7 + sample) / 8,
+                None => sample,
+            });
+            self.latest = Some(sample);
+        }
+    }
+}
+
+/// Round-trip time monitor
+///
+/// Sends the probes with [`RttMonitor::probe`] and matches the replies read from the stream,
+/// keeping a rolling estimate of the RTT.
+///
+/// On native targets, the probe is a [`Message::Ping`] carrying a nonce, matched with its [`Message::Pong`].
+/// If an [`RttEcho`] is set, it's used instead (mandatory on WASM targets).
+///
+/// The monitor is cheap to clone: all the clones refer to the same connection,
+/// so it can be used also after the socket has been split.
+#[derive(Clone)]
+pub struct RttMonitor {
+    state: Arc<Mutex<State>>,
+    echo: Option<RttEcho>,
+}
+
+impl fmt::Debug for RttMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RttMonitor")
+            .field("latest", &self.latest())
+            .field("smoothed", &self.smoothed())
+            .finish()
+    }
+}
+
+impl RttMonitor {
+    #[inline]
+    pub(crate) fn new(echo: Option<RttEcho>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State::default())),
+            echo,
+        }
+    }
+
+    /// Build a new probe, to send to the peer
+    ///
+    /// Returns `None` on WASM targets if no [`RttEcho`] is set.
+    pub fn probe(&self) -> Option<Message> {
+        let mut state = self.state.lock().ok()?;
+
+        let nonce: u64 = state.next_nonce;
+        let probe: Message = match &self.echo {
+            Some(echo) => (echo.encode)(nonce),
+            #[cfg(not(target_arch = "wasm32"))]
+            None => Message::Ping(Bytes::copy_from_slice(&nonce.to_be_bytes())),
+            #[cfg(target_arch = "wasm32")]
+            None => return None,
+        };
+
+        state.next_nonce = nonce.wrapping_add(1);
+        if state.pending.len() >= MAX_PENDING {
+            state.pending.pop_front();
+        }
+        state.pending.push_back((nonce, Instant::now()));
+
+        Some(probe)
+    }
+
+    /// RTT of the last answered probe
+    pub fn latest(&self) -> Option<Duration> {
+        self.state.lock().ok()?.latest
+    }
+
+    /// Smoothed RTT estimate
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.state.lock().ok()?.smoothed
+    }
+
+    /// Match an incoming message with the pending probes
+    pub(crate) fn observe(&self, msg: &Message) {
+        let nonce: Option<u64> = match &self.echo {
+            Some(echo) => (echo.decode)(msg),
+            None => match msg {
+                Message::Pong(data) => data.as_ref().try_into().ok().map(u64::from_be_bytes),
+                _ => None,
+            },
+        };
+
+        if let Some(nonce) = nonce {
+            if let Ok(mut state) = self.state.lock() {
+                state.on_reply(nonce);
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! [tower](https://docs.rs/tower) integration
+//!
+//! [`ConnectService`] establishes the connections as a [`Service`],
+//! so the retry, timeout and rate-limit layers of the tower ecosystem can be composed around it.
+
+use std::task::{Context, Poll};
+
+use tower_service::Service;
+use url::Url;
+
+use crate::{BoxedFuture, ConnectError, ConnectionMode, WebSocket, WebSocketBuilder};
+
+/// Connection request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectRequest {
+    /// URL
+    pub url: Url,
+    /// Connection mode (default: the one of the service builder)
+    pub mode: Option<ConnectionMode>,
+    /// Custom HTTP headers, added to the ones of the service builder
+    pub headers: Vec<(String, String)>,
+}
+
+impl From<Url> for ConnectRequest {
+    #[inline]
+    fn from(url: Url) -> Self {
+        Self::new(url)
+    }
+}
+
+impl ConnectRequest {
+    /// New request, with the connection mode and the headers of the service builder
+    #[inline]
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            mode: None,
+            headers: Vec::new(),
+        }
+    }
+
+    /// Set the connection mode
+    #[inline]
+    pub fn mode(mut self, mode: ConnectionMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Add a custom HTTP header
+    ///
+    /// Check [`WebSocketBuilder::header`] for more details.
+    #[inline]
+    pub fn header<K, V>(mut self, name: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+/// Connection establishment, as tower [`Service`]
+///
+/// Every call connects with a clone of the builder, updated with the mode and the headers of the [`ConnectRequest`].
+/// The service is always ready: use the tower layers to limit the concurrent connections.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectService {
+    builder: WebSocketBuilder,
+}
+
+impl From<WebSocketBuilder> for ConnectService {
+    #[inline]
+    fn from(builder: WebSocketBuilder) -> Self {
+        Self::new(builder)
+    }
+}
+
+impl ConnectService {
+    /// New service, connecting with `builder`
+    #[inline]
+    pub fn new(builder: WebSocketBuilder) -> Self {
+        Self { builder }
+    }
+}
+
+impl Service<ConnectRequest> for ConnectService {
+    type Response = WebSocket;
+    type Error = ConnectError;
+    type Future = BoxedFuture<'static, Result<WebSocket, ConnectError>>;
+
+    #[inline]
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: ConnectRequest) -> Self::Future {
+        let mut builder: WebSocketBuilder = self.builder.clone().headers(req.headers);
+        if let Some(mode) = req.mode {
+            builder = builder.mode(mode);
+        }
+
+        Box::pin(async move { builder.connect(&req.url).await })
+    }
+}
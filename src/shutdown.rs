@@ -0,0 +1,276 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Graceful shutdown
+//!
+//! The connections created with a [`ShutdownToken`] (check [`WebSocketBuilder::shutdown_token`](crate::WebSocketBuilder::shutdown_token))
+//! can be closed all together with [`ShutdownHandle::shutdown`]:
+//! every connection sends a close frame and waits for the peer to complete the closing handshake,
+//! until the deadline, after which the remaining connections are aborted.
+//!
+//! The closing handshake is driven by reading the connections: their streams must be polled.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use futures_util::future;
+use futures_util::task::AtomicWaker;
+
+use crate::time;
+
+const RUNNING: u8 = 0;
+const CLOSING: u8 = 1;
+const ABORTED: u8 = 2;
+
+/// Shutdown state, as seen by a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ShutdownState {
+    Running,
+    /// Close gracefully
+    Closing,
+    /// Deadline expired: drop the connection
+    Aborted,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: AtomicU8,
+    /// Number of registered connections
+    active: AtomicUsize,
+    next_id: AtomicU64,
+    /// Wakers of the connections
+    wakers: Mutex<HashMap<u64, Waker>>,
+    /// Waker of the shutdown future
+    done: AtomicWaker,
+}
+
+impl Shared {
+    fn set_state(&self, state: u8) {
+        self.state.store(state, Ordering::SeqCst);
+
+        let mut wakers = self.wakers.lock().unwrap_or_else(|e| e.into_inner());
+        for (_, waker) in wakers.drain() {
+            waker.wake();
+        }
+    }
+}
+
+/// Shutdown coordinator
+///
+/// Check the [module docs](self) for more details.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for ShutdownHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShutdownHandle")
+            .field("active", &self.active())
+            .finish()
+    }
+}
+
+impl ShutdownHandle {
+    /// New shutdown coordinator
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get a token, to register the connections
+    #[inline]
+    pub fn token(&self) -> ShutdownToken {
+        ShutdownToken {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Number of connections not closed yet
+    #[inline]
+    pub fn active(&self) -> usize {
+        self.shared.active.load(Ordering::SeqCst)
+    }
+
+    /// Close all the connections gracefully, aborting the ones not closed within `deadline`
+    ///
+    /// Returns the number of aborted connections.
+    /// The connections registered after the shutdown started are closed immediately.
+    pub async fn shutdown(&self, deadline: Duration) -> usize {
+        self.shared.set_state(CLOSING);
+
+        let all_closed = future::poll_fn(|cx| {
+            self.shared.done.register(cx.waker());
+
+            if self.active() == 0 {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        });
+
+        if time::timeout(deadline, all_closed).await.is_some() {
+            return 0;
+        }
+
+        self.shared.set_state(ABORTED);
+        self.active()
+    }
+}
+
+/// Shutdown token
+///
+/// Check the [module docs](self) for more details.
+#[derive(Clone)]
+pub struct ShutdownToken {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for ShutdownToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShutdownToken")
+            .field("shutdown", &self.is_shutdown())
+            .finish()
+    }
+}
+
+impl PartialEq for ShutdownToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+}
+
+impl Eq for ShutdownToken {}
+
+impl ShutdownToken {
+    /// Check if the shutdown has been started
+    #[inline]
+    pub fn is_shutdown(&self) -> bool {
+        self.shared.state.load(Ordering::SeqCst) != RUNNING
+    }
+
+    /// Register a connection
+    pub(crate) fn register(&self) -> ShutdownGuard {
+        self.shared.active.fetch_add(1, Ordering::SeqCst);
+        ShutdownGuard {
+            shared: self.shared.clone(),
+            id: self.shared.next_id.fetch_add(1, Ordering::SeqCst),
+        }
+    }
+}
+
+/// Registration of a connection: dropping it marks the connection as closed
+pub(crate) struct ShutdownGuard {
+    shared: Arc<Shared>,
+    id: u64,
+}
+
+impl ShutdownGuard {
+    /// Get the shutdown state, registering the waker to be notified of the changes
+    pub(crate) fn poll_state(&self, cx: &mut Context<'_>) -> ShutdownState {
+        let mut wakers = self.shared.wakers.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Checked under the lock, so no state change can be missed
+        match self.shared.state.load(Ordering::SeqCst) {
+            RUNNING => {
+                wakers.insert(self.id, cx.waker().clone());
+                ShutdownState::Running
+            }
+            CLOSING => {
+                wakers.insert(self.id, cx.waker().clone());
+                ShutdownState::Closing
+            }
+            _ => ShutdownState::Aborted,
+        }
+    }
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        let mut wakers = self.shared.wakers.lock().unwrap_or_else(|e| e.into_inner());
+        wakers.remove(&self.id);
+        drop(wakers);
+
+        if self.shared.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.done.wake();
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+    use crate::message::{CloseCode, CloseFrame};
+    use crate::{native, Message, WebSocket};
+
+    /// Read the connection until the end, returning the received messages
+    fn drive(mut socket: WebSocket) -> tokio::task::JoinHandle<Vec<Message>> {
+        tokio::spawn(async move {
+            let mut received: Vec<Message> = Vec::new();
+            while let Some(Ok(msg)) = socket.next().await {
+                received.push(msg);
+            }
+            received
+        })
+    }
+
+    #[tokio::test]
+    async fn test_graceful() {
+        let handle = ShutdownHandle::new();
+        let token: ShutdownToken = handle.token();
+        assert!(!token.is_shutdown());
+
+        let (client, server) = native::duplex().await;
+        let client = drive(client.with_shutdown(Some(&token)));
+        let server = drive(server);
+        assert_eq!(handle.active(), 1);
+
+        // Closed by the peer within the deadline
+        assert_eq!(handle.shutdown(Duration::from_secs(10)).await, 0);
+        assert!(token.is_shutdown());
+        assert_eq!(handle.active(), 0);
+
+        let frame = CloseFrame {
+            code: CloseCode::GoingAway,
+            reason: "shutdown".into(),
+        };
+        assert_eq!(server.await.unwrap(), [Message::Close(Some(frame.clone()))]);
+        assert_eq!(client.await.unwrap(), [Message::Close(Some(frame))]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deadline() {
+        let handle = ShutdownHandle::new();
+
+        // The peer never completes the closing handshake
+        let (client, _server) = native::duplex().await;
+        let client = drive(client.with_shutdown(Some(&handle.token())));
+
+        let start = time::Instant::now();
+        assert_eq!(handle.shutdown(Duration::from_secs(1)).await, 1);
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+
+        // Aborted: the stream ends
+        assert!(client.await.unwrap().is_empty());
+        assert_eq!(handle.active(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_registered_after_shutdown() {
+        let handle = ShutdownHandle::new();
+        assert_eq!(handle.shutdown(Duration::from_secs(1)).await, 0);
+
+        // Closed immediately
+        let (client, server) = native::duplex().await;
+        let client = drive(client.with_shutdown(Some(&handle.token())));
+        drive(server);
+        assert_eq!(client.await.unwrap().len(), 1);
+        assert_eq!(handle.active(), 0);
+    }
+}
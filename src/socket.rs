@@ -1,6 +1,7 @@
 // Copyright (c) 2022-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::net::SocketAddr;
 use std::ops::DerefMut;
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -8,46 +9,556 @@ use std::time::Duration;
 
 #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
 use arti_client::DataStream;
-use futures_util::{Sink, Stream};
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{ready, Sink, SinkExt, Stream, StreamExt};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::io::DuplexStream;
 #[cfg(not(target_arch = "wasm32"))]
 use tokio::net::TcpStream;
+#[cfg(all(unix, not(target_arch = "wasm32")))]
+use tokio::net::UnixStream;
 #[cfg(not(target_arch = "wasm32"))]
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use url::Url;
 
+use crate::coalesce::Coalescer;
+use crate::drain::Drain;
+use crate::idle::{IdleEvent, IdleTimeoutConfig, IdleTimer};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::message::Bytes;
+use crate::message::{CloseCode, CloseFrame, Utf8Bytes};
+#[cfg(feature = "test-util")]
+use crate::mock::MockWebSocket;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::native::admission::ConnectionPermit;
+#[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+use crate::native::deflate::DeflateStream;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::native::fallback::{FallbackServerSocket, FallbackSocket};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::native::raw::RawSocket;
+#[cfg(all(feature = "axum", not(target_arch = "wasm32")))]
+use crate::native::upgrade::AxumSocket;
+#[cfg(all(feature = "warp", not(target_arch = "wasm32")))]
+use crate::native::upgrade::WarpSocket;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::native::IoStream;
+#[cfg(all(
+    any(feature = "tls-rustls", feature = "tls-native"),
+    not(target_arch = "wasm32")
+))]
+use crate::native::{tls, ServerTlsStream, TlsInfo};
+use crate::rtt::RttMonitor;
+use crate::shutdown::{ShutdownGuard, ShutdownState, ShutdownToken};
+use crate::stats::StatsHandle;
 #[cfg(target_arch = "wasm32")]
-use crate::wasm::WsStream;
-use crate::{ConnectionMode, Error, Message};
+use crate::wasm::{self, WasmStream, WsState};
+use crate::{
+    time, ConnectError, ConnectionEvent, ConnectionMode, ConnectionObserver, Error, ErrorKind,
+    Extensions, Message, WebSocketBuilder, WriteCoalescingConfig,
+};
+
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[cfg(not(target_arch = "wasm32"))]
-type WsStream<T> = WebSocketStream<MaybeTlsStream<T>>;
+pub(crate) type WsStream<T> = WebSocketStream<MaybeTlsStream<T>>;
 
-pub enum WebSocket {
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum InnerWebSocket {
     #[cfg(not(target_arch = "wasm32"))]
     Tokio(WsStream<TcpStream>),
     #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
     Tor(WsStream<DataStream>),
+    #[cfg(all(feature = "futures-io", not(target_arch = "wasm32")))]
+    Compat(WsStream<Box<dyn IoStream>>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Custom(WsStream<Box<dyn IoStream>>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Raw(RawSocket),
+    #[cfg(not(target_arch = "wasm32"))]
+    Fallback(FallbackSocket),
+    #[cfg(not(target_arch = "wasm32"))]
+    FallbackServer(FallbackServerSocket),
+    #[cfg(all(feature = "axum", not(target_arch = "wasm32")))]
+    Axum(AxumSocket),
+    #[cfg(all(feature = "warp", not(target_arch = "wasm32")))]
+    Warp(WarpSocket),
+    #[cfg(all(unix, not(target_arch = "wasm32")))]
+    Unix(WsStream<UnixStream>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Server(WebSocketStream<TcpStream>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Duplex(WebSocketStream<DuplexStream>),
+    #[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+    Deflate(WebSocketStream<DeflateStream>),
+    #[cfg(all(
+        any(feature = "tls-rustls", feature = "tls-native"),
+        not(target_arch = "wasm32")
+    ))]
+    ServerTls(WebSocketStream<ServerTlsStream<TcpStream>>),
     #[cfg(target_arch = "wasm32")]
-    Wasm(WsStream),
+    Wasm(WasmStream),
+    #[cfg(feature = "test-util")]
+    Mock(MockWebSocket),
+}
+
+/// WebSocket
+pub struct WebSocket {
+    inner: InnerWebSocket,
+    /// Negotiated subprotocol
+    protocol: Option<String>,
+    /// Address of the remote peer
+    peer_addr: Option<SocketAddr>,
+    /// Connection events observer
+    observer: Option<ConnectionObserver>,
+    /// Answer the pings automatically, without yielding them
+    auto_pong: bool,
+    /// Round-trip time monitor
+    rtt: RttMonitor,
+    /// Connection stats
+    stats: StatsHandle,
+    /// Read-idle timer
+    idle: Option<IdleTimer>,
+    /// Deferred flushes
+    coalescer: Option<Coalescer>,
+    /// Registration to the shutdown coordinator
+    shutdown: Option<ShutdownGuard>,
+    /// Slot in the server connection limits, released on drop
+    #[cfg(not(target_arch = "wasm32"))]
+    permit: Option<ConnectionPermit>,
+    /// The closing handshake has been started by us
+    closing: bool,
+    /// Close frame of the peer, if it started the closing handshake
+    peer_close: Option<CloseFrame>,
+    /// The connection has been closed (close frame received or stream ended)
+    closed: bool,
 }
 
 impl WebSocket {
+    #[inline]
+    pub(crate) fn new(inner: InnerWebSocket, protocol: Option<String>) -> Self {
+        Self {
+            inner,
+            protocol,
+            peer_addr: None,
+            observer: None,
+            auto_pong: true,
+            rtt: RttMonitor::new(None),
+            stats: StatsHandle::default(),
+            idle: None,
+            coalescer: None,
+            shutdown: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            permit: None,
+            closing: false,
+            peer_close: None,
+            closed: false,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn with_peer_addr(mut self, peer_addr: Option<SocketAddr>) -> Self {
+        self.peer_addr = peer_addr;
+        self
+    }
+
+    #[inline]
+    pub(crate) fn with_auto_pong(mut self, auto_pong: bool) -> Self {
+        self.auto_pong = auto_pong;
+        self
+    }
+
+    #[inline]
+    pub(crate) fn with_rtt(mut self, rtt: RttMonitor) -> Self {
+        self.rtt = rtt;
+        self
+    }
+
+    #[inline]
+    pub(crate) fn with_stats(mut self, stats: StatsHandle) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    #[inline]
+    pub(crate) fn with_extensions(mut self, extensions: Extensions) -> Self {
+        self.stats = self.stats.with_extensions(extensions);
+        self
+    }
+
+    #[inline]
+    pub(crate) fn with_idle_timeout(mut self, config: Option<IdleTimeoutConfig>) -> Self {
+        self.idle = config.map(IdleTimer::new);
+        self
+    }
+
+    #[inline]
+    pub(crate) fn with_write_coalescing(mut self, config: Option<WriteCoalescingConfig>) -> Self {
+        self.coalescer = config.map(Coalescer::new);
+        self
+    }
+
+    #[inline]
+    pub(crate) fn with_shutdown(mut self, token: Option<&ShutdownToken>) -> Self {
+        self.shutdown = token.map(ShutdownToken::register);
+        self
+    }
+
+    #[inline]
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn with_permit(mut self, permit: Option<ConnectionPermit>) -> Self {
+        self.permit = permit;
+        self
+    }
+
+    #[inline]
+    pub(crate) fn with_observer(mut self, observer: Option<ConnectionObserver>) -> Self {
+        self.observer = observer;
+        self
+    }
+
     pub async fn connect(
         url: &Url,
-        _mode: &ConnectionMode,
+        mode: &ConnectionMode,
+        timeout: Duration,
+    ) -> Result<Self, ConnectError> {
+        WebSocketBuilder::new()
+            .mode(mode.clone())
+            .timeout(timeout)
+            .connect(url)
+            .await
+    }
+
+    /// Get the subprotocol accepted by the server, if any
+    ///
+    /// Check [`WebSocketBuilder::protocols`].
+    #[inline]
+    pub fn negotiated_protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Get the address of the remote peer
+    ///
+    /// When connected through a proxy, this is the address of the proxy.
+    ///
+    /// Always `None` on WASM targets and for connections through the embedded tor client or a unix socket.
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Get the info about the TLS session (protocol version, cipher suite and peer certificates)
+    ///
+    /// Returns `None` for the unencrypted connections, the raw streams and the HTTP fallback.
+    #[cfg(all(
+        any(feature = "tls-rustls", feature = "tls-native"),
+        not(target_arch = "wasm32")
+    ))]
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match &self.inner {
+            InnerWebSocket::Tokio(s) => tls::client_tls_info(s.get_ref()),
+            #[cfg(feature = "tor")]
+            InnerWebSocket::Tor(s) => tls::client_tls_info(s.get_ref()),
+            #[cfg(feature = "futures-io")]
+            InnerWebSocket::Compat(s) => tls::client_tls_info(s.get_ref()),
+            InnerWebSocket::Custom(s) => tls::client_tls_info(s.get_ref()),
+            #[cfg(unix)]
+            InnerWebSocket::Unix(s) => tls::client_tls_info(s.get_ref()),
+            InnerWebSocket::ServerTls(s) => Some(tls::server_tls_info(s.get_ref())),
+            #[cfg(feature = "deflate")]
+            InnerWebSocket::Deflate(s) => s.get_ref().tls_info(),
+            _ => None,
+        }
+    }
+
+    /// Check if the permessage-deflate compression has been negotiated
+    ///
+    /// Check [`CompressionConfig`](crate::native::CompressionConfig) for more details.
+    #[inline]
+    #[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+    pub fn is_compressed(&self) -> bool {
+        match &self.inner {
+            InnerWebSocket::Deflate(s) => s.get_ref().is_compressed(),
+            _ => false,
+        }
+    }
+
+    /// Check if the connection is open
+    ///
+    /// Returns `false` after the closing handshake has been started or the connection has been closed.
+    #[inline]
+    pub fn is_open(&self) -> bool {
+        !self.closing && !self.closed
+    }
+
+    /// Get the state of the browser socket (`readyState`)
+    #[cfg(target_arch = "wasm32")]
+    pub fn ready_state(&self) -> Result<WsState, Error> {
+        match &self.inner {
+            InnerWebSocket::Wasm(s) => s.ready_state(),
+            #[cfg(feature = "test-util")]
+            InnerWebSocket::Mock(s) if s.is_terminated() => Ok(WsState::Closed),
+            #[cfg(feature = "test-util")]
+            InnerWebSocket::Mock(..) => Ok(WsState::Open),
+        }
+    }
+
+    /// Get the number of bytes queued by the browser but not transmitted yet (`bufferedAmount`)
+    #[cfg(target_arch = "wasm32")]
+    pub fn buffered_amount(&self) -> u32 {
+        match &self.inner {
+            InnerWebSocket::Wasm(s) => s.buffered_amount(),
+            #[cfg(feature = "test-util")]
+            InnerWebSocket::Mock(..) => 0,
+        }
+    }
+
+    /// Wait until all the data queued by the browser has been transmitted
+    ///
+    /// Useful for flow control: the browser accepts all the sent messages without backpressure.
+    #[cfg(target_arch = "wasm32")]
+    pub async fn wait_drained(&self) -> Result<(), Error> {
+        match &self.inner {
+            InnerWebSocket::Wasm(s) => s.wait_drained().await,
+            #[cfg(feature = "test-util")]
+            InnerWebSocket::Mock(..) => Ok(()),
+        }
+    }
+
+    /// Send a probe to measure the round-trip time
+    ///
+    /// The measurement completes when the reply is read from the [`Stream`].
+    /// No-op on WASM targets if no [`RttEcho`](crate::RttEcho) is set.
+    ///
+    /// Check [`RttMonitor`] for more details.
+    pub async fn ping_rtt(&mut self) -> Result<(), Error> {
+        match self.rtt.probe() {
+            Some(probe) => self.send(probe).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Send many messages, flushing only once at the end
+    ///
+    /// Unlike sending them one by one, the frames are buffered and written together,
+    /// avoiding a flush (and usually a syscall) per message.
+    /// Use [`SinkExt::feed`] and [`SinkExt::flush`] to batch messages produced over time.
+    ///
+    /// Returns the first error: the following messages aren't sent.
+    pub async fn send_all_batched<I>(&mut self, messages: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = Message>,
+    {
+        for msg in messages {
+            self.feed(msg).await?;
+        }
+        self.flush().await
+    }
+
+    /// Get the smoothed round-trip time estimate, if any probe has been answered
+    ///
+    /// Check [`WebSocket::ping_rtt`].
+    #[inline]
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.smoothed()
+    }
+
+    /// Get the round-trip time monitor
+    ///
+    /// Useful to send the probes and read the estimate after the socket has been split.
+    #[inline]
+    pub fn rtt_monitor(&self) -> RttMonitor {
+        self.rtt.clone()
+    }
+
+    /// Get the connection stats handle
+    ///
+    /// Check [`StatsHandle`] for more details.
+    #[inline]
+    pub fn stats(&self) -> StatsHandle {
+        self.stats.clone()
+    }
+
+    /// Get the [`Extensions`] of the connection
+    #[inline]
+    pub fn extensions(&self) -> &Extensions {
+        self.stats.extensions()
+    }
+
+    /// Split into separate [`Sink`] and [`Stream`] halves
+    ///
+    /// The halves can be used from different tasks.
+    #[inline]
+    pub fn split(self) -> (SplitSink<Self, Message>, SplitStream<Self>) {
+        StreamExt::split(self)
+    }
+
+    /// Gracefully close the connection
+    ///
+    /// Check [`WebSocket::close_with_timeout`] for more details. The default timeout is 10 secs.
+    #[inline]
+    pub async fn close<C, R>(&mut self, code: C, reason: R) -> Result<(), Error>
+    where
+        C: Into<CloseCode>,
+        R: Into<Utf8Bytes>,
+    {
+        self.close_with_timeout(code, reason, DEFAULT_CLOSE_TIMEOUT)
+            .await
+    }
+
+    /// Gracefully close the connection
+    ///
+    /// Perform the RFC 6455 closing handshake:
+    /// send a close frame with the specified `code` and `reason`,
+    /// wait for the close frame of the peer (discarding any other incoming message)
+    /// and then close the underlying connection.
+    ///
+    /// Returns [`Error::CloseTimeout`] if the closing handshake isn't completed within `timeout`.
+    pub async fn close_with_timeout<C, R>(
+        &mut self,
+        code: C,
+        reason: R,
         timeout: Duration,
-    ) -> Result<Self, Error> {
-        #[cfg(not(target_arch = "wasm32"))]
-        let socket: WebSocket = crate::native::connect(url, _mode, timeout).await?;
+    ) -> Result<(), Error>
+    where
+        C: Into<CloseCode>,
+        R: Into<Utf8Bytes>,
+    {
+        let frame: CloseFrame = CloseFrame {
+            code: code.into(),
+            reason: reason.into(),
+        };
+
+        let handshake = async {
+            self.send(Message::Close(Some(frame))).await?;
+
+            // Drain until the close frame of the peer (or the end of the stream)
+            while let Some(msg) = self.next().await {
+                if let Message::Close(..) = msg? {
+                    break;
+                }
+            }
 
-        #[cfg(target_arch = "wasm32")]
-        let socket: WebSocket = crate::wasm::connect(url, timeout).await?;
+            SinkExt::close(&mut *self).await
+        };
 
-        Ok(socket)
+        time::timeout(timeout, handshake)
+            .await
+            .ok_or(Error::CloseTimeout)?
+    }
+
+    /// Gracefully close the connection, yielding the messages received in the meantime
+    ///
+    /// Same as [`WebSocket::close_with_timeout`], but the messages received before the close frame of the peer
+    /// are yielded by the returned stream instead of being discarded.
+    /// The stream ends when the closing handshake is completed:
+    /// if it isn't completed within `timeout`, it yields [`Error::CloseTimeout`] and ends.
+    pub fn close_and_drain<C, R>(&mut self, code: C, reason: R, timeout: Duration) -> Drain<'_>
+    where
+        C: Into<CloseCode>,
+        R: Into<Utf8Bytes>,
+    {
+        let frame: CloseFrame = CloseFrame {
+            code: code.into(),
+            reason: reason.into(),
+        };
+        Drain::new(self, frame, timeout)
+    }
+
+    #[inline]
+    fn notify(&self, event: ConnectionEvent) {
+        if let Some(observer) = &self.observer {
+            observer.notify(event, self.stats.extensions());
+        }
+    }
+
+    fn notify_closing(&mut self) {
+        if !self.closing && !self.closed {
+            self.closing = true;
+            self.notify(ConnectionEvent::Closing);
+        }
+    }
+
+    fn notify_closed(&mut self, event: ConnectionEvent) {
+        if !self.closed {
+            self.closed = true;
+            self.notify(event);
+        }
+    }
+
+    /// Drive the read-idle timer. Returns the error if the connection expired.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Option<Error> {
+        loop {
+            match self.idle.as_mut()?.poll(cx) {
+                Poll::Ready(IdleEvent::Probe) => {
+                    #[cfg(not(target_arch = "wasm32"))]
+                    self.send_probe(cx);
+                }
+                Poll::Ready(IdleEvent::Expired) => return Some(Error::IdleTimeout),
+                Poll::Pending => return None,
+            }
+        }
+    }
+
+    /// Best-effort ping: if it can't be sent, the idle timer expires anyway
+    #[cfg(not(target_arch = "wasm32"))]
+    fn send_probe(&mut self, cx: &mut Context<'_>) {
+        if let Poll::Ready(Ok(())) = Pin::new(&mut self.inner).poll_ready(cx) {
+            if Pin::new(&mut self.inner)
+                .start_send(Message::Ping(Bytes::new()))
+                .is_ok()
+            {
+                let _ = Pin::new(&mut self.inner).poll_flush(cx);
+            }
+        }
+    }
+
+    /// Start the closing handshake, on shutdown
+    ///
+    /// If the socket isn't writable, it's retried on the next read.
+    fn start_shutdown(&mut self, cx: &mut Context<'_>) {
+        if let Poll::Ready(Ok(())) = Pin::new(&mut self.inner).poll_ready(cx) {
+            let frame: CloseFrame = CloseFrame {
+                code: CloseCode::GoingAway,
+                reason: Utf8Bytes::from_static("shutdown"),
+            };
+
+            if Pin::new(&mut self.inner)
+                .start_send(Message::Close(Some(frame)))
+                .is_ok()
+            {
+                self.notify_closing();
+                let _ = Pin::new(&mut self.inner).poll_flush(cx);
+            }
+        }
+    }
+
+    /// Notify the error, if any
+    fn inspect_res<T>(&self, res: Result<T, Error>) -> Result<T, Error> {
+        let res = res.map_err(|e| self.closed_by_peer(e));
+        if let Err(e) = &res {
+            self.notify(ConnectionEvent::Error(e.to_string()));
+        }
+        res
+    }
+
+    /// Attach the close frame of the peer to the errors of a connection it closed
+    fn closed_by_peer(&self, e: Error) -> Error {
+        match &self.peer_close {
+            Some(frame)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::ClosedByPeer | ErrorKind::ConnectionLost
+                ) =>
+            {
+                Error::ClosedByPeer(frame.clone())
+            }
+            _ => e,
+        }
     }
 }
 
-impl Sink<Message> for WebSocket {
+impl Sink<Message> for InnerWebSocket {
     type Error = Error;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -56,8 +567,37 @@ impl Sink<Message> for WebSocket {
             Self::Tokio(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
             #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
             Self::Tor(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
+            #[cfg(all(feature = "futures-io", not(target_arch = "wasm32")))]
+            Self::Compat(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Custom(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Raw(s) => Pin::new(s).poll_ready(cx),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Fallback(s) => Pin::new(s).poll_ready(cx),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::FallbackServer(s) => Pin::new(s).poll_ready(cx),
+            #[cfg(all(feature = "axum", not(target_arch = "wasm32")))]
+            Self::Axum(s) => Pin::new(s).poll_ready(cx),
+            #[cfg(all(feature = "warp", not(target_arch = "wasm32")))]
+            Self::Warp(s) => Pin::new(s).poll_ready(cx),
+            #[cfg(all(unix, not(target_arch = "wasm32")))]
+            Self::Unix(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Server(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Duplex(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
+            #[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+            Self::Deflate(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
+            #[cfg(all(
+                any(feature = "tls-rustls", feature = "tls-native"),
+                not(target_arch = "wasm32")
+            ))]
+            Self::ServerTls(s) => Pin::new(s).poll_ready(cx).map_err(Into::into),
             #[cfg(target_arch = "wasm32")]
-            Self::Wasm(s) => Pin::new(s).poll_ready(cx),
+            Self::Wasm(s) => Pin::new(wasm::stream_mut(s)).poll_ready(cx),
+            #[cfg(feature = "test-util")]
+            Self::Mock(s) => Pin::new(s).poll_ready(cx),
         }
     }
 
@@ -67,8 +607,37 @@ impl Sink<Message> for WebSocket {
             Self::Tokio(s) => Pin::new(s).start_send(item.into()).map_err(Into::into),
             #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
             Self::Tor(s) => Pin::new(s).start_send(item.into()).map_err(Into::into),
+            #[cfg(all(feature = "futures-io", not(target_arch = "wasm32")))]
+            Self::Compat(s) => Pin::new(s).start_send(item.into()).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Custom(s) => Pin::new(s).start_send(item.into()).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Raw(s) => Pin::new(s).start_send(item),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Fallback(s) => Pin::new(s).start_send(item),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::FallbackServer(s) => Pin::new(s).start_send(item),
+            #[cfg(all(feature = "axum", not(target_arch = "wasm32")))]
+            Self::Axum(s) => Pin::new(s).start_send(item),
+            #[cfg(all(feature = "warp", not(target_arch = "wasm32")))]
+            Self::Warp(s) => Pin::new(s).start_send(item),
+            #[cfg(all(unix, not(target_arch = "wasm32")))]
+            Self::Unix(s) => Pin::new(s).start_send(item.into()).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Server(s) => Pin::new(s).start_send(item.into()).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Duplex(s) => Pin::new(s).start_send(item.into()).map_err(Into::into),
+            #[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+            Self::Deflate(s) => Pin::new(s).start_send(item.into()).map_err(Into::into),
+            #[cfg(all(
+                any(feature = "tls-rustls", feature = "tls-native"),
+                not(target_arch = "wasm32")
+            ))]
+            Self::ServerTls(s) => Pin::new(s).start_send(item.into()).map_err(Into::into),
             #[cfg(target_arch = "wasm32")]
-            Self::Wasm(s) => Pin::new(s).start_send(item),
+            Self::Wasm(s) => Pin::new(wasm::stream_mut(s)).start_send(item),
+            #[cfg(feature = "test-util")]
+            Self::Mock(s) => Pin::new(s).start_send(item),
         }
     }
 
@@ -78,8 +647,37 @@ impl Sink<Message> for WebSocket {
             Self::Tokio(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
             #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
             Self::Tor(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
+            #[cfg(all(feature = "futures-io", not(target_arch = "wasm32")))]
+            Self::Compat(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Custom(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Raw(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Fallback(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::FallbackServer(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(all(feature = "axum", not(target_arch = "wasm32")))]
+            Self::Axum(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(all(feature = "warp", not(target_arch = "wasm32")))]
+            Self::Warp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(all(unix, not(target_arch = "wasm32")))]
+            Self::Unix(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Server(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Duplex(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
+            #[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+            Self::Deflate(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
+            #[cfg(all(
+                any(feature = "tls-rustls", feature = "tls-native"),
+                not(target_arch = "wasm32")
+            ))]
+            Self::ServerTls(s) => Pin::new(s).poll_flush(cx).map_err(Into::into),
             #[cfg(target_arch = "wasm32")]
-            Self::Wasm(s) => Pin::new(s).poll_flush(cx),
+            Self::Wasm(s) => Pin::new(wasm::stream_mut(s)).poll_flush(cx),
+            #[cfg(feature = "test-util")]
+            Self::Mock(s) => Pin::new(s).poll_flush(cx),
         }
     }
 
@@ -89,13 +687,44 @@ impl Sink<Message> for WebSocket {
             Self::Tokio(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
             #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
             Self::Tor(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
+            #[cfg(all(feature = "futures-io", not(target_arch = "wasm32")))]
+            Self::Compat(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Custom(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Raw(s) => Pin::new(s).poll_close(cx),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Fallback(s) => Pin::new(s).poll_close(cx),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::FallbackServer(s) => Pin::new(s).poll_close(cx),
+            #[cfg(all(feature = "axum", not(target_arch = "wasm32")))]
+            Self::Axum(s) => Pin::new(s).poll_close(cx),
+            #[cfg(all(feature = "warp", not(target_arch = "wasm32")))]
+            Self::Warp(s) => Pin::new(s).poll_close(cx),
+            #[cfg(all(unix, not(target_arch = "wasm32")))]
+            Self::Unix(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Server(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Duplex(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
+            #[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+            Self::Deflate(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
+            #[cfg(all(
+                any(feature = "tls-rustls", feature = "tls-native"),
+                not(target_arch = "wasm32")
+            ))]
+            Self::ServerTls(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
             #[cfg(target_arch = "wasm32")]
-            Self::Wasm(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
+            Self::Wasm(s) => Pin::new(wasm::stream_mut(s))
+                .poll_close(cx)
+                .map_err(Into::into),
+            #[cfg(feature = "test-util")]
+            Self::Mock(s) => Pin::new(s).poll_close(cx).map_err(Into::into),
         }
     }
 }
 
-impl Stream for WebSocket {
+impl Stream for InnerWebSocket {
     type Item = Result<Message, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
@@ -103,15 +732,58 @@ impl Stream for WebSocket {
             #[cfg(not(target_arch = "wasm32"))]
             Self::Tokio(s) => Pin::new(s)
                 .poll_next(cx)
-                .map(|i| i.map(|res| res.map(Message::from_native)))
-                .map_err(Into::into),
+                .map(|i| i.map(|res| res.map(Message::from).map_err(Into::into))),
             #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
             Self::Tor(s) => Pin::new(s)
                 .poll_next(cx)
-                .map(|i| i.map(|res| res.map(Message::from_native)))
-                .map_err(Into::into),
+                .map(|i| i.map(|res| res.map(Message::from).map_err(Into::into))),
+            #[cfg(all(feature = "futures-io", not(target_arch = "wasm32")))]
+            Self::Compat(s) => Pin::new(s)
+                .poll_next(cx)
+                .map(|i| i.map(|res| res.map(Message::from).map_err(Into::into))),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Custom(s) => Pin::new(s)
+                .poll_next(cx)
+                .map(|i| i.map(|res| res.map(Message::from).map_err(Into::into))),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Raw(s) => Pin::new(s).poll_next(cx),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Fallback(s) => Pin::new(s).poll_next(cx),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::FallbackServer(s) => Pin::new(s).poll_next(cx),
+            #[cfg(all(feature = "axum", not(target_arch = "wasm32")))]
+            Self::Axum(s) => Pin::new(s).poll_next(cx),
+            #[cfg(all(feature = "warp", not(target_arch = "wasm32")))]
+            Self::Warp(s) => Pin::new(s).poll_next(cx),
+            #[cfg(all(unix, not(target_arch = "wasm32")))]
+            Self::Unix(s) => Pin::new(s)
+                .poll_next(cx)
+                .map(|i| i.map(|res| res.map(Message::from).map_err(Into::into))),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Server(s) => Pin::new(s)
+                .poll_next(cx)
+                .map(|i| i.map(|res| res.map(Message::from).map_err(Into::into))),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Duplex(s) => Pin::new(s)
+                .poll_next(cx)
+                .map(|i| i.map(|res| res.map(Message::from).map_err(Into::into))),
+            #[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+            Self::Deflate(s) => Pin::new(s)
+                .poll_next(cx)
+                .map(|i| i.map(|res| res.map(Message::from).map_err(Into::into))),
+            #[cfg(all(
+                any(feature = "tls-rustls", feature = "tls-native"),
+                not(target_arch = "wasm32")
+            ))]
+            Self::ServerTls(s) => Pin::new(s)
+                .poll_next(cx)
+                .map(|i| i.map(|res| res.map(Message::from).map_err(Into::into))),
             #[cfg(target_arch = "wasm32")]
-            Self::Wasm(s) => Pin::new(s).poll_next(cx).map_err(Into::into),
+            Self::Wasm(s) => Pin::new(wasm::stream_mut(s))
+                .poll_next(cx)
+                .map_err(Into::into),
+            #[cfg(feature = "test-util")]
+            Self::Mock(s) => Pin::new(s).poll_next(cx).map_err(Into::into),
         }
     }
 
@@ -121,8 +793,176 @@ impl Stream for WebSocket {
             Self::Tokio(s) => s.size_hint(),
             #[cfg(all(feature = "tor", not(target_arch = "wasm32")))]
             Self::Tor(s) => s.size_hint(),
+            #[cfg(all(feature = "futures-io", not(target_arch = "wasm32")))]
+            Self::Compat(s) => s.size_hint(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Custom(s) => s.size_hint(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Raw(s) => s.size_hint(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Fallback(s) => s.size_hint(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::FallbackServer(s) => s.size_hint(),
+            #[cfg(all(feature = "axum", not(target_arch = "wasm32")))]
+            Self::Axum(s) => s.size_hint(),
+            #[cfg(all(feature = "warp", not(target_arch = "wasm32")))]
+            Self::Warp(s) => s.size_hint(),
+            #[cfg(all(unix, not(target_arch = "wasm32")))]
+            Self::Unix(s) => s.size_hint(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Server(s) => s.size_hint(),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Duplex(s) => s.size_hint(),
+            #[cfg(all(feature = "deflate", not(target_arch = "wasm32")))]
+            Self::Deflate(s) => s.size_hint(),
+            #[cfg(all(
+                any(feature = "tls-rustls", feature = "tls-native"),
+                not(target_arch = "wasm32")
+            ))]
+            Self::ServerTls(s) => s.size_hint(),
             #[cfg(target_arch = "wasm32")]
             Self::Wasm(s) => s.size_hint(),
+            #[cfg(feature = "test-util")]
+            Self::Mock(s) => s.size_hint(),
+        }
+    }
+}
+
+impl Sink<Message> for WebSocket {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Write the fed messages together, when enough are buffered or the delay expired
+        if self.coalescer.as_mut().is_some_and(|c| c.poll_due(cx)) {
+            if let Err(e) = ready!(self.as_mut().poll_flush(cx)) {
+                return Poll::Ready(Err(e));
+            }
         }
+
+        let res = ready!(Pin::new(&mut self.inner).poll_ready(cx));
+        Poll::Ready(self.inspect_res(res))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        if let Message::Close(..) = item {
+            self.notify_closing();
+        }
+
+        self.stats.on_sent(&item);
+
+        if let Some(coalescer) = &mut self.coalescer {
+            match &item {
+                Message::Close(..) => coalescer.force(),
+                msg => coalescer.on_sent(msg.len()),
+            }
+        }
+
+        let res = Pin::new(&mut self.inner).start_send(item);
+        self.inspect_res(res)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let res = ready!(Pin::new(&mut self.inner).poll_flush(cx));
+        if let (Some(coalescer), Ok(())) = (&mut self.coalescer, &res) {
+            coalescer.reset();
+        }
+        Poll::Ready(self.inspect_res(res))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.notify_closing();
+
+        let res = ready!(Pin::new(&mut self.inner).poll_close(cx));
+        Poll::Ready(self.inspect_res(res))
+    }
+}
+
+impl Stream for WebSocket {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.idle.as_ref().is_some_and(IdleTimer::is_expired) {
+            self.notify_closed(ConnectionEvent::dropped());
+            return Poll::Ready(None);
+        }
+
+        match self.shutdown.as_ref().map(|guard| guard.poll_state(cx)) {
+            Some(ShutdownState::Closing) if !self.closing && !self.closed => {
+                self.start_shutdown(cx)
+            }
+            Some(ShutdownState::Aborted) => {
+                self.shutdown = None;
+                self.notify_closed(ConnectionEvent::dropped());
+                return Poll::Ready(None);
+            }
+            _ => {}
+        }
+
+        // Perform the deferred flush, if due: the errors are returned by the next write
+        if self.coalescer.as_mut().is_some_and(|c| c.poll_due(cx)) {
+            if let Poll::Ready(Ok(())) = Pin::new(&mut self.inner).poll_flush(cx) {
+                if let Some(coalescer) = &mut self.coalescer {
+                    coalescer.reset();
+                }
+            }
+        }
+
+        let item = loop {
+            let item = match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(item) => item,
+                Poll::Pending => match self.poll_idle(cx) {
+                    Some(e) => break Some(Err(e)),
+                    None => return Poll::Pending,
+                },
+            };
+
+            // Any frame is a proof of liveness
+            if let (Some(idle), Some(Ok(..))) = (&mut self.idle, &item) {
+                idle.reset();
+            }
+
+            match &item {
+                Some(Ok(Message::Ping(..))) if self.auto_pong => {
+                    // The pong has been queued by the protocol: write it out now.
+                    // If the socket isn't writable, it's written by the next read or write.
+                    if let Poll::Ready(Err(e)) = Pin::new(&mut self.inner).poll_flush(cx) {
+                        break Some(Err(e));
+                    }
+                }
+                _ => break item,
+            }
+        };
+
+        if let Some(Ok(msg)) = &item {
+            self.stats.on_received(msg);
+            self.rtt.observe(msg);
+        }
+
+        match &item {
+            Some(Ok(Message::Close(frame))) => {
+                if !self.closing {
+                    self.peer_close = Some(frame.clone().unwrap_or_else(|| CloseFrame {
+                        code: CloseCode::NoStatusReceived,
+                        reason: Utf8Bytes::default(),
+                    }));
+                }
+
+                let event = ConnectionEvent::closed(frame.clone());
+                self.notify_closed(event);
+            }
+            Some(Ok(..)) => {}
+            Some(Err(e)) => self.notify(ConnectionEvent::Error(e.to_string())),
+            None => {
+                self.shutdown = None;
+                self.notify_closed(ConnectionEvent::dropped());
+            }
+        }
+
+        Poll::Ready(item)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
     }
 }
@@ -0,0 +1,142 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection stats
+
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{time, Extensions, Message};
+
+/// Snapshot of the connection stats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionStats {
+    /// Messages sent
+    pub messages_sent: u64,
+    /// Messages received
+    pub messages_received: u64,
+    /// Payload bytes sent
+    pub bytes_sent: u64,
+    /// Payload bytes received
+    pub bytes_received: u64,
+    /// Time of the last sent message
+    pub last_sent: Option<SystemTime>,
+    /// Time of the last received message
+    pub last_received: Option<SystemTime>,
+    /// Number of reconnections
+    ///
+    /// Only counted by [`ReconnectingWebSocket`](crate::reconnect::ReconnectingWebSocket).
+    pub reconnects: u32,
+    /// Size of the queued messages, in bytes
+    ///
+    /// Only reported by a [`SendQueue`](crate::SendQueue) with [`SendQueue::with_stats`](crate::SendQueue::with_stats).
+    pub queued_bytes: u64,
+}
+
+impl ConnectionStats {
+    /// Time of the last activity (sent or received message)
+    #[inline]
+    pub fn last_activity(&self) -> Option<SystemTime> {
+        self.last_sent.max(self.last_received)
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    /// Milliseconds since UNIX epoch (`0` means never)
+    last_sent: AtomicU64,
+    /// Milliseconds since UNIX epoch (`0` means never)
+    last_received: AtomicU64,
+    reconnects: AtomicU32,
+    queued_bytes: AtomicU64,
+}
+
+/// Connection stats handle
+///
+/// Updated by the socket as the messages are sent and received.
+///
+/// The handle is cheap to clone: all the clones refer to the same counters,
+/// so they can be read from another task (ex. a dashboard) without locking.
+#[derive(Clone, Default)]
+pub struct StatsHandle {
+    counters: Arc<Counters>,
+    extensions: Extensions,
+}
+
+impl fmt::Debug for StatsHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("StatsHandle")
+            .field(&self.snapshot())
+            .finish()
+    }
+}
+
+impl StatsHandle {
+    #[inline]
+    pub(crate) fn with_extensions(mut self, extensions: Extensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Get the [`Extensions`] of the connection
+    #[inline]
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Get a snapshot of the stats
+    pub fn snapshot(&self) -> ConnectionStats {
+        let c: &Counters = &self.counters;
+        ConnectionStats {
+            messages_sent: c.messages_sent.load(Ordering::Relaxed),
+            messages_received: c.messages_received.load(Ordering::Relaxed),
+            bytes_sent: c.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: c.bytes_received.load(Ordering::Relaxed),
+            last_sent: to_time(c.last_sent.load(Ordering::Relaxed)),
+            last_received: to_time(c.last_received.load(Ordering::Relaxed)),
+            reconnects: c.reconnects.load(Ordering::Relaxed),
+            queued_bytes: c.queued_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn on_sent(&self, msg: &Message) {
+        let c: &Counters = &self.counters;
+        c.messages_sent.fetch_add(1, Ordering::Relaxed);
+        c.bytes_sent.fetch_add(msg.len() as u64, Ordering::Relaxed);
+        c.last_sent.store(time::unix_millis(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_received(&self, msg: &Message) {
+        let c: &Counters = &self.counters;
+        c.messages_received.fetch_add(1, Ordering::Relaxed);
+        c.bytes_received
+            .fetch_add(msg.len() as u64, Ordering::Relaxed);
+        c.last_received
+            .store(time::unix_millis(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_reconnected(&self) {
+        self.counters.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_queued_bytes(&self, bytes: usize) {
+        self.counters
+            .queued_bytes
+            .store(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+#[inline]
+fn to_time(millis: u64) -> Option<SystemTime> {
+    if millis == 0 {
+        return None;
+    }
+
+    Some(UNIX_EPOCH + Duration::from_millis(millis))
+}
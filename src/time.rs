@@ -0,0 +1,248 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Time
+//!
+//! All the timers of the crate (timeouts, keep-alive, idle, reconnect backoff, etc.) go through this module.
+//! On native targets they use the tokio clock, so the tests can run them in virtual time
+//! with `tokio::time::pause` (enable the `test-util` feature).
+
+use std::future::Future;
+use std::time::Duration;
+
+/// Sleep for the specified duration
+#[inline]
+pub(crate) async fn sleep(duration: Duration) {
+    #[cfg(not(target_arch = "wasm32"))]
+    tokio::time::sleep(duration).await;
+
+    #[cfg(all(target_arch = "wasm32", not(feature = "wasm-send")))]
+    async_utility::time::sleep(duration).await;
+
+    // The browser timers are bound to the thread that created them
+    #[cfg(all(target_arch = "wasm32", feature = "wasm-send"))]
+    send_wrapper::SendWrapper::new(async_utility::time::sleep(duration)).await;
+}
+
+/// Require a future to complete before the specified duration has elapsed
+///
+/// Returns `None` if the duration has elapsed.
+#[inline]
+pub(crate) async fn timeout<F>(duration: Duration, future: F) -> Option<F::Output>
+where
+    F: Future,
+{
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        tokio::time::timeout(duration, future).await.ok()
+    }
+
+    #[cfg(all(target_arch = "wasm32", not(feature = "wasm-send")))]
+    {
+        async_utility::time::timeout(Some(duration), future).await
+    }
+
+    #[cfg(all(target_arch = "wasm32", feature = "wasm-send"))]
+    {
+        send_wrapper::SendWrapper::new(async_utility::time::timeout(Some(duration), future)).await
+    }
+}
+
+/// Milliseconds since UNIX epoch
+#[inline]
+pub(crate) fn unix_millis() -> u64 {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default()
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        js_sys::Date::now() as u64
+    }
+}
+
+/// Point in time, usable also on WASM targets (where [`std::time::Instant`] panics)
+///
+/// On native targets it follows the tokio clock, also when paused.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Instant(
+    #[cfg(not(target_arch = "wasm32"))] tokio::time::Instant,
+    /// Milliseconds since UNIX epoch
+    #[cfg(target_arch = "wasm32")]
+    f64,
+);
+
+impl Instant {
+    #[inline]
+    pub(crate) fn now() -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            Self(tokio::time::Instant::now())
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            Self(js_sys::Date::now())
+        }
+    }
+
+    /// Time elapsed since this instant
+    #[inline]
+    pub(crate) fn elapsed(&self) -> Duration {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.0.elapsed()
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            let millis: f64 = (js_sys::Date::now() - self.0).max(0.0);
+            Duration::from_secs_f64(millis / 1000.0)
+        }
+    }
+}
+
+/// The timers run in virtual time: the paused clock auto-advances when the runtime is idle
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use futures_util::StreamExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use url::Url;
+
+    use super::*;
+    use crate::native::{self, WsServer};
+    use crate::reconnect::{ExponentialBackoff, ReconnectEvent, ReconnectingWebSocket};
+    use crate::{
+        ConnectError, Error, ErrorKind, KeepAlive, KeepAliveConfig, Message, Phase,
+        WebSocketBuilder,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_handshake_timeout() {
+        // Accepts the TCP connections but never answers the handshake
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let mut streams: Vec<TcpStream> = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let start = Instant::now();
+        let res = WebSocketBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .connect(&url)
+            .await;
+        assert!(res.is_err_and(|e| e.kind() == ErrorKind::Timeout));
+        assert!(start.elapsed() >= Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(11));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_server_handshake_timeout() {
+        let mut server: WsServer = WsServer::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .handshake_timeout(Duration::from_secs(5));
+
+        // Connects but never sends the handshake request
+        let _stream = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let start = Instant::now();
+        let res = server.next().await.unwrap();
+        assert!(matches!(res, Err(ConnectError::Timeout(Phase::Handshake))));
+        assert!(start.elapsed() >= Duration::from_secs(5));
+        assert!(start.elapsed() < Duration::from_secs(6));
+    }
+
+    fn keepalive_config() -> KeepAliveConfig {
+        KeepAliveConfig {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+            probe: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_timeout() {
+        // The server is never polled: the pings aren't answered
+        let (client, _server) = native::duplex().await;
+        let mut socket = KeepAlive::new(client, keepalive_config());
+
+        let start = Instant::now();
+        assert!(matches!(
+            socket.next().await,
+            Some(Err(Error::KeepAliveTimeout))
+        ));
+        assert!(start.elapsed() >= Duration::from_secs(40));
+        assert!(start.elapsed() < Duration::from_secs(41));
+        assert!(socket.next().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_pong() {
+        // The server answers the pings while reading
+        let (client, mut server) = native::duplex().await;
+        tokio::spawn(async move { while server.next().await.is_some() {} });
+        let mut socket = KeepAlive::new(client, keepalive_config());
+
+        let start = Instant::now();
+        for i in 1..=5 {
+            let msg: Message = socket.next().await.unwrap().unwrap();
+            assert!(msg.is_pong());
+            assert!(start.elapsed() >= Duration::from_secs(30 * i));
+            assert!(start.elapsed() < Duration::from_secs(30 * i + 1));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnect_backoff() {
+        // Nothing listening: the connections are refused
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+
+        let backoff = ExponentialBackoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            max_attempts: Some(4),
+            jitter: 0.0,
+        };
+        let mut socket = ReconnectingWebSocket::new(url, WebSocketBuilder::new(), backoff);
+        let events = socket.events();
+
+        let start = Instant::now();
+        assert!(socket.next().await.is_none());
+        let elapsed: Duration = start.elapsed();
+
+        drop(socket);
+        let events: Vec<ReconnectEvent> = events.collect().await;
+        let delays: Vec<Duration> = events
+            .iter()
+            .filter_map(|event| match event {
+                ReconnectEvent::Reconnecting { delay, .. } => Some(*delay),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            delays,
+            [
+                Duration::from_secs(2),
+                Duration::from_secs(4),
+                Duration::from_secs(8)
+            ]
+        );
+        assert_eq!(events.last(), Some(&ReconnectEvent::GaveUp));
+
+        // The backoff delays elapsed in virtual time
+        assert!(elapsed >= Duration::from_secs(14));
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Timeouts
+
+use std::fmt;
+use std::time::Duration;
+
+/// Connection phase
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Phase {
+    /// DNS resolution
+    Dns,
+    /// TCP (or unix socket) connection, including the proxy negotiation (if any)
+    Tcp,
+    /// TLS handshake
+    Tls,
+    /// WebSocket handshake
+    Handshake,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dns => write!(f, "DNS resolution"),
+            Self::Tcp => write!(f, "TCP connection"),
+            Self::Tls => write!(f, "TLS handshake"),
+            Self::Handshake => write!(f, "WebSocket handshake"),
+        }
+    }
+}
+
+/// Per-phase connection timeouts
+///
+/// Each phase is bounded by its own timeout (if set) and by the overall
+/// [`WebSocketBuilder::timeout`](crate::WebSocketBuilder::timeout), whichever expires first.
+///
+/// On WASM targets the browser performs all the phases at once, so only `handshake` is applied.
+/// With the embedded tor client, the circuit building isn't bounded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timeouts {
+    /// DNS resolution timeout
+    pub dns: Option<Duration>,
+    /// TCP (or unix socket) connection timeout, including the proxy negotiation (if any)
+    pub tcp: Option<Duration>,
+    /// TLS handshake timeout
+    pub tls: Option<Duration>,
+    /// WebSocket handshake timeout
+    pub handshake: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Get the timeout of a phase
+    pub fn get(&self, phase: Phase) -> Option<Duration> {
+        match phase {
+            Phase::Dns => self.dns,
+            Phase::Tcp => self.tcp,
+            Phase::Tls => self.tls,
+            Phase::Handshake => self.handshake,
+        }
+    }
+}
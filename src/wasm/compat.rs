@@ -0,0 +1,42 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Feature detection of the JS environment
+//!
+//! Some environments (ex. Deno, browser extension service workers, Node.js)
+//! don't expose all the classes available in the browser main thread.
+
+use js_sys::Reflect;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{Blob, DomException};
+
+/// Check if the global scope has a property (ex. a class)
+pub(crate) fn has_global(name: &str) -> bool {
+    Reflect::has(&js_sys::global(), &JsValue::from_str(name)).unwrap_or(false)
+}
+
+/// Check if a value is a `Blob`, without failing where the class isn't defined
+#[inline]
+pub(crate) fn is_blob(value: &JsValue) -> bool {
+    has_global("Blob") && value.is_instance_of::<Blob>()
+}
+
+/// Get the code of a `DOMException`, if it is one
+pub(crate) fn dom_exception_code(value: &JsValue) -> Option<u16> {
+    if has_global("DOMException") {
+        value.dyn_ref::<DomException>().map(|e| e.code())
+    } else {
+        None
+    }
+}
+
+/// Get the message of a thrown value
+pub(crate) fn error_message(value: &JsValue) -> String {
+    if let Some(e) = value.dyn_ref::<js_sys::Error>() {
+        return String::from(e.message());
+    }
+
+    value
+        .as_string()
+        .unwrap_or_else(|| String::from("unknown JS error"))
+}
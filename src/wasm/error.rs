@@ -5,9 +5,14 @@
 use core::fmt;
 use core::str::Utf8Error;
 
+use crate::error::{ErrorKind, ProtocolViolation};
+use crate::limits::Limit;
+use crate::message::CloseFrame;
+use crate::response::HandshakeRejection;
+use crate::timeouts::Phase;
 use crate::wasm::CloseEvent;
 
-/// WebSocket Error
+/// WebSocket error, returned once connected
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     /// UTF-8 error
@@ -19,12 +24,6 @@ pub enum Error {
     },
     /// When trying to send and [WsState](crate::WsState) is anything but [WsState::Open](crate::WsState::Open) this error is returned.
     ConnectionNotOpen,
-    /// An invalid URL was given to [WsMeta::connect](crate::WsMeta::connect), please see:
-    /// [HTML Living Standard](https://html.spec.whatwg.org/multipage/web-sockets.html#dom-websocket).
-    InvalidUrl {
-        /// The user supplied value that is invalid.
-        supplied: String,
-    },
     /// An invalid close code was given to a close method. For valid close codes, please see:
     /// [MDN Documentation](https://developer.mozilla.org/en-US/docs/Web/API/CloseEvent#Status_codes).
     InvalidCloseCode {
@@ -34,14 +33,7 @@ pub enum Error {
     /// The reason string given to a close method is longer than 123 bytes, please see:
     /// [MDN Documentation](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/close).
     ReasonStringToLong,
-    /// Failed to connect to the server.
-    ConnectionFailed {
-        /// The close event that might hold extra code and reason information.
-        event: CloseEvent,
-    },
-    /// When converting the JavaScript Message into a WsMessage, it's possible that
-    /// a String message doesn't convert correctly as Js does not guarantee that
-    /// strings are valid Unicode. Happens in `impl TryFrom< MessageEvent > for WsMessage`.
+    /// No longer returned: check [`Error::InvalidUtf8`].
     InvalidEncoding,
     /// When converting the JavaScript Message into a WsMessage, it's not possible to
     /// convert Blob type messages, as Blob is a streaming type, that needs to be read
@@ -57,9 +49,51 @@ pub enum Error {
     ///
     /// Happens in `impl TryFrom< MessageEvent > for WsMessage`.
     UnknownDataType,
-    Dom(u16),
-    Other(String),
-    Timeout,
+    /// The connection has been closed by the peer, with this close frame
+    ///
+    /// Returned when writing to a connection closed by the peer.
+    ClosedByPeer(CloseFrame),
+    /// WebSocket protocol violation
+    ///
+    /// Never returned: the browsers handle the protocol and don't expose the violations.
+    Protocol(ProtocolViolation),
+    /// The closing handshake isn't completed within the close timeout
+    CloseTimeout,
+    /// No messages received within the keepalive timeout
+    KeepAliveTimeout,
+    /// No frames received within the read-idle timeout
+    IdleTimeout,
+    /// The send queue is full
+    QueueFull,
+    /// Incoming message too large
+    MessageTooLarge {
+        /// Size of the message
+        size: usize,
+        /// Max allowed size
+        max_size: usize,
+    },
+    /// Incoming limit exceeded
+    LimitExceeded(Limit),
+    /// Text message with invalid UTF-8
+    ///
+    /// Check [`Utf8Policy`](crate::Utf8Policy).
+    InvalidUtf8,
+    /// The request id wasn't found by the correlator
+    MissingRequestId,
+    /// A request with the same id is already waiting for its response
+    DuplicateRequestId(String),
+    /// No response received within the request timeout
+    RequestTimeout,
+    /// JSON error
+    ///
+    /// Stored as string, to keep the error cloneable.
+    #[cfg(feature = "json")]
+    Json(String),
+    /// Codec error
+    ///
+    /// Stored as string, to keep the error cloneable.
+    #[cfg(any(feature = "cbor", feature = "msgpack"))]
+    Codec(String),
 }
 
 impl std::error::Error for Error {}
@@ -72,10 +106,6 @@ impl fmt::Display for Error {
                 write!(f, "Invalid input to conversion to WsReadyState: {supplied}")
             }
             Self::ConnectionNotOpen => write!(f, "The connection state is not \"Open\"."),
-            Self::InvalidUrl { supplied } => write!(
-                f,
-                "An invalid URL was given to the connect method: {supplied}"
-            ),
             Self::InvalidCloseCode { supplied } => write!(
                 f,
                 "An invalid close code was given to a close method: {supplied}"
@@ -83,9 +113,6 @@ impl fmt::Display for Error {
             Self::ReasonStringToLong => {
                 write!(f, "The reason string given to a close method is to long.")
             }
-            Self::ConnectionFailed { event } => {
-                write!(f, "Failed to connect to the server. CloseEvent: {event:?}")
-            }
             Self::InvalidEncoding => write!(
                 f,
                 "Received a String message that couldn't be decoded to valid UTF-8"
@@ -95,9 +122,28 @@ impl fmt::Display for Error {
                 f,
                 "Received a message that is neither ArrayBuffer, String or Blob."
             ),
-            Self::Dom(code) => write!(f, "DOM Exception: {code}"),
-            Self::Other(e) => write!(f, "{e}"),
-            Self::Timeout => write!(f, "timeout"),
+            Self::ClosedByPeer(frame) => write!(
+                f,
+                "closed by peer: code={}, reason={}",
+                frame.code, frame.reason
+            ),
+            Self::Protocol(violation) => write!(f, "protocol violation: {violation}"),
+            Self::CloseTimeout => write!(f, "close timeout"),
+            Self::KeepAliveTimeout => write!(f, "keepalive timeout"),
+            Self::IdleTimeout => write!(f, "idle timeout"),
+            Self::QueueFull => write!(f, "send queue full"),
+            Self::MessageTooLarge { size, max_size } => {
+                write!(f, "message too large: {size} > {max_size}")
+            }
+            Self::LimitExceeded(limit) => write!(f, "{limit} limit exceeded"),
+            Self::InvalidUtf8 => write!(f, "invalid UTF-8 in text message"),
+            Self::MissingRequestId => write!(f, "missing request id"),
+            Self::DuplicateRequestId(id) => write!(f, "duplicate request id: {id}"),
+            Self::RequestTimeout => write!(f, "request timeout"),
+            #[cfg(feature = "json")]
+            Self::Json(e) => write!(f, "{e}"),
+            #[cfg(any(feature = "cbor", feature = "msgpack"))]
+            Self::Codec(e) => write!(f, "{e}"),
         }
     }
 }
@@ -107,3 +153,153 @@ impl From<Utf8Error> for Error {
         Self::Utf8(e)
     }
 }
+
+#[cfg(feature = "json")]
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e.to_string())
+    }
+}
+
+impl Error {
+    /// Get the error kind
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Utf8(..) | Self::InvalidEncoding | Self::InvalidUtf8 => ErrorKind::Protocol,
+            Self::InvalidWsState { .. }
+            | Self::InvalidCloseCode { .. }
+            | Self::ReasonStringToLong => ErrorKind::InvalidInput,
+            Self::ConnectionNotOpen => ErrorKind::ConnectionLost,
+            Self::ClosedByPeer(..) => ErrorKind::ClosedByPeer,
+            Self::Protocol(violation) => violation.kind(),
+            Self::CantDecodeBlob
+            | Self::UnknownDataType
+            | Self::MissingRequestId
+            | Self::DuplicateRequestId(..) => ErrorKind::InvalidData,
+            Self::QueueFull => ErrorKind::Other,
+            Self::CloseTimeout
+            | Self::KeepAliveTimeout
+            | Self::IdleTimeout
+            | Self::RequestTimeout => ErrorKind::Timeout,
+            Self::MessageTooLarge { .. } | Self::LimitExceeded(..) => ErrorKind::Limit,
+            #[cfg(feature = "json")]
+            Self::Json(..) => ErrorKind::InvalidData,
+            #[cfg(any(feature = "cbor", feature = "msgpack"))]
+            Self::Codec(..) => ErrorKind::InvalidData,
+        }
+    }
+
+    /// Check if retrying (i.e. reconnecting) may succeed
+    ///
+    /// Check [`ErrorKind::is_retryable`].
+    #[inline]
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+
+    /// Check if retrying will fail the same way
+    #[inline]
+    pub fn is_permanent(&self) -> bool {
+        self.kind().is_permanent()
+    }
+
+    #[inline]
+    pub(crate) fn not_connected() -> Self {
+        Self::ConnectionNotOpen
+    }
+
+    #[inline]
+    #[cfg(any(feature = "cbor", feature = "msgpack"))]
+    pub(crate) fn codec<E>(e: E) -> Self
+    where
+        E: fmt::Display,
+    {
+        Self::Codec(e.to_string())
+    }
+}
+
+/// Error of a connection attempt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectError {
+    /// An invalid URL was given to [WsMeta::connect](crate::WsMeta::connect), please see:
+    /// [HTML Living Standard](https://html.spec.whatwg.org/multipage/web-sockets.html#dom-websocket).
+    InvalidUrl {
+        /// The user supplied value that is invalid.
+        supplied: String,
+    },
+    /// Failed to connect to the server.
+    ConnectionFailed {
+        /// The close event that might hold extra code and reason information.
+        event: CloseEvent,
+    },
+    Dom(u16),
+    Other(String),
+    /// The server rejected the HTTP handshake
+    ///
+    /// Never returned: the browsers don't expose the response of a rejected handshake,
+    /// that fails with [`ConnectError::ConnectionFailed`] instead.
+    HandshakeRejected(Box<HandshakeRejection>),
+    /// Timeout of a connection phase
+    Timeout(Phase),
+    /// The connection attempt has been cancelled
+    Cancelled,
+    /// No URL to connect to
+    NoUrl,
+}
+
+impl std::error::Error for ConnectError {}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUrl { supplied } => write!(
+                f,
+                "An invalid URL was given to the connect method: {supplied}"
+            ),
+            Self::ConnectionFailed { event } => {
+                write!(f, "Failed to connect to the server. CloseEvent: {event:?}")
+            }
+            Self::Dom(code) => write!(f, "DOM Exception: {code}"),
+            Self::Other(e) => write!(f, "{e}"),
+            Self::HandshakeRejected(rejection) => {
+                write!(f, "handshake rejected: status={}", rejection.status)
+            }
+            Self::Timeout(phase) => write!(f, "{phase} timeout"),
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::NoUrl => write!(f, "no url to connect to"),
+        }
+    }
+}
+
+impl ConnectError {
+    /// Get the error kind
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::InvalidUrl { .. } | Self::NoUrl => ErrorKind::InvalidInput,
+            // TLS handshake failure
+            Self::ConnectionFailed { event } if event.code == 1015 => ErrorKind::Tls,
+            // The browsers hide the reason
+            Self::ConnectionFailed { .. } => ErrorKind::Unreachable,
+            // SyntaxError (invalid URL or protocols) and SecurityError (blocked port, mixed content)
+            Self::Dom(12 | 18) => ErrorKind::InvalidInput,
+            Self::Dom(..) | Self::Other(..) => ErrorKind::Other,
+            Self::HandshakeRejected(rejection) => ErrorKind::HandshakeRejected(rejection.status),
+            Self::Timeout(..) => ErrorKind::Timeout,
+            Self::Cancelled => ErrorKind::Cancelled,
+        }
+    }
+
+    /// Check if retrying (i.e. reconnecting) may succeed
+    ///
+    /// Check [`ErrorKind::is_retryable`].
+    #[inline]
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+
+    /// Check if retrying will fail the same way
+    #[inline]
+    pub fn is_permanent(&self) -> bool {
+        self.kind().is_permanent()
+    }
+}
@@ -4,6 +4,7 @@
 
 use web_sys::CloseEvent as JsCloseEvt;
 
+use crate::message::{CloseCode, CloseFrame};
 use crate::wasm::Error;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -58,6 +59,23 @@ pub struct CloseEvent {
     pub was_clean: bool,
 }
 
+impl CloseEvent {
+    /// Convert to the close frame yielded by the stream.
+    ///
+    /// `1005` means that no status code was received, so no close frame is returned (same as native).
+    pub(crate) fn into_close_frame(self) -> Option<CloseFrame> {
+        let code: CloseCode = CloseCode::from(self.code);
+        if code == CloseCode::NoStatusReceived {
+            return None;
+        }
+
+        Some(CloseFrame {
+            code,
+            reason: self.reason.into(),
+        })
+    }
+}
+
 impl From<JsCloseEvt> for CloseEvent {
     fn from(js_evt: JsCloseEvt) -> Self {
         Self {
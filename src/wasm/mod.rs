@@ -3,6 +3,13 @@
 // Distributed under the MIT software license
 
 //! Wasm
+//!
+//! Works in the browser main thread and in the workers (dedicated, shared and service workers):
+//! only the `WebSocket` and timer APIs of the global scope are used, never the `Window` ones.
+//!
+//! Node.js >= 22 (and Deno) expose a global `WebSocket`, so they are supported out of the box.
+//! For older Node.js versions and Electron, enable the `wasm-node` feature to use the `ws` npm package
+//! (must be installed as dependency of the JS package).
 
 #![allow(clippy::arc_with_non_send_sync)]
 
@@ -11,6 +18,7 @@ use std::time::Duration;
 use async_utility::{task, time};
 use url::Url;
 
+mod compat;
 mod error;
 mod event;
 mod message;
@@ -19,19 +27,84 @@ mod socket;
 mod state;
 mod stream;
 
-pub use self::error::Error;
+pub use self::error::{ConnectError, Error};
 use self::event::{CloseEvent, WsEvent};
 use self::pharos::SharedPharos;
+pub use self::socket::BinaryType;
 use self::socket::WebSocket as WasmWebSocket;
-use self::state::WsState;
+pub use self::state::WsState;
 pub(crate) use self::stream::WsStream;
-use crate::socket::WebSocket;
+use crate::socket::{InnerWebSocket, WebSocket};
+use crate::{ConnectionMode, HandshakeResponse, Phase, WebSocketBuilder};
 
-pub async fn connect(url: &Url, timeout: Duration) -> Result<WebSocket, Error> {
-    let (_ws, stream) = time::timeout(Some(timeout), WasmWebSocket::connect(url))
-        .await
-        .ok_or(Error::Timeout)??;
-    Ok(WebSocket::Wasm(stream))
+#[inline]
+pub async fn connect(url: &Url, builder: &WebSocketBuilder) -> Result<WebSocket, ConnectError> {
+    let (socket, _) = connect_with_response(url, builder).await?;
+    Ok(socket)
+}
+
+pub async fn connect_with_response(
+    url: &Url,
+    builder: &WebSocketBuilder,
+) -> Result<(WebSocket, HandshakeResponse), ConnectError> {
+    // Only direct connections are available on WASM (see `ConnectionMode` docs).
+    // Irrefutable: adding a variant without handling it here breaks the build.
+    let ConnectionMode::Direct = &builder.mode;
+
+    // The browser performs all the phases at once
+    let timeout: Duration = match builder.timeouts.handshake {
+        Some(handshake) => handshake.min(builder.timeout),
+        None => builder.timeout,
+    };
+    let (ws, stream) = time::timeout(
+        Some(timeout),
+        WasmWebSocket::connect(
+            url,
+            &builder.protocols,
+            builder.limits,
+            builder.binary_type,
+            builder.utf8_policy,
+        ),
+    )
+    .await
+    .ok_or(ConnectError::Timeout(Phase::Handshake))??;
+
+    // Empty string if no protocol was negotiated
+    let protocol: String = ws.protocol();
+    let protocol: Option<String> = if protocol.is_empty() {
+        None
+    } else {
+        Some(protocol)
+    };
+
+    // The browser doesn't expose the handshake response
+    let response: HandshakeResponse = HandshakeResponse {
+        status: 101,
+        headers: Vec::new(),
+    };
+
+    #[cfg(feature = "wasm-send")]
+    let stream: WasmStream = send_wrapper::SendWrapper::new(stream);
+
+    Ok((
+        WebSocket::new(InnerWebSocket::Wasm(stream), protocol),
+        response,
+    ))
+}
+
+/// Browser socket
+#[cfg(not(feature = "wasm-send"))]
+pub(crate) type WasmStream = WsStream;
+
+/// Browser socket, wrapped to be `Send + Sync`:
+/// it can be moved across the threads, but it panics if used (or dropped) outside the thread that created it.
+#[cfg(feature = "wasm-send")]
+pub(crate) type WasmStream = send_wrapper::SendWrapper<WsStream>;
+
+/// Get the browser socket
+#[inline]
+pub(crate) fn stream_mut(stream: &mut WasmStream) -> &mut WsStream {
+    stream
 }
 
 /// Helper function to reduce code bloat
@@ -2,17 +2,83 @@
 // Copyright (c) 2023-2024 Yuki Kishimoto
 // Distributed under the MIT software license
 
+use std::cell::RefCell;
 use std::fmt;
 use std::sync::Arc;
 
 use futures::StreamExt;
+use js_sys::Array;
 use url::Url;
 use wasm_bindgen::closure::Closure;
-use wasm_bindgen::JsCast;
-use web_sys::{BinaryType, CloseEvent as JsCloseEvt, DomException, WebSocket as WebSysSocket};
+#[cfg(feature = "wasm-node")]
+use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{
+    BinaryType as WebSysBinaryType, CloseEvent as JsCloseEvt, DomException,
+    WebSocket as WebSysSocket,
+};
 
+use crate::limits::Limits;
+use crate::message::Utf8Policy;
+use crate::wasm::compat;
 use crate::wasm::pharos::{Filter, Observable, Observe, ObserveConfig, PharErr, SharedPharos};
-use crate::wasm::{notify, CloseEvent, Error, WsEvent, WsState, WsStream};
+use crate::wasm::{notify, CloseEvent, ConnectError, WsEvent, WsState, WsStream};
+
+#[cfg(feature = "wasm-node")]
+#[wasm_bindgen(module = "ws")]
+extern "C" {
+    /// `WebSocket` class of the `ws` npm package
+    #[wasm_bindgen(js_name = WebSocket)]
+    type NodeWebSocket;
+
+    #[wasm_bindgen(constructor, js_class = "WebSocket", catch)]
+    fn new(url: &str, protocols: &Array) -> Result<NodeWebSocket, JsValue>;
+}
+
+/// Create the JS socket
+#[cfg(not(feature = "wasm-node"))]
+fn new_socket(url: &Url, protocols: &[String]) -> Result<WebSysSocket, JsValue> {
+    if protocols.is_empty() {
+        WebSysSocket::new(url.as_str())
+    } else {
+        let protocols: Array = protocols.iter().map(|p| JsValue::from_str(p)).collect();
+        WebSysSocket::new_with_str_sequence(url.as_str(), &protocols)
+    }
+}
+
+/// Create the JS socket
+///
+/// The `ws` socket exposes the same API of the browser one, so it's used through the `web_sys` bindings.
+#[cfg(feature = "wasm-node")]
+fn new_socket(url: &Url, protocols: &[String]) -> Result<WebSysSocket, JsValue> {
+    let protocols: Array = protocols.iter().map(|p| JsValue::from_str(p)).collect();
+    let socket: NodeWebSocket = NodeWebSocket::new(url.as_str(), &protocols)?;
+    Ok(socket.unchecked_into())
+}
+
+/// Type of the binary messages delivered by the browser (`binaryType`)
+///
+/// The [`Blob`](https://developer.mozilla.org/en-US/docs/Web/API/Blob) messages are read asynchronously,
+/// keeping the order of the incoming messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum BinaryType {
+    /// `ArrayBuffer` (default)
+    #[default]
+    ArrayBuffer,
+    /// `Blob`: the browser may keep large messages out of the JS heap until they are read
+    ///
+    /// Not supported by the `ws` npm package (`wasm-node` feature).
+    Blob,
+}
+
+impl From<BinaryType> for WebSysBinaryType {
+    fn from(binary_type: BinaryType) -> Self {
+        match binary_type {
+            BinaryType::ArrayBuffer => Self::Arraybuffer,
+            BinaryType::Blob => Self::Blob,
+        }
+    }
+}
 
 /// The metadata related to a websocket. Allows access to the methods on the WebSocket API.
 /// This is split from the `Stream`/`Sink` so you can pass the latter to a combinator whilst
@@ -28,29 +94,38 @@ pub struct WebSocket {
 }
 
 impl WebSocket {
-    const OPEN_CLOSE: Filter<WsEvent> =
-        Filter::Pointer(|evt: &WsEvent| evt.is_open() | evt.is_closed());
+    const CONNECT_EVENTS: Filter<WsEvent> = Filter::Pointer(|evt: &WsEvent| {
+        evt.is_open() | evt.is_closed() | matches!(evt, WsEvent::Error)
+    });
 
     /// Connect to the server. The future will resolve when the connection has been established with a successful WebSocket
     /// handshake.
-    pub async fn connect(url: &Url) -> Result<(Self, WsStream), Error> {
-        let ws: Arc<WebSysSocket> = match WebSysSocket::new(url.as_str()) {
+    pub async fn connect(
+        url: &Url,
+        protocols: &[String],
+        limits: Limits,
+        binary_type: BinaryType,
+        utf8: Utf8Policy,
+    ) -> Result<(Self, WsStream), ConnectError> {
+        #[cfg(not(feature = "wasm-node"))]
+        if !compat::has_global("WebSocket") {
+            return Err(ConnectError::Other(String::from(
+                "WebSocket API not available in this environment",
+            )));
+        }
+
+        let socket = new_socket(url, protocols);
+
+        let ws: Arc<WebSysSocket> = match socket {
             Ok(ws) => Arc::new(ws),
+            // Not all the runtimes throw a `DOMException`
             Err(e) => {
-                let de: &DomException = e.unchecked_ref();
-                return match de.code() {
-                    DomException::SYNTAX_ERR => Err(Error::InvalidUrl {
+                return match compat::dom_exception_code(&e) {
+                    Some(DomException::SYNTAX_ERR) => Err(ConnectError::InvalidUrl {
                         supplied: url.to_string(),
                     }),
-                    code => {
-                        if code == 0 {
-                            Err(Error::Other(
-                                e.as_string().unwrap_or_else(|| String::from("None")),
-                            ))
-                        } else {
-                            Err(Error::Dom(code))
-                        }
-                    }
+                    Some(code) if code != 0 => Err(ConnectError::Dom(code)),
+                    _ => Err(ConnectError::Other(compat::error_message(&e))),
                 };
             }
         };
@@ -62,6 +137,10 @@ impl WebSocket {
         let ph3 = pharos.clone();
         let ph4 = pharos.clone();
 
+        // The close event, to be yielded as last item of the stream
+        let close_evt: Arc<RefCell<Option<CloseEvent>>> = Arc::new(RefCell::new(None));
+        let ce2 = close_evt.clone();
+
         // Setup our event listeners
         let on_open = Closure::wrap(Box::new(move || {
             // notify observers
@@ -77,13 +156,12 @@ impl WebSocket {
 
         #[allow(trivial_casts)]
         let on_close = Closure::wrap(Box::new(move |evt: JsCloseEvt| {
-            let c = WsEvent::Closed(CloseEvent {
-                code: evt.code(),
-                reason: evt.reason(),
-                was_clean: evt.was_clean(),
-            });
+            let evt: CloseEvent = CloseEvent::from(evt);
+
+            // Store it synchronously: the ready state is already `Closed` at this point.
+            *ce2.borrow_mut() = Some(evt.clone());
 
-            notify(ph3.clone(), c)
+            notify(ph3.clone(), WsEvent::Closed(evt))
         }) as Box<dyn FnMut(JsCloseEvt)>);
 
         ws.set_onopen(Some(on_open.as_ref().unchecked_ref()));
@@ -120,26 +198,40 @@ impl WebSocket {
             Guard { ws: &ws }
         };
 
-        // Listen to the events to figure out whether the connection opens successfully. We don't want to deal with
-        // the error event. Either a close event happens, in which case we want to recover the CloseEvent to return it
-        // to the user, or an Open event happens in which case we are happy campers.
+        // Listen to the events to figure out whether the connection opens successfully. Either a close event happens,
+        // in which case we want to recover the CloseEvent to return it to the user, or an Open event happens in which
+        // case we are happy campers.
         let mut evts = pharos
-            .observe(Self::OPEN_CLOSE.into())
+            .observe(Self::CONNECT_EVENTS.into())
             .await
             .expect("we didn't close pharos");
 
-        // If the connection is closed, return error
-
-        if let Some(WsEvent::Closed(evt)) = evts.next().await {
-            return Err(Error::ConnectionFailed { event: evt });
+        loop {
+            match evts.next().await {
+                Some(WsEvent::Closed(evt)) => {
+                    return Err(ConnectError::ConnectionFailed { event: evt })
+                }
+                // Some runtimes (ex. browser extensions) fire only the error event, without the close one
+                Some(WsEvent::Error) => {
+                    if let Ok(WsState::Closed) = ws.ready_state().try_into() {
+                        return Err(ConnectError::ConnectionFailed {
+                            event: CloseEvent {
+                                code: 1006,
+                                reason: String::new(),
+                                was_clean: false,
+                            },
+                        });
+                    }
+                }
+                _ => break,
+            }
         }
 
         // We have now passed all the `await` points in this function and so the `WsStream` construction is guaranteed
         // so we let it take over the responsibility of unregistering the callbacks by disabling our guard.
         std::mem::forget(guard);
 
-        // We don't handle Blob's
-        ws.set_binary_type(BinaryType::Arraybuffer);
+        ws.set_binary_type(binary_type.into());
 
         Ok((
             Self {
@@ -149,9 +241,12 @@ impl WebSocket {
             WsStream::new(
                 ws,
                 ph4,
+                close_evt,
                 Arc::new(on_open),
                 Arc::new(on_error),
                 Arc::new(on_close),
+                limits,
+                utf8,
             ),
         ))
     }
@@ -160,6 +255,11 @@ impl WebSocket {
     pub fn url(&self) -> String {
         self.ws.url()
     }
+
+    /// Retrieve the subprotocol selected by the server (empty string if none).
+    pub fn protocol(&self) -> String {
+        self.ws.protocol()
+    }
 }
 
 impl fmt::Debug for WebSocket {
@@ -9,17 +9,28 @@ use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll, Waker};
+use std::time::Duration;
 
-use async_utility::task;
+use async_utility::{task, time};
 use futures::prelude::{Sink, Stream};
 use futures::{ready, FutureExt, StreamExt};
+use js_sys::{ArrayBuffer, Uint8Array};
 use wasm_bindgen::closure::Closure;
-use wasm_bindgen::JsCast;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
 use web_sys::{CloseEvent as JsCloseEvt, WebSocket, *};
 
-use crate::message::Message;
+use crate::limits::{Limit, Limits};
+use crate::message::{Message, Utf8Policy};
 use crate::wasm::pharos::{Filter, Observable, SharedPharos};
-use crate::wasm::{notify, Error, WsEvent, WsState};
+use crate::wasm::{compat, message};
+use crate::wasm::{notify, CloseEvent, Error, WsEvent, WsState};
+
+/// Received message, `None` until read (for blobs)
+type Slot = Arc<RefCell<Option<Result<Message, Error>>>>;
+
+/// Interval between the checks of the buffered amount, while waiting for it to be drained
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
 
 /// A futures 0.3 Sink/Stream of [Message]. Created with [WsMeta::connect](crate::WsMeta::connect).
 ///
@@ -43,8 +54,8 @@ use crate::wasm::{notify, Error, WsEvent, WsState};
 pub struct WsStream {
     ws: Arc<WebSocket>,
 
-    // The queue of received messages
-    queue: Arc<RefCell<VecDeque<Message>>>,
+    // The queue of received messages (or of the limit error)
+    queue: Arc<RefCell<VecDeque<Slot>>>,
 
     // Last waker of task that wants to read incoming messages to be woken up on a new message
     waker: Arc<RefCell<Option<Waker>>>,
@@ -55,6 +66,9 @@ pub struct WsStream {
     // A pointer to the pharos of WsMeta for when we need to listen to events
     pharos: SharedPharos<WsEvent>,
 
+    // The close event, yielded as last message of the stream
+    close_evt: Arc<RefCell<Option<CloseEvent>>>,
+
     // The callback closures.
     _on_open: Arc<Closure<dyn FnMut()>>,
     _on_error: Arc<Closure<dyn FnMut()>>,
@@ -70,9 +84,12 @@ impl WsStream {
     pub(crate) fn new(
         ws: Arc<WebSocket>,
         pharos: SharedPharos<WsEvent>,
+        close_evt: Arc<RefCell<Option<CloseEvent>>>,
         on_open: Arc<Closure<dyn FnMut()>>,
         on_error: Arc<Closure<dyn FnMut()>>,
         on_close: Arc<Closure<dyn FnMut(JsCloseEvt)>>,
+        limits: Limits,
+        utf8: Utf8Policy,
     ) -> Self {
         let waker: Arc<RefCell<Option<Waker>>> = Arc::new(RefCell::new(None));
         let sink_waker: Arc<RefCell<Option<Waker>>> = Arc::new(RefCell::new(None));
@@ -81,15 +98,89 @@ impl WsStream {
         let q2 = queue.clone();
         let w2 = waker.clone();
         let ph2 = pharos.clone();
+        let ws2 = ws.clone();
+
+        // Set when a limit is exceeded: the following messages are discarded
+        let mut exceeded: bool = false;
 
         // Send the incoming ws messages to the WsMeta object
         #[allow(trivial_casts)]
         let on_msg = Closure::wrap(Box::new(move |msg_evt: MessageEvent| {
-            match Message::try_from(msg_evt) {
-                Ok(msg) => q2.borrow_mut().push_back(msg),
-                Err(err) => notify(ph2.clone(), WsEvent::WsErr(err)),
+            if exceeded {
+                return;
             }
 
+            // The blobs are read asynchronously, but their size is known in advance
+            let data: JsValue = msg_evt.data();
+            let blob: Option<Blob> = if compat::is_blob(&data) {
+                Some(data.unchecked_into())
+            } else {
+                None
+            };
+            let (size, msg) = match &blob {
+                Some(blob) => (blob.size() as usize, None),
+                None => match message::from_event(msg_evt, utf8) {
+                    Ok(msg) => (msg.len(), Some(msg)),
+                    Err(err) => {
+                        // Yield the error, like the native stream does
+                        q2.borrow_mut()
+                            .push_back(Arc::new(RefCell::new(Some(Err(err.clone())))));
+                        notify(ph2.clone(), WsEvent::WsErr(err));
+
+                        if let Some(w) = w2.borrow_mut().take() {
+                            w.wake()
+                        }
+                        return;
+                    }
+                },
+            };
+
+            let mut queue = q2.borrow_mut();
+
+            // The browser has already received the whole message: just discard it
+            let error: Option<Error> = if limits.exceeds_message_size(size) {
+                Some(Error::MessageTooLarge {
+                    size,
+                    max_size: limits.max_message_size.unwrap_or_default(),
+                })
+            } else if limits
+                .max_buffered_messages
+                .is_some_and(|max| queue.len() >= max)
+            {
+                Some(Error::LimitExceeded(Limit::BufferedMessages))
+            } else {
+                None
+            };
+
+            match (error, msg, blob) {
+                (Some(e), ..) => {
+                    exceeded = true;
+                    queue.push_back(Arc::new(RefCell::new(Some(Err(e)))));
+
+                    // Stop receiving
+                    let _ = ws2.close();
+                    notify(ph2.clone(), WsEvent::Closing);
+                }
+                (None, Some(msg), _) => queue.push_back(Arc::new(RefCell::new(Some(Ok(msg))))),
+                (None, None, Some(blob)) => {
+                    // Keep the order: the slot is filled when the blob has been read
+                    let slot: Slot = Arc::new(RefCell::new(None));
+                    queue.push_back(slot.clone());
+
+                    let w3 = w2.clone();
+                    task::spawn(async move {
+                        *slot.borrow_mut() = Some(read_blob(blob).await);
+
+                        if let Some(w) = w3.borrow_mut().take() {
+                            w.wake()
+                        }
+                    });
+                }
+                (None, None, None) => {}
+            }
+
+            drop(queue);
+
             if let Some(w) = w2.borrow_mut().take() {
                 w.wake()
             }
@@ -137,6 +228,7 @@ impl WsStream {
             waker,
             sink_waker,
             pharos,
+            close_evt,
             closer: None,
             _on_msg: Arc::new(on_msg),
             _on_open: on_open,
@@ -150,6 +242,32 @@ impl WsStream {
         self.ws.ready_state().try_into()
     }
 
+    /// Number of bytes queued by the browser but not transmitted yet ([`bufferedAmount`](https://developer.mozilla.org/en-US/docs/Web/API/WebSocket/bufferedAmount))
+    ///
+    /// The browser accepts all the sent messages, so this is the only way to apply backpressure.
+    pub fn buffered_amount(&self) -> u32 {
+        self.ws.buffered_amount()
+    }
+
+    /// Wait until all the queued data has been transmitted
+    ///
+    /// The browser doesn't emit any event for this, so the buffered amount is checked periodically.
+    /// Returns [`Error::ConnectionNotOpen`] if the connection closes with data still queued.
+    pub async fn wait_drained(&self) -> Result<(), Error> {
+        loop {
+            if self.buffered_amount() == 0 {
+                return Ok(());
+            }
+
+            match self.ready_state()? {
+                WsState::Connecting | WsState::Open => {}
+                WsState::Closing | WsState::Closed => return Err(Error::ConnectionNotOpen),
+            }
+
+            time::sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+    }
+
     /// Access the wrapped [web_sys::WebSocket](https://docs.rs/web-sys/0.3.25/web_sys/struct.WebSocket.html) directly.
     ///
     /// _ws_stream_wasm_ tries to expose all useful functionality through an idiomatic rust API, so hopefully
@@ -163,6 +281,15 @@ impl WsStream {
     }
 }
 
+/// Read the content of a blob message
+async fn read_blob(blob: Blob) -> Result<Message, Error> {
+    let buf: JsValue = JsFuture::from(blob.array_buffer())
+        .await
+        .map_err(|_| Error::CantDecodeBlob)?;
+    let buf: ArrayBuffer = buf.dyn_into().map_err(|_| Error::CantDecodeBlob)?;
+    Ok(Message::Binary(Uint8Array::new(&buf).to_vec().into()))
+}
+
 impl fmt::Debug for WsStream {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "WsStream for connection: {}", self.ws.url())
@@ -205,18 +332,41 @@ impl Stream for WsStream {
     // day we will be able to receive the MessageEvt directly in WASM.
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         // Once the queue is empty, check the state of the connection.
-        // When it is closing or closed, no more messages will arrive, so
-        // return Poll::Ready( None )
-        if self.queue.borrow().is_empty() {
-            *self.waker.borrow_mut() = Some(cx.waker().clone());
-
-            match self.ready_state() {
-                Ok(WsState::Open) | Ok(WsState::Connecting) => Poll::Pending,
-                _ => None.into(),
+        // When it is closing, wait for the close event.
+        // When it is closed, yield the close frame (once) and then return Poll::Ready( None ),
+        // like the native stream does.
+        let front_ready: Option<bool> = self
+            .queue
+            .borrow()
+            .front()
+            .map(|slot| slot.borrow().is_some());
+
+        match front_ready {
+            None => {
+                *self.waker.borrow_mut() = Some(cx.waker().clone());
+
+                match self.ready_state() {
+                    Ok(WsState::Open) | Ok(WsState::Connecting) | Ok(WsState::Closing) => {
+                        Poll::Pending
+                    }
+                    _ => match self.close_evt.borrow_mut().take() {
+                        Some(evt) => Some(Ok(Message::Close(evt.into_close_frame()))).into(),
+                        None => None.into(),
+                    },
+                }
+            }
+            // Waiting for a blob to be read
+            Some(false) => {
+                *self.waker.borrow_mut() = Some(cx.waker().clone());
+                Poll::Pending
             }
-        } else {
             // As long as there is things in the queue, just keep reading
-            self.queue.borrow_mut().pop_front().map(Ok).into()
+            Some(true) => self
+                .queue
+                .borrow_mut()
+                .pop_front()
+                .and_then(|slot| slot.take())
+                .into(),
         }
     }
 }
@@ -255,6 +405,28 @@ impl Sink<Message> for WsStream {
                         .ws
                         .send_with_str(&s)
                         .map_err(|_| Error::ConnectionNotOpen)?,
+                    // The browser answers to pings automatically and doesn't allow to send control frames
+                    Message::Ping(..) | Message::Pong(..) => {}
+                    Message::Close(None) => {
+                        self.ws.close().map_err(|_| Error::ConnectionNotOpen)?;
+                        notify(self.pharos.clone(), WsEvent::Closing);
+                    }
+                    Message::Close(Some(frame)) => {
+                        // The web API only accepts 1000 or a code in the 3000-4999 range
+                        let code: u16 = frame.code.as_u16();
+                        if code != 1000 && !(3000..=4999).contains(&code) {
+                            return Err(Error::InvalidCloseCode { supplied: code });
+                        }
+
+                        if frame.reason.len() > 123 {
+                            return Err(Error::ReasonStringToLong);
+                        }
+
+                        self.ws
+                            .close_with_code_and_reason(code, &frame.reason)
+                            .map_err(|_| Error::ConnectionNotOpen)?;
+                        notify(self.pharos.clone(), WsEvent::Closing);
+                    }
                 }
 
                 Ok(())
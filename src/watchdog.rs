@@ -0,0 +1,399 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Connection watchdog
+//!
+//! Watches the [`StatsHandle`] of a connection and raises a [`WatchdogAlert`] when the connectivity degrades:
+//! no inbound traffic, send backlog or reconnect storm.
+//!
+//! Every alert is raised once, when its condition starts, and re-armed when the condition is cleared.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_channel::mpsc::{self, UnboundedReceiver};
+use futures_util::future::AbortHandle;
+use futures_util::{Stream, StreamExt};
+
+use crate::stats::ConnectionStats;
+use crate::time::{self, Instant};
+use crate::{half, BoxedFuture, StatsHandle};
+
+/// Reconnect storm threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ReconnectStorm {
+    /// Max number of reconnections within the window
+    pub max_reconnects: u32,
+    /// Window
+    pub window: Duration,
+}
+
+impl Default for ReconnectStorm {
+    fn default() -> Self {
+        Self {
+            max_reconnects: 5,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Watchdog config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WatchdogConfig {
+    /// Alert if no message is received for this long (default: 60 secs)
+    pub inbound_timeout: Option<Duration>,
+    /// Alert if the send queue is larger than this, in bytes (default: disabled)
+    ///
+    /// Requires a [`SendQueue`](crate::SendQueue) reporting to the stats, check [`SendQueue::with_stats`](crate::SendQueue::with_stats).
+    pub max_queued_bytes: Option<u64>,
+    /// Alert if the connection is re-established too often (default: 5 times per min)
+    ///
+    /// Only counted by [`ReconnectingWebSocket`](crate::reconnect::ReconnectingWebSocket).
+    pub reconnect_storm: Option<ReconnectStorm>,
+    /// Interval of the checks (default: 1 sec)
+    pub check_interval: Duration,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            inbound_timeout: Some(Duration::from_secs(60)),
+            max_queued_bytes: None,
+            reconnect_storm: Some(ReconnectStorm::default()),
+            check_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Watchdog alert
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WatchdogAlert {
+    /// No message received for the [`WatchdogConfig::inbound_timeout`]
+    InboundSilence {
+        /// Time since the last received message (or since the start of the watchdog)
+        silence: Duration,
+    },
+    /// The send queue exceeded [`WatchdogConfig::max_queued_bytes`]
+    QueueBacklog {
+        /// Size of the queued messages, in bytes
+        queued_bytes: u64,
+    },
+    /// Too many reconnections, check [`WatchdogConfig::reconnect_storm`]
+    ReconnectStorm {
+        /// Reconnections within the window
+        reconnects: u32,
+        /// Window
+        window: Duration,
+    },
+}
+
+/// Connection watchdog
+///
+/// A [`Stream`] of the [`WatchdogAlert`]s, that never ends.
+/// Use [`Watchdog::spawn`] to run a callback or [`Watchdog::channel`] to get a channel instead.
+pub struct Watchdog {
+    stats: StatsHandle,
+    config: WatchdogConfig,
+    tick: BoxedFuture<'static, ()>,
+    /// Received messages at the last check
+    received: u64,
+    /// Time of the last received message
+    last_received: Instant,
+    /// Reconnections at the last check
+    reconnects: u32,
+    /// Time of the recent reconnections
+    recent_reconnects: VecDeque<Instant>,
+    silence_alerted: bool,
+    backlog_alerted: bool,
+    storm_alerted: bool,
+    pending: VecDeque<WatchdogAlert>,
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Watchdog {
+    /// Watch the connection of `stats`
+    pub fn new(stats: StatsHandle, config: WatchdogConfig) -> Self {
+        let snapshot: ConnectionStats = stats.snapshot();
+        Self {
+            stats,
+            tick: Box::pin(time::sleep(config.check_interval)),
+            config,
+            received: snapshot.messages_received,
+            last_received: Instant::now(),
+            reconnects: snapshot.reconnects,
+            recent_reconnects: VecDeque::new(),
+            silence_alerted: false,
+            backlog_alerted: false,
+            storm_alerted: false,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Run `callback` on every alert, in a background task
+    ///
+    /// On native targets, the task is spawned on the current tokio runtime.
+    /// The task is stopped when the returned [`WatchdogHandle`] is dropped.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn spawn<F>(mut self, mut callback: F) -> WatchdogHandle
+    where
+        F: FnMut(WatchdogAlert) + Send + 'static,
+    {
+        let task: AbortHandle = half::spawn(async move {
+            while let Some(alert) = self.next().await {
+                callback(alert);
+            }
+        });
+        WatchdogHandle { task }
+    }
+
+    /// Run `callback` on every alert, in a background task
+    ///
+    /// The task is stopped when the returned [`WatchdogHandle`] is dropped.
+    #[cfg(target_arch = "wasm32")]
+    pub fn spawn<F>(mut self, mut callback: F) -> WatchdogHandle
+    where
+        F: FnMut(WatchdogAlert) + 'static,
+    {
+        let task: AbortHandle = half::spawn(async move {
+            while let Some(alert) = self.next().await {
+                callback(alert);
+            }
+        });
+        WatchdogHandle { task }
+    }
+
+    /// Send the alerts to a channel, from a background task
+    ///
+    /// The task is stopped when the returned [`WatchdogHandle`] is dropped.
+    pub fn channel(self) -> (WatchdogHandle, UnboundedReceiver<WatchdogAlert>) {
+        let (tx, rx) = mpsc::unbounded();
+        let handle: WatchdogHandle = self.spawn(move |alert| {
+            let _ = tx.unbounded_send(alert);
+        });
+        (handle, rx)
+    }
+
+    fn check(&mut self) {
+        let stats: ConnectionStats = self.stats.snapshot();
+        let now: Instant = Instant::now();
+
+        // Inbound silence
+        if stats.messages_received != self.received {
+            self.received = stats.messages_received;
+            self.last_received = now;
+            self.silence_alerted = false;
+        }
+
+        if let Some(timeout) = self.config.inbound_timeout {
+            let silence: Duration = self.last_received.elapsed();
+            if silence >= timeout && !self.silence_alerted {
+                self.silence_alerted = true;
+                self.pending
+                    .push_back(WatchdogAlert::InboundSilence { silence });
+            }
+        }
+
+        // Send backlog
+        if let Some(max) = self.config.max_queued_bytes {
+            if stats.queued_bytes > max {
+                if !self.backlog_alerted {
+                    self.backlog_alerted = true;
+                    self.pending.push_back(WatchdogAlert::QueueBacklog {
+                        queued_bytes: stats.queued_bytes,
+                    });
+                }
+            } else {
+                self.backlog_alerted = false;
+            }
+        }
+
+        // Reconnect storm
+        let new_reconnects: u32 = stats.reconnects.saturating_sub(self.reconnects);
+        self.reconnects = stats.reconnects;
+        for _ in 0..new_reconnects {
+            self.recent_reconnects.push_back(now);
+        }
+
+        if let Some(storm) = self.config.reconnect_storm {
+            while self
+                .recent_reconnects
+                .front()
+                .is_some_and(|t| t.elapsed() > storm.window)
+            {
+                self.recent_reconnects.pop_front();
+            }
+
+            let reconnects: u32 = self.recent_reconnects.len() as u32;
+            if reconnects > storm.max_reconnects {
+                if !self.storm_alerted {
+                    self.storm_alerted = true;
+                    self.pending.push_back(WatchdogAlert::ReconnectStorm {
+                        reconnects,
+                        window: storm.window,
+                    });
+                }
+            } else {
+                self.storm_alerted = false;
+            }
+        }
+    }
+}
+
+impl Stream for Watchdog {
+    type Item = WatchdogAlert;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(alert) = this.pending.pop_front() {
+                return Poll::Ready(Some(alert));
+            }
+
+            if this.tick.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+
+            this.tick = Box::pin(time::sleep(this.config.check_interval));
+            this.check();
+        }
+    }
+}
+
+/// Handle of a spawned [`Watchdog`]
+///
+/// The watchdog is stopped when dropped.
+pub struct WatchdogHandle {
+    task: AbortHandle,
+}
+
+impl fmt::Debug for WatchdogHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WatchdogHandle").finish_non_exhaustive()
+    }
+}
+
+impl Drop for WatchdogHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::Message;
+
+    fn config() -> WatchdogConfig {
+        WatchdogConfig {
+            inbound_timeout: None,
+            max_queued_bytes: None,
+            reconnect_storm: None,
+            check_interval: Duration::from_secs(1),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_inbound_silence() {
+        let stats = StatsHandle::default();
+        let mut watchdog = Watchdog::new(
+            stats.clone(),
+            WatchdogConfig {
+                inbound_timeout: Some(Duration::from_secs(5)),
+                ..config()
+            },
+        );
+
+        let start = Instant::now();
+        assert_eq!(
+            watchdog.next().await,
+            Some(WatchdogAlert::InboundSilence {
+                silence: Duration::from_secs(5)
+            })
+        );
+
+        // Re-armed by a received message, seen by the next check
+        stats.on_received(&Message::text("hello"));
+        assert_eq!(
+            watchdog.next().await,
+            Some(WatchdogAlert::InboundSilence {
+                silence: Duration::from_secs(5)
+            })
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(11));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queue_backlog() {
+        let stats = StatsHandle::default();
+        let watchdog = Watchdog::new(
+            stats.clone(),
+            WatchdogConfig {
+                max_queued_bytes: Some(100),
+                ..config()
+            },
+        );
+        let (_handle, mut alerts) = watchdog.channel();
+
+        stats.set_queued_bytes(200);
+        assert_eq!(
+            alerts.next().await,
+            Some(WatchdogAlert::QueueBacklog { queued_bytes: 200 })
+        );
+
+        // Raised once while the condition lasts
+        assert!(time::timeout(Duration::from_secs(10), alerts.next())
+            .await
+            .is_none());
+
+        stats.set_queued_bytes(0);
+        time::sleep(Duration::from_secs(1)).await;
+        stats.set_queued_bytes(300);
+        assert_eq!(
+            alerts.next().await,
+            Some(WatchdogAlert::QueueBacklog { queued_bytes: 300 })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnect_storm() {
+        let stats = StatsHandle::default();
+        let storm = ReconnectStorm {
+            max_reconnects: 2,
+            window: Duration::from_secs(60),
+        };
+        let mut watchdog = Watchdog::new(
+            stats.clone(),
+            WatchdogConfig {
+                reconnect_storm: Some(storm),
+                ..config()
+            },
+        );
+
+        for _ in 0..3 {
+            stats.on_reconnected();
+        }
+        assert_eq!(
+            watchdog.next().await,
+            Some(WatchdogAlert::ReconnectStorm {
+                reconnects: 3,
+                window: storm.window
+            })
+        );
+
+        // Nothing else within the window
+        assert!(time::timeout(Duration::from_secs(30), watchdog.next())
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,24 @@
+// Copyright (c) 2022-2024 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! WASM backend in a dedicated worker (no `Window`)
+//!
+//! Run with `make test-wasm-worker`.
+
+#![cfg(target_arch = "wasm32")]
+
+use std::time::Duration;
+
+use async_wsocket::{ConnectError, ConnectionMode};
+use url::Url;
+use wasm_bindgen_test::{wasm_bindgen_test, wasm_bindgen_test_configure};
+
+wasm_bindgen_test_configure!(run_in_dedicated_worker);
+
+#[wasm_bindgen_test]
+async fn connection_refused() {
+    // Nothing listens on port 1
+    let url: Url = Url::parse("ws://127.0.0.1:1").unwrap();
+    let res = async_wsocket::connect(&url, &ConnectionMode::Direct, Duration::from_secs(10)).await;
+    assert!(matches!(res, Err(ConnectError::ConnectionFailed { .. })));
+}